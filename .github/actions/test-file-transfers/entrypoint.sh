@@ -54,13 +54,13 @@ cargo build --bin aquatic
 
 # echo "log_level = 'debug'
 # 
-# [network]
+# [[network.listeners]]
 # address = '127.0.0.1:3000'" > http.toml
 # ./target/debug/aquatic http -c http.toml > "$HOME/http.log" 2>&1 &
 
 echo "log_level = 'debug'
 
-[network]
+[[network.listeners]]
 address = '127.0.0.1:3001'
 enable_tls = true
 tls_certificate_path = './cert.crt'
@@ -0,0 +1,129 @@
+use std::path::PathBuf;
+
+use aquatic_common::admin::{send_admin_request, AdminConfig, AdminRequest, AdminResponse};
+use aquatic_common::cli::LogLevel;
+
+const DEFAULT_TOP_TORRENTS_LIMIT: usize = 10;
+
+/// Run `aquatic ctl` subcommand and return exit code
+pub fn run<I>(arg_iter: I) -> i32
+where
+    I: Iterator<Item = String>,
+{
+    let (path, request) = match parse_args(arg_iter) {
+        Ok(args) => args,
+        Err(opt_err) => {
+            print_help(opt_err.as_deref());
+
+            return if opt_err.is_some() { 1 } else { 0 };
+        }
+    };
+
+    match send_admin_request(&path, &request) {
+        Ok(response) => {
+            println!("{}", serde_json::to_string_pretty(&response).unwrap());
+
+            if let AdminResponse::Error { .. } = response {
+                1
+            } else {
+                0
+            }
+        }
+        Err(err) => {
+            eprintln!("Error: {:#}", err);
+
+            1
+        }
+    }
+}
+
+fn parse_args<I>(mut arg_iter: I) -> Result<(PathBuf, AdminRequest), Option<String>>
+where
+    I: Iterator<Item = String>,
+{
+    let mut path = AdminConfig::default().path;
+
+    let command = loop {
+        match arg_iter.next().as_deref() {
+            Some("-s" | "--socket") => {
+                path = arg_iter
+                    .next()
+                    .ok_or_else(|| Some("No socket path given".to_string()))?
+                    .into();
+            }
+            Some("-h" | "--help") | None => return Err(None),
+            Some(command) => break command.to_string(),
+        }
+    };
+
+    let mut next_arg = |name: &str| {
+        arg_iter
+            .next()
+            .ok_or_else(|| Some(format!("No {} given", name)))
+    };
+
+    let request = match command.as_str() {
+        "access-list-add" => AdminRequest::AccessListAdd {
+            info_hash: parse_info_hash(&next_arg("info hash")?)?,
+        },
+        "access-list-remove" => AdminRequest::AccessListRemove {
+            info_hash: parse_info_hash(&next_arg("info hash")?)?,
+        },
+        "torrent" => AdminRequest::Torrent {
+            info_hash: parse_info_hash(&next_arg("info hash")?)?,
+        },
+        "top-torrents" => AdminRequest::TopTorrents {
+            limit: match arg_iter.next() {
+                Some(limit) => limit
+                    .parse()
+                    .map_err(|_| Some("Invalid limit".to_string()))?,
+                None => DEFAULT_TOP_TORRENTS_LIMIT,
+            },
+        },
+        "set-log-level" => AdminRequest::SetLogLevel {
+            level: next_arg("log level")?
+                .parse::<LogLevel>()
+                .map_err(|err| Some(err.to_string()))?,
+        },
+        "reload" => AdminRequest::Reload,
+        _ => return Err(Some("Invalid command".to_string())),
+    };
+
+    Ok((path, request))
+}
+
+fn parse_info_hash(text: &str) -> Result<[u8; 20], Option<String>> {
+    let mut info_hash = [0u8; 20];
+
+    hex::decode_to_slice(text, &mut info_hash)
+        .map_err(|_| Some("Info hash must be 40 hex characters".to_string()))?;
+
+    Ok(info_hash)
+}
+
+fn print_help(opt_error: Option<&str>) {
+    let app_path = ::std::env::args().next().unwrap();
+
+    println!("Send command to running tracker over admin socket");
+    println!("\nUsage: {} ctl [OPTIONS] COMMAND [ARGS]", app_path);
+    println!("\nCommands:");
+    println!("    access-list-add INFO_HASH       Add info hash to access list");
+    println!("    access-list-remove INFO_HASH    Remove info hash from access list");
+    println!("    torrent INFO_HASH               Print torrent statistics");
+    println!(
+        "    top-torrents [LIMIT]            Print statistics of torrents with most peers (default {})",
+        DEFAULT_TOP_TORRENTS_LIMIT
+    );
+    println!("    set-log-level LEVEL             Set log level (off, error, warn, info, debug or trace)");
+    println!("    reload                          Reload files as on SIGUSR1");
+    println!("\nOptions:");
+    println!(
+        "    -s, --socket PATH    Admin socket path (default {})",
+        AdminConfig::default().path.display()
+    );
+    println!("    -h, --help           Print this help message");
+
+    if let Some(error) = opt_error {
+        println!("\nError: {}.", error);
+    }
+}
@@ -3,12 +3,18 @@ use aquatic_http::config::Config as HttpConfig;
 use aquatic_udp::config::Config as UdpConfig;
 use aquatic_ws::config::Config as WsConfig;
 
+mod ctl;
+
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 const APP_NAME: &str = "aquatic: BitTorrent tracker";
 
 fn main() {
+    if ::std::env::args().nth(1).as_deref() == Some("ctl") {
+        ::std::process::exit(ctl::run(::std::env::args().skip(2)));
+    }
+
     ::std::process::exit(match run() {
         Ok(()) => 0,
         Err(None) => {
@@ -86,6 +92,10 @@ fn gen_info() -> String {
     info.push_str("\n    udp                   BitTorrent over UDP");
     info.push_str("\n    http                  BitTorrent over HTTP");
     info.push_str("\n    ws                    WebTorrent");
+    info.push_str(&format!(
+        "\n\nRun '{} ctl --help' for sending commands to a running tracker.",
+        app_path
+    ));
 
     info
 }
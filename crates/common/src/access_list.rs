@@ -1,6 +1,6 @@
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::Context;
@@ -8,6 +8,10 @@ use aquatic_toml_config::TomlConfig;
 use arc_swap::{ArcSwap, Cache};
 use hashbrown::HashSet;
 use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+/// Maximum nesting depth of bencoded values in torrent files
+const MAX_BENCODE_DEPTH: usize = 64;
 
 /// Access list mode. Available modes are allow, deny and off.
 #[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
@@ -27,11 +31,27 @@ impl AccessListMode {
     }
 }
 
+/// Access list source. Available sources are hex_file, torrent_directory
+/// and magnet_file.
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessListSource {
+    /// File consisting of newline-separated hex-encoded info hashes
+    HexFile,
+    /// Directory of .torrent files. Info hashes are calculated from the
+    /// info dictionaries.
+    TorrentDirectory,
+    /// File consisting of newline-separated magnet links with hex or base32
+    /// encoded BitTorrent info hashes
+    MagnetFile,
+}
+
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct AccessListConfig {
     pub mode: AccessListMode,
-    /// Path to access list file consisting of newline-separated hex-encoded info hashes.
+    pub source: AccessListSource,
+    /// Path to access list file or directory, depending on source.
     ///
     /// If using chroot mode, path must be relative to new root.
     pub path: PathBuf,
@@ -41,6 +61,7 @@ impl Default for AccessListConfig {
     fn default() -> Self {
         Self {
             path: "./access-list.txt".into(),
+            source: AccessListSource::HexFile,
             mode: AccessListMode::Off,
         }
     }
@@ -56,7 +77,63 @@ impl AccessList {
         Ok(())
     }
 
+    pub fn insert(&mut self, info_hash: [u8; 20]) {
+        self.0.insert(info_hash);
+    }
+
+    pub fn remove(&mut self, info_hash: &[u8; 20]) {
+        self.0.remove(info_hash);
+    }
+
+    pub fn create(config: &AccessListConfig) -> anyhow::Result<Self> {
+        match config.source {
+            AccessListSource::HexFile => Self::create_from_path(&config.path),
+            AccessListSource::TorrentDirectory => Self::create_from_torrent_directory(&config.path),
+            AccessListSource::MagnetFile => Self::create_from_magnet_file(&config.path),
+        }
+    }
+
     pub fn create_from_path(path: &PathBuf) -> anyhow::Result<Self> {
+        Self::create_from_lines(path, parse_info_hash)
+    }
+
+    pub fn create_from_magnet_file(path: &PathBuf) -> anyhow::Result<Self> {
+        Self::create_from_lines(path, parse_magnet_info_hash)
+    }
+
+    pub fn create_from_torrent_directory(path: &Path) -> anyhow::Result<Self> {
+        let mut new_list = Self::default();
+
+        for entry in ::std::fs::read_dir(path)
+            .with_context(|| format!("Read directory {}", path.display()))?
+        {
+            let path = entry?.path();
+
+            let is_torrent_file = path
+                .extension()
+                .map(|extension| extension.eq_ignore_ascii_case("torrent"))
+                .unwrap_or(false);
+
+            if !is_torrent_file || !path.is_file() {
+                continue;
+            }
+
+            let bytes = ::std::fs::read(&path)
+                .with_context(|| format!("Read torrent file {}", path.display()))?;
+
+            new_list.0.insert(
+                calculate_torrent_info_hash(&bytes)
+                    .with_context(|| format!("Invalid torrent file {}", path.display()))?,
+            );
+        }
+
+        Ok(new_list)
+    }
+
+    fn create_from_lines(
+        path: &PathBuf,
+        parse: fn(&str) -> anyhow::Result<[u8; 20]>,
+    ) -> anyhow::Result<Self> {
         let file = File::open(path)?;
         let reader = BufReader::new(file);
 
@@ -70,9 +147,9 @@ impl AccessList {
                 continue;
             }
 
-            new_list
-                .insert_from_line(&line)
-                .with_context(|| format!("Invalid line in access list: {}", line))?;
+            new_list.0.insert(
+                parse(line).with_context(|| format!("Invalid line in access list: {}", line))?,
+            );
         }
 
         Ok(new_list)
@@ -101,7 +178,7 @@ pub type AccessListCache = Cache<Arc<AccessListArcSwap>, Arc<AccessList>>;
 
 impl AccessListQuery for AccessListArcSwap {
     fn update(&self, config: &AccessListConfig) -> anyhow::Result<()> {
-        self.store(Arc::new(AccessList::create_from_path(&config.path)?));
+        self.store(Arc::new(AccessList::create(config)?));
 
         Ok(())
     }
@@ -147,6 +224,120 @@ fn parse_info_hash(line: &str) -> anyhow::Result<[u8; 20]> {
     Ok(bytes)
 }
 
+/// Parse BitTorrent v1 info hash from magnet link, e.g.,
+/// `magnet:?xt=urn:btih:<hex or base32 info hash>&dn=...`
+fn parse_magnet_info_hash(line: &str) -> anyhow::Result<[u8; 20]> {
+    let query = line
+        .strip_prefix("magnet:?")
+        .ok_or_else(|| anyhow::anyhow!("not a magnet link"))?;
+
+    for parameter in query.split('&') {
+        // Byte 9 might not be on a char boundary, so don't index directly
+        let encoded = match parameter.strip_prefix("xt=") {
+            Some(value)
+                if matches!(
+                    value.get(..9),
+                    Some(prefix) if prefix.eq_ignore_ascii_case("urn:btih:")
+                ) =>
+            {
+                &value[9..]
+            }
+            _ => continue,
+        };
+
+        return match encoded.len() {
+            40 => parse_info_hash(encoded),
+            32 => {
+                let decoded = data_encoding::BASE32
+                    .decode(encoded.to_ascii_uppercase().as_bytes())
+                    .context("invalid base32 info hash")?;
+
+                let mut bytes = [0u8; 20];
+
+                bytes.copy_from_slice(&decoded);
+
+                Ok(bytes)
+            }
+            _ => Err(anyhow::anyhow!("invalid info hash length")),
+        };
+    }
+
+    Err(anyhow::anyhow!("no urn:btih exact topic"))
+}
+
+/// Calculate info hash of torrent file, i.e., SHA-1 hash of its bencoded
+/// info dictionary
+fn calculate_torrent_info_hash(bytes: &[u8]) -> anyhow::Result<[u8; 20]> {
+    if bytes.first() != Some(&b'd') {
+        return Err(anyhow::anyhow!("torrent file is not a bencoded dictionary"));
+    }
+
+    let mut position = 1;
+
+    while bytes.get(position) != Some(&b'e') {
+        let key_start = position;
+        let value_start = bencode_value_end(bytes, key_start, 0)?;
+        let value_end = bencode_value_end(bytes, value_start, 0)?;
+
+        if &bytes[key_start..value_start] == b"4:info" {
+            let info = &bytes[value_start..value_end];
+
+            if info.first() != Some(&b'd') {
+                return Err(anyhow::anyhow!("info is not a dictionary"));
+            }
+
+            return Ok(Sha1::digest(info).into());
+        }
+
+        position = value_end;
+    }
+
+    Err(anyhow::anyhow!("info dictionary missing"))
+}
+
+/// Return position after end of bencoded value starting at `start`
+fn bencode_value_end(bytes: &[u8], start: usize, depth: usize) -> anyhow::Result<usize> {
+    if depth > MAX_BENCODE_DEPTH {
+        return Err(anyhow::anyhow!("bencode nesting too deep"));
+    }
+
+    match bytes.get(start) {
+        Some(b'i') => bytes[start..]
+            .iter()
+            .position(|b| *b == b'e')
+            .map(|i| start + i + 1)
+            .ok_or_else(|| anyhow::anyhow!("unterminated bencode integer")),
+        Some(b'l' | b'd') => {
+            let mut position = start + 1;
+
+            loop {
+                match bytes.get(position) {
+                    Some(b'e') => return Ok(position + 1),
+                    Some(_) => {
+                        position = bencode_value_end(bytes, position, depth + 1)?;
+                    }
+                    None => return Err(anyhow::anyhow!("unterminated bencode list or dictionary")),
+                }
+            }
+        }
+        Some(b'0'..=b'9') => {
+            let colon = bytes[start..]
+                .iter()
+                .position(|b| *b == b':')
+                .map(|i| start + i)
+                .ok_or_else(|| anyhow::anyhow!("unterminated bencode string length"))?;
+
+            let len: usize = ::std::str::from_utf8(&bytes[start..colon])?.parse()?;
+
+            colon
+                .checked_add(1 + len)
+                .filter(|end| *end <= bytes.len())
+                .ok_or_else(|| anyhow::anyhow!("truncated bencode string"))
+        }
+        _ => Err(anyhow::anyhow!("invalid bencode value")),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -161,6 +352,59 @@ mod tests {
         assert!(f("aaaabbbbccccddddeeeeaaaabbbbccccddddeeeö".into()).is_err());
     }
 
+    #[test]
+    fn test_parse_magnet_info_hash() {
+        let f = parse_magnet_info_hash;
+
+        let expected = parse_info_hash("5f58cd86ba354ee76ce33b956a7003f8ec043249").unwrap();
+
+        assert_eq!(
+            f("magnet:?xt=urn:btih:5f58cd86ba354ee76ce33b956a7003f8ec043249&dn=test").unwrap(),
+            expected
+        );
+        assert_eq!(
+            f("magnet:?dn=test&xt=urn:btih:L5MM3BV2GVHOO3HDHOKWU4AD7DWAIMSJ").unwrap(),
+            expected
+        );
+        assert_eq!(
+            f("magnet:?xt=urn:btih:l5mm3bv2gvhoo3hdhokwu4ad7dwaimsj").unwrap(),
+            expected
+        );
+        assert!(f("5f58cd86ba354ee76ce33b956a7003f8ec043249").is_err());
+        assert!(f("magnet:?dn=test").is_err());
+        assert!(f("magnet:?xt=urn:btih:5f58cd86ba354ee76ce33b956a7003f8ec04324").is_err());
+        assert!(f("magnet:?xt=urn:bti€5f58cd86ba354ee76ce33b956a7003f8ec043249").is_err());
+        assert!(f("magnet:?xt=urn:btih:5f58cd86ba354ee76ce33b956a7003f8ec0432€").is_err());
+    }
+
+    #[test]
+    fn test_calculate_torrent_info_hash() {
+        let f = calculate_torrent_info_hash;
+
+        let info = [
+            &b"d6:lengthi12345e4:name8:test.bin12:piece lengthi16384e6:pieces20:"[..],
+            &[b'a'; 20],
+            b"e",
+        ]
+        .concat();
+
+        let torrent = [
+            &b"d8:announce19:udp://example.com:14:info"[..],
+            &info,
+            b"5:nodesll9:127.0.0.1i6881eeee",
+        ]
+        .concat();
+
+        assert_eq!(
+            f(&torrent).unwrap(),
+            parse_info_hash("5f58cd86ba354ee76ce33b956a7003f8ec043249").unwrap()
+        );
+        assert!(f(&torrent[..60]).is_err());
+        assert!(f(b"d8:announce19:udp://example.com:1e").is_err());
+        assert!(f(b"d4:infoi1ee").is_err());
+        assert!(f(b"le").is_err());
+    }
+
     #[test]
     fn test_cache_allows() {
         let mut access_list = AccessList::default();
@@ -0,0 +1,342 @@
+//! Per-user or per-key transfer accounting
+//!
+//! Peers report cumulative uploaded and downloaded byte counts in announce
+//! requests. Swarm workers store the last reported counts for each peer,
+//! compute deltas on every announce and aggregate them by account (e.g.,
+//! passkey or announce key). Aggregated deltas are periodically appended to
+//! a file as JSON lines.
+//!
+//! Counts of peers removed during cleaning are remembered for a while, so
+//! that transfers are counted correctly if they announce again.
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::hash::Hash;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::{SecondsSinceServerStart, ValidUntil};
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountingConfig {
+    /// Track uploaded and downloaded bytes between announces per account
+    /// and periodically append them to a file
+    pub enabled: bool,
+    /// Path to append-only accounting file
+    ///
+    /// Each line is a JSON object with fields `time` (unix timestamp),
+    /// `uploaded` and `downloaded` (bytes since previous flush), and a
+    /// protocol specific account identifier.
+    ///
+    /// If using chroot mode, path must be relative to new root.
+    pub path: PathBuf,
+    /// Append aggregated transfer deltas to file this often (seconds)
+    pub flush_interval: u64,
+    /// Remember transfer counts of peers removed during cleaning for this
+    /// long (seconds), so that transfers are counted correctly if they
+    /// announce again without the `started` event
+    pub max_expired_peer_age: u32,
+}
+
+impl Default for AccountingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "./accounting.jsonl".into(),
+            flush_interval: 60,
+            max_expired_peer_age: 60 * 60,
+        }
+    }
+}
+
+/// Uploaded and downloaded byte counts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Transfer {
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+impl Transfer {
+    pub fn new(uploaded: u64, downloaded: u64) -> Self {
+        Self {
+            uploaded,
+            downloaded,
+        }
+    }
+
+    /// Calculate delta between counts reported in announce request and
+    /// counts previously reported by same peer
+    ///
+    /// For unknown peers, counts are only used as a baseline unless the
+    /// announce event is `started`, since they might already have been
+    /// accounted for before the peer was removed. Pass counts of expired
+    /// peers (see [`TransferAccounting::take_expired_peer_transfer`]) as
+    /// `previous` to count transfers since their last announce.
+    ///
+    /// Counts lower than previous ones mean that the client reset them, so
+    /// they are counted in full.
+    pub fn delta(self, previous: Option<Self>, started: bool) -> Self {
+        match previous {
+            Some(previous) => Self {
+                uploaded: self
+                    .uploaded
+                    .checked_sub(previous.uploaded)
+                    .unwrap_or(self.uploaded),
+                downloaded: self
+                    .downloaded
+                    .checked_sub(previous.downloaded)
+                    .unwrap_or(self.downloaded),
+            },
+            None if started => self,
+            None => Self::default(),
+        }
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.uploaded == 0 && self.downloaded == 0
+    }
+}
+
+#[derive(Serialize)]
+struct AccountingRecord<'a, A> {
+    time: u64,
+    #[serde(flatten)]
+    account: &'a A,
+    uploaded: u64,
+    downloaded: u64,
+}
+
+/// Transfer deltas aggregated per account since last flush
+///
+/// `P` identifies a peer within a torrent, e.g., info hash and peer id.
+pub struct TransferAccounting<A, P> {
+    enabled: bool,
+    max_expired_peer_age: u32,
+    transfers: HashMap<A, Transfer>,
+    expired_peers: HashMap<(A, P), (Transfer, ValidUntil)>,
+}
+
+impl<A: Hash + Eq + Serialize, P: Hash + Eq> TransferAccounting<A, P> {
+    pub fn new(config: &AccountingConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            max_expired_peer_age: config.max_expired_peer_age,
+            transfers: Default::default(),
+            expired_peers: Default::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Add transfer delta to account. Does nothing if accounting is disabled.
+    pub fn record(&mut self, account: A, delta: Transfer) {
+        if !self.enabled || delta.is_zero() {
+            return;
+        }
+
+        let transfer = self.transfers.entry(account).or_default();
+
+        transfer.uploaded = transfer.uploaded.saturating_add(delta.uploaded);
+        transfer.downloaded = transfer.downloaded.saturating_add(delta.downloaded);
+    }
+
+    /// Remember last transfer counts of peer removed during cleaning. Does
+    /// nothing if accounting is disabled.
+    pub fn record_expired_peer(
+        &mut self,
+        account: A,
+        peer: P,
+        transfer: Transfer,
+        now: SecondsSinceServerStart,
+    ) {
+        if !self.enabled || self.max_expired_peer_age == 0 {
+            return;
+        }
+
+        let valid_until = ValidUntil::new_with_now(now, self.max_expired_peer_age);
+
+        self.expired_peers
+            .insert((account, peer), (transfer, valid_until));
+    }
+
+    /// Take last transfer counts of peer removed during cleaning, if it
+    /// hasn't been forgotten yet
+    pub fn take_expired_peer_transfer(&mut self, account: A, peer: P) -> Option<Transfer> {
+        if self.expired_peers.is_empty() {
+            return None;
+        }
+
+        self.expired_peers
+            .remove(&(account, peer))
+            .map(|(transfer, _)| transfer)
+    }
+
+    /// Forget transfer counts of peers that expired long enough ago
+    pub fn clean(&mut self, now: SecondsSinceServerStart) {
+        self.expired_peers
+            .retain(|_, (_, valid_until)| valid_until.valid(now));
+        self.expired_peers.shrink_to_fit();
+    }
+
+    /// Append aggregated transfers to file and reset them. On failure,
+    /// they are kept for next attempt.
+    pub fn flush(&mut self, config: &AccountingConfig) {
+        if !self.enabled || self.transfers.is_empty() {
+            return;
+        }
+
+        match self.write(config) {
+            Ok(()) => {
+                ::log::debug!("Flushed transfers for {} accounts", self.transfers.len());
+
+                self.transfers.clear();
+                self.transfers.shrink_to_fit();
+            }
+            Err(err) => {
+                ::log::error!("Couldn't flush transfer accounting: {:#}", err);
+            }
+        }
+    }
+
+    fn write(&self, config: &AccountingConfig) -> anyhow::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        let mut buffer = Vec::new();
+
+        for (account, transfer) in self.transfers.iter() {
+            let record = AccountingRecord {
+                time,
+                account,
+                uploaded: transfer.uploaded,
+                downloaded: transfer.downloaded,
+            };
+
+            serde_json::to_writer(&mut buffer, &record)?;
+
+            buffer.push(b'\n');
+        }
+
+        // Write everything at once, so that lines from multiple swarm
+        // workers aren't interleaved
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .with_context(|| format!("Open {}", config.path.display()))?
+            .write_all(&buffer)
+            .with_context(|| format!("Write to {}", config.path.display()))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Hash, PartialEq, Eq, Serialize)]
+    struct Account {
+        key: u32,
+    }
+
+    #[test]
+    fn test_transfer_delta() {
+        let current = Transfer::new(100, 50);
+
+        assert_eq!(
+            current.delta(Some(Transfer::new(60, 50)), false),
+            Transfer::new(40, 0)
+        );
+        assert_eq!(
+            current.delta(Some(Transfer::new(200, 10)), false),
+            Transfer::new(100, 40)
+        );
+        assert_eq!(current.delta(None, true), current);
+        assert_eq!(current.delta(None, false), Transfer::default());
+    }
+
+    #[test]
+    fn test_transfer_delta_expired_peer() {
+        let config = AccountingConfig {
+            enabled: true,
+            max_expired_peer_age: 10,
+            ..Default::default()
+        };
+
+        let mut accounting = TransferAccounting::new(&config);
+
+        let now = SecondsSinceServerStart(0);
+
+        let first = Transfer::new(100, 50);
+        let second = Transfer::new(300, 150);
+
+        assert_eq!(first.delta(None, true), first);
+
+        // Peer expires before second announce
+        accounting.record_expired_peer(Account { key: 1 }, 1u8, first, now);
+
+        assert_eq!(
+            accounting.take_expired_peer_transfer(Account { key: 2 }, 1),
+            None
+        );
+
+        let previous = accounting.take_expired_peer_transfer(Account { key: 1 }, 1);
+
+        assert_eq!(previous, Some(first));
+        assert_eq!(second.delta(previous, false), Transfer::new(200, 100));
+
+        // Counts are only used once
+        assert_eq!(
+            accounting.take_expired_peer_transfer(Account { key: 1 }, 1),
+            None
+        );
+
+        // Counts of peers that expired long ago are forgotten
+        accounting.record_expired_peer(Account { key: 1 }, 1, second, now);
+        accounting.clean(SecondsSinceServerStart(10));
+
+        assert_eq!(
+            accounting.take_expired_peer_transfer(Account { key: 1 }, 1),
+            None
+        );
+    }
+
+    #[test]
+    fn test_transfer_accounting_flush() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let config = AccountingConfig {
+            enabled: true,
+            path: dir.path().join("accounting.jsonl"),
+            ..Default::default()
+        };
+
+        let mut accounting = TransferAccounting::<_, ()>::new(&config);
+
+        accounting.record(Account { key: 1 }, Transfer::new(10, 20));
+        accounting.record(Account { key: 1 }, Transfer::new(5, 0));
+        accounting.record(Account { key: 2 }, Transfer::default());
+        accounting.flush(&config);
+
+        accounting.record(Account { key: 2 }, Transfer::new(0, 1));
+        accounting.flush(&config);
+
+        let contents = ::std::fs::read_to_string(&config.path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains(r#""key":1,"uploaded":15,"downloaded":20"#));
+        assert!(lines[1].contains(r#""key":2,"uploaded":0,"downloaded":1"#));
+    }
+}
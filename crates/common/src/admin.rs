@@ -0,0 +1,548 @@
+//! Local admin socket
+//!
+//! Listens on a Unix domain socket for newline-delimited JSON requests and
+//! responds to each with a single line of JSON. Swarm queries are passed on
+//! to the swarm workers owning the torrents.
+//!
+//! Example request: `{"command":"torrent","info_hash":"<40 hex chars>"}`
+
+use std::collections::HashMap;
+use std::fs::Permissions;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::Builder;
+use std::time::Duration;
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::access_list::{AccessList, AccessListArcSwap};
+use crate::cli::LogLevel;
+
+/// How long to wait for swarm workers to answer queries
+const SWARM_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Listen for admin commands on a Unix domain socket
+    pub enabled: bool,
+    /// Path of Unix domain socket. Existing sockets at path are replaced.
+    /// Only the user running the tracker can connect.
+    pub path: PathBuf,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "./aquatic-admin.sock".into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminRequest {
+    /// Add info hash to access list. Changes are lost when access list is
+    /// reloaded from file.
+    AccessListAdd {
+        #[serde(with = "info_hash_hex")]
+        info_hash: [u8; 20],
+    },
+    /// Remove info hash from access list. Changes are lost when access
+    /// list is reloaded from file.
+    AccessListRemove {
+        #[serde(with = "info_hash_hex")]
+        info_hash: [u8; 20],
+    },
+    /// Get statistics for torrent
+    Torrent {
+        #[serde(with = "info_hash_hex")]
+        info_hash: [u8; 20],
+    },
+    /// Get statistics for torrents with most peers
+    TopTorrents {
+        limit: usize,
+    },
+    SetLogLevel {
+        level: LogLevel,
+    },
+    /// Reload files as on SIGUSR1
+    Reload,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AdminResponse {
+    Ok,
+    Torrent { torrent: Option<TorrentSummary> },
+    TopTorrents { torrents: Vec<TorrentSummary> },
+    Error { message: String },
+}
+
+/// Torrent statistics summed over IP versions
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TorrentSummary {
+    #[serde(with = "info_hash_hex")]
+    pub info_hash: [u8; 20],
+    pub seeders: usize,
+    pub leechers: usize,
+    pub completed: usize,
+}
+
+impl TorrentSummary {
+    pub fn num_peers(&self) -> usize {
+        self.seeders + self.leechers
+    }
+}
+
+/// Merge summaries with same info hash (e.g., from IPv4 and IPv6 torrent
+/// maps) and return the `limit` ones with most peers
+pub fn top_torrents(
+    summaries: impl IntoIterator<Item = TorrentSummary>,
+    limit: usize,
+) -> Vec<TorrentSummary> {
+    let mut merged: HashMap<[u8; 20], TorrentSummary> = HashMap::new();
+
+    for summary in summaries {
+        merged
+            .entry(summary.info_hash)
+            .and_modify(|entry| {
+                entry.seeders += summary.seeders;
+                entry.leechers += summary.leechers;
+                entry.completed += summary.completed;
+            })
+            .or_insert(summary);
+    }
+
+    let mut summaries = merged.into_values().collect::<Vec<_>>();
+
+    summaries.sort_unstable_by(|a, b| {
+        b.num_peers()
+            .cmp(&a.num_peers())
+            .then(a.info_hash.cmp(&b.info_hash))
+    });
+    summaries.truncate(limit);
+
+    summaries
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SwarmQuery {
+    Torrent([u8; 20]),
+    TopTorrents(usize),
+}
+
+/// Query sent to swarm worker, which should respond with matching
+/// torrents
+pub struct SwarmQueryRequest {
+    pub query: SwarmQuery,
+    response_sender: Sender<Vec<TorrentSummary>>,
+}
+
+impl SwarmQueryRequest {
+    pub fn respond(self, torrents: Vec<TorrentSummary>) {
+        // Admin connection might have timed out, which is fine
+        let _ = self.response_sender.send(torrents);
+    }
+}
+
+pub type SwarmQuerySender = Sender<SwarmQueryRequest>;
+pub type SwarmQueryReceiver = Receiver<SwarmQueryRequest>;
+
+/// Create swarm query channels, one per swarm worker
+pub fn create_swarm_query_channels(
+    num_swarm_workers: usize,
+) -> (Vec<SwarmQuerySender>, Vec<SwarmQueryReceiver>) {
+    (0..num_swarm_workers).map(|_| channel()).unzip()
+}
+
+struct AdminHandler<F> {
+    access_list: Arc<AccessListArcSwap>,
+    swarm_query_senders: Vec<SwarmQuerySender>,
+    swarm_worker_index: F,
+}
+
+impl<F: Fn(&[u8; 20]) -> usize> AdminHandler<F> {
+    fn handle(&self, request: AdminRequest) -> AdminResponse {
+        match request {
+            AdminRequest::AccessListAdd { info_hash } => {
+                self.access_list.rcu(|access_list| {
+                    let mut access_list = AccessList::clone(access_list);
+
+                    access_list.insert(info_hash);
+
+                    access_list
+                });
+
+                ::log::info!("Admin: added {} to access list", hex::encode(info_hash));
+
+                AdminResponse::Ok
+            }
+            AdminRequest::AccessListRemove { info_hash } => {
+                self.access_list.rcu(|access_list| {
+                    let mut access_list = AccessList::clone(access_list);
+
+                    access_list.remove(&info_hash);
+
+                    access_list
+                });
+
+                ::log::info!("Admin: removed {} from access list", hex::encode(info_hash));
+
+                AdminResponse::Ok
+            }
+            AdminRequest::Torrent { info_hash } => {
+                let worker_index = (self.swarm_worker_index)(&info_hash);
+
+                match self.query_swarm_workers(&[worker_index], SwarmQuery::Torrent(info_hash)) {
+                    Ok(torrents) => AdminResponse::Torrent {
+                        torrent: top_torrents(torrents, 1).pop(),
+                    },
+                    Err(err) => AdminResponse::Error {
+                        message: format!("{:#}", err),
+                    },
+                }
+            }
+            AdminRequest::TopTorrents { limit } => {
+                let worker_indices = (0..self.swarm_query_senders.len()).collect::<Vec<_>>();
+
+                match self.query_swarm_workers(&worker_indices, SwarmQuery::TopTorrents(limit)) {
+                    Ok(torrents) => AdminResponse::TopTorrents {
+                        torrents: top_torrents(torrents, limit),
+                    },
+                    Err(err) => AdminResponse::Error {
+                        message: format!("{:#}", err),
+                    },
+                }
+            }
+            AdminRequest::SetLogLevel { level } => {
+                ::log::set_max_level(level.into());
+
+                ::log::info!("Admin: set log level to {:?}", level);
+
+                AdminResponse::Ok
+            }
+            AdminRequest::Reload => match crate::raise_reload_signal() {
+                Ok(()) => AdminResponse::Ok,
+                Err(err) => AdminResponse::Error {
+                    message: format!("{:#}", err),
+                },
+            },
+        }
+    }
+
+    fn query_swarm_workers(
+        &self,
+        worker_indices: &[usize],
+        query: SwarmQuery,
+    ) -> anyhow::Result<Vec<TorrentSummary>> {
+        let (response_sender, response_receiver) = channel();
+
+        for index in worker_indices.iter().copied() {
+            let request = SwarmQueryRequest {
+                query,
+                response_sender: response_sender.clone(),
+            };
+
+            self.swarm_query_senders
+                .get(index)
+                .ok_or_else(|| anyhow::anyhow!("no swarm worker with index {}", index))?
+                .send(request)
+                .map_err(|_| anyhow::anyhow!("swarm worker {} not running", index))?;
+        }
+
+        let mut torrents = Vec::new();
+
+        for _ in worker_indices {
+            torrents.extend(
+                response_receiver
+                    .recv_timeout(SWARM_QUERY_TIMEOUT)
+                    .context("swarm worker did not respond")?,
+            );
+        }
+
+        Ok(torrents)
+    }
+}
+
+/// Bind admin socket and spawn thread handling connections. Does nothing
+/// if admin socket is disabled.
+///
+/// `swarm_worker_index` must return index of swarm worker owning torrent
+/// with given info hash.
+pub fn spawn_admin_server<F>(
+    config: &AdminConfig,
+    access_list: Arc<AccessListArcSwap>,
+    swarm_query_senders: Vec<SwarmQuerySender>,
+    swarm_worker_index: F,
+) -> anyhow::Result<()>
+where
+    F: Fn(&[u8; 20]) -> usize + Send + Sync + 'static,
+{
+    if !config.enabled {
+        return Ok(());
+    }
+
+    let listener = bind(&config.path)?;
+
+    let handler = Arc::new(AdminHandler {
+        access_list,
+        swarm_query_senders,
+        swarm_worker_index,
+    });
+
+    Builder::new()
+        .name("admin".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        ::log::error!("Admin socket: couldn't accept connection: {:#}", err);
+
+                        continue;
+                    }
+                };
+
+                let handler = handler.clone();
+
+                let result = Builder::new()
+                    .name("admin-connection".into())
+                    .spawn(move || {
+                        if let Err(err) = handle_connection(&handler, stream) {
+                            ::log::debug!("Admin socket: connection error: {:#}", err);
+                        }
+                    });
+
+                if let Err(err) = result {
+                    ::log::error!("Admin socket: couldn't spawn connection thread: {:#}", err);
+                }
+            }
+        })
+        .context("Spawn admin socket thread")?;
+
+    Ok(())
+}
+
+fn bind(path: &Path) -> anyhow::Result<UnixListener> {
+    // Remove socket left behind by previous run
+    if let Ok(metadata) = ::std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            ::std::fs::remove_file(path)
+                .with_context(|| format!("Remove old admin socket {}", path.display()))?;
+        }
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Bind admin socket {}", path.display()))?;
+
+    ::std::fs::set_permissions(path, Permissions::from_mode(0o600))
+        .with_context(|| format!("Set permissions of admin socket {}", path.display()))?;
+
+    Ok(listener)
+}
+
+fn handle_connection<F: Fn(&[u8; 20]) -> usize>(
+    handler: &AdminHandler<F>,
+    stream: UnixStream,
+) -> anyhow::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    for line in reader.lines() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => handler.handle(request),
+            Err(err) => AdminResponse::Error {
+                message: format!("invalid request: {:#}", err),
+            },
+        };
+
+        serde_json::to_writer(&mut writer, &response)?;
+
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Send request to admin socket and wait for response
+pub fn send_admin_request(path: &Path, request: &AdminRequest) -> anyhow::Result<AdminResponse> {
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("Connect to admin socket {}", path.display()))?;
+
+    let mut line = serde_json::to_string(request)?;
+
+    line.push('\n');
+
+    stream.write_all(line.as_bytes())?;
+
+    let mut line = String::new();
+
+    BufReader::new(stream).read_line(&mut line)?;
+
+    serde_json::from_str(&line).context("Parse admin response")
+}
+
+mod info_hash_hex {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        info_hash: &[u8; 20],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(info_hash))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 20], D::Error> {
+        let text = String::deserialize(deserializer)?;
+
+        let mut info_hash = [0u8; 20];
+
+        hex::decode_to_slice(text, &mut info_hash).map_err(::serde::de::Error::custom)?;
+
+        Ok(info_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use arc_swap::ArcSwap;
+
+    use crate::access_list::AccessListMode;
+
+    use super::*;
+
+    fn summary(first_byte: u8, seeders: usize, leechers: usize) -> TorrentSummary {
+        let mut info_hash = [0; 20];
+
+        info_hash[0] = first_byte;
+
+        TorrentSummary {
+            info_hash,
+            seeders,
+            leechers,
+            completed: 0,
+        }
+    }
+
+    #[test]
+    fn test_top_torrents() {
+        let summaries = vec![
+            summary(1, 1, 1),
+            summary(2, 5, 0),
+            summary(3, 0, 1),
+            summary(1, 2, 2),
+        ];
+
+        assert_eq!(
+            top_torrents(summaries.clone(), 2),
+            vec![summary(1, 3, 3), summary(2, 5, 0)]
+        );
+        assert_eq!(top_torrents(summaries, 0), vec![]);
+    }
+
+    #[test]
+    fn test_request_format() {
+        let request: AdminRequest = serde_json::from_str(
+            r#"{"command":"torrent","info_hash":"0101010101010101010101010101010101010101"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(request, AdminRequest::Torrent { info_hash: [1; 20] });
+
+        let request: AdminRequest =
+            serde_json::from_str(r#"{"command":"set_log_level","level":"debug"}"#).unwrap();
+
+        assert_eq!(
+            request,
+            AdminRequest::SetLogLevel {
+                level: LogLevel::Debug
+            }
+        );
+
+        assert!(serde_json::from_str::<AdminRequest>(
+            r#"{"command":"access_list_add","info_hash":"0101"}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_admin_socket() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let config = AdminConfig {
+            enabled: true,
+            path: dir.path().join("admin.sock"),
+        };
+
+        let access_list = Arc::new(ArcSwap::from_pointee(AccessList::default()));
+        let (senders, receivers) = create_swarm_query_channels(2);
+
+        for (worker_index, receiver) in receivers.into_iter().enumerate() {
+            thread::spawn(move || {
+                for request in receiver {
+                    let torrents = match request.query {
+                        SwarmQuery::Torrent(info_hash) if info_hash[0] as usize == worker_index => {
+                            vec![summary(info_hash[0], 1, 0)]
+                        }
+                        SwarmQuery::Torrent(_) => vec![],
+                        SwarmQuery::TopTorrents(_) => vec![summary(worker_index as u8, 1, 0)],
+                    };
+
+                    request.respond(torrents);
+                }
+            });
+        }
+
+        spawn_admin_server(&config, access_list.clone(), senders, |info_hash| {
+            info_hash[0] as usize % 2
+        })
+        .unwrap();
+
+        let mut info_hash = [0; 20];
+
+        info_hash[0] = 1;
+
+        assert_eq!(
+            send_admin_request(&config.path, &AdminRequest::AccessListAdd { info_hash }).unwrap(),
+            AdminResponse::Ok
+        );
+        assert!(access_list.load().allows(AccessListMode::Allow, &info_hash));
+
+        assert_eq!(
+            send_admin_request(&config.path, &AdminRequest::Torrent { info_hash }).unwrap(),
+            AdminResponse::Torrent {
+                torrent: Some(summary(1, 1, 0))
+            }
+        );
+        assert_eq!(
+            send_admin_request(&config.path, &AdminRequest::TopTorrents { limit: 5 }).unwrap(),
+            AdminResponse::TopTorrents {
+                torrents: vec![summary(0, 1, 0), summary(1, 1, 0)]
+            }
+        );
+
+        assert_eq!(
+            send_admin_request(&config.path, &AdminRequest::AccessListRemove { info_hash })
+                .unwrap(),
+            AdminResponse::Ok
+        );
+        assert!(!access_list.load().allows(AccessListMode::Allow, &info_hash));
+    }
+}
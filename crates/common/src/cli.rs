@@ -1,5 +1,6 @@
 use std::fs::File;
 use std::io::Read;
+use std::str::FromStr;
 
 use anyhow::Context;
 use aquatic_toml_config::TomlConfig;
@@ -26,6 +27,35 @@ impl Default for LogLevel {
     }
 }
 
+impl FromStr for LogLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            "trace" => Ok(Self::Trace),
+            _ => Err(anyhow::anyhow!("invalid log level: {}", s)),
+        }
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(log_level: LogLevel) -> Self {
+        match log_level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
 pub trait Config: Default + TomlConfig + DeserializeOwned + std::fmt::Debug {
     fn get_log_level(&self) -> Option<LogLevel> {
         None
@@ -202,21 +232,16 @@ where
 }
 
 fn start_logger(log_level: LogLevel) -> ::anyhow::Result<()> {
-    let level_filter = match log_level {
-        LogLevel::Off => LevelFilter::Off,
-        LogLevel::Error => LevelFilter::Error,
-        LogLevel::Warn => LevelFilter::Warn,
-        LogLevel::Info => LevelFilter::Info,
-        LogLevel::Debug => LevelFilter::Debug,
-        LogLevel::Trace => LevelFilter::Trace,
-    };
-
+    // Filter using global max level only, so that it can be changed at
+    // runtime (e.g., through admin socket)
     SimpleLogger::new()
-        .with_level(level_filter)
+        .with_level(LevelFilter::Trace)
         .with_utc_timestamps()
         .init()
         .context("Couldn't initialize logger")?;
 
+    ::log::set_max_level(log_level.into());
+
     Ok(())
 }
 
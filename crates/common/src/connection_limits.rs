@@ -0,0 +1,287 @@
+//! Limits on concurrent connections per socket worker and per source
+//!
+//! Worker counts are local to each socket worker, while source counts are
+//! shared by all socket workers, since connections from the same source are
+//! spread over workers by `SO_REUSEPORT`.
+
+use std::cell::Cell;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use aquatic_toml_config::TomlConfig;
+use hashbrown::HashMap;
+use serde::Deserialize;
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionLimitConfig {
+    /// Maximum number of concurrent connections per socket worker. Set to
+    /// zero for no limit.
+    pub max_per_worker: usize,
+    /// Maximum number of concurrent connections per IP address across all
+    /// socket workers. Set to zero for no limit.
+    pub max_per_ip: usize,
+    /// Maximum number of concurrent connections per IPv6 network (with
+    /// prefix length ipv6_prefix_length) across all socket workers. Set to
+    /// zero for no limit.
+    pub max_per_ipv6_prefix: usize,
+    pub ipv6_prefix_length: u8,
+}
+
+impl Default for ConnectionLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_per_worker: 0,
+            max_per_ip: 0,
+            max_per_ipv6_prefix: 0,
+            ipv6_prefix_length: 64,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum SourceKey {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    Ipv6Prefix(Ipv6Addr),
+}
+
+/// Number of connections per source, shared by all socket workers
+#[derive(Default)]
+pub struct SourceConnectionCounts(Mutex<HashMap<SourceKey, usize>>);
+
+impl SourceConnectionCounts {
+    /// Number of sources with connections
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Connection limiter for a single socket worker
+pub struct ConnectionLimiter {
+    config: ConnectionLimitConfig,
+    worker_connections: Rc<Cell<usize>>,
+    source_counts: Arc<SourceConnectionCounts>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(config: &ConnectionLimitConfig, source_counts: Arc<SourceConnectionCounts>) -> Self {
+        Self {
+            config: config.clone(),
+            worker_connections: Default::default(),
+            source_counts,
+        }
+    }
+
+    /// Number of connections with slots on this socket worker
+    pub fn worker_connections(&self) -> usize {
+        self.worker_connections.get()
+    }
+
+    /// Returns None if socket worker is at capacity
+    pub fn try_acquire_worker_slot(&self) -> Option<WorkerSlot> {
+        let connections = self.worker_connections.get();
+
+        if self.config.max_per_worker != 0 && connections >= self.config.max_per_worker {
+            return None;
+        }
+
+        self.worker_connections.set(connections + 1);
+
+        Some(WorkerSlot(self.worker_connections.clone()))
+    }
+
+    /// Returns None if source is at capacity
+    pub fn try_acquire_source_slot(&self, ip: IpAddr) -> Option<SourceSlot> {
+        let mut limited_keys = [None, None];
+
+        match ip {
+            IpAddr::V4(ip) => {
+                limited_keys[0] = Some((SourceKey::Ipv4(ip), self.config.max_per_ip));
+            }
+            IpAddr::V6(ip) => {
+                if let Some(ip) = ip.to_ipv4_mapped() {
+                    limited_keys[0] = Some((SourceKey::Ipv4(ip), self.config.max_per_ip));
+                } else {
+                    let prefix = ipv6_prefix(ip, self.config.ipv6_prefix_length);
+
+                    limited_keys[0] = Some((SourceKey::Ipv6(ip), self.config.max_per_ip));
+                    limited_keys[1] = Some((
+                        SourceKey::Ipv6Prefix(prefix),
+                        self.config.max_per_ipv6_prefix,
+                    ));
+                }
+            }
+        }
+
+        let keys = limited_keys
+            .map(|opt_key| opt_key.filter(|(_, limit)| *limit != 0).map(|(key, _)| key));
+
+        if keys.iter().all(Option::is_none) {
+            return Some(SourceSlot {
+                source_counts: self.source_counts.clone(),
+                keys,
+            });
+        }
+
+        let mut counts = self.source_counts.0.lock().unwrap();
+
+        for (key, limit) in limited_keys.iter().flatten() {
+            if *limit != 0 && counts.get(key).copied().unwrap_or(0) >= *limit {
+                return None;
+            }
+        }
+
+        for key in keys.iter().flatten() {
+            *counts.entry(*key).or_insert(0) += 1;
+        }
+
+        Some(SourceSlot {
+            source_counts: self.source_counts.clone(),
+            keys,
+        })
+    }
+}
+
+/// Released when dropped
+pub struct WorkerSlot(Rc<Cell<usize>>);
+
+impl Drop for WorkerSlot {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+/// Released when dropped
+pub struct SourceSlot {
+    source_counts: Arc<SourceConnectionCounts>,
+    keys: [Option<SourceKey>; 2],
+}
+
+impl Drop for SourceSlot {
+    fn drop(&mut self) {
+        if self.keys.iter().all(Option::is_none) {
+            return;
+        }
+
+        let mut counts = self.source_counts.0.lock().unwrap();
+
+        for key in self.keys.iter().flatten() {
+            if let Some(count) = counts.get_mut(key) {
+                *count -= 1;
+
+                if *count == 0 {
+                    counts.remove(key);
+                }
+            }
+        }
+    }
+}
+
+fn ipv6_prefix(ip: Ipv6Addr, prefix_length: u8) -> Ipv6Addr {
+    let prefix_length = u32::from(prefix_length.min(128));
+
+    let mask = u128::MAX.checked_shl(128 - prefix_length).unwrap_or(0);
+
+    Ipv6Addr::from(u128::from(ip) & mask)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_limiter(
+        max_per_worker: usize,
+        max_per_ip: usize,
+        max_per_ipv6_prefix: usize,
+    ) -> ConnectionLimiter {
+        let config = ConnectionLimitConfig {
+            max_per_worker,
+            max_per_ip,
+            max_per_ipv6_prefix,
+            ipv6_prefix_length: 64,
+        };
+
+        ConnectionLimiter::new(&config, Default::default())
+    }
+
+    #[test]
+    fn test_worker_limit() {
+        let limiter = create_limiter(2, 0, 0);
+
+        let a = limiter.try_acquire_worker_slot().unwrap();
+        let _b = limiter.try_acquire_worker_slot().unwrap();
+
+        assert!(limiter.try_acquire_worker_slot().is_none());
+        assert_eq!(limiter.worker_connections(), 2);
+
+        drop(a);
+
+        assert!(limiter.try_acquire_worker_slot().is_some());
+    }
+
+    #[test]
+    fn test_ip_limit() {
+        let limiter = create_limiter(0, 2, 0);
+
+        let ip = IpAddr::from([1, 2, 3, 4]);
+        let mapped_ip = IpAddr::from(Ipv4Addr::new(1, 2, 3, 4).to_ipv6_mapped());
+
+        let a = limiter.try_acquire_source_slot(ip).unwrap();
+        let _b = limiter.try_acquire_source_slot(mapped_ip).unwrap();
+
+        assert!(limiter.try_acquire_source_slot(ip).is_none());
+        assert!(limiter
+            .try_acquire_source_slot(IpAddr::from([1, 2, 3, 5]))
+            .is_some());
+
+        drop(a);
+
+        assert!(limiter.try_acquire_source_slot(ip).is_some());
+    }
+
+    #[test]
+    fn test_ipv6_prefix_limit() {
+        let limiter = create_limiter(0, 1, 2);
+
+        let a = IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, 1));
+        let b = IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, 2));
+        let c = IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, 3));
+        let d = IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 0, 2, 0, 0, 0, 1));
+
+        let slot_a = limiter.try_acquire_source_slot(a).unwrap();
+
+        assert!(limiter.try_acquire_source_slot(a).is_none());
+
+        let slot_b = limiter.try_acquire_source_slot(b).unwrap();
+
+        assert!(limiter.try_acquire_source_slot(c).is_none());
+        assert!(limiter.try_acquire_source_slot(d).is_some());
+
+        drop(slot_a);
+
+        assert!(limiter.try_acquire_source_slot(c).is_some());
+
+        drop(slot_b);
+
+        assert!(limiter.source_counts.is_empty());
+    }
+
+    #[test]
+    fn test_ipv6_prefix() {
+        let ip = Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 3, 4, 5, 6);
+
+        assert_eq!(
+            ipv6_prefix(ip, 64),
+            Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 0, 0, 0, 0)
+        );
+        assert_eq!(ipv6_prefix(ip, 0), Ipv6Addr::UNSPECIFIED);
+        assert_eq!(ipv6_prefix(ip, 128), ip);
+        assert_eq!(ipv6_prefix(ip, 200), ip);
+    }
+}
@@ -0,0 +1,197 @@
+//! Automatic reloading of files on change
+//!
+//! Watches parent directories of files (or directories themselves) instead
+//! of the files, so that atomically renaming new versions into place is
+//! detected. Once no further changes have been seen for the debounce
+//! duration, SIGUSR1 is sent to the current process, so that the regular
+//! reload path is used.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread::Builder;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use notify::event::{AccessKind, AccessMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use serde::Deserialize;
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileWatcherConfig {
+    /// Reload access list (and other files reloaded on SIGUSR1, such as
+    /// TLS certificates) automatically when they change
+    pub enabled: bool,
+    /// Wait until files have been unchanged for this long before reloading
+    /// (milliseconds)
+    pub debounce_ms: u64,
+}
+
+impl Default for FileWatcherConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            debounce_ms: 1000,
+        }
+    }
+}
+
+/// File or directory to watch
+struct WatchTarget {
+    directory: PathBuf,
+    /// File name in directory, or None if any change in directory is relevant
+    file_name: Option<PathBuf>,
+}
+
+impl WatchTarget {
+    fn new(path: &Path) -> Self {
+        if path.is_dir() {
+            return Self {
+                directory: path.to_owned(),
+                file_name: None,
+            };
+        }
+
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+            _ => PathBuf::from("."),
+        };
+
+        Self {
+            directory,
+            file_name: path.file_name().map(PathBuf::from),
+        }
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        if path.parent() != Some(self.directory.as_path()) {
+            return false;
+        }
+
+        match self.file_name.as_ref() {
+            Some(file_name) => path.file_name() == Some(file_name.as_os_str()),
+            None => true,
+        }
+    }
+}
+
+/// Spawn thread sending SIGUSR1 to current process when any of the files
+/// change. Does nothing if file watching is disabled or paths are empty.
+///
+/// Must be called before privileges are dropped, since watched paths are
+/// resolved relative to the original root.
+pub fn spawn_file_watcher(config: &FileWatcherConfig, paths: Vec<PathBuf>) -> anyhow::Result<()> {
+    if !config.enabled || paths.is_empty() {
+        return Ok(());
+    }
+
+    // Event paths are absolute
+    let current_dir = ::std::env::current_dir().context("Get current directory")?;
+
+    let targets = paths
+        .iter()
+        .map(|path| WatchTarget::new(&current_dir.join(path)))
+        .collect::<Vec<_>>();
+
+    let (sender, receiver) = channel();
+
+    let mut watcher = notify::recommended_watcher(sender).context("Create file watcher")?;
+
+    let directories = targets
+        .iter()
+        .map(|target| target.directory.clone())
+        .collect::<HashSet<_>>();
+
+    for directory in directories {
+        watcher
+            .watch(&directory, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Watch directory {}", directory.display()))?;
+    }
+
+    let debounce = Duration::from_millis(config.debounce_ms);
+
+    let is_relevant = move |event: notify::Result<Event>| match event {
+        Ok(event) => {
+            let kind_is_relevant = match event.kind {
+                EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+                EventKind::Access(_) => false,
+                _ => true,
+            };
+
+            kind_is_relevant
+                && event
+                    .paths
+                    .iter()
+                    .any(|path| targets.iter().any(|target| target.matches(path)))
+        }
+        Err(err) => {
+            ::log::error!("File watcher error: {:#}", err);
+
+            false
+        }
+    };
+
+    Builder::new()
+        .name("file-watcher".into())
+        .spawn(move || {
+            // Keep watcher alive for as long as thread runs
+            let _watcher = watcher;
+
+            while let Ok(event) = receiver.recv() {
+                if !is_relevant(event) {
+                    continue;
+                }
+
+                let mut deadline = Instant::now() + debounce;
+
+                loop {
+                    match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    {
+                        Ok(event) => {
+                            if is_relevant(event) {
+                                deadline = Instant::now() + debounce;
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+
+                ::log::info!("Watched files changed, reloading");
+
+                if let Err(err) = crate::raise_reload_signal() {
+                    ::log::error!("{:#}", err);
+                }
+            }
+        })
+        .context("Spawn file watcher thread")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watch_target_matches() {
+        let target = WatchTarget::new(Path::new("access-list.txt"));
+
+        assert!(target.matches(Path::new("./access-list.txt")));
+        assert!(!target.matches(Path::new("./access-list.txt.tmp")));
+        assert!(!target.matches(Path::new("./other/access-list.txt")));
+
+        let target = WatchTarget::new(Path::new("/etc/aquatic/cert.pem"));
+
+        assert!(target.matches(Path::new("/etc/aquatic/cert.pem")));
+        assert!(!target.matches(Path::new("/etc/aquatic/key.pem")));
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = WatchTarget::new(dir.path());
+
+        assert!(target.matches(&dir.path().join("a.torrent")));
+        assert!(!target.matches(dir.path()));
+    }
+}
@@ -0,0 +1,248 @@
+//! Cached full scrape responses
+//!
+//! Full scrapes (scrape requests without any info hashes) are too expensive
+//! to handle by querying swarm workers for every request. Instead, when
+//! enabled, swarm workers periodically publish their part of the data. The
+//! swarm worker that publishes the last missing part of a generation merges
+//! all parts into a protocol specific response, which socket workers then
+//! serve as-is.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, TryLockError};
+use std::time::Instant;
+
+use aquatic_toml_config::TomlConfig;
+use arc_swap::ArcSwapOption;
+use serde::Deserialize;
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FullScrapeConfig {
+    /// Allow full scrapes (scrape requests without info hashes)
+    ///
+    /// Responses are generated periodically in the background and served
+    /// from a cache.
+    pub enabled: bool,
+    /// Regenerate full scrape response this often (seconds)
+    pub refresh_interval: u64,
+    /// Maximum number of full scrape responses to send per minute, across
+    /// all socket workers. Further full scrape requests get an error
+    /// response. Set to zero for no limit.
+    pub max_responses_per_minute: u32,
+}
+
+impl Default for FullScrapeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            refresh_interval: 60,
+            max_responses_per_minute: 60,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FullScrapeError {
+    /// Not all swarm workers have published their data yet
+    NotReady,
+    RateLimited,
+}
+
+impl FullScrapeError {
+    pub fn failure_reason(&self) -> &'static str {
+        match self {
+            Self::NotReady => "Full scrape not available yet, try again later",
+            Self::RateLimited => "Too many full scrape requests, try again later",
+        }
+    }
+}
+
+/// Full scrape parts published by swarm workers (`P`) and the merged
+/// response served by socket workers (`R`)
+pub struct FullScrapeCache<P, R> {
+    parts: Vec<ArcSwapOption<P>>,
+    /// Set for parts published since they were last merged
+    fresh_parts: Vec<AtomicBool>,
+    num_fresh_parts: AtomicUsize,
+    response: ArcSwapOption<R>,
+    merge_lock: Mutex<()>,
+    max_responses_per_minute: u32,
+    /// Current rate limit window (upper 32 bits) and number of responses
+    /// sent in it (lower 32 bits)
+    rate_limit_state: AtomicU64,
+    created: Instant,
+}
+
+impl<P, R> FullScrapeCache<P, R> {
+    pub fn new(config: &FullScrapeConfig, num_swarm_workers: usize) -> Self {
+        Self {
+            parts: (0..num_swarm_workers)
+                .map(|_| ArcSwapOption::empty())
+                .collect(),
+            fresh_parts: (0..num_swarm_workers)
+                .map(|_| AtomicBool::new(false))
+                .collect(),
+            num_fresh_parts: AtomicUsize::new(0),
+            response: ArcSwapOption::empty(),
+            merge_lock: Mutex::new(()),
+            max_responses_per_minute: config.max_responses_per_minute,
+            rate_limit_state: AtomicU64::new(0),
+            created: Instant::now(),
+        }
+    }
+
+    /// Publish part from swarm worker. If parts from all swarm workers have
+    /// been published since the last merge, merge them into a new response.
+    ///
+    /// Never blocks: if another swarm worker is merging, merging is left to
+    /// a later call.
+    pub fn update<F>(&self, worker_index: usize, part: P, merge: F)
+    where
+        F: FnOnce(&[Arc<P>]) -> R,
+    {
+        self.parts[worker_index].store(Some(Arc::new(part)));
+
+        if !self.fresh_parts[worker_index].swap(true, Ordering::AcqRel) {
+            self.num_fresh_parts.fetch_add(1, Ordering::AcqRel);
+        }
+
+        if self.num_fresh_parts.load(Ordering::Acquire) < self.parts.len() {
+            return;
+        }
+
+        // Serialize merging so that a response built from older parts can't
+        // overwrite one built from newer parts
+        let _guard = match self.merge_lock.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => return,
+        };
+
+        // Another swarm worker might have merged this generation already
+        if self.num_fresh_parts.load(Ordering::Acquire) < self.parts.len() {
+            return;
+        }
+
+        for fresh in self.fresh_parts.iter() {
+            if fresh.swap(false, Ordering::AcqRel) {
+                self.num_fresh_parts.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+
+        let parts: Option<Vec<Arc<P>>> = self.parts.iter().map(|part| part.load_full()).collect();
+
+        if let Some(parts) = parts {
+            self.response.store(Some(Arc::new(merge(&parts))));
+        }
+    }
+
+    /// Get cached response, if available and rate limit allows it
+    pub fn get(&self) -> Result<Arc<R>, FullScrapeError> {
+        let response = self.response.load_full().ok_or(FullScrapeError::NotReady)?;
+
+        if self.consume_rate_limit() {
+            Ok(response)
+        } else {
+            Err(FullScrapeError::RateLimited)
+        }
+    }
+
+    fn consume_rate_limit(&self) -> bool {
+        if self.max_responses_per_minute == 0 {
+            return true;
+        }
+
+        let window = self.created.elapsed().as_secs() / 60;
+        let mut state = self.rate_limit_state.load(Ordering::Relaxed);
+
+        loop {
+            let new_state = if state >> 32 == window {
+                if (state & u64::from(u32::MAX)) >= u64::from(self.max_responses_per_minute) {
+                    return false;
+                }
+
+                state + 1
+            } else {
+                (window << 32) | 1
+            };
+
+            match self.rate_limit_state.compare_exchange_weak(
+                state,
+                new_state,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(current) => state = current,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(parts: &[Arc<usize>]) -> usize {
+        parts.iter().map(|part| **part).sum()
+    }
+
+    #[test]
+    fn test_full_scrape_cache_merge() {
+        let cache = FullScrapeCache::new(&FullScrapeConfig::default(), 2);
+
+        assert_eq!(cache.get(), Err(FullScrapeError::NotReady));
+
+        cache.update(0, 1, merge);
+
+        assert_eq!(cache.get(), Err(FullScrapeError::NotReady));
+
+        cache.update(1, 2, merge);
+
+        assert_eq!(cache.get().map(|r| *r), Ok(3));
+
+        // Response is only regenerated once all workers have published again
+        cache.update(0, 5, merge);
+
+        assert_eq!(cache.get().map(|r| *r), Ok(3));
+
+        cache.update(0, 6, merge);
+        cache.update(1, 2, merge);
+
+        assert_eq!(cache.get().map(|r| *r), Ok(8));
+    }
+
+    #[test]
+    fn test_full_scrape_cache_merge_locked() {
+        let cache = FullScrapeCache::new(&FullScrapeConfig::default(), 1);
+
+        let guard = cache.merge_lock.lock().unwrap();
+
+        // Doesn't block while another worker is merging
+        cache.update(0, 1, merge);
+
+        assert_eq!(cache.get(), Err(FullScrapeError::NotReady));
+
+        drop(guard);
+
+        cache.update(0, 2, merge);
+
+        assert_eq!(cache.get().map(|r| *r), Ok(2));
+    }
+
+    #[test]
+    fn test_full_scrape_cache_rate_limit() {
+        let config = FullScrapeConfig {
+            max_responses_per_minute: 2,
+            ..Default::default()
+        };
+
+        let cache = FullScrapeCache::new(&config, 1);
+
+        cache.update(0, 1, merge);
+
+        assert!(cache.get().is_ok());
+        assert!(cache.get().is_ok());
+        assert_eq!(cache.get(), Err(FullScrapeError::RateLimited));
+    }
+}
@@ -5,13 +5,23 @@ use std::time::Instant;
 
 use ahash::RandomState;
 use rand::Rng;
+use serde::{Deserialize, Deserializer};
 
 pub mod access_list;
+pub mod accounting;
+pub mod admin;
 pub mod cli;
+pub mod connection_limits;
 pub mod cpu_pinning;
+pub mod file_watcher;
+pub mod full_scrape;
 pub mod privileges;
+pub mod proxy_protocol;
+pub mod reverse_proxy;
 #[cfg(feature = "rustls")]
 pub mod rustls_config;
+pub mod snapshot;
+pub mod token_bucket;
 
 /// IndexMap using AHash hasher
 pub type IndexMap<K, V> = indexmap::IndexMap<K, V, RandomState>;
@@ -33,6 +43,10 @@ impl ValidUntil {
     pub fn valid(&self, now: SecondsSinceServerStart) -> bool {
         self.0 .0 > now.0
     }
+    /// Seconds left until expiry, or zero if already expired
+    pub fn seconds_remaining(&self, now: SecondsSinceServerStart) -> u32 {
+        self.0 .0.saturating_sub(now.0)
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -94,6 +108,41 @@ impl Drop for PanicSentinel {
     }
 }
 
+/// Send SIGUSR1 to current process, causing files such as the access list
+/// to be reloaded
+pub(crate) fn raise_reload_signal() -> anyhow::Result<()> {
+    if unsafe { libc::kill(libc::getpid(), libc::SIGUSR1) } == -1 {
+        Err(anyhow::anyhow!(
+            "Could not send SIGUSR1: {:#}",
+            ::std::io::Error::last_os_error()
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Deserialize either a single value or a list of values
+///
+/// Useful for turning config fields into lists without breaking existing
+/// config files.
+pub fn deserialize_one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => Ok(vec![value]),
+        OneOrMany::Many(values) => Ok(values),
+    }
+}
+
 /// SocketAddr that is not an IPv6-mapped IPv4 address
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
 pub struct CanonicalSocketAddr(SocketAddr);
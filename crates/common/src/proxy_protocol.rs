@@ -0,0 +1,334 @@
+//! PROXY protocol header parsing
+//!
+//! Supports version 1 (text) and version 2 (binary) headers as sent by load
+//! balancers such as HAProxy and AWS NLB. See
+//! https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::Context;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_FIXED_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Length of header in bytes
+    pub len: usize,
+    /// Client address. None means that the connection address should be
+    /// used, e.g., for health checks by the proxy itself.
+    pub opt_source_addr: Option<SocketAddr>,
+}
+
+/// Parse PROXY protocol header at start of bytes
+///
+/// Returns Ok(None) if more bytes are needed.
+pub fn parse_header(bytes: &[u8]) -> anyhow::Result<Option<Header>> {
+    if matches_prefix(bytes, V2_SIGNATURE) {
+        parse_v2_header(bytes)
+    } else if matches_prefix(bytes, V1_PREFIX) {
+        parse_v1_header(bytes)
+    } else {
+        Err(anyhow::anyhow!("no PROXY protocol header"))
+    }
+}
+
+/// Read PROXY protocol header from stream and return client address
+///
+/// Connections from addresses not in trusted_sources are rejected. Only the
+/// header is consumed, so TLS or HTTP processing can continue on the stream
+/// afterwards.
+#[cfg(feature = "glommio")]
+pub async fn read_source_addr(
+    stream: &mut ::glommio::net::TcpStream,
+    trusted_sources: &[ipnet::IpNet],
+) -> anyhow::Result<SocketAddr> {
+    use futures_lite::AsyncReadExt;
+
+    let connection_addr = stream
+        .peer_addr()
+        .map_err(|err| anyhow::anyhow!("could not get peer address: {:#}", err))?;
+    let connection_addr = crate::CanonicalSocketAddr::new(connection_addr).get();
+
+    if !trusted_sources
+        .iter()
+        .any(|network| network.contains(&connection_addr.ip()))
+    {
+        return Err(anyhow::anyhow!(
+            "PROXY protocol connection from untrusted address {}",
+            connection_addr.ip()
+        ));
+    }
+
+    let mut header_bytes = Vec::new();
+    let mut buffer = [0u8; 512];
+
+    loop {
+        let bytes_peeked = stream
+            .peek(&mut buffer)
+            .await
+            .map_err(|err| anyhow::anyhow!("error peeking: {:#}", err))?;
+
+        if bytes_peeked == 0 {
+            return Err(anyhow::anyhow!(
+                "connection closed before end of PROXY protocol header"
+            ));
+        }
+
+        let bytes_consumed = header_bytes.len();
+
+        header_bytes.extend_from_slice(&buffer[..bytes_peeked]);
+
+        if let Some(header) = parse_header(&header_bytes)? {
+            stream
+                .read_exact(&mut buffer[..header.len - bytes_consumed])
+                .await
+                .context("read PROXY protocol header")?;
+
+            return Ok(header.opt_source_addr.unwrap_or(connection_addr));
+        }
+
+        stream
+            .read_exact(&mut buffer[..bytes_peeked])
+            .await
+            .context("read PROXY protocol header")?;
+    }
+}
+
+fn matches_prefix(bytes: &[u8], prefix: &[u8]) -> bool {
+    let len = bytes.len().min(prefix.len());
+
+    bytes[..len] == prefix[..len]
+}
+
+fn parse_v1_header(bytes: &[u8]) -> anyhow::Result<Option<Header>> {
+    let end = match bytes
+        .windows(2)
+        .take(V1_MAX_LEN - 1)
+        .position(|window| window == b"\r\n")
+    {
+        Some(end) => end,
+        None if bytes.len() >= V1_MAX_LEN => {
+            return Err(anyhow::anyhow!("PROXY protocol v1 header too long"))
+        }
+        None => return Ok(None),
+    };
+
+    let line = ::std::str::from_utf8(&bytes[..end]).context("PROXY protocol v1 header")?;
+    let mut fields = line.split(' ').skip(1);
+
+    let opt_source_addr = match fields.next() {
+        Some("UNKNOWN") => None,
+        Some(protocol @ ("TCP4" | "TCP6")) => {
+            let (source_ip, destination_ip, source_port) =
+                match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                    (Some(source_ip), Some(destination_ip), Some(source_port), Some(_)) => {
+                        (source_ip, destination_ip, source_port)
+                    }
+                    _ => return Err(anyhow::anyhow!("PROXY protocol v1 header missing fields")),
+                };
+
+            let source_ip: IpAddr = source_ip
+                .parse()
+                .context("PROXY protocol v1 source address")?;
+            let destination_ip: IpAddr = destination_ip
+                .parse()
+                .context("PROXY protocol v1 destination address")?;
+            let source_port: u16 = source_port
+                .parse()
+                .context("PROXY protocol v1 source port")?;
+
+            if source_ip.is_ipv4() != (protocol == "TCP4")
+                || destination_ip.is_ipv4() != (protocol == "TCP4")
+            {
+                return Err(anyhow::anyhow!(
+                    "PROXY protocol v1 address doesn't match protocol {}",
+                    protocol
+                ));
+            }
+
+            Some(SocketAddr::new(source_ip, source_port))
+        }
+        _ => return Err(anyhow::anyhow!("PROXY protocol v1 header invalid protocol")),
+    };
+
+    Ok(Some(Header {
+        len: end + 2,
+        opt_source_addr,
+    }))
+}
+
+fn parse_v2_header(bytes: &[u8]) -> anyhow::Result<Option<Header>> {
+    if bytes.len() < V2_FIXED_LEN {
+        return Ok(None);
+    }
+
+    let version_and_command = bytes[12];
+    let family_and_protocol = bytes[13];
+    let len = V2_FIXED_LEN + u16::from_be_bytes([bytes[14], bytes[15]]) as usize;
+
+    if version_and_command >> 4 != 2 {
+        return Err(anyhow::anyhow!("PROXY protocol v2 header invalid version"));
+    }
+    if bytes.len() < len {
+        return Ok(None);
+    }
+
+    let addresses = &bytes[V2_FIXED_LEN..len];
+
+    let opt_source_addr = match (version_and_command & 0x0f, family_and_protocol) {
+        // LOCAL command: connection was established by proxy itself
+        (0x0, _) => None,
+        // PROXY command, TCP over IPv4
+        (0x1, 0x11) if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        // PROXY command, TCP over IPv6
+        (0x1, 0x21) if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+
+            octets.copy_from_slice(&addresses[..16]);
+
+            let ip = Ipv6Addr::from(octets);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        (0x1, 0x11 | 0x21) => {
+            return Err(anyhow::anyhow!(
+                "PROXY protocol v2 header address block too short"
+            ))
+        }
+        // PROXY command, unspecified or unsupported protocol
+        (0x1, _) => None,
+        _ => return Err(anyhow::anyhow!("PROXY protocol v2 header invalid command")),
+    };
+
+    Ok(Some(Header {
+        len,
+        opt_source_addr,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_header(command: u8, family_and_protocol: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut bytes = V2_SIGNATURE.to_vec();
+
+        bytes.push(0x20 | command);
+        bytes.push(family_and_protocol);
+        bytes.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(addresses);
+
+        bytes
+    }
+
+    #[test]
+    fn test_parse_v1_header() {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /";
+
+        assert_eq!(
+            parse_header(header).unwrap(),
+            Some(Header {
+                len: header.len() - 5,
+                opt_source_addr: Some("192.0.2.1:56324".parse().unwrap()),
+            })
+        );
+
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+
+        assert_eq!(
+            parse_header(header).unwrap(),
+            Some(Header {
+                len: header.len(),
+                opt_source_addr: Some("[2001:db8::1]:56324".parse().unwrap()),
+            })
+        );
+
+        assert_eq!(
+            parse_header(b"PROXY UNKNOWN\r\n").unwrap(),
+            Some(Header {
+                len: 15,
+                opt_source_addr: None,
+            })
+        );
+
+        assert_eq!(parse_header(b"").unwrap(), None);
+        assert_eq!(parse_header(b"PRO").unwrap(), None);
+        assert_eq!(parse_header(b"PROXY TCP4 192.0.2.1").unwrap(), None);
+
+        assert!(parse_header(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 2001:db8::1 198.51.100.1 1 2\r\n").is_err());
+        assert!(parse_header(b"PROXY TCP4 192.0.2.1 198.51.100.1 1\r\n").is_err());
+        assert!(parse_header(b"PROXY UDP4 192.0.2.1 198.51.100.1 1 2\r\n").is_err());
+
+        let mut header = V1_PREFIX.to_vec();
+
+        header.resize(V1_MAX_LEN - 1, b'0');
+
+        assert_eq!(parse_header(&header).unwrap(), None);
+
+        header.push(b'0');
+
+        assert!(parse_header(&header).is_err());
+    }
+
+    #[test]
+    fn test_parse_v2_header() {
+        let addresses = [
+            192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb, 0xaa, 0xbb,
+        ];
+        let header = v2_header(0x1, 0x11, &addresses);
+
+        assert_eq!(
+            parse_header(&header).unwrap(),
+            Some(Header {
+                len: header.len(),
+                opt_source_addr: Some("192.0.2.1:56324".parse().unwrap()),
+            })
+        );
+
+        for len in 0..header.len() {
+            assert_eq!(parse_header(&header[..len]).unwrap(), None);
+        }
+
+        let mut addresses = Vec::new();
+
+        addresses.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+
+        let header = v2_header(0x1, 0x21, &addresses);
+
+        assert_eq!(
+            parse_header(&header).unwrap(),
+            Some(Header {
+                len: header.len(),
+                opt_source_addr: Some("[2001:db8::1]:56324".parse().unwrap()),
+            })
+        );
+
+        assert_eq!(
+            parse_header(&v2_header(0x0, 0x00, &[])).unwrap(),
+            Some(Header {
+                len: 16,
+                opt_source_addr: None,
+            })
+        );
+
+        assert!(parse_header(&v2_header(0x1, 0x11, &[192, 0, 2, 1])).is_err());
+        assert!(parse_header(&v2_header(0x2, 0x11, &[0; 12])).is_err());
+
+        let mut header = v2_header(0x1, 0x11, &[0; 12]);
+
+        header[12] = 0x11;
+
+        assert!(parse_header(&header).is_err());
+    }
+}
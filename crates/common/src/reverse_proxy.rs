@@ -0,0 +1,148 @@
+//! Peer IP extraction from reverse proxy headers such as `X-Forwarded-For`
+//! and `Forwarded` (RFC 7239)
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, TomlConfig, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReverseProxyPeerIpHeaderFormat {
+    #[default]
+    LastAddress,
+    NthLastAddress,
+    RightmostUntrustedAddress,
+    Forwarded,
+}
+
+/// Extract peer IP from values of all instances of the header, in order
+///
+/// Multiple instances of the header are equivalent to a single one with the
+/// values joined by commas. `position` is only used with
+/// `NthLastAddress` and `trusted_networks` only with
+/// `RightmostUntrustedAddress` and `Forwarded`.
+pub fn extract_peer_ip<'a>(
+    format: ReverseProxyPeerIpHeaderFormat,
+    position: usize,
+    trusted_networks: &[IpNet],
+    header_values: impl IntoIterator<Item = &'a [u8]>,
+) -> anyhow::Result<IpAddr> {
+    let mut elements = Vec::new();
+
+    for value in header_values {
+        elements.extend(::std::str::from_utf8(value)?.split(',').map(str::trim));
+    }
+
+    if elements.is_empty() {
+        return Err(anyhow::anyhow!("header not present"));
+    }
+
+    match format {
+        ReverseProxyPeerIpHeaderFormat::LastAddress => parse_ip(elements[elements.len() - 1]),
+        ReverseProxyPeerIpHeaderFormat::NthLastAddress => {
+            let element = position
+                .checked_sub(1)
+                .and_then(|index| elements.iter().rev().nth(index))
+                .ok_or(anyhow::anyhow!("too few addresses in header"))?;
+
+            parse_ip(element)
+        }
+        ReverseProxyPeerIpHeaderFormat::RightmostUntrustedAddress => {
+            rightmost_untrusted_ip(&elements, trusted_networks, parse_ip)
+        }
+        ReverseProxyPeerIpHeaderFormat::Forwarded => {
+            rightmost_untrusted_ip(&elements, trusted_networks, parse_forwarded_element_ip)
+        }
+    }
+}
+
+/// Return rightmost address not in trusted networks, or leftmost address if
+/// all are trusted
+fn rightmost_untrusted_ip(
+    elements: &[&str],
+    trusted_networks: &[IpNet],
+    parse: fn(&str) -> anyhow::Result<IpAddr>,
+) -> anyhow::Result<IpAddr> {
+    let mut opt_leftmost_ip = None;
+
+    for element in elements.iter().rev() {
+        let ip = parse(element)?;
+
+        if !trusted_networks.iter().any(|network| network.contains(&ip)) {
+            return Ok(ip);
+        }
+
+        opt_leftmost_ip = Some(ip);
+    }
+
+    opt_leftmost_ip.ok_or(anyhow::anyhow!("no header value"))
+}
+
+fn parse_ip(element: &str) -> anyhow::Result<IpAddr> {
+    element.parse::<IpAddr>().with_context(|| "parse ip")
+}
+
+/// Parse IP from for parameter of RFC 7239 element, e.g.,
+/// `for=192.0.2.60;proto=http` or `for="[2001:db8::1]:4711"`
+fn parse_forwarded_element_ip(element: &str) -> anyhow::Result<IpAddr> {
+    for pair in element.split(';') {
+        let (name, value) = match pair.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+
+        if !name.eq_ignore_ascii_case("for") {
+            continue;
+        }
+
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+
+        let ip = if let Some(value) = value.strip_prefix('[') {
+            let (ip, _) = value
+                .split_once(']')
+                .ok_or(anyhow::anyhow!("unterminated ipv6 address"))?;
+
+            IpAddr::V6(ip.parse::<Ipv6Addr>().with_context(|| "parse ip")?)
+        } else {
+            // Port is optional. Values such as unknown or obfuscated
+            // identifiers fail to parse.
+            let ip = value.split(':').next().unwrap_or(value);
+
+            IpAddr::V4(ip.parse::<Ipv4Addr>().with_context(|| "parse ip")?)
+        };
+
+        return Ok(ip);
+    }
+
+    Err(anyhow::anyhow!("no for parameter in element"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_forwarded_element_ip() {
+        assert_eq!(
+            parse_forwarded_element_ip("for=192.0.2.60;proto=http").unwrap(),
+            IpAddr::from([192, 0, 2, 60])
+        );
+        assert_eq!(
+            parse_forwarded_element_ip("proto=https; FOR=\"192.0.2.60:8080\"").unwrap(),
+            IpAddr::from([192, 0, 2, 60])
+        );
+        assert_eq!(
+            parse_forwarded_element_ip("for=\"[2001:db8::1]\"").unwrap(),
+            IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1])
+        );
+
+        assert!(parse_forwarded_element_ip("for=_hidden").is_err());
+        assert!(parse_forwarded_element_ip("for=\"[2001:db8::1\"").is_err());
+        assert!(parse_forwarded_element_ip("by=192.0.2.60").is_err());
+    }
+}
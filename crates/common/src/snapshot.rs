@@ -0,0 +1,603 @@
+//! Swarm state snapshots
+//!
+//! Swarm workers can periodically save their state to disk and load it on
+//! startup, so that restarting the tracker doesn't wipe all swarms.
+//!
+//! Snapshot file layout (all integers big-endian):
+//!
+//! - magic bytes (8)
+//! - container format version (u16)
+//! - protocol (u8)
+//! - payload format version (u16)
+//! - swarm worker index (u32)
+//! - number of swarm workers (u32)
+//! - payload length (u64)
+//! - payload
+//! - CRC32 checksum of everything above (u32)
+//!
+//! Payload encoding is protocol specific. Expiry times (`ValidUntil`) are
+//! stored as seconds remaining, so that they can be rebased onto the
+//! `ServerStartInstant` of the next server run.
+
+use std::fs::File;
+use std::io::Write;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use aquatic_toml_config::TomlConfig;
+use serde::Deserialize;
+
+use crate::{ServerStartInstant, ValidUntil};
+
+const MAGIC: &[u8; 8] = b"AQSNAPSH";
+const CONTAINER_FORMAT_VERSION: u16 = 1;
+const HEADER_LEN: usize = 8 + 2 + 1 + 2 + 4 + 4 + 8;
+const CHECKSUM_LEN: usize = 4;
+
+/// How long to wait for swarm workers to save snapshots on shutdown
+pub const SHUTDOWN_SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// Save swarm state to disk on shutdown (SIGTERM) and periodically,
+    /// and load it on startup
+    pub enabled: bool,
+    /// Directory to store snapshot files in, one per swarm worker
+    ///
+    /// Snapshots are loaded before privileges are dropped, but saved after.
+    /// If using chroot mode, make sure that the path resolves to the same
+    /// directory in both cases.
+    pub directory: PathBuf,
+    /// Save snapshots this often (seconds). Set to zero to only save them
+    /// on shutdown.
+    pub interval: u64,
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            directory: "./snapshots".into(),
+            interval: 60 * 5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SnapshotProtocol {
+    Udp = 0,
+    Http = 1,
+    Ws = 2,
+}
+
+impl SnapshotProtocol {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Udp),
+            1 => Some(Self::Http),
+            2 => Some(Self::Ws),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Udp => "udp",
+            Self::Http => "http",
+            Self::Ws => "ws",
+        }
+    }
+}
+
+/// Identifies which swarm worker a snapshot belongs to and how its payload
+/// is encoded. Snapshots are only loaded if their header matches exactly.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SnapshotHeader {
+    pub protocol: SnapshotProtocol,
+    pub payload_format_version: u16,
+    pub worker_index: u32,
+    pub num_workers: u32,
+}
+
+impl SnapshotHeader {
+    pub fn new(
+        protocol: SnapshotProtocol,
+        payload_format_version: u16,
+        worker_index: usize,
+        num_workers: usize,
+    ) -> Self {
+        Self {
+            protocol,
+            payload_format_version,
+            worker_index: worker_index as u32,
+            num_workers: num_workers as u32,
+        }
+    }
+
+    pub fn file_path(&self, config: &SnapshotConfig) -> PathBuf {
+        config.directory.join(format!(
+            "{}-swarm-worker-{}.snapshot",
+            self.protocol.as_str(),
+            self.worker_index
+        ))
+    }
+}
+
+/// Encode snapshot payload and write it to file, logging the outcome
+///
+/// The file is written to a temporary path first and then renamed, so
+/// existing snapshots are never left half-written.
+pub fn save_snapshot<F>(config: &SnapshotConfig, header: SnapshotHeader, encode: F)
+where
+    F: FnOnce(&mut SnapshotEncoder),
+{
+    let path = header.file_path(config);
+    let start = Instant::now();
+
+    let mut encoder = SnapshotEncoder::default();
+
+    encode(&mut encoder);
+
+    match write_snapshot_file(config, &path, header, &encoder.0) {
+        Ok(()) => ::log::info!(
+            "Saved snapshot to {} ({} bytes) in {} ms",
+            path.display(),
+            encoder.0.len(),
+            start.elapsed().as_millis()
+        ),
+        Err(err) => ::log::error!("Saving snapshot to {} failed: {:#}", path.display(), err),
+    }
+}
+
+/// Read snapshot file and decode its payload, logging the outcome
+///
+/// Returns None if no snapshot exists or if it couldn't be loaded.
+pub fn load_snapshot<T, F>(config: &SnapshotConfig, header: SnapshotHeader, decode: F) -> Option<T>
+where
+    F: FnOnce(&mut SnapshotDecoder) -> anyhow::Result<T>,
+{
+    let path = header.file_path(config);
+
+    if !path.exists() {
+        ::log::info!("No snapshot found at {}", path.display());
+
+        return None;
+    }
+
+    let result = read_snapshot_file(&path, header).and_then(|payload| {
+        let mut decoder = SnapshotDecoder::new(&payload);
+
+        let value = decode(&mut decoder)?;
+
+        decoder.finish()?;
+
+        Ok(value)
+    });
+
+    match result {
+        Ok(value) => {
+            ::log::info!("Loaded snapshot from {}", path.display());
+
+            Some(value)
+        }
+        Err(err) => {
+            ::log::error!(
+                "Loading snapshot from {} failed, starting with empty state: {:#}",
+                path.display(),
+                err
+            );
+
+            None
+        }
+    }
+}
+
+fn write_snapshot_file(
+    config: &SnapshotConfig,
+    path: &Path,
+    header: SnapshotHeader,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len() + CHECKSUM_LEN);
+
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&CONTAINER_FORMAT_VERSION.to_be_bytes());
+    bytes.push(header.protocol as u8);
+    bytes.extend_from_slice(&header.payload_format_version.to_be_bytes());
+    bytes.extend_from_slice(&header.worker_index.to_be_bytes());
+    bytes.extend_from_slice(&header.num_workers.to_be_bytes());
+    bytes.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    bytes.extend_from_slice(payload);
+
+    let checksum = crc32fast::hash(&bytes);
+
+    bytes.extend_from_slice(&checksum.to_be_bytes());
+
+    ::std::fs::create_dir_all(&config.directory)
+        .with_context(|| format!("create directory {}", config.directory.display()))?;
+
+    let tmp_path = path.with_extension("snapshot.tmp");
+
+    {
+        let mut file = File::create(&tmp_path)
+            .with_context(|| format!("create file {}", tmp_path.display()))?;
+
+        file.write_all(&bytes).with_context(|| "write file")?;
+        file.sync_all().with_context(|| "sync file")?;
+    }
+
+    ::std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("rename {} to {}", tmp_path.display(), path.display()))?;
+
+    Ok(())
+}
+
+fn read_snapshot_file(path: &Path, expected_header: SnapshotHeader) -> anyhow::Result<Vec<u8>> {
+    let bytes = ::std::fs::read(path).with_context(|| "read file")?;
+
+    parse_snapshot(bytes, expected_header)
+}
+
+fn parse_snapshot(mut bytes: Vec<u8>, expected_header: SnapshotHeader) -> anyhow::Result<Vec<u8>> {
+    if bytes.len() < HEADER_LEN + CHECKSUM_LEN || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(anyhow::anyhow!("not a snapshot file"));
+    }
+
+    let checksum_offset = bytes.len() - CHECKSUM_LEN;
+    let expected_checksum = u32::from_be_bytes(bytes[checksum_offset..].try_into().unwrap());
+
+    if crc32fast::hash(&bytes[..checksum_offset]) != expected_checksum {
+        return Err(anyhow::anyhow!("checksum mismatch, file is corrupt"));
+    }
+
+    let mut decoder = SnapshotDecoder::new(&bytes[MAGIC.len()..checksum_offset]);
+
+    let container_format_version = decoder.read_u16()?;
+
+    if container_format_version != CONTAINER_FORMAT_VERSION {
+        return Err(anyhow::anyhow!(
+            "unsupported container format version {} (expected {})",
+            container_format_version,
+            CONTAINER_FORMAT_VERSION
+        ));
+    }
+
+    let protocol = decoder.read_u8()?;
+    let protocol = SnapshotProtocol::from_u8(protocol)
+        .with_context(|| format!("unknown protocol {}", protocol))?;
+
+    let header = SnapshotHeader {
+        protocol,
+        payload_format_version: decoder.read_u16()?,
+        worker_index: decoder.read_u32()?,
+        num_workers: decoder.read_u32()?,
+    };
+
+    if header != expected_header {
+        return Err(anyhow::anyhow!(
+            "header mismatch: snapshot has {:?}, expected {:?}",
+            header,
+            expected_header
+        ));
+    }
+
+    let payload_len = decoder.read_u64()?;
+
+    if payload_len != (checksum_offset - HEADER_LEN) as u64 {
+        return Err(anyhow::anyhow!("payload length mismatch"));
+    }
+
+    bytes.truncate(checksum_offset);
+    bytes.drain(..HEADER_LEN);
+
+    Ok(bytes)
+}
+
+/// Write snapshot payload values
+#[derive(Default)]
+pub struct SnapshotEncoder(Vec<u8>);
+
+impl SnapshotEncoder {
+    pub fn write_u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value.into());
+    }
+
+    pub fn write_len(&mut self, len: usize) {
+        self.write_u64(len as u64);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    /// Write length-prefixed string
+    pub fn write_str(&mut self, value: &str) {
+        self.write_len(value.len());
+        self.write_bytes(value.as_bytes());
+    }
+
+    pub fn write_ip<I: SnapshotIp>(&mut self, ip: I) {
+        ip.write(self);
+    }
+
+    /// Write seconds remaining until expiry
+    pub fn write_valid_until(
+        &mut self,
+        valid_until: ValidUntil,
+        server_start_instant: ServerStartInstant,
+    ) {
+        self.write_u32(valid_until.seconds_remaining(server_start_instant.seconds_elapsed()));
+    }
+}
+
+/// Read snapshot payload values
+pub struct SnapshotDecoder<'a>(&'a [u8]);
+
+impl<'a> SnapshotDecoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    pub fn read_u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_bool(&mut self) -> anyhow::Result<bool> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(anyhow::anyhow!("invalid bool value {}", value)),
+        }
+    }
+
+    /// Read collection length, checking that it is plausible given the
+    /// number of remaining bytes and the minimum item size
+    pub fn read_len(&mut self, min_item_size: usize) -> anyhow::Result<usize> {
+        let len = self.read_u64()?;
+
+        if len.saturating_mul(min_item_size as u64) > self.0.len() as u64 {
+            return Err(anyhow::anyhow!("invalid collection length {}", len));
+        }
+
+        Ok(len as usize)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        if self.0.len() < N {
+            return Err(anyhow::anyhow!("unexpected end of payload"));
+        }
+
+        let (bytes, rest) = self.0.split_at(N);
+
+        self.0 = rest;
+
+        Ok(bytes.try_into().unwrap())
+    }
+
+    /// Read length-prefixed string
+    pub fn read_str(&mut self) -> anyhow::Result<&'a str> {
+        let len = self.read_len(1)?;
+
+        let (bytes, rest) = self.0.split_at(len);
+
+        self.0 = rest;
+
+        Ok(::std::str::from_utf8(bytes)?)
+    }
+
+    pub fn read_ip<I: SnapshotIp>(&mut self) -> anyhow::Result<I> {
+        I::read(self)
+    }
+
+    /// Read seconds remaining until expiry and rebase onto current server run
+    pub fn read_valid_until(
+        &mut self,
+        server_start_instant: ServerStartInstant,
+    ) -> anyhow::Result<ValidUntil> {
+        Ok(ValidUntil::new(server_start_instant, self.read_u32()?))
+    }
+
+    fn finish(self) -> anyhow::Result<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "{} trailing bytes in payload",
+                self.0.len()
+            ))
+        }
+    }
+}
+
+/// IP address types that can be stored in snapshots
+pub trait SnapshotIp: Sized {
+    fn write(&self, encoder: &mut SnapshotEncoder);
+    fn read(decoder: &mut SnapshotDecoder) -> anyhow::Result<Self>;
+}
+
+impl SnapshotIp for Ipv4Addr {
+    fn write(&self, encoder: &mut SnapshotEncoder) {
+        encoder.write_bytes(&self.octets());
+    }
+    fn read(decoder: &mut SnapshotDecoder) -> anyhow::Result<Self> {
+        Ok(Self::from(decoder.read_array::<4>()?))
+    }
+}
+
+impl SnapshotIp for Ipv6Addr {
+    fn write(&self, encoder: &mut SnapshotEncoder) {
+        encoder.write_bytes(&self.octets());
+    }
+    fn read(decoder: &mut SnapshotDecoder) -> anyhow::Result<Self> {
+        Ok(Self::from(decoder.read_array::<16>()?))
+    }
+}
+
+/// Lets the main thread ask swarm workers to save snapshots on shutdown and
+/// wait for them to finish
+#[derive(Clone, Default)]
+pub struct ShutdownSnapshotSignal {
+    requested: Arc<AtomicBool>,
+    num_done: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl ShutdownSnapshotSignal {
+    /// Called by swarm workers to check if they should save a final snapshot
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    /// Called by swarm workers after saving final snapshot
+    pub fn mark_done(&self) {
+        let (lock, condvar) = &*self.num_done;
+
+        *lock.lock().unwrap() += 1;
+
+        condvar.notify_all();
+    }
+
+    /// Request final snapshots and wait until `num_workers` swarm workers
+    /// have saved them or timeout is reached
+    pub fn request_and_wait(&self, num_workers: usize, timeout: Duration) {
+        self.requested.store(true, Ordering::Relaxed);
+
+        let (lock, condvar) = &*self.num_done;
+
+        let (num_done, _) = condvar
+            .wait_timeout_while(lock.lock().unwrap(), timeout, |num_done| {
+                *num_done < num_workers
+            })
+            .unwrap();
+
+        if *num_done < num_workers {
+            ::log::error!(
+                "Only {} of {} swarm workers saved snapshots before timeout",
+                num_done,
+                num_workers
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> SnapshotHeader {
+        SnapshotHeader::new(SnapshotProtocol::Udp, 1, 0, 2)
+    }
+
+    fn config() -> (tempfile::TempDir, SnapshotConfig) {
+        let dir = tempfile::tempdir().unwrap();
+
+        let config = SnapshotConfig {
+            enabled: true,
+            directory: dir.path().into(),
+            interval: 0,
+        };
+
+        (dir, config)
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let (_dir, config) = config();
+        let server_start_instant = ServerStartInstant::new();
+
+        save_snapshot(&config, header(), |encoder| {
+            encoder.write_len(1);
+            encoder.write_ip(Ipv6Addr::LOCALHOST);
+            encoder.write_bool(true);
+            encoder.write_valid_until(
+                ValidUntil::new(server_start_instant, 100),
+                server_start_instant,
+            );
+            encoder.write_str("passkey");
+        });
+
+        let loaded = load_snapshot(&config, header(), |decoder| {
+            let len = decoder.read_len(1)?;
+            let ip: Ipv6Addr = decoder.read_ip()?;
+            let seeder = decoder.read_bool()?;
+            let valid_until = decoder.read_valid_until(server_start_instant)?;
+            let passkey = decoder.read_str()?.to_owned();
+
+            Ok((len, ip, seeder, valid_until, passkey))
+        });
+
+        let (len, ip, seeder, valid_until, passkey) = loaded.unwrap();
+
+        assert_eq!(len, 1);
+        assert_eq!(ip, Ipv6Addr::LOCALHOST);
+        assert!(seeder);
+        assert_eq!(
+            valid_until.seconds_remaining(server_start_instant.seconds_elapsed()),
+            100
+        );
+        assert_eq!(passkey, "passkey");
+    }
+
+    #[test]
+    fn test_snapshot_corrupt_or_mismatched() {
+        let (_dir, config) = config();
+
+        save_snapshot(&config, header(), |encoder| encoder.write_u64(1));
+
+        let decode = |decoder: &mut SnapshotDecoder| decoder.read_u64();
+
+        // Different number of workers
+        let other_header = SnapshotHeader::new(SnapshotProtocol::Udp, 1, 0, 3);
+
+        ::std::fs::rename(header().file_path(&config), other_header.file_path(&config)).unwrap();
+
+        assert!(load_snapshot(&config, other_header, decode).is_none());
+
+        ::std::fs::rename(other_header.file_path(&config), header().file_path(&config)).unwrap();
+
+        assert_eq!(load_snapshot(&config, header(), decode), Some(1));
+
+        // Flipped bit
+        let path = header().file_path(&config);
+        let mut bytes = ::std::fs::read(&path).unwrap();
+
+        bytes[HEADER_LEN] ^= 1;
+
+        ::std::fs::write(&path, bytes).unwrap();
+
+        assert!(load_snapshot(&config, header(), decode).is_none());
+    }
+}
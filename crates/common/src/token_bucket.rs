@@ -0,0 +1,121 @@
+//! Token bucket rate limiting with separate buckets for each request type
+//!
+//! Limits are passed in on each call instead of being stored in the buckets,
+//! so that rate limiters tracking many sources only keep them once.
+
+use std::time::Instant;
+
+/// Sustained rate and burst size of a token bucket
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub per_second: f32,
+    pub burst: f32,
+}
+
+impl Limit {
+    /// A rate of zero disables limiting
+    pub fn is_unlimited(&self) -> bool {
+        self.per_second <= 0.0
+    }
+}
+
+/// Token buckets for `N` request types, refilled together
+#[derive(Clone, Debug)]
+pub struct TokenBuckets<const N: usize> {
+    tokens: [f32; N],
+    last_refill: Instant,
+}
+
+impl<const N: usize> TokenBuckets<N> {
+    /// Create full buckets
+    pub fn new(limits: &[Limit; N], now: Instant) -> Self {
+        Self {
+            tokens: limits.map(|limit| limit.burst),
+            last_refill: now,
+        }
+    }
+
+    /// Refill buckets and take a token from the one at `index`. Returns false
+    /// if it was empty.
+    pub fn take(&mut self, limits: &[Limit; N], index: usize, now: Instant) -> bool {
+        if limits[index].is_unlimited() {
+            return true;
+        }
+
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f32();
+
+        for (tokens, limit) in self.tokens.iter_mut().zip(limits) {
+            *tokens = (*tokens + elapsed * limit.per_second).min(limit.burst);
+        }
+
+        self.last_refill = now;
+
+        let tokens = &mut self.tokens[index];
+
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn last_refill(&self) -> Instant {
+        self.last_refill
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const LIMITS: [Limit; 3] = [
+        Limit {
+            per_second: 1.0,
+            burst: 2.0,
+        },
+        Limit {
+            per_second: 0.0,
+            burst: 0.0,
+        },
+        Limit {
+            per_second: 1.0,
+            burst: 1.0,
+        },
+    ];
+
+    #[test]
+    fn test_token_buckets() {
+        let now = Instant::now();
+
+        let mut buckets = TokenBuckets::new(&LIMITS, now);
+
+        assert!(buckets.take(&LIMITS, 0, now));
+        assert!(buckets.take(&LIMITS, 0, now));
+        assert!(!buckets.take(&LIMITS, 0, now));
+
+        // Buckets are separate for each request type
+        assert!(buckets.take(&LIMITS, 2, now));
+        assert!(!buckets.take(&LIMITS, 2, now));
+
+        // Request types with zero rate are not limited
+        for _ in 0..10 {
+            assert!(buckets.take(&LIMITS, 1, now));
+        }
+
+        // Tokens are refilled over time, up to burst size
+        let now = now + Duration::from_secs(10);
+
+        assert!(buckets.take(&LIMITS, 0, now));
+        assert!(buckets.take(&LIMITS, 0, now));
+        assert!(!buckets.take(&LIMITS, 0, now));
+        assert!(buckets.take(&LIMITS, 2, now));
+
+        assert_eq!(buckets.last_refill(), now);
+    }
+}
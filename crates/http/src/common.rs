@@ -1,15 +1,28 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
 use std::sync::Arc;
 
+use anyhow::Context;
 use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::connection_limits::SourceConnectionCounts;
+use aquatic_common::snapshot::ShutdownSnapshotSignal;
 use aquatic_common::CanonicalSocketAddr;
 
 pub use aquatic_common::ValidUntil;
 
 use aquatic_http_protocol::{
+    common::{InfoHash, Passkey},
     request::{AnnounceRequest, ScrapeRequest},
-    response::{AnnounceResponse, ScrapeResponse},
+    response::{AnnounceResponse, ScrapeResponse, ScrapeStatistics},
 };
+
+use crate::config::{Config, PrivateModeConfig};
+use arc_swap::{ArcSwap, Cache};
+use compact_str::CompactString;
 use glommio::channels::shared_channel::SharedSender;
+use serde::Serialize;
 use slotmap::new_key_type;
 
 #[derive(Copy, Clone, Debug)]
@@ -33,7 +46,147 @@ pub enum ChannelRequest {
     },
 }
 
-#[derive(Default, Clone)]
+/// Account that transfers are aggregated by
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountId {
+    Passkey(Passkey),
+    Key(CompactString),
+}
+
+impl AccountId {
+    pub fn from_announce_request(request: &AnnounceRequest) -> Option<Self> {
+        if let Some(ref passkey) = request.passkey {
+            Some(Self::Passkey(passkey.clone()))
+        } else {
+            request.key.clone().map(Self::Key)
+        }
+    }
+}
+
+pub type FullScrapeCache =
+    aquatic_common::full_scrape::FullScrapeCache<FullScrapePart, FullScrapeResponse>;
+
+/// Scrape statistics of all torrents of a swarm worker
+pub struct FullScrapePart {
+    pub ipv4: Vec<(InfoHash, ScrapeStatistics)>,
+    pub ipv6: Vec<(InfoHash, ScrapeStatistics)>,
+}
+
+/// Pre-encoded full scrape response bodies
+pub struct FullScrapeResponse {
+    pub ipv4: Vec<u8>,
+    pub ipv6: Vec<u8>,
+}
+
+impl FullScrapeResponse {
+    pub fn merge(parts: &[Arc<FullScrapePart>]) -> Self {
+        Self {
+            ipv4: Self::encode(parts.iter().flat_map(|part| part.ipv4.iter())),
+            ipv6: Self::encode(parts.iter().flat_map(|part| part.ipv6.iter())),
+        }
+    }
+
+    fn encode<'a>(files: impl Iterator<Item = &'a (InfoHash, ScrapeStatistics)>) -> Vec<u8> {
+        let response = ScrapeResponse {
+            files: files.cloned().collect::<BTreeMap<_, _>>(),
+        };
+
+        let mut body = Vec::new();
+
+        response
+            .write(&mut body)
+            .expect("write scrape response to vec");
+
+        body
+    }
+}
+
+/// Passkeys of users allowed to use the tracker in private mode
+#[derive(Default)]
+pub struct UserDatabase(HashSet<Passkey>);
+
+impl UserDatabase {
+    pub fn create_from_path(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+
+        let mut passkeys = HashSet::new();
+
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let passkey = Passkey::from_path_segment(line)
+                .with_context(|| format!("Invalid line in user database: {}", line))?;
+
+            passkeys.insert(passkey);
+        }
+
+        Ok(Self(passkeys))
+    }
+
+    pub fn contains(&self, passkey: &Passkey) -> bool {
+        self.0.contains(passkey)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+pub type UserDatabaseArcSwap = ArcSwap<UserDatabase>;
+pub type UserDatabaseCache = Cache<Arc<UserDatabaseArcSwap>, Arc<UserDatabase>>;
+
+pub fn update_user_database(
+    config: &PrivateModeConfig,
+    user_database: &Arc<UserDatabaseArcSwap>,
+) -> anyhow::Result<()> {
+    if config.enabled {
+        match UserDatabase::create_from_path(&config.user_database_path) {
+            Ok(new_user_database) => {
+                ::log::info!(
+                    "User database updated ({} passkeys)",
+                    new_user_database.len()
+                );
+
+                user_database.store(Arc::new(new_user_database));
+            }
+            Err(err) => {
+                ::log::error!("Updating user database failed: {:#}", err);
+
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Clone)]
 pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
+    pub user_database: Arc<UserDatabaseArcSwap>,
+    pub shutdown_snapshot_signal: ShutdownSnapshotSignal,
+    pub full_scrape: Arc<FullScrapeCache>,
+    pub source_connection_counts: Arc<SourceConnectionCounts>,
+}
+
+impl State {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            access_list: Default::default(),
+            user_database: Default::default(),
+            shutdown_snapshot_signal: Default::default(),
+            full_scrape: Arc::new(FullScrapeCache::new(
+                &config.full_scrape,
+                config.swarm_workers,
+            )),
+            source_connection_counts: Default::default(),
+        }
+    }
 }
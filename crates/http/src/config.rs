@@ -1,19 +1,25 @@
 use std::{net::SocketAddr, path::PathBuf};
 
 use aquatic_common::{
-    access_list::AccessListConfig, cpu_pinning::asc::CpuPinningConfigAsc,
-    privileges::PrivilegeConfig,
+    access_list::AccessListConfig, accounting::AccountingConfig, admin::AdminConfig,
+    connection_limits::ConnectionLimitConfig, cpu_pinning::asc::CpuPinningConfigAsc,
+    file_watcher::FileWatcherConfig, full_scrape::FullScrapeConfig, privileges::PrivilegeConfig,
+    reverse_proxy::ReverseProxyPeerIpHeaderFormat, rustls_config::SniCertificateConfig,
+    snapshot::SnapshotConfig,
 };
 use aquatic_toml_config::TomlConfig;
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 
 use aquatic_common::cli::LogLevel;
 
 #[derive(Clone, Copy, Debug, PartialEq, Serialize, TomlConfig, Deserialize, Default)]
 #[serde(rename_all = "snake_case")]
-pub enum ReverseProxyPeerIpHeaderFormat {
+pub enum AnnounceAddressPolicy {
     #[default]
-    LastAddress,
+    Ignore,
+    OtherVersion,
+    Any,
 }
 
 /// aquatic_http configuration
@@ -32,9 +38,18 @@ pub struct Config {
     /// generate responses and send them back to the socket workers.
     pub swarm_workers: usize,
     pub log_level: LogLevel,
+    #[serde(deserialize_with = "deserialize_network_config")]
     pub network: NetworkConfig,
     pub protocol: ProtocolConfig,
     pub cleaning: CleaningConfig,
+    /// Concurrent connection limits
+    ///
+    /// Connections over a limit are closed right after being accepted.
+    /// Per-IP limits use the TCP peer address, or the source address from the
+    /// PROXY protocol header on listeners with proxy_protocol enabled. They
+    /// are not applied on other listeners when running behind a reverse
+    /// proxy, since all connections would then come from the proxy.
+    pub connection_limits: ConnectionLimitConfig,
     pub privileges: PrivilegeConfig,
     /// Access list configuration
     ///
@@ -43,6 +58,37 @@ pub struct Config {
     /// emitting of an error-level log message, while successful updates of the
     /// access list result in emitting of an info-level log message.
     pub access_list: AccessListConfig,
+    /// Private tracker mode configuration
+    ///
+    /// The user database is read on start and when the program receives
+    /// `SIGUSR1`, in the same way as the access list.
+    pub private_mode: PrivateModeConfig,
+    /// File watcher configuration
+    ///
+    /// When enabled, changes to the access list, the user database and the
+    /// TLS certificate and private key trigger the same reload as `SIGUSR1`.
+    pub file_watcher: FileWatcherConfig,
+    /// Transfer accounting configuration
+    ///
+    /// Transfers are aggregated per passkey if present in request path,
+    /// otherwise per `key` query parameter. Lines look like
+    /// `{"time":1700000000,"passkey":"abc","uploaded":10,"downloaded":20}`.
+    /// Pending transfers are also flushed on `SIGTERM`.
+    pub accounting: AccountingConfig,
+    /// Swarm state snapshot configuration
+    ///
+    /// Snapshots are only loaded if they were saved with the same number of
+    /// swarm workers.
+    pub snapshot: SnapshotConfig,
+    /// Full scrape configuration
+    ///
+    /// A scrape request without info hashes (`/scrape`) is a full scrape.
+    /// The response contains all torrents for the IP version of the request.
+    pub full_scrape: FullScrapeConfig,
+    /// Admin socket configuration
+    ///
+    /// Use `aquatic ctl` to send commands.
+    pub admin: AdminConfig,
     pub cpu_pinning: CpuPinningConfigAsc,
     #[cfg(feature = "metrics")]
     pub metrics: MetricsConfig,
@@ -57,8 +103,15 @@ impl Default for Config {
             network: NetworkConfig::default(),
             protocol: ProtocolConfig::default(),
             cleaning: CleaningConfig::default(),
+            connection_limits: ConnectionLimitConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            private_mode: PrivateModeConfig::default(),
+            file_watcher: FileWatcherConfig::default(),
+            accounting: AccountingConfig::default(),
+            snapshot: SnapshotConfig::default(),
+            full_scrape: FullScrapeConfig::default(),
+            admin: AdminConfig::default(),
             cpu_pinning: Default::default(),
             #[cfg(feature = "metrics")]
             metrics: Default::default(),
@@ -75,24 +128,46 @@ impl aquatic_common::cli::Config for Config {
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct NetworkConfig {
-    /// Bind to this address
-    pub address: SocketAddr,
-    /// Only allow access over IPv6
-    pub only_ipv6: bool,
-    /// Maximum number of pending TCP connections
-    pub tcp_backlog: i32,
-    /// Enable TLS
+    /// Accept connections on these listeners
+    ///
+    /// Each listener is a table with the following fields:
+    /// - address: bind to this address
+    /// - enable_tls: enable TLS
+    /// - tls_certificate_path: path to default TLS certificate chain (PEM)
+    /// - tls_private_key_path: path to default TLS private key (PEM, in
+    ///   PKCS#8, PKCS#1 or SEC1 format)
+    /// - tls_sni_certificates: optional list of additional certificates,
+    ///   each a table with server_names (list of names, which may start
+    ///   with `*.`), certificate_path and private_key_path. They are served
+    ///   to clients requesting one of the names through SNI. Other clients
+    ///   get the default certificate.
+    /// - proxy_protocol: require a PROXY protocol (v1 or v2) header at the
+    ///   start of each connection, before TLS, and use the client address in
+    ///   it as peer address
+    /// - proxy_protocol_trusted_sources: networks, e.g., `10.0.0.0/8`,
+    ///   allowed to connect to the listener when proxy_protocol is enabled.
+    ///   Connections from other addresses are closed. Can't be empty when
+    ///   proxy_protocol is enabled.
+    ///
+    /// The TLS fields can be left out for listeners without TLS. Every socket
+    /// worker accepts connections on all listeners, and requests received on
+    /// any of them are handled by the same swarm workers.
     ///
     /// The TLS files are read on start and when the program receives `SIGUSR1`.
     /// If initial parsing fails, the program exits. Later failures result in
     /// in emitting of an error-level log message, while successful updates
     /// result in emitting of an info-level log message. Updates only affect
     /// new connections.
-    pub enable_tls: bool,
-    /// Path to TLS certificate (DER-encoded X.509)
-    pub tls_certificate_path: PathBuf,
-    /// Path to TLS private key (DER-encoded ASN.1 in PKCS#8 or PKCS#1 format)
-    pub tls_private_key_path: PathBuf,
+    ///
+    /// The old flat address, enable_tls, tls_certificate_path and
+    /// tls_private_key_path fields of this section are still accepted and
+    /// are turned into a single listener. They can't be combined with
+    /// listeners.
+    pub listeners: Vec<ListenerConfig>,
+    /// Only allow access over IPv6 on listeners bound to IPv6 addresses
+    pub only_ipv6: bool,
+    /// Maximum number of pending TCP connections
+    pub tcp_backlog: i32,
     /// Keep connections alive after sending a response
     pub keep_alive: bool,
     /// Does tracker run behind reverse proxy?
@@ -110,26 +185,115 @@ pub struct NetworkConfig {
     pub reverse_proxy_ip_header_name: String,
     /// How to extract peer IP from header field
     ///
+    /// Multiple instances of the header are treated as a single
+    /// comma-separated list of addresses.
+    ///
     /// Options:
     /// - last_address: use the last address in the last instance of the
     ///   header. Works with typical multi-IP setups (e.g., "X-Forwarded-For")
     ///   as well as for single-IP setups (e.g., nginx "X-Real-IP")
+    /// - nth_last_address: use the address at position
+    ///   reverse_proxy_ip_header_position counted from the right. Use when
+    ///   requests always pass through the same number of proxies.
+    /// - rightmost_untrusted_address: use the rightmost address not in
+    ///   reverse_proxy_trusted_networks. Use when requests pass through a
+    ///   varying number of proxies, e.g., a CDN in front of nginx. If all
+    ///   addresses are trusted, the leftmost one is used.
+    /// - forwarded: parse the for parameter of RFC 7239 elements (set header
+    ///   name to Forwarded) and select address like
+    ///   rightmost_untrusted_address
     pub reverse_proxy_ip_header_format: ReverseProxyPeerIpHeaderFormat,
+    /// Position of peer address counted from the right, starting at 1 (the
+    /// last address). Only used with nth_last_address format.
+    pub reverse_proxy_ip_header_position: usize,
+    /// Addresses of proxies, e.g., `10.0.0.0/8`. Only used with
+    /// rightmost_untrusted_address and forwarded formats.
+    pub reverse_proxy_trusted_networks: Vec<IpNet>,
 }
 
 impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
-            address: SocketAddr::from(([0, 0, 0, 0], 3000)),
-            enable_tls: false,
-            tls_certificate_path: "".into(),
-            tls_private_key_path: "".into(),
+            listeners: vec![ListenerConfig::default()],
             only_ipv6: false,
             tcp_backlog: 1024,
             keep_alive: true,
             runs_behind_reverse_proxy: false,
             reverse_proxy_ip_header_name: "X-Forwarded-For".into(),
             reverse_proxy_ip_header_format: Default::default(),
+            reverse_proxy_ip_header_position: 1,
+            reverse_proxy_trusted_networks: Vec::new(),
+        }
+    }
+}
+
+/// Fields of the network section that were replaced by listeners
+const LEGACY_LISTENER_FIELDS: [&str; 4] = [
+    "address",
+    "enable_tls",
+    "tls_certificate_path",
+    "tls_private_key_path",
+];
+
+/// Deserialize network section, turning legacy flat listener fields into a
+/// single listener
+fn deserialize_network_config<'de, D>(deserializer: D) -> Result<NetworkConfig, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use aquatic_toml_config::toml::Value;
+    use serde::de::Error;
+
+    let mut value = Value::deserialize(deserializer)?;
+
+    if let Value::Table(table) = &mut value {
+        let mut listener = aquatic_toml_config::toml::value::Table::new();
+
+        for field in LEGACY_LISTENER_FIELDS {
+            if let Some(field_value) = table.remove(field) {
+                listener.insert(field.to_string(), field_value);
+            }
+        }
+
+        if !listener.is_empty() {
+            if table.contains_key("listeners") {
+                return Err(D::Error::custom(
+                    "network.address, network.enable_tls, network.tls_certificate_path and network.tls_private_key_path have been replaced by network.listeners and can't be combined with it; move them to a [[network.listeners]] entry",
+                ));
+            }
+
+            table.insert(
+                "listeners".to_string(),
+                Value::Array(vec![Value::Table(listener)]),
+            );
+        }
+    }
+
+    NetworkConfig::deserialize(value).map_err(D::Error::custom)
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    pub enable_tls: bool,
+    pub tls_certificate_path: PathBuf,
+    pub tls_private_key_path: PathBuf,
+    pub tls_sni_certificates: Vec<SniCertificateConfig>,
+    pub proxy_protocol: bool,
+    pub proxy_protocol_trusted_sources: Vec<IpNet>,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            address: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            enable_tls: false,
+            tls_certificate_path: "".into(),
+            tls_private_key_path: "".into(),
+            tls_sni_certificates: Vec::new(),
+            proxy_protocol: false,
+            proxy_protocol_trusted_sources: Vec::new(),
         }
     }
 }
@@ -143,6 +307,28 @@ pub struct ProtocolConfig {
     pub max_peers: usize,
     /// Ask peers to announce this often (seconds)
     pub peer_announce_interval: usize,
+    /// Allow peers to request non-compact peer lists (a list of dictionaries
+    /// with ip, port and optionally peer id) by sending compact=0. Such
+    /// responses are several times larger than compact ones.
+    pub allow_non_compact_responses: bool,
+    /// Policy for addresses sent in ipv4 and ipv6 announce parameters (BEP 7)
+    ///
+    /// Options:
+    /// - ignore: only register peers with the address of the connection
+    /// - other_version: also register peers with the address of the IP
+    ///   version that the request was not sent over
+    /// - any: like other_version, but also let the address of the IP version
+    ///   of the connection replace the connection address
+    ///
+    /// Only globally routable unicast addresses are accepted. Peers
+    /// registered with addresses of both IP versions receive peers of both
+    /// versions in announce responses.
+    ///
+    /// Options other than ignore let clients register addresses that they
+    /// don't control, so that other peers can be made to send traffic to
+    /// third parties. Only enable them if announcing clients are trusted,
+    /// e.g. in private mode.
+    pub announce_address_policy: AnnounceAddressPolicy,
 }
 
 impl Default for ProtocolConfig {
@@ -151,6 +337,33 @@ impl Default for ProtocolConfig {
             max_scrape_torrents: 100,
             max_peers: 50,
             peer_announce_interval: 120,
+            allow_non_compact_responses: false,
+            announce_address_policy: AnnounceAddressPolicy::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrivateModeConfig {
+    /// Only serve requests with a passkey present in the user database
+    ///
+    /// Clients include the passkey in the announce URL, as either
+    /// `/announce/<passkey>` or `/<passkey>/announce` (and likewise for
+    /// scrape).
+    pub enabled: bool,
+    /// Path to user database file consisting of newline-separated passkeys.
+    /// Passkeys may contain ASCII alphanumeric characters, `-` and `_`.
+    ///
+    /// If using chroot mode, path must be relative to new root.
+    pub user_database_path: PathBuf,
+}
+
+impl Default for PrivateModeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            user_database_path: "./users.txt".into(),
         }
     }
 }
@@ -164,6 +377,9 @@ pub struct CleaningConfig {
     pub connection_cleaning_interval: u64,
     /// Remove peers that have not announced for this long (seconds)
     pub max_peer_age: u32,
+    /// Keep torrents without peers for this long if they have a non-zero
+    /// completed count, so that it isn't lost (seconds)
+    pub max_torrent_idle_age: u32,
     /// Remove connections that haven't seen valid requests for this long (seconds)
     pub max_connection_idle: u32,
 }
@@ -174,6 +390,7 @@ impl Default for CleaningConfig {
             torrent_cleaning_interval: 30,
             connection_cleaning_interval: 60,
             max_peer_age: 1800,
+            max_torrent_idle_age: 60 * 60 * 24,
             max_connection_idle: 180,
         }
     }
@@ -207,4 +424,87 @@ mod tests {
     use super::Config;
 
     ::aquatic_toml_config::gen_serialize_deserialize_test!(Config);
+
+    #[test]
+    fn test_network_legacy_listener_fields() {
+        let config: Config = ::aquatic_toml_config::toml::from_str(
+            r#"
+            [network]
+            address = "[::]:443"
+            enable_tls = true
+            tls_certificate_path = "cert.crt"
+            tls_private_key_path = "key.pk8"
+            keep_alive = false
+            "#,
+        )
+        .unwrap();
+
+        let listeners = config.network.listeners;
+
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].address, "[::]:443".parse().unwrap());
+        assert!(listeners[0].enable_tls);
+        assert_eq!(listeners[0].tls_certificate_path.to_str(), Some("cert.crt"));
+        assert_eq!(listeners[0].tls_private_key_path.to_str(), Some("key.pk8"));
+        assert!(!config.network.keep_alive);
+
+        let result = ::aquatic_toml_config::toml::from_str::<Config>(
+            r#"
+            [network]
+            address = "0.0.0.0:80"
+
+            [[network.listeners]]
+            address = "[::]:443"
+            "#,
+        );
+
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("[[network.listeners]]"));
+    }
+
+    #[test]
+    fn test_network_listeners() {
+        let config: Config = ::aquatic_toml_config::toml::from_str(
+            r#"
+            [[network.listeners]]
+            address = "0.0.0.0:80"
+            proxy_protocol = true
+            proxy_protocol_trusted_sources = ["10.0.0.0/8", "fd00::/8"]
+
+            [[network.listeners]]
+            address = "[::]:443"
+            enable_tls = true
+            tls_certificate_path = "cert.crt"
+            tls_private_key_path = "key.pk8"
+
+            [[network.listeners.tls_sni_certificates]]
+            server_names = ["a.example.com", "*.b.example.com"]
+            certificate_path = "a.crt"
+            private_key_path = "a.key"
+            "#,
+        )
+        .unwrap();
+
+        let listeners = config.network.listeners;
+
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].address, "0.0.0.0:80".parse().unwrap());
+        assert!(!listeners[0].enable_tls);
+        assert!(listeners[0].proxy_protocol);
+        assert_eq!(
+            listeners[0].proxy_protocol_trusted_sources,
+            vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()]
+        );
+        assert!(!listeners[1].proxy_protocol);
+        assert_eq!(listeners[1].address, "[::]:443".parse().unwrap());
+        assert!(listeners[1].enable_tls);
+        assert_eq!(listeners[1].tls_certificate_path.to_str(), Some("cert.crt"));
+        assert_eq!(listeners[1].tls_sni_certificates.len(), 1);
+        assert_eq!(
+            listeners[1].tls_sni_certificates[0].server_names,
+            vec!["a.example.com", "*.b.example.com"]
+        );
+    }
 }
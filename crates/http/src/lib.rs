@@ -1,16 +1,20 @@
 use anyhow::Context;
 use aquatic_common::{
     access_list::update_access_list,
+    admin::{create_swarm_query_channels, spawn_admin_server},
     cpu_pinning::{
         glommio::{get_worker_placement, set_affinity_for_util_worker},
         WorkerIndex,
     },
+    file_watcher::spawn_file_watcher,
     privileges::PrivilegeDropper,
     rustls_config::create_rustls_config,
+    snapshot::SHUTDOWN_SNAPSHOT_TIMEOUT,
     PanicSentinelWatcher, ServerStartInstant,
 };
+use aquatic_http_protocol::common::InfoHash;
 use arc_swap::ArcSwap;
-use common::State;
+use common::{update_user_database, State};
 use glommio::{channels::channel_mesh::MeshBuilder, prelude::*};
 use signal_hook::{
     consts::{SIGTERM, SIGUSR1},
@@ -19,6 +23,8 @@ use signal_hook::{
 use std::sync::Arc;
 
 use crate::config::Config;
+use crate::workers::socket::calculate_request_consumer_index;
+use crate::workers::swarm::TorrentMaps;
 
 mod common;
 pub mod config;
@@ -30,6 +36,24 @@ pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 const SHARED_CHANNEL_SIZE: usize = 1024;
 
 pub fn run(config: Config) -> ::anyhow::Result<()> {
+    if config.network.listeners.is_empty() {
+        return Err(anyhow::anyhow!(
+            "configuration: network.listeners can't be empty"
+        ));
+    }
+    if config.network.listeners.iter().any(|listener| {
+        listener.proxy_protocol && listener.proxy_protocol_trusted_sources.is_empty()
+    }) {
+        return Err(anyhow::anyhow!(
+            "configuration: network.listeners proxy_protocol_trusted_sources can't be empty when proxy_protocol is enabled"
+        ));
+    }
+    if config.network.reverse_proxy_ip_header_position == 0 {
+        return Err(anyhow::anyhow!(
+            "configuration: network.reverse_proxy_ip_header_position must be at least 1"
+        ));
+    }
+
     let mut signals = Signals::new([SIGUSR1, SIGTERM])?;
 
     #[cfg(feature = "prometheus")]
@@ -47,9 +71,10 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
             })?;
     }
 
-    let state = State::default();
+    let state = State::new(&config);
 
     update_access_list(&config.access_list, &state.access_list)?;
+    update_user_database(&config.private_mode, &state.user_database)?;
 
     let num_peers = config.socket_workers + config.swarm_workers;
 
@@ -58,24 +83,85 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
     let (sentinel_watcher, sentinel) = PanicSentinelWatcher::create_with_sentinel();
     let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers);
 
-    let opt_tls_config = if config.network.enable_tls {
-        Some(Arc::new(ArcSwap::from_pointee(create_rustls_config(
-            &config.network.tls_certificate_path,
-            &config.network.tls_private_key_path,
-        )?)))
-    } else {
-        None
-    };
+    // One entry per listener
+    let tls_configs = config
+        .network
+        .listeners
+        .iter()
+        .map(|listener| {
+            if listener.enable_tls {
+                let tls_config = create_rustls_config(
+                    &listener.tls_certificate_path,
+                    &listener.tls_private_key_path,
+                    &listener.tls_sni_certificates,
+                )?;
+
+                Ok(Some(Arc::new(ArcSwap::from_pointee(tls_config))))
+            } else {
+                Ok(None)
+            }
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut watched_paths = Vec::new();
+
+    if config.access_list.mode.is_on() {
+        watched_paths.push(config.access_list.path.clone());
+    }
+    if config.private_mode.enabled {
+        watched_paths.push(config.private_mode.user_database_path.clone());
+    }
+    for listener in config.network.listeners.iter() {
+        if listener.enable_tls {
+            watched_paths.push(listener.tls_certificate_path.clone());
+            watched_paths.push(listener.tls_private_key_path.clone());
+
+            for sni_certificate in listener.tls_sni_certificates.iter() {
+                watched_paths.push(sni_certificate.certificate_path.clone());
+                watched_paths.push(sni_certificate.private_key_path.clone());
+            }
+        }
+    }
+
+    spawn_file_watcher(&config.file_watcher, watched_paths)?;
+
+    let (swarm_query_senders, swarm_query_receivers) =
+        create_swarm_query_channels(config.swarm_workers);
+    let mut swarm_query_receivers = swarm_query_receivers.into_iter();
+
+    // Bind before socket workers spawn, since they might chroot
+    spawn_admin_server(
+        &config.admin,
+        state.access_list.clone(),
+        swarm_query_senders,
+        {
+            let config = config.clone();
+
+            move |info_hash| calculate_request_consumer_index(&config, InfoHash(*info_hash))
+        },
+    )?;
 
     let server_start_instant = ServerStartInstant::new();
 
+    // Load snapshots before spawning socket workers, which might chroot
+    let mut loaded_torrents = (0..config.swarm_workers)
+        .map(|i| {
+            if config.snapshot.enabled {
+                TorrentMaps::load_snapshot(&config, server_start_instant, i)
+            } else {
+                TorrentMaps::default()
+            }
+        })
+        .collect::<Vec<_>>()
+        .into_iter();
+
     let mut executors = Vec::new();
 
     for i in 0..(config.socket_workers) {
         let sentinel = sentinel.clone();
         let config = config.clone();
         let state = state.clone();
-        let opt_tls_config = opt_tls_config.clone();
+        let tls_configs = tls_configs.clone();
         let request_mesh_builder = request_mesh_builder.clone();
         let priv_dropper = priv_dropper.clone();
 
@@ -93,7 +179,7 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                     sentinel,
                     config,
                     state,
-                    opt_tls_config,
+                    tls_configs,
                     request_mesh_builder,
                     priv_dropper,
                     server_start_instant,
@@ -111,6 +197,8 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         let config = config.clone();
         let state = state.clone();
         let request_mesh_builder = request_mesh_builder.clone();
+        let torrents = loaded_torrents.next().unwrap();
+        let swarm_query_receiver = swarm_query_receivers.next().unwrap();
 
         let placement = get_worker_placement(
             &config.cpu_pinning,
@@ -128,6 +216,8 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                     state,
                     request_mesh_builder,
                     server_start_instant,
+                    torrents,
+                    swarm_query_receiver,
                     i,
                 )
                 .await
@@ -149,27 +239,47 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         match signal {
             SIGUSR1 => {
                 let _ = update_access_list(&config.access_list, &state.access_list);
+                let _ = update_user_database(&config.private_mode, &state.user_database);
 
-                if let Some(tls_config) = opt_tls_config.as_ref() {
-                    match create_rustls_config(
-                        &config.network.tls_certificate_path,
-                        &config.network.tls_private_key_path,
-                    ) {
-                        Ok(config) => {
-                            tls_config.store(Arc::new(config));
+                for (listener, opt_tls_config) in
+                    config.network.listeners.iter().zip(tls_configs.iter())
+                {
+                    if let Some(tls_config) = opt_tls_config {
+                        match create_rustls_config(
+                            &listener.tls_certificate_path,
+                            &listener.tls_private_key_path,
+                            &listener.tls_sni_certificates,
+                        ) {
+                            Ok(config) => {
+                                tls_config.store(Arc::new(config));
 
-                            ::log::info!("successfully updated tls config");
+                                ::log::info!(
+                                    "successfully updated tls config for listener {}",
+                                    listener.address
+                                );
+                            }
+                            Err(err) => ::log::error!(
+                                "could not update tls config for listener {}: {:#}",
+                                listener.address,
+                                err
+                            ),
                         }
-                        Err(err) => ::log::error!("could not update tls config: {:#}", err),
                     }
                 }
             }
             SIGTERM => {
                 if sentinel_watcher.panic_was_triggered() {
                     return Err(anyhow::anyhow!("worker thread panicked"));
-                } else {
-                    return Ok(());
                 }
+
+                // Swarm workers also flush transfer accounting when signaled
+                if config.snapshot.enabled || config.accounting.enabled {
+                    state
+                        .shutdown_snapshot_signal
+                        .request_and_wait(config.swarm_workers, SHUTDOWN_SNAPSHOT_TIMEOUT);
+                }
+
+                return Ok(());
             }
             _ => unreachable!(),
         }
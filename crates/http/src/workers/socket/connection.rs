@@ -6,9 +6,11 @@ use std::sync::Arc;
 
 use anyhow::Context;
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::connection_limits::ConnectionLimiter;
+use aquatic_common::proxy_protocol::read_source_addr;
 use aquatic_common::rustls_config::RustlsConfig;
 use aquatic_common::{CanonicalSocketAddr, ServerStartInstant};
-use aquatic_http_protocol::common::InfoHash;
+use aquatic_http_protocol::common::{InfoHash, Passkey, PeerListFormat};
 use aquatic_http_protocol::request::{Request, ScrapeRequest};
 use aquatic_http_protocol::response::{
     FailureResponse, Response, ScrapeResponse, ScrapeStatistics,
@@ -28,12 +30,20 @@ use once_cell::sync::Lazy;
 use crate::common::*;
 use crate::config::Config;
 
+use super::calculate_request_consumer_index;
 use super::request::{parse_request, RequestParseError};
 #[cfg(feature = "metrics")]
 use super::{peer_addr_to_ip_version_str, WORKER_INDEX};
 
 const REQUEST_BUFFER_SIZE: usize = 2048;
-const RESPONSE_BUFFER_SIZE: usize = 4096;
+/// Longest possible peer in non-compact announce responses:
+/// `d2:ip45:<ip>7:peer id20:<peer id>4:porti65535ee`
+const MAX_DICTIONARY_PEER_LEN: usize = 5 + 3 + 45 + 12 + 20 + 7 + 5 + 2;
+/// Longest possible file in scrape responses:
+/// `20:<info hash>d8:completei<n>e10:downloadedi<n>e10:incompletei<n>ee`
+const MAX_SCRAPE_FILE_LEN: usize = 3 + 20 + 12 + 20 + 15 + 20 + 15 + 20 + 2;
+/// Room for the remaining response fields and for failure responses
+const RESPONSE_BUFFER_OVERHEAD: usize = 1024;
 
 const RESPONSE_HEADER_A: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: ";
 const RESPONSE_HEADER_B: &[u8] = b"        ";
@@ -53,6 +63,8 @@ pub enum ConnectionError {
     Inactive,
     #[error("socket peer addr extraction failed")]
     NoSocketPeerAddr(String),
+    #[error("source connection limit reached")]
+    SourceConnectionLimit,
     #[error("request buffer full")]
     RequestBufferFull,
     #[error("response buffer full")]
@@ -72,23 +84,55 @@ pub enum ConnectionError {
 pub(super) async fn run_connection(
     config: Rc<Config>,
     access_list: Arc<AccessListArcSwap>,
+    user_database: Arc<UserDatabaseArcSwap>,
+    full_scrape: Arc<FullScrapeCache>,
     request_senders: Rc<Senders<ChannelRequest>>,
     server_start_instant: ServerStartInstant,
     opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
+    listener_index: usize,
+    connection_limiter: Rc<ConnectionLimiter>,
     valid_until: Rc<RefCell<ValidUntil>>,
     close_conn_receiver: LocalReceiver<()>,
-    stream: TcpStream,
+    mut stream: TcpStream,
 ) -> Result<(), ConnectionError> {
     let access_list_cache = create_access_list_cache(&access_list);
+    let user_database_cache = UserDatabaseCache::from(user_database);
     let request_buffer = Box::new([0u8; REQUEST_BUFFER_SIZE]);
 
-    let mut response_buffer = Box::new([0; RESPONSE_BUFFER_SIZE]);
+    let mut response_buffer = vec![0; response_buffer_size(&config)].into_boxed_slice();
 
     response_buffer[..RESPONSE_HEADER.len()].copy_from_slice(&RESPONSE_HEADER);
 
-    let remote_addr = stream
-        .peer_addr()
-        .map_err(|err| ConnectionError::NoSocketPeerAddr(err.to_string()))?;
+    let listener_config = &config.network.listeners[listener_index];
+
+    let remote_addr = if listener_config.proxy_protocol {
+        read_source_addr(&mut stream, &listener_config.proxy_protocol_trusted_sources).await?
+    } else {
+        stream
+            .peer_addr()
+            .map_err(|err| ConnectionError::NoSocketPeerAddr(err.to_string()))?
+    };
+
+    // Without PROXY protocol, connections behind a reverse proxy all come
+    // from the proxy
+    let _opt_source_slot =
+        if listener_config.proxy_protocol || !config.network.runs_behind_reverse_proxy {
+            match connection_limiter.try_acquire_source_slot(remote_addr.ip()) {
+                Some(slot) => Some(slot),
+                None => {
+                    #[cfg(feature = "metrics")]
+                    ::metrics::increment_counter!(
+                        "aquatic_connections_rejected_total",
+                        "reason" => "source_limit",
+                        "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+                    );
+
+                    return Err(ConnectionError::SourceConnectionLimit);
+                }
+            }
+        } else {
+            None
+        };
 
     let opt_peer_addr = if config.network.runs_behind_reverse_proxy {
         None
@@ -108,6 +152,8 @@ pub(super) async fn run_connection(
         let mut conn = Connection {
             config,
             access_list_cache,
+            user_database_cache,
+            full_scrape,
             request_senders,
             valid_until,
             server_start_instant,
@@ -124,6 +170,8 @@ pub(super) async fn run_connection(
         let mut conn = Connection {
             config,
             access_list_cache,
+            user_database_cache,
+            full_scrape,
             request_senders,
             valid_until,
             server_start_instant,
@@ -141,9 +189,23 @@ pub(super) async fn run_connection(
     Ok(())
 }
 
+/// Size response buffer so that announce responses with `max_peers` IPv4 and
+/// `max_peers` IPv6 peers and scrape responses with `max_scrape_torrents`
+/// files always fit
+fn response_buffer_size(config: &Config) -> usize {
+    let max_announce_peers_len = 2 * config.protocol.max_peers * MAX_DICTIONARY_PEER_LEN;
+    let max_scrape_files_len = config.protocol.max_scrape_torrents * MAX_SCRAPE_FILE_LEN;
+
+    RESPONSE_HEADER.len()
+        + RESPONSE_BUFFER_OVERHEAD
+        + max_announce_peers_len.max(max_scrape_files_len)
+}
+
 struct Connection<S> {
     config: Rc<Config>,
     access_list_cache: AccessListCache,
+    user_database_cache: UserDatabaseCache,
+    full_scrape: Arc<FullScrapeCache>,
     request_senders: Rc<Senders<ChannelRequest>>,
     valid_until: Rc<RefCell<ValidUntil>>,
     server_start_instant: ServerStartInstant,
@@ -151,7 +213,7 @@ struct Connection<S> {
     peer_port: u16,
     request_buffer: Box<[u8; REQUEST_BUFFER_SIZE]>,
     request_buffer_position: usize,
-    response_buffer: Box<[u8; RESPONSE_BUFFER_SIZE]>,
+    response_buffer: Box<[u8]>,
     stream: S,
 }
 
@@ -174,6 +236,21 @@ where
         loop {
             let response = match self.read_request().await? {
                 Either::Left(response) => Response::Failure(response),
+                Either::Right(Request::Scrape(ScrapeRequest {
+                    ref info_hashes, ..
+                })) if info_hashes.is_empty() => match self.handle_full_scrape_request() {
+                    Ok(full_scrape_response) => {
+                        self.write_full_scrape_response(&full_scrape_response)
+                            .await?;
+
+                        if self.config.network.keep_alive {
+                            continue;
+                        } else {
+                            break;
+                        }
+                    }
+                    Err(response) => Response::Failure(response),
+                },
                 Either::Right(request) => self.handle_request(request).await?,
             };
 
@@ -221,6 +298,10 @@ where
                         )));
                     }
 
+                    if let Err(response) = self.check_passkey(request.passkey()) {
+                        return Ok(Either::Left(response));
+                    }
+
                     return Ok(Either::Right(request));
                 }
                 Err(RequestParseError::MoreDataNeeded) => continue,
@@ -240,6 +321,24 @@ where
         }
     }
 
+    /// In private mode, only allow requests with passkeys present in user
+    /// database
+    fn check_passkey(&mut self, opt_passkey: Option<&Passkey>) -> Result<(), FailureResponse> {
+        if !self.config.private_mode.enabled {
+            return Ok(());
+        }
+
+        match opt_passkey {
+            Some(passkey) if self.user_database_cache.load().contains(passkey) => Ok(()),
+            Some(_) => Err(FailureResponse {
+                failure_reason: "Unknown passkey".into(),
+            }),
+            None => Err(FailureResponse {
+                failure_reason: "Passkey required".into(),
+            }),
+        }
+    }
+
     /// Take a request and:
     /// - Update connection ValidUntil
     /// - Return error response if request is not allowed
@@ -269,6 +368,16 @@ where
 
                 let info_hash = request.info_hash;
 
+                if request.peer_list_format != PeerListFormat::Compact
+                    && !self.config.protocol.allow_non_compact_responses
+                {
+                    let response = Response::Failure(FailureResponse {
+                        failure_reason: "Non-compact responses are not allowed".into(),
+                    });
+
+                    return Ok(response);
+                }
+
                 if self
                     .access_list_cache
                     .load()
@@ -305,7 +414,10 @@ where
                     Ok(response)
                 }
             }
-            Request::Scrape(ScrapeRequest { info_hashes }) => {
+            Request::Scrape(ScrapeRequest {
+                info_hashes,
+                passkey,
+            }) => {
                 #[cfg(feature = "metrics")]
                 ::metrics::increment_counter!(
                     "aquatic_requests_total",
@@ -333,7 +445,10 @@ where
                     response_receivers.push(response_receiver);
 
                     let request = ChannelRequest::Scrape {
-                        request: ScrapeRequest { info_hashes },
+                        request: ScrapeRequest {
+                            info_hashes,
+                            passkey: passkey.clone(),
+                        },
                         peer_addr,
                         response_sender,
                     };
@@ -356,6 +471,39 @@ where
         }
     }
 
+    /// Return cached full scrape response, or failure response if full
+    /// scrapes are disabled, not yet available or rate limited
+    fn handle_full_scrape_request(&mut self) -> Result<Arc<FullScrapeResponse>, FailureResponse> {
+        *self.valid_until.borrow_mut() = ValidUntil::new(
+            self.server_start_instant,
+            self.config.cleaning.max_connection_idle,
+        );
+
+        #[cfg(feature = "metrics")]
+        {
+            let peer_addr = self
+                .opt_peer_addr
+                .expect("peer addr should already have been extracted by now");
+
+            ::metrics::increment_counter!(
+                "aquatic_requests_total",
+                "type" => "scrape",
+                "ip_version" => peer_addr_to_ip_version_str(&peer_addr),
+                "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+            );
+        }
+
+        if !self.config.full_scrape.enabled {
+            return Err(FailureResponse {
+                failure_reason: "Full scrapes are not allowed".into(),
+            });
+        }
+
+        self.full_scrape.get().map_err(|err| FailureResponse {
+            failure_reason: err.failure_reason().into(),
+        })
+    }
+
     /// Wait for partial scrape responses to arrive,
     /// return full response
     async fn wait_for_scrape_responses(
@@ -392,6 +540,50 @@ where
         }
     }
 
+    /// Write pre-encoded full scrape response body for the IP version of
+    /// the peer. It is usually too large for the regular response buffer.
+    async fn write_full_scrape_response(
+        &mut self,
+        response: &FullScrapeResponse,
+    ) -> Result<(), ConnectionError> {
+        let peer_addr = self
+            .opt_peer_addr
+            .expect("peer addr should already have been extracted by now");
+
+        let body = if peer_addr.is_ipv4() {
+            &response.ipv4
+        } else {
+            &response.ipv6
+        };
+
+        let mut header = Vec::with_capacity(RESPONSE_HEADER.len());
+
+        header.extend_from_slice(RESPONSE_HEADER_A);
+        header.extend_from_slice(::itoa::Buffer::new().format(body.len() + 2).as_bytes());
+        header.extend_from_slice(RESPONSE_HEADER_C);
+
+        self.stream
+            .write_all(&header)
+            .await
+            .with_context(|| "write")?;
+        self.stream.write_all(body).await.with_context(|| "write")?;
+        self.stream
+            .write_all(b"\r\n")
+            .await
+            .with_context(|| "write")?;
+        self.stream.flush().await.with_context(|| "flush")?;
+
+        #[cfg(feature = "metrics")]
+        ::metrics::increment_counter!(
+            "aquatic_responses_total",
+            "type" => "scrape",
+            "ip_version" => peer_addr_to_ip_version_str(&peer_addr),
+            "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+        );
+
+        Ok(())
+    }
+
     async fn write_response(&mut self, response: &Response) -> Result<(), ConnectionError> {
         // Write body and final newline to response buffer
 
@@ -466,6 +658,82 @@ where
     }
 }
 
-fn calculate_request_consumer_index(config: &Config, info_hash: InfoHash) -> usize {
-    (info_hash.0[0] as usize) % config.swarm_workers
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use aquatic_http_protocol::common::PeerId;
+    use aquatic_http_protocol::response::{
+        AnnounceResponse, ResponsePeer, ResponsePeerListV4, ResponsePeerListV6,
+    };
+
+    use super::*;
+
+    fn assert_response_fits(config: &Config, response: Response) {
+        let mut body = Vec::new();
+
+        let body_len = response.write(&mut body).unwrap();
+
+        assert!(RESPONSE_HEADER.len() + body_len + 2 <= response_buffer_size(config));
+    }
+
+    #[test]
+    fn test_response_buffer_size_announce_max_peers() {
+        let mut config = Config::default();
+
+        config.protocol.max_peers = 500;
+
+        let peer_id = Some(PeerId([255; 20]));
+        let ipv4_peer = ResponsePeer {
+            ip_address: Ipv4Addr::new(255, 255, 255, 255),
+            port: u16::MAX,
+            peer_id,
+        };
+        let ipv6_peer = ResponsePeer {
+            ip_address: Ipv6Addr::new(
+                0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff, 0xffff,
+            ),
+            port: u16::MAX,
+            peer_id,
+        };
+
+        let response = AnnounceResponse {
+            announce_interval: usize::MAX,
+            complete: usize::MAX,
+            incomplete: usize::MAX,
+            peers: ResponsePeerListV4(vec![ipv4_peer; config.protocol.max_peers]),
+            peers6: ResponsePeerListV6(vec![ipv6_peer; config.protocol.max_peers]),
+            warning_message: None,
+            peer_list_format: PeerListFormat::Dictionary {
+                include_peer_id: true,
+            },
+        };
+
+        assert_response_fits(&config, Response::Announce(response));
+    }
+
+    #[test]
+    fn test_response_buffer_size_scrape_max_torrents() {
+        let mut config = Config::default();
+
+        config.protocol.max_scrape_torrents = 500;
+
+        let files = (0..config.protocol.max_scrape_torrents)
+            .map(|i| {
+                let mut info_hash = InfoHash([0; 20]);
+
+                info_hash.0[..8].copy_from_slice(&(i as u64).to_be_bytes());
+
+                let statistics = ScrapeStatistics {
+                    complete: usize::MAX,
+                    incomplete: usize::MAX,
+                    downloaded: usize::MAX,
+                };
+
+                (info_hash, statistics)
+            })
+            .collect();
+
+        assert_response_fits(&config, Response::Scrape(ScrapeResponse { files }));
+    }
 }
@@ -2,15 +2,18 @@ mod connection;
 mod request;
 
 use std::cell::RefCell;
+use std::net::SocketAddr;
 use std::os::unix::prelude::{FromRawFd, IntoRawFd};
 use std::rc::Rc;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context;
+use aquatic_common::connection_limits::ConnectionLimiter;
 use aquatic_common::privileges::PrivilegeDropper;
 use aquatic_common::rustls_config::RustlsConfig;
 use aquatic_common::{CanonicalSocketAddr, PanicSentinel, ServerStartInstant};
+use aquatic_http_protocol::common::InfoHash;
 use arc_swap::ArcSwap;
 use futures_lite::StreamExt;
 use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role};
@@ -36,7 +39,7 @@ pub async fn run_socket_worker(
     _sentinel: PanicSentinel,
     config: Config,
     state: State,
-    opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
+    tls_configs: Vec<Option<Arc<ArcSwap<RustlsConfig>>>>,
     request_mesh_builder: MeshBuilder<ChannelRequest, Partial>,
     priv_dropper: PrivilegeDropper,
     server_start_instant: ServerStartInstant,
@@ -47,8 +50,14 @@ pub async fn run_socket_worker(
 
     let config = Rc::new(config);
     let access_list = state.access_list;
+    let user_database = state.user_database;
+    let full_scrape = state.full_scrape;
+    let connection_limiter = Rc::new(ConnectionLimiter::new(
+        &config.connection_limits,
+        state.source_connection_counts,
+    ));
 
-    let listener = create_tcp_listener(&config, priv_dropper).expect("create tcp listener");
+    let listeners = create_tcp_listeners(&config, priv_dropper).expect("create tcp listeners");
 
     let (request_senders, _) = request_mesh_builder.join(Role::Producer).await.unwrap();
     let request_senders = Rc::new(request_senders);
@@ -63,85 +72,136 @@ pub async fn run_socket_worker(
         )
     }));
 
-    let mut incoming = listener.incoming();
-
-    while let Some(stream) = incoming.next().await {
-        match stream {
-            Ok(stream) => {
-                let (close_conn_sender, close_conn_receiver) = new_bounded(1);
-
-                let valid_until = Rc::new(RefCell::new(ValidUntil::new(
-                    server_start_instant,
-                    config.cleaning.max_connection_idle,
-                )));
-
-                let connection_id = connection_handles.borrow_mut().insert(ConnectionHandle {
-                    close_conn_sender,
-                    valid_until: valid_until.clone(),
-                });
-
-                spawn_local(enclose!(
-                    (
-                        config,
-                        access_list,
-                        request_senders,
-                        opt_tls_config,
-                        connection_handles,
-                        valid_until,
-                    )
-                    async move {
-                        #[cfg(feature = "metrics")]
-                        ::metrics::increment_gauge!(
-                            "aquatic_active_connections",
-                            1.0,
-                            "worker_index" => worker_index.to_string(),
-                        );
-
-                        let result = run_connection(
-                            config,
-                            access_list,
-                            request_senders,
-                            server_start_instant,
-                            opt_tls_config,
-                            valid_until.clone(),
-                            close_conn_receiver,
-                            stream,
-                        ).await;
-
-                        #[cfg(feature = "metrics")]
-                        ::metrics::decrement_gauge!(
-                            "aquatic_active_connections",
-                            1.0,
-                            "worker_index" => worker_index.to_string(),
-                        );
-
-                        match result {
-                            Ok(()) => (),
-                            Err(err@(
-                                ConnectionError::ResponseBufferWrite(_) |
-                                ConnectionError::ResponseBufferFull |
-                                ConnectionError::ScrapeChannelError(_) |
-                                ConnectionError::ResponseSenderClosed
-                            )) => {
-                                ::log::error!("connection closed: {:#}", err);
-                            }
-                            Err(err@ConnectionError::RequestBufferFull) => {
-                                ::log::info!("connection closed: {:#}", err);
+    // Accept connections on all listeners concurrently
+    let listener_tasks = listeners
+        .into_iter()
+        .zip(tls_configs)
+        .enumerate()
+        .map(|(listener_index, (listener, opt_tls_config))| {
+            spawn_local(enclose!(
+                (
+                    config,
+                    access_list,
+                    user_database,
+                    full_scrape,
+                    request_senders,
+                    connection_handles,
+                    connection_limiter
+                )
+                async move {
+                    let mut incoming = listener.incoming();
+
+                    while let Some(stream) = incoming.next().await {
+                        match stream {
+                            Ok(stream) => {
+                                // Stream is closed when dropped
+                                let worker_slot = match connection_limiter.try_acquire_worker_slot() {
+                                    Some(slot) => slot,
+                                    None => {
+                                        ::log::debug!("connection closed: socket worker connection limit reached");
+
+                                        #[cfg(feature = "metrics")]
+                                        ::metrics::increment_counter!(
+                                            "aquatic_connections_rejected_total",
+                                            "reason" => "worker_limit",
+                                            "worker_index" => worker_index.to_string(),
+                                        );
+
+                                        continue;
+                                    }
+                                };
+
+                                let (close_conn_sender, close_conn_receiver) = new_bounded(1);
+
+                                let valid_until = Rc::new(RefCell::new(ValidUntil::new(
+                                    server_start_instant,
+                                    config.cleaning.max_connection_idle,
+                                )));
+
+                                let connection_id = connection_handles.borrow_mut().insert(ConnectionHandle {
+                                    close_conn_sender,
+                                    valid_until: valid_until.clone(),
+                                });
+
+                                spawn_local(enclose!(
+                                    (
+                                        config,
+                                        access_list,
+                                        user_database,
+                                        full_scrape,
+                                        request_senders,
+                                        opt_tls_config,
+                                        connection_handles,
+                                        connection_limiter,
+                                        valid_until,
+                                    )
+                                    async move {
+                                        #[cfg(feature = "metrics")]
+                                        ::metrics::increment_gauge!(
+                                            "aquatic_active_connections",
+                                            1.0,
+                                            "worker_index" => worker_index.to_string(),
+                                        );
+
+                                        let result = run_connection(
+                                            config,
+                                            access_list,
+                                            user_database,
+                                            full_scrape,
+                                            request_senders,
+                                            server_start_instant,
+                                            opt_tls_config,
+                                            listener_index,
+                                            connection_limiter,
+                                            valid_until.clone(),
+                                            close_conn_receiver,
+                                            stream,
+                                        ).await;
+
+                                        #[cfg(feature = "metrics")]
+                                        ::metrics::decrement_gauge!(
+                                            "aquatic_active_connections",
+                                            1.0,
+                                            "worker_index" => worker_index.to_string(),
+                                        );
+
+                                        match result {
+                                            Ok(()) => (),
+                                            Err(err@(
+                                                ConnectionError::ResponseBufferWrite(_) |
+                                                ConnectionError::ResponseBufferFull |
+                                                ConnectionError::ScrapeChannelError(_) |
+                                                ConnectionError::ResponseSenderClosed
+                                            )) => {
+                                                ::log::error!("connection closed: {:#}", err);
+                                            }
+                                            Err(err@ConnectionError::RequestBufferFull) => {
+                                                ::log::info!("connection closed: {:#}", err);
+                                            }
+                                            Err(err) => {
+                                                ::log::debug!("connection closed: {:#}", err);
+                                            }
+                                        }
+
+                                        connection_handles.borrow_mut().remove(connection_id);
+
+                                        drop(worker_slot);
+                                    }
+                                ))
+                                .detach();
                             }
                             Err(err) => {
-                                ::log::debug!("connection closed: {:#}", err);
+                                ::log::error!("accept connection: {:?}", err);
                             }
                         }
-
-                        connection_handles.borrow_mut().remove(connection_id);
                     }
-                ))
-                .detach();
-            }
-            Err(err) => {
-                ::log::error!("accept connection: {:?}", err);
-            }
-        }
+                }
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    for task in listener_tasks {
+        task.await;
     }
 }
 
@@ -167,11 +227,25 @@ async fn clean_connections(
     ))
 }
 
-fn create_tcp_listener(
+/// Create one listener for each entry in `config.network.listeners`
+fn create_tcp_listeners(
     config: &Config,
     priv_dropper: PrivilegeDropper,
-) -> anyhow::Result<TcpListener> {
-    let domain = if config.network.address.is_ipv4() {
+) -> anyhow::Result<Vec<TcpListener>> {
+    let listeners = config
+        .network
+        .listeners
+        .iter()
+        .map(|listener| create_tcp_listener(config, listener.address))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    priv_dropper.after_socket_creation()?;
+
+    Ok(listeners)
+}
+
+fn create_tcp_listener(config: &Config, address: SocketAddr) -> anyhow::Result<TcpListener> {
+    let domain = if address.is_ipv4() {
         socket2::Domain::IPV4
     } else {
         socket2::Domain::IPV6
@@ -179,7 +253,7 @@ fn create_tcp_listener(
 
     let socket = socket2::Socket::new(domain, socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
 
-    if config.network.only_ipv6 {
+    if address.is_ipv6() && config.network.only_ipv6 {
         socket
             .set_only_v6(true)
             .with_context(|| "socket: set only ipv6")?;
@@ -190,18 +264,20 @@ fn create_tcp_listener(
         .with_context(|| "socket: set reuse port")?;
 
     socket
-        .bind(&config.network.address.into())
-        .with_context(|| format!("socket: bind to {}", config.network.address))?;
+        .bind(&address.into())
+        .with_context(|| format!("socket: bind to {}", address))?;
 
     socket
         .listen(config.network.tcp_backlog)
-        .with_context(|| format!("socket: listen on {}", config.network.address))?;
-
-    priv_dropper.after_socket_creation()?;
+        .with_context(|| format!("socket: listen on {}", address))?;
 
     Ok(unsafe { TcpListener::from_raw_fd(socket.into_raw_fd()) })
 }
 
+pub(crate) fn calculate_request_consumer_index(config: &Config, info_hash: InfoHash) -> usize {
+    (info_hash.0[0] as usize) % config.swarm_workers
+}
+
 #[cfg(feature = "metrics")]
 fn peer_addr_to_ip_version_str(addr: &CanonicalSocketAddr) -> &'static str {
     if addr.is_ipv4() {
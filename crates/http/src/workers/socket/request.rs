@@ -1,9 +1,10 @@
 use std::net::IpAddr;
 
 use anyhow::Context;
+use aquatic_common::reverse_proxy::extract_peer_ip;
 use aquatic_http_protocol::request::Request;
 
-use crate::config::{Config, ReverseProxyPeerIpHeaderFormat};
+use crate::config::{Config, NetworkConfig};
 
 #[derive(Debug, thiserror::Error)]
 pub enum RequestParseError {
@@ -28,10 +29,7 @@ pub fn parse_request(
             let request = Request::from_http_get_path(path)?;
 
             let opt_peer_ip = if config.network.runs_behind_reverse_proxy {
-                let header_name = &config.network.reverse_proxy_ip_header_name;
-                let header_format = config.network.reverse_proxy_ip_header_format;
-
-                match parse_forwarded_header(header_name, header_format, http_request.headers) {
+                match parse_forwarded_header(&config.network, http_request.headers) {
                     Ok(peer_ip) => Some(peer_ip),
                     Err(err) => {
                         return Err(RequestParseError::RequiredPeerIpHeaderMissing(err));
@@ -48,31 +46,26 @@ pub fn parse_request(
 }
 
 fn parse_forwarded_header(
-    header_name: &str,
-    header_format: ReverseProxyPeerIpHeaderFormat,
+    config: &NetworkConfig,
     headers: &[httparse::Header<'_>],
 ) -> anyhow::Result<IpAddr> {
-    for header in headers.into_iter().rev() {
-        if header.name == header_name {
-            match header_format {
-                ReverseProxyPeerIpHeaderFormat::LastAddress => {
-                    return ::std::str::from_utf8(header.value)?
-                        .split(',')
-                        .last()
-                        .ok_or(anyhow::anyhow!("no header value"))?
-                        .trim()
-                        .parse::<IpAddr>()
-                        .with_context(|| "parse ip");
-                }
-            }
-        }
-    }
+    let header_values = headers
+        .iter()
+        .filter(|header| header.name == config.reverse_proxy_ip_header_name)
+        .map(|header| header.value);
 
-    Err(anyhow::anyhow!("header not present"))
+    extract_peer_ip(
+        config.reverse_proxy_ip_header_format,
+        config.reverse_proxy_ip_header_position,
+        &config.reverse_proxy_trusted_networks,
+        header_values,
+    )
 }
 
 #[cfg(test)]
 mod tests {
+    use aquatic_common::reverse_proxy::ReverseProxyPeerIpHeaderFormat;
+
     use super::*;
 
     const REQUEST_START: &str = "GET /announce?info_hash=%04%0bkV%3f%5cr%14%a6%b7%98%adC%c3%c9.%40%24%00%b9&peer_id=-ABC940-5ert69muw5t8&port=12345&uploaded=1&downloaded=2&left=3&numwant=0&key=4ab4b877&compact=1&supportcrypto=1&event=started HTTP/1.1\r\nHost: example.com\r\n";
@@ -144,4 +137,137 @@ mod tests {
             Err(RequestParseError::RequiredPeerIpHeaderMissing(_))
         ));
     }
+
+    #[test]
+    fn test_parse_peer_ip_header_nth_last() {
+        let mut config = Config::default();
+
+        config.network.runs_behind_reverse_proxy = true;
+        config.network.reverse_proxy_ip_header_format =
+            ReverseProxyPeerIpHeaderFormat::NthLastAddress;
+        config.network.reverse_proxy_ip_header_position = 2;
+
+        let mut request = REQUEST_START.to_string();
+
+        request.push_str("X-Forwarded-For: 1.2.3.4, 5.6.7.8\r\n");
+        request.push_str("X-Forwarded-For: 9.10.11.12\r\n");
+        request.push_str("\r\n");
+
+        let expected_ip = IpAddr::from([5, 6, 7, 8]);
+
+        assert_eq!(
+            parse_request(&config, request.as_bytes())
+                .unwrap()
+                .1
+                .unwrap(),
+            expected_ip
+        );
+
+        config.network.reverse_proxy_ip_header_position = 4;
+
+        let res = parse_request(&config, request.as_bytes());
+
+        assert!(matches!(
+            res,
+            Err(RequestParseError::RequiredPeerIpHeaderMissing(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_peer_ip_header_rightmost_untrusted() {
+        let mut config = Config::default();
+
+        config.network.runs_behind_reverse_proxy = true;
+        config.network.reverse_proxy_ip_header_format =
+            ReverseProxyPeerIpHeaderFormat::RightmostUntrustedAddress;
+        config.network.reverse_proxy_trusted_networks =
+            vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()];
+
+        let mut request = REQUEST_START.to_string();
+
+        request.push_str("X-Forwarded-For: 1.2.3.4, 5.6.7.8, fd00::1, 10.0.0.1\r\n");
+        request.push_str("\r\n");
+
+        let expected_ip = IpAddr::from([5, 6, 7, 8]);
+
+        assert_eq!(
+            parse_request(&config, request.as_bytes())
+                .unwrap()
+                .1
+                .unwrap(),
+            expected_ip
+        );
+    }
+
+    #[test]
+    fn test_parse_peer_ip_header_rightmost_untrusted_all_trusted() {
+        let mut config = Config::default();
+
+        config.network.runs_behind_reverse_proxy = true;
+        config.network.reverse_proxy_ip_header_format =
+            ReverseProxyPeerIpHeaderFormat::RightmostUntrustedAddress;
+        config.network.reverse_proxy_trusted_networks = vec!["10.0.0.0/8".parse().unwrap()];
+
+        let mut request = REQUEST_START.to_string();
+
+        request.push_str("X-Forwarded-For: 10.0.0.3, 10.0.0.2, 10.0.0.1\r\n");
+        request.push_str("\r\n");
+
+        let expected_ip = IpAddr::from([10, 0, 0, 3]);
+
+        assert_eq!(
+            parse_request(&config, request.as_bytes())
+                .unwrap()
+                .1
+                .unwrap(),
+            expected_ip
+        );
+    }
+
+    #[test]
+    fn test_parse_peer_ip_header_forwarded() {
+        let mut config = Config::default();
+
+        config.network.runs_behind_reverse_proxy = true;
+        config.network.reverse_proxy_ip_header_name = "Forwarded".into();
+        config.network.reverse_proxy_ip_header_format = ReverseProxyPeerIpHeaderFormat::Forwarded;
+        config.network.reverse_proxy_trusted_networks = vec!["10.0.0.0/8".parse().unwrap()];
+
+        let mut request = REQUEST_START.to_string();
+
+        request.push_str("Forwarded: for=1.2.3.4;proto=https\r\n");
+        request.push_str("Forwarded: For=\"[2001:db8::1]:4711\", for=10.0.0.1:80;by=10.0.0.2\r\n");
+        request.push_str("\r\n");
+
+        let expected_ip = IpAddr::from([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1]);
+
+        assert_eq!(
+            parse_request(&config, request.as_bytes())
+                .unwrap()
+                .1
+                .unwrap(),
+            expected_ip
+        );
+    }
+
+    #[test]
+    fn test_parse_peer_ip_header_forwarded_unknown() {
+        let mut config = Config::default();
+
+        config.network.runs_behind_reverse_proxy = true;
+        config.network.reverse_proxy_ip_header_name = "Forwarded".into();
+        config.network.reverse_proxy_ip_header_format = ReverseProxyPeerIpHeaderFormat::Forwarded;
+
+        let mut request = REQUEST_START.to_string();
+
+        request.push_str("Forwarded: for=unknown\r\n");
+        request.push_str("\r\n");
+
+        let res = parse_request(&config, request.as_bytes());
+
+        assert!(matches!(
+            res,
+            Err(RequestParseError::RequiredPeerIpHeaderMissing(_))
+        ));
+    }
 }
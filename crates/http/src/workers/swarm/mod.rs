@@ -1,8 +1,8 @@
 mod storage;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures_lite::{Stream, StreamExt};
 use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role};
@@ -11,12 +11,15 @@ use glommio::{enclose, prelude::*};
 use rand::prelude::SmallRng;
 use rand::SeedableRng;
 
+use aquatic_common::accounting::TransferAccounting;
+use aquatic_common::admin::SwarmQueryReceiver;
 use aquatic_common::{PanicSentinel, ServerStartInstant, ValidUntil};
+use aquatic_http_protocol::common::{InfoHash, PeerId};
 
 use crate::common::*;
 use crate::config::Config;
 
-use self::storage::TorrentMaps;
+pub use self::storage::TorrentMaps;
 
 #[cfg(feature = "metrics")]
 thread_local! { static WORKER_INDEX: ::std::cell::Cell<usize> = Default::default() }
@@ -27,6 +30,8 @@ pub async fn run_swarm_worker(
     state: State,
     request_mesh_builder: MeshBuilder<ChannelRequest, Partial>,
     server_start_instant: ServerStartInstant,
+    torrents: TorrentMaps,
+    swarm_query_receiver: SwarmQueryReceiver,
     worker_index: usize,
 ) {
     #[cfg(feature = "metrics")]
@@ -34,17 +39,104 @@ pub async fn run_swarm_worker(
 
     let (_, mut request_receivers) = request_mesh_builder.join(Role::Consumer).await.unwrap();
 
-    let torrents = Rc::new(RefCell::new(TorrentMaps::default()));
+    let torrents = Rc::new(RefCell::new(torrents));
+    let accounting = Rc::new(RefCell::new(TransferAccounting::new(&config.accounting)));
     let access_list = state.access_list;
+    let shutdown_snapshot_signal = state.shutdown_snapshot_signal;
+    let full_scrape = state.full_scrape;
 
     // Periodically clean torrents
-    TimerActionRepeat::repeat(enclose!((config, torrents, access_list) move || {
-        enclose!((config, torrents, access_list) move || async move {
-            torrents.borrow_mut().clean(&config, &access_list, server_start_instant);
+    TimerActionRepeat::repeat(
+        enclose!((config, torrents, accounting, access_list) move || {
+            enclose!((config, torrents, accounting, access_list) move || async move {
+                torrents.borrow_mut().clean(
+                    &config,
+                    &mut accounting.borrow_mut(),
+                    &access_list,
+                    server_start_instant,
+                );
 
-            Some(Duration::from_secs(config.cleaning.torrent_cleaning_interval))
-        })()
-    }));
+                Some(Duration::from_secs(config.cleaning.torrent_cleaning_interval))
+            })()
+        }),
+    );
+
+    // Periodically flush transfer accounting
+    if config.accounting.enabled {
+        TimerActionRepeat::repeat(enclose!((config, accounting) move || {
+            enclose!((config, accounting) move || async move {
+                accounting.borrow_mut().flush(&config.accounting);
+
+                Some(Duration::from_secs(config.accounting.flush_interval))
+            })()
+        }));
+    }
+
+    // Periodically save snapshots. Also save one and flush transfer
+    // accounting when requested on shutdown.
+    if config.snapshot.enabled || config.accounting.enabled {
+        let last_snapshot = Rc::new(Cell::new(Instant::now()));
+        let snapshot_interval = Duration::from_secs(config.snapshot.interval);
+
+        TimerActionRepeat::repeat(
+            enclose!((config, torrents, accounting, shutdown_snapshot_signal) move || {
+                enclose!((config, torrents, accounting, shutdown_snapshot_signal, last_snapshot) move || async move {
+                    if shutdown_snapshot_signal.requested() {
+                        if config.snapshot.enabled {
+                            torrents.borrow().save_snapshot(&config, server_start_instant, worker_index);
+                        }
+
+                        accounting.borrow_mut().flush(&config.accounting);
+                        shutdown_snapshot_signal.mark_done();
+
+                        return None;
+                    }
+
+                    if config.snapshot.enabled
+                        && config.snapshot.interval != 0
+                        && last_snapshot.get().elapsed() >= snapshot_interval
+                    {
+                        torrents.borrow().save_snapshot(&config, server_start_instant, worker_index);
+                        last_snapshot.set(Instant::now());
+                    }
+
+                    Some(Duration::from_secs(1))
+                })()
+            }),
+        );
+    }
+
+    // Periodically publish data for full scrape responses
+    if config.full_scrape.enabled {
+        TimerActionRepeat::repeat(enclose!((config, torrents, full_scrape) move || {
+            enclose!((config, torrents, full_scrape) move || async move {
+                full_scrape.update(
+                    worker_index,
+                    torrents.borrow().full_scrape_part(),
+                    FullScrapeResponse::merge,
+                );
+
+                Some(Duration::from_secs(config.full_scrape.refresh_interval))
+            })()
+        }));
+    }
+
+    // Answer admin socket queries
+    if config.admin.enabled {
+        let swarm_query_receiver = Rc::new(swarm_query_receiver);
+
+        TimerActionRepeat::repeat(enclose!((torrents, swarm_query_receiver) move || {
+            enclose!((torrents, swarm_query_receiver) move || async move {
+                while let Ok(query_request) = swarm_query_receiver.try_recv() {
+                    let summaries = torrents.borrow().handle_swarm_query(query_request.query);
+
+                    query_request.respond(summaries);
+                }
+
+                Some(Duration::from_millis(100))
+            })()
+        }));
+    }
 
     let max_peer_age = config.cleaning.max_peer_age;
     let peer_valid_until = Rc::new(RefCell::new(ValidUntil::new(
@@ -90,6 +182,7 @@ pub async fn run_swarm_worker(
         let handle = spawn_local(handle_request_stream(
             config.clone(),
             torrents.clone(),
+            accounting.clone(),
             peer_valid_until.clone(),
             receiver,
         ))
@@ -106,6 +199,7 @@ pub async fn run_swarm_worker(
 async fn handle_request_stream<S>(
     config: Config,
     torrents: Rc<RefCell<TorrentMaps>>,
+    accounting: Rc<RefCell<TransferAccounting<AccountId, (InfoHash, PeerId)>>>,
     peer_valid_until: Rc<RefCell<ValidUntil>>,
     mut stream: S,
 ) where
@@ -123,6 +217,7 @@ async fn handle_request_stream<S>(
                 let response = torrents.borrow_mut().handle_announce_request(
                     &config,
                     &mut rng,
+                    &mut accounting.borrow_mut(),
                     peer_valid_until.borrow().to_owned(),
                     peer_addr,
                     request,
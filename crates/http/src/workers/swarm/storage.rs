@@ -1,10 +1,16 @@
 use std::collections::BTreeMap;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 use std::sync::Arc;
 
 use rand::Rng;
 
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::accounting::{Transfer, TransferAccounting};
+use aquatic_common::admin::{top_torrents, SwarmQuery, TorrentSummary};
+use aquatic_common::snapshot::{
+    load_snapshot, save_snapshot, SnapshotDecoder, SnapshotEncoder, SnapshotHeader, SnapshotIp,
+    SnapshotProtocol,
+};
 use aquatic_common::{
     extract_response_peers, CanonicalSocketAddr, IndexMap, SecondsSinceServerStart,
     ServerStartInstant, ValidUntil,
@@ -14,12 +20,16 @@ use aquatic_http_protocol::request::*;
 use aquatic_http_protocol::response::ResponsePeer;
 use aquatic_http_protocol::response::*;
 
-use crate::config::Config;
+use crate::common::{AccountId, FullScrapePart};
+use crate::config::{AnnounceAddressPolicy, Config};
 
 #[cfg(feature = "metrics")]
 use crate::workers::swarm::WORKER_INDEX;
 
-pub trait Ip: ::std::fmt::Debug + Copy + Eq + ::std::hash::Hash {
+/// Bump when changing snapshot payload encoding
+const SNAPSHOT_FORMAT_VERSION: u16 = 6;
+
+pub trait Ip: ::std::fmt::Debug + Copy + Eq + ::std::hash::Hash + SnapshotIp {
     #[cfg(feature = "metrics")]
     fn ip_version_str() -> &'static str;
 }
@@ -44,64 +54,125 @@ pub struct TorrentMaps {
 }
 
 impl TorrentMaps {
+    /// Load torrents from snapshot file, falling back to empty maps
+    pub fn load_snapshot(
+        config: &Config,
+        server_start_instant: ServerStartInstant,
+        worker_index: usize,
+    ) -> Self {
+        let header = Self::snapshot_header(config, worker_index);
+
+        load_snapshot(&config.snapshot, header, |decoder| {
+            Ok(Self {
+                ipv4: read_torrent_map_snapshot(decoder, server_start_instant)?,
+                ipv6: read_torrent_map_snapshot(decoder, server_start_instant)?,
+            })
+        })
+        .unwrap_or_default()
+    }
+
+    pub fn save_snapshot(
+        &self,
+        config: &Config,
+        server_start_instant: ServerStartInstant,
+        worker_index: usize,
+    ) {
+        let header = Self::snapshot_header(config, worker_index);
+
+        save_snapshot(&config.snapshot, header, |encoder| {
+            write_torrent_map_snapshot(encoder, &self.ipv4, server_start_instant);
+            write_torrent_map_snapshot(encoder, &self.ipv6, server_start_instant);
+        });
+    }
+
+    fn snapshot_header(config: &Config, worker_index: usize) -> SnapshotHeader {
+        SnapshotHeader::new(
+            SnapshotProtocol::Http,
+            SNAPSHOT_FORMAT_VERSION,
+            worker_index,
+            config.swarm_workers,
+        )
+    }
+
+    /// Register peer with connection address and any accepted BEP 7
+    /// addresses and respond with peers of the registered IP versions
+    ///
+    /// Statistics, metrics and transfer accounting only include peers
+    /// registered with the IP version of the connection, so that dual-stack
+    /// peers aren't counted twice. Peers registered with addresses of the
+    /// other IP version only show up in response peer lists.
     pub fn handle_announce_request(
         &mut self,
         config: &Config,
         rng: &mut impl Rng,
+        accounting: &mut TransferAccounting<AccountId, (InfoHash, PeerId)>,
         valid_until: ValidUntil,
         peer_addr: CanonicalSocketAddr,
         request: AnnounceRequest,
     ) -> AnnounceResponse {
-        match peer_addr.get().ip() {
-            IpAddr::V4(peer_ip_address) => {
-                let (seeders, leechers, response_peers) = self
-                    .ipv4
-                    .entry(request.info_hash)
-                    .or_default()
-                    .upsert_peer_and_get_response_peers(
-                        config,
-                        rng,
-                        peer_ip_address,
-                        request,
-                        valid_until,
-                    );
-
-                let response = AnnounceResponse {
-                    complete: seeders,
-                    incomplete: leechers,
-                    announce_interval: config.protocol.peer_announce_interval,
-                    peers: ResponsePeerListV4(response_peers),
-                    peers6: ResponsePeerListV6(vec![]),
-                    warning_message: None,
-                };
+        let (opt_ipv4_addr, opt_ipv6_addr) = announce_addresses(config, peer_addr, &request);
+        let connection_is_ipv4 = peer_addr.is_ipv4();
+
+        let mut ctx = AnnounceContext {
+            config,
+            rng,
+            accounting,
+            valid_until,
+        };
 
-                response
+        let mut response = AnnounceResponse {
+            complete: 0,
+            incomplete: 0,
+            announce_interval: config.protocol.peer_announce_interval,
+            peers: ResponsePeerListV4(vec![]),
+            peers6: ResponsePeerListV6(vec![]),
+            warning_message: None,
+            peer_list_format: request.peer_list_format,
+        };
+
+        if let Some(addr) = opt_ipv4_addr {
+            let (seeders, leechers, response_peers) = self
+                .ipv4
+                .entry(request.info_hash)
+                .or_default()
+                .upsert_peer_and_get_response_peers(
+                    &mut ctx,
+                    connection_is_ipv4,
+                    *addr.ip(),
+                    addr.port(),
+                    &request,
+                );
+
+            if connection_is_ipv4 {
+                response.complete = seeders;
+                response.incomplete = leechers;
             }
-            IpAddr::V6(peer_ip_address) => {
-                let (seeders, leechers, response_peers) = self
-                    .ipv6
-                    .entry(request.info_hash)
-                    .or_default()
-                    .upsert_peer_and_get_response_peers(
-                        config,
-                        rng,
-                        peer_ip_address,
-                        request,
-                        valid_until,
-                    );
-
-                let response = AnnounceResponse {
-                    complete: seeders,
-                    incomplete: leechers,
-                    announce_interval: config.protocol.peer_announce_interval,
-                    peers: ResponsePeerListV4(vec![]),
-                    peers6: ResponsePeerListV6(response_peers),
-                    warning_message: None,
-                };
 
-                response
+            response.peers = ResponsePeerListV4(response_peers);
+        }
+
+        if let Some(addr) = opt_ipv6_addr {
+            let (seeders, leechers, response_peers) = self
+                .ipv6
+                .entry(request.info_hash)
+                .or_default()
+                .upsert_peer_and_get_response_peers(
+                    &mut ctx,
+                    !connection_is_ipv4,
+                    *addr.ip(),
+                    addr.port(),
+                    &request,
+                );
+
+            if !connection_is_ipv4 {
+                response.complete = seeders;
+                response.incomplete = leechers;
             }
+
+            response.peers6 = ResponsePeerListV6(response_peers);
         }
+
+        response
     }
 
     pub fn handle_scrape_request(
@@ -128,7 +199,7 @@ impl TorrentMaps {
                 if let Some(torrent_data) = self.ipv4.get(&info_hash) {
                     let stats = ScrapeStatistics {
                         complete: torrent_data.num_seeders,
-                        downloaded: 0, // No implementation planned
+                        downloaded: torrent_data.num_completed,
                         incomplete: torrent_data.num_leechers(),
                     };
 
@@ -140,7 +211,7 @@ impl TorrentMaps {
                 if let Some(torrent_data) = self.ipv6.get(&info_hash) {
                     let stats = ScrapeStatistics {
                         complete: torrent_data.num_seeders,
-                        downloaded: 0, // No implementation planned
+                        downloaded: torrent_data.num_completed,
                         incomplete: torrent_data.num_leechers(),
                     };
 
@@ -152,9 +223,61 @@ impl TorrentMaps {
         response
     }
 
+    pub fn full_scrape_part(&self) -> FullScrapePart {
+        FullScrapePart {
+            ipv4: Self::full_scrape_statistics(&self.ipv4),
+            ipv6: Self::full_scrape_statistics(&self.ipv6),
+        }
+    }
+
+    fn full_scrape_statistics<I: Ip>(
+        torrent_map: &TorrentMap<I>,
+    ) -> Vec<(InfoHash, ScrapeStatistics)> {
+        torrent_map
+            .iter()
+            .map(|(info_hash, torrent_data)| {
+                let stats = ScrapeStatistics {
+                    complete: torrent_data.num_seeders,
+                    downloaded: torrent_data.num_completed,
+                    incomplete: torrent_data.num_leechers(),
+                };
+
+                (*info_hash, stats)
+            })
+            .collect()
+    }
+
+    pub fn handle_swarm_query(&self, query: SwarmQuery) -> Vec<TorrentSummary> {
+        match query {
+            SwarmQuery::Torrent(info_hash) => {
+                let info_hash = InfoHash(info_hash);
+
+                let summaries = [
+                    self.ipv4.get(&info_hash).map(|t| t.summary(info_hash)),
+                    self.ipv6.get(&info_hash).map(|t| t.summary(info_hash)),
+                ];
+
+                top_torrents(summaries.into_iter().flatten(), 1)
+            }
+            SwarmQuery::TopTorrents(limit) => top_torrents(
+                Self::torrent_summaries(&self.ipv4).chain(Self::torrent_summaries(&self.ipv6)),
+                limit,
+            ),
+        }
+    }
+
+    fn torrent_summaries<I: Ip>(
+        torrent_map: &TorrentMap<I>,
+    ) -> impl Iterator<Item = TorrentSummary> + '_ {
+        torrent_map
+            .iter()
+            .map(|(info_hash, torrent_data)| torrent_data.summary(*info_hash))
+    }
+
     pub fn clean(
         &mut self,
         config: &Config,
+        accounting: &mut TransferAccounting<AccountId, (InfoHash, PeerId)>,
         access_list: &Arc<AccessListArcSwap>,
         server_start_instant: ServerStartInstant,
     ) {
@@ -162,12 +285,27 @@ impl TorrentMaps {
 
         let now = server_start_instant.seconds_elapsed();
 
-        Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv4, now);
-        Self::clean_torrent_map(config, &mut access_list_cache, &mut self.ipv6, now);
+        Self::clean_torrent_map(
+            config,
+            accounting,
+            &mut access_list_cache,
+            &mut self.ipv4,
+            now,
+        );
+        Self::clean_torrent_map(
+            config,
+            accounting,
+            &mut access_list_cache,
+            &mut self.ipv6,
+            now,
+        );
+
+        accounting.clean(now);
     }
 
     fn clean_torrent_map<I: Ip>(
         config: &Config,
+        accounting: &mut TransferAccounting<AccountId, (InfoHash, PeerId)>,
         access_list_cache: &mut AccessListCache,
         torrent_map: &mut TorrentMap<I>,
         now: SecondsSinceServerStart,
@@ -183,20 +321,34 @@ impl TorrentMaps {
             }
 
             let num_seeders = &mut torrent_data.num_seeders;
+            let num_uncounted_peers = &mut torrent_data.num_uncounted_peers;
 
-            torrent_data.peers.retain(|_, peer| {
+            torrent_data.peers.retain(|key, peer| {
                 let keep = peer.valid_until.valid(now);
 
-                if (!keep) & peer.seeder {
-                    *num_seeders -= 1;
+                if !keep {
+                    if !peer.counted {
+                        *num_uncounted_peers -= 1;
+                    } else if peer.seeder {
+                        *num_seeders -= 1;
+                    }
+
+                    if let Some(account) = peer.account.take() {
+                        accounting.record_expired_peer(
+                            account,
+                            (*info_hash, key.peer_id),
+                            peer.transfer,
+                            now,
+                        );
+                    }
                 }
 
                 keep
             });
 
-            total_num_peers += torrent_data.peers.len() as u64;
+            total_num_peers += torrent_data.num_counted_peers() as u64;
 
-            !torrent_data.peers.is_empty()
+            torrent_data.keep_after_cleaning(config, now)
         });
 
         let total_num_peers = total_num_peers as f64;
@@ -213,11 +365,213 @@ impl TorrentMaps {
     }
 }
 
+/// Addresses to register peer with, based on connection address, BEP 7
+/// announce parameters and configured policy
+fn announce_addresses(
+    config: &Config,
+    peer_addr: CanonicalSocketAddr,
+    request: &AnnounceRequest,
+) -> (Option<SocketAddrV4>, Option<SocketAddrV6>) {
+    let policy = config.protocol.announce_address_policy;
+
+    let opt_ipv4_param = request
+        .ipv4
+        .filter(|addr| policy != AnnounceAddressPolicy::Ignore && is_valid_ipv4(*addr.ip()));
+    let opt_ipv6_param = request
+        .ipv6
+        .filter(|addr| policy != AnnounceAddressPolicy::Ignore && is_valid_ipv6(*addr.ip()));
+
+    match peer_addr.get() {
+        SocketAddr::V4(addr) => {
+            let addr = SocketAddrV4::new(*addr.ip(), request.port);
+
+            let ipv4_addr = match opt_ipv4_param {
+                Some(param) if policy == AnnounceAddressPolicy::Any => param,
+                _ => addr,
+            };
+
+            (Some(ipv4_addr), opt_ipv6_param)
+        }
+        SocketAddr::V6(addr) => {
+            let addr = SocketAddrV6::new(*addr.ip(), request.port, 0, 0);
+
+            let ipv6_addr = match opt_ipv6_param {
+                Some(param) if policy == AnnounceAddressPolicy::Any => param,
+                _ => addr,
+            };
+
+            (opt_ipv4_param, Some(ipv6_addr))
+        }
+    }
+}
+
+/// Only accept globally routable unicast addresses
+fn is_valid_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+
+    // 0.0.0.0/8, 100.64.0.0/10, 192.0.0.0/24, 198.18.0.0/15 and 240.0.0.0/4
+    let is_special_purpose = a == 0
+        || (a == 100 && (b & 0xc0) == 64)
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (b & 0xfe) == 18)
+        || a >= 240;
+
+    !(is_special_purpose
+        || ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_multicast()
+        || ip.is_documentation())
+}
+
+/// Only accept globally routable unicast addresses
+fn is_valid_ipv6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+
+    // 2000::/3
+    let is_global_unicast = (segments[0] & 0xe000) == 0x2000;
+    // 2001::/23 (includes Teredo and benchmarking) and 2001:db8::/32
+    let is_special_purpose = segments[0] == 0x2001 && (segments[1] < 0x200 || segments[1] == 0xdb8);
+
+    is_global_unicast && !is_special_purpose
+}
+
 pub type TorrentMap<I> = IndexMap<InfoHash, TorrentData<I>>;
 
+fn write_torrent_map_snapshot<I: Ip>(
+    encoder: &mut SnapshotEncoder,
+    torrent_map: &TorrentMap<I>,
+    server_start_instant: ServerStartInstant,
+) {
+    encoder.write_len(torrent_map.len());
+
+    for (info_hash, torrent_data) in torrent_map.iter() {
+        encoder.write_bytes(&info_hash.0);
+        encoder.write_u64(torrent_data.num_completed as u64);
+
+        if let Some(idle_valid_until) = torrent_data.idle_valid_until {
+            encoder.write_bool(true);
+            encoder.write_valid_until(idle_valid_until, server_start_instant);
+        } else {
+            encoder.write_bool(false);
+        }
+
+        encoder.write_len(torrent_data.peers.len());
+
+        for (key, peer) in torrent_data.peers.iter() {
+            encoder.write_bytes(&key.peer_id.0);
+            encoder.write_ip(peer.ip_address);
+            encoder.write_u16(peer.port);
+            encoder.write_bool(peer.seeder);
+            encoder.write_bool(peer.counted);
+            encoder.write_valid_until(peer.valid_until, server_start_instant);
+            encoder.write_u64(peer.transfer.uploaded);
+            encoder.write_u64(peer.transfer.downloaded);
+
+            if let Some(ref passkey) = peer.passkey {
+                encoder.write_bool(true);
+                encoder.write_str(passkey.as_str());
+            } else {
+                encoder.write_bool(false);
+            }
+
+            match peer.account {
+                Some(AccountId::Passkey(ref passkey)) => {
+                    encoder.write_u8(1);
+                    encoder.write_str(passkey.as_str());
+                }
+                Some(AccountId::Key(ref key)) => {
+                    encoder.write_u8(2);
+                    encoder.write_str(key);
+                }
+                None => encoder.write_u8(0),
+            }
+        }
+    }
+}
+
+fn read_torrent_map_snapshot<I: Ip>(
+    decoder: &mut SnapshotDecoder,
+    server_start_instant: ServerStartInstant,
+) -> anyhow::Result<TorrentMap<I>> {
+    let num_torrents = decoder.read_len(20)?;
+
+    let mut torrent_map = TorrentMap::with_capacity_and_hasher(num_torrents, Default::default());
+
+    for _ in 0..num_torrents {
+        let info_hash = InfoHash(decoder.read_array()?);
+        let num_completed = decoder.read_u64()? as usize;
+        let idle_valid_until = if decoder.read_bool()? {
+            Some(decoder.read_valid_until(server_start_instant)?)
+        } else {
+            None
+        };
+        let num_peers = decoder.read_len(20)?;
+
+        let mut torrent_data = TorrentData {
+            peers: PeerMap::with_capacity_and_hasher(num_peers, Default::default()),
+            num_seeders: 0,
+            num_uncounted_peers: 0,
+            num_completed,
+            idle_valid_until,
+        };
+
+        for _ in 0..num_peers {
+            let peer_id = PeerId(decoder.read_array()?);
+            let peer = Peer {
+                ip_address: decoder.read_ip()?,
+                port: decoder.read_u16()?,
+                seeder: decoder.read_bool()?,
+                counted: decoder.read_bool()?,
+                valid_until: decoder.read_valid_until(server_start_instant)?,
+                transfer: Transfer::new(decoder.read_u64()?, decoder.read_u64()?),
+                passkey: if decoder.read_bool()? {
+                    Some(Passkey::from_path_segment(decoder.read_str()?)?)
+                } else {
+                    None
+                },
+                account: match decoder.read_u8()? {
+                    0 => None,
+                    1 => Some(AccountId::Passkey(Passkey::from_path_segment(
+                        decoder.read_str()?,
+                    )?)),
+                    2 => Some(AccountId::Key(decoder.read_str()?.into())),
+                    n => return Err(anyhow::anyhow!("invalid account type {}", n)),
+                },
+            };
+
+            if !peer.counted {
+                torrent_data.num_uncounted_peers += 1;
+            } else if peer.seeder {
+                torrent_data.num_seeders += 1;
+            }
+
+            let key = PeerMapKey {
+                peer_id,
+                ip: peer.ip_address,
+            };
+
+            if torrent_data.peers.insert(key, peer).is_some() {
+                return Err(anyhow::anyhow!("duplicate peer in snapshot"));
+            }
+        }
+
+        torrent_map.insert(info_hash, torrent_data);
+    }
+
+    Ok(torrent_map)
+}
+
 pub struct TorrentData<I: Ip> {
     peers: PeerMap<I>,
+    /// Number of counted seeders
     num_seeders: usize,
+    /// Number of peers registered with addresses of the IP version that
+    /// their announce request wasn't sent over
+    num_uncounted_peers: usize,
+    num_completed: usize,
+    /// Set when torrent without peers is kept because of its completed count
+    idle_valid_until: Option<ValidUntil>,
 }
 
 impl<I: Ip> Default for TorrentData<I> {
@@ -226,53 +580,104 @@ impl<I: Ip> Default for TorrentData<I> {
         Self {
             peers: Default::default(),
             num_seeders: 0,
+            num_uncounted_peers: 0,
+            num_completed: 0,
+            idle_valid_until: None,
         }
     }
 }
 
 impl<I: Ip> TorrentData<I> {
+    fn num_counted_peers(&self) -> usize {
+        self.peers.len() - self.num_uncounted_peers
+    }
+
     fn num_leechers(&self) -> usize {
-        self.peers.len() - self.num_seeders
+        self.num_counted_peers() - self.num_seeders
+    }
+
+    fn summary(&self, info_hash: InfoHash) -> TorrentSummary {
+        TorrentSummary {
+            info_hash: info_hash.0,
+            seeders: self.num_seeders,
+            leechers: self.num_leechers(),
+            completed: self.num_completed,
+        }
+    }
+
+    /// Returns true if torrent should be kept after its peers were cleaned
+    /// (see `cleaning.max_torrent_idle_age`)
+    fn keep_after_cleaning(&mut self, config: &Config, now: SecondsSinceServerStart) -> bool {
+        if !self.peers.is_empty() {
+            self.idle_valid_until = None;
+
+            true
+        } else if self.num_completed == 0 {
+            false
+        } else if let Some(valid_until) = self.idle_valid_until {
+            valid_until.valid(now)
+        } else {
+            self.idle_valid_until = Some(ValidUntil::new_with_now(
+                now,
+                config.cleaning.max_torrent_idle_age,
+            ));
+
+            true
+        }
     }
 
     /// Insert/update peer. Return num_seeders, num_leechers and response peers
+    ///
+    /// Statistics, metrics and transfers are only updated if `counted` is
+    /// true, i.e., if the peer is registered with the IP version of the
+    /// connection.
     fn upsert_peer_and_get_response_peers(
         &mut self,
-        config: &Config,
-        rng: &mut impl Rng,
+        ctx: &mut AnnounceContext<impl Rng>,
+        counted: bool,
         peer_ip_address: I,
-        request: AnnounceRequest,
-        valid_until: ValidUntil,
+        peer_port: u16,
+        request: &AnnounceRequest,
     ) -> (usize, usize, Vec<ResponsePeer<I>>) {
         // Insert/update/remove peer who sent this request
 
         let peer_status =
             PeerStatus::from_event_and_bytes_left(request.event, Some(request.bytes_left));
 
+        let transfer = Transfer::new(
+            request.bytes_uploaded as u64,
+            request.bytes_downloaded as u64,
+        );
+        let opt_account = if counted && ctx.accounting.is_enabled() {
+            AccountId::from_announce_request(request)
+        } else {
+            None
+        };
+
         let peer_map_key = PeerMapKey {
             peer_id: request.peer_id,
             ip: peer_ip_address,
         };
 
         let opt_removed_peer = match peer_status {
-            PeerStatus::Leeching => {
-                let peer = Peer {
-                    ip_address: peer_ip_address,
-                    port: request.port,
-                    valid_until,
-                    seeder: false,
-                };
+            PeerStatus::Leeching | PeerStatus::Seeding => {
+                let seeder = peer_status == PeerStatus::Seeding;
 
-                self.peers.insert(peer_map_key.clone(), peer)
-            }
-            PeerStatus::Seeding => {
-                self.num_seeders += 1;
+                if !counted {
+                    self.num_uncounted_peers += 1;
+                } else if seeder {
+                    self.num_seeders += 1;
+                }
 
                 let peer = Peer {
                     ip_address: peer_ip_address,
-                    port: request.port,
-                    valid_until,
-                    seeder: true,
+                    port: peer_port,
+                    valid_until: ctx.valid_until,
+                    seeder,
+                    counted,
+                    transfer,
+                    passkey: request.passkey.clone(),
+                    account: opt_account.clone(),
                 };
 
                 self.peers.insert(peer_map_key.clone(), peer)
@@ -280,13 +685,59 @@ impl<I: Ip> TorrentData<I> {
             PeerStatus::Stopped => self.peers.remove(&peer_map_key),
         };
 
-        if let Some(&Peer { seeder: true, .. }) = opt_removed_peer.as_ref() {
-            self.num_seeders -= 1;
+        // Removed peer may have been registered by a request sent over the
+        // other IP version
+        let previously_seeding = match opt_removed_peer {
+            Some(Peer { counted: false, .. }) => {
+                self.num_uncounted_peers -= 1;
+
+                false
+            }
+            Some(Peer { seeder: true, .. }) => {
+                self.num_seeders -= 1;
+
+                true
+            }
+            _ => false,
+        };
+        #[cfg(feature = "metrics")]
+        let previously_counted = matches!(opt_removed_peer, Some(Peer { counted: true, .. }));
+
+        // Stopped peers are accounted for here, since they were just removed
+        if let Some(account) = opt_account {
+            let started = request.event == AnnounceEvent::Started;
+            let previous_transfer = match opt_removed_peer.as_ref() {
+                Some(peer) => Some(peer.transfer),
+                None => ctx
+                    .accounting
+                    .take_expired_peer_transfer(
+                        account.clone(),
+                        (request.info_hash, request.peer_id),
+                    )
+                    .filter(|_| !started),
+            };
+
+            ctx.accounting
+                .record(account, transfer.delta(previous_transfer, started));
+        }
+
+        // Ignore repeated completed events from seeders
+        let completed = counted && request.event == AnnounceEvent::Completed && !previously_seeding;
+
+        if completed {
+            self.num_completed += 1;
+
+            #[cfg(feature = "metrics")]
+            ::metrics::increment_counter!(
+                "aquatic_completed_downloads_total",
+                "ip_version" => I::ip_version_str(),
+                "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+            );
         }
 
         #[cfg(feature = "metrics")]
         match peer_status {
-            PeerStatus::Stopped if opt_removed_peer.is_some() => {
+            PeerStatus::Stopped if previously_counted => {
                 ::metrics::decrement_gauge!(
                     "aquatic_peers",
                     1.0,
@@ -294,7 +745,7 @@ impl<I: Ip> TorrentData<I> {
                     "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
                 );
             }
-            PeerStatus::Leeching | PeerStatus::Seeding if opt_removed_peer.is_none() => {
+            PeerStatus::Leeching | PeerStatus::Seeding if counted && !previously_counted => {
                 ::metrics::increment_gauge!(
                     "aquatic_peers",
                     1.0,
@@ -302,6 +753,14 @@ impl<I: Ip> TorrentData<I> {
                     "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
                 );
             }
+            PeerStatus::Leeching | PeerStatus::Seeding if !counted && previously_counted => {
+                ::metrics::decrement_gauge!(
+                    "aquatic_peers",
+                    1.0,
+                    "ip_version" => I::ip_version_str(),
+                    "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+                );
+            }
             _ => {}
         }
 
@@ -309,12 +768,12 @@ impl<I: Ip> TorrentData<I> {
             Vec::new()
         } else {
             let max_num_peers_to_take = match request.numwant {
-                Some(0) | None => config.protocol.max_peers,
-                Some(numwant) => numwant.min(config.protocol.max_peers),
+                Some(0) | None => ctx.config.protocol.max_peers,
+                Some(numwant) => numwant.min(ctx.config.protocol.max_peers),
             };
 
             extract_response_peers(
-                rng,
+                &mut *ctx.rng,
                 &self.peers,
                 max_num_peers_to_take,
                 peer_map_key,
@@ -326,6 +785,14 @@ impl<I: Ip> TorrentData<I> {
     }
 }
 
+/// State used when registering a peer in the torrent maps of both IP versions
+struct AnnounceContext<'a, R> {
+    config: &'a Config,
+    rng: &'a mut R,
+    accounting: &'a mut TransferAccounting<AccountId, (InfoHash, PeerId)>,
+    valid_until: ValidUntil,
+}
+
 type PeerMap<I> = IndexMap<PeerMapKey<I>, Peer<I>>;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -334,19 +801,30 @@ struct PeerMapKey<I: Ip> {
     pub ip: I,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 struct Peer<I: Ip> {
     pub ip_address: I,
     pub port: u16,
     pub valid_until: ValidUntil,
     pub seeder: bool,
+    /// Peer was registered with the IP version of the connection and is
+    /// included in statistics and metrics
+    pub counted: bool,
+    /// Transfer counts from latest announce request
+    pub transfer: Transfer,
+    /// Passkey of user, if sent in announce request path
+    pub passkey: Option<Passkey>,
+    /// Account that transfers are recorded to, if accounting is enabled and
+    /// peer was registered with the IP version of the connection
+    pub account: Option<AccountId>,
 }
 
 impl<I: Ip> Peer<I> {
-    fn to_response_peer(_: &PeerMapKey<I>, peer: &Self) -> ResponsePeer<I> {
+    fn to_response_peer(key: &PeerMapKey<I>, peer: &Self) -> ResponsePeer<I> {
         ResponsePeer {
             ip_address: peer.ip_address,
             port: peer.port,
+            peer_id: Some(key.peer_id),
         }
     }
 }
@@ -373,3 +851,194 @@ impl PeerStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn announce_request(event: AnnounceEvent, bytes_uploaded: usize) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: InfoHash([1; 20]),
+            peer_id: PeerId([2; 20]),
+            port: 1,
+            bytes_uploaded,
+            bytes_downloaded: 0,
+            bytes_left: 1,
+            event,
+            numwant: None,
+            key: Some("key".into()),
+            peer_list_format: PeerListFormat::Compact,
+            ipv4: None,
+            ipv6: None,
+            passkey: None,
+        }
+    }
+
+    #[test]
+    fn test_accounting_expired_peer() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut config = Config::default();
+
+        config.accounting.enabled = true;
+        config.accounting.path = dir.path().join("accounting.jsonl");
+
+        let server_start_instant = ServerStartInstant::new();
+        let access_list = Arc::new(AccessListArcSwap::default());
+        let peer_addr = CanonicalSocketAddr::new(SocketAddr::from(([127, 0, 0, 1], 1)));
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut accounting = TransferAccounting::new(&config.accounting);
+        let mut torrent_maps = TorrentMaps::default();
+
+        // Peer expires right away
+        torrent_maps.handle_announce_request(
+            &config,
+            &mut rng,
+            &mut accounting,
+            ValidUntil::new(server_start_instant, 0),
+            peer_addr,
+            announce_request(AnnounceEvent::Started, 100),
+        );
+        torrent_maps.clean(&config, &mut accounting, &access_list, server_start_instant);
+
+        assert!(torrent_maps.ipv4.is_empty());
+
+        // Transfer since last announce before expiring is counted
+        torrent_maps.handle_announce_request(
+            &config,
+            &mut rng,
+            &mut accounting,
+            ValidUntil::new(server_start_instant, 60),
+            peer_addr,
+            announce_request(AnnounceEvent::Empty, 300),
+        );
+
+        accounting.flush(&config.accounting);
+
+        let contents = ::std::fs::read_to_string(&config.accounting.path).unwrap();
+
+        assert!(contents.contains(r#""uploaded":300,"#));
+    }
+
+    #[test]
+    fn test_dual_stack_completed_peer() {
+        let mut config = Config::default();
+
+        config.protocol.announce_address_policy = AnnounceAddressPolicy::OtherVersion;
+
+        let server_start_instant = ServerStartInstant::new();
+        let valid_until = ValidUntil::new(server_start_instant, 60);
+        let peer_addr = CanonicalSocketAddr::new(SocketAddr::from(([127, 0, 0, 1], 1)));
+
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut accounting = TransferAccounting::new(&config.accounting);
+        let mut torrent_maps = TorrentMaps::default();
+
+        let mut request = announce_request(AnnounceEvent::Completed, 0);
+
+        request.bytes_left = 0;
+        request.ipv6 = Some(SocketAddrV6::new("2a00::1".parse().unwrap(), 1, 0, 0));
+
+        let response = torrent_maps.handle_announce_request(
+            &config,
+            &mut rng,
+            &mut accounting,
+            valid_until,
+            peer_addr,
+            request.clone(),
+        );
+
+        assert_eq!(response.complete, 1);
+        assert_eq!(response.incomplete, 0);
+
+        let torrent_data_ipv4 = &torrent_maps.ipv4[&request.info_hash];
+        let torrent_data_ipv6 = &torrent_maps.ipv6[&request.info_hash];
+
+        assert_eq!(torrent_data_ipv4.num_seeders, 1);
+        assert_eq!(torrent_data_ipv4.num_completed, 1);
+        assert_eq!(torrent_data_ipv6.peers.len(), 1);
+        assert_eq!(torrent_data_ipv6.num_seeders, 0);
+        assert_eq!(torrent_data_ipv6.num_leechers(), 0);
+        assert_eq!(torrent_data_ipv6.num_completed, 0);
+
+        let summaries = torrent_maps.handle_swarm_query(SwarmQuery::TopTorrents(10));
+
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].seeders, 1);
+        assert_eq!(summaries[0].leechers, 0);
+        assert_eq!(summaries[0].completed, 1);
+
+        request.event = AnnounceEvent::Stopped;
+
+        torrent_maps.handle_announce_request(
+            &config,
+            &mut rng,
+            &mut accounting,
+            valid_until,
+            peer_addr,
+            request.clone(),
+        );
+
+        let torrent_data_ipv6 = &torrent_maps.ipv6[&request.info_hash];
+
+        assert!(torrent_data_ipv6.peers.is_empty());
+        assert_eq!(torrent_data_ipv6.num_uncounted_peers, 0);
+    }
+
+    #[test]
+    fn test_is_valid_ipv4() {
+        for ip in ["1.1.1.1", "8.8.8.8", "93.184.216.34"] {
+            assert!(is_valid_ipv4(ip.parse().unwrap()), "{}", ip);
+        }
+
+        for ip in [
+            "0.0.0.0",
+            "0.1.2.3",
+            "10.0.0.1",
+            "100.64.0.1",
+            "127.0.0.1",
+            "169.254.1.1",
+            "172.16.0.1",
+            "192.0.0.1",
+            "192.0.2.1",
+            "192.168.1.1",
+            "198.18.0.1",
+            "203.0.113.1",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!is_valid_ipv4(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_is_valid_ipv6() {
+        for ip in [
+            "2606:4700:4700::1111",
+            "2a00:1450:4001::1",
+            "2001:4860::8888",
+        ] {
+            assert!(is_valid_ipv6(ip.parse().unwrap()), "{}", ip);
+        }
+
+        for ip in [
+            "::",
+            "::1",
+            "::ffff:1.1.1.1",
+            "64:ff9b::1.1.1.1",
+            "2001::1",
+            "2001:db8::1",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "ff02::1",
+        ] {
+            assert!(!is_valid_ipv6(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -49,6 +49,10 @@ fn create_announce_request(config: &Config, state: &LoadTestState, rng: &mut imp
         port: rng.gen(),
         bytes_uploaded: 0,
         bytes_downloaded: 0,
+        peer_list_format: PeerListFormat::Compact,
+        ipv4: None,
+        ipv6: None,
+        passkey: None,
     })
 }
 
@@ -64,6 +68,7 @@ fn create_scrape_request(config: &Config, state: &LoadTestState, rng: &mut impl
 
     Request::Scrape(ScrapeRequest {
         info_hashes: scrape_hashes,
+        passkey: None,
     })
 }
 
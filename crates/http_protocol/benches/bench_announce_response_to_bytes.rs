@@ -12,6 +12,7 @@ pub fn bench(c: &mut Criterion) {
         peers.push(ResponsePeer {
             ip_address: Ipv4Addr::new(127, 0, 0, i),
             port: i as u16,
+            peer_id: None,
         })
     }
 
@@ -22,6 +23,7 @@ pub fn bench(c: &mut Criterion) {
         peers: ResponsePeerListV4(peers),
         peers6: ResponsePeerListV6(Vec::new()),
         warning_message: None,
+        peer_list_format: Default::default(),
     };
 
     let response = Response::Announce(announce_response);
@@ -1,5 +1,6 @@
 use std::str::FromStr;
 
+use compact_str::CompactString;
 use serde::{Deserialize, Serialize};
 
 use super::utils::*;
@@ -24,6 +25,36 @@ pub struct InfoHash(
     pub [u8; 20],
 );
 
+/// User passkey, sent as part of request path in private tracker mode
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(transparent)]
+pub struct Passkey(pub CompactString);
+
+impl Passkey {
+    pub const MAX_LEN: usize = 64;
+
+    /// Parse passkey from path segment. Only ASCII alphanumeric characters,
+    /// `-` and `_` are allowed.
+    pub fn from_path_segment(segment: &str) -> anyhow::Result<Self> {
+        if segment.is_empty() || segment.len() > Self::MAX_LEN {
+            return Err(anyhow::anyhow!("invalid passkey length"));
+        }
+
+        if !segment
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            return Err(anyhow::anyhow!("invalid character in passkey"));
+        }
+
+        Ok(Self(segment.into()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AnnounceEvent {
     Started,
@@ -63,6 +94,18 @@ impl AnnounceEvent {
     }
 }
 
+/// Peer list representation requested in announce request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerListFormat {
+    /// Compact byte strings (BEP 23), requested with `compact=1` or by
+    /// leaving out `compact`
+    #[default]
+    Compact,
+    /// List of dictionaries (BEP 3), requested with `compact=0`. Peer ids
+    /// are left out if `no_peer_id=1` is sent.
+    Dictionary { include_peer_id: bool },
+}
+
 #[cfg(test)]
 impl quickcheck::Arbitrary for InfoHash {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
@@ -89,6 +132,21 @@ impl quickcheck::Arbitrary for PeerId {
     }
 }
 
+#[cfg(test)]
+impl quickcheck::Arbitrary for Passkey {
+    fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+        const CHARS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ-_";
+
+        let len = usize::arbitrary(g) % Self::MAX_LEN + 1;
+
+        let passkey = (0..len)
+            .map(|_| *g.choose(CHARS).unwrap() as char)
+            .collect::<String>();
+
+        Self(passkey.into())
+    }
+}
+
 #[cfg(test)]
 impl quickcheck::Arbitrary for AnnounceEvent {
     fn arbitrary(g: &mut quickcheck::Gen) -> Self {
@@ -100,3 +158,13 @@ impl quickcheck::Arbitrary for AnnounceEvent {
         }
     }
 }
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for PeerListFormat {
+    fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+        match (bool::arbitrary(g), bool::arbitrary(g)) {
+            (false, _) => Self::Compact,
+            (true, include_peer_id) => Self::Dictionary { include_peer_id },
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use std::io::Write;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
 
 use anyhow::Context;
 use compact_str::CompactString;
@@ -18,11 +19,21 @@ pub struct AnnounceRequest {
     /// Number of response peers wanted
     pub numwant: Option<usize>,
     pub key: Option<CompactString>,
+    pub peer_list_format: PeerListFormat,
+    /// Address from `ipv4` parameter (BEP 7). Port defaults to `port` if
+    /// not included. Not validated beyond parsing.
+    pub ipv4: Option<SocketAddrV4>,
+    /// Address from `ipv6` parameter (BEP 7). Port defaults to `port` if
+    /// not included. Not validated beyond parsing.
+    pub ipv6: Option<SocketAddrV6>,
+    /// Passkey from request path (private tracker mode)
+    pub passkey: Option<Passkey>,
 }
 
 impl AnnounceRequest {
     fn write<W: Write>(&self, output: &mut W, url_suffix: &[u8]) -> ::std::io::Result<()> {
         output.write_all(b"GET /announce")?;
+        write_passkey(output, self.passkey.as_ref())?;
         output.write_all(url_suffix)?;
         output.write_all(b"?info_hash=")?;
         urlencode_20_bytes(self.info_hash.0, output)?;
@@ -59,15 +70,32 @@ impl AnnounceRequest {
             output.write_all(::urlencoding::encode(key.as_str()).as_bytes())?;
         }
 
-        // Always ask for compact responses to ease load testing of non-aquatic trackers
-        output.write_all(b"&compact=1")?;
+        match self.peer_list_format {
+            PeerListFormat::Compact => output.write_all(b"&compact=1")?,
+            PeerListFormat::Dictionary { include_peer_id } => {
+                output.write_all(b"&compact=0")?;
+
+                if !include_peer_id {
+                    output.write_all(b"&no_peer_id=1")?;
+                }
+            }
+        }
+
+        if let Some(addr) = self.ipv4 {
+            output.write_all(b"&ipv4=")?;
+            write_bep_seven_address(output, addr.into(), self.port)?;
+        }
+        if let Some(addr) = self.ipv6 {
+            output.write_all(b"&ipv6=")?;
+            write_bep_seven_address(output, addr.into(), self.port)?;
+        }
 
         output.write_all(b" HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
 
         Ok(())
     }
 
-    pub fn from_query_string(query_string: &str) -> anyhow::Result<Self> {
+    pub fn from_query_string(query_string: &str, passkey: Option<Passkey>) -> anyhow::Result<Self> {
         // -- Parse key-value pairs
 
         let mut opt_info_hash = None;
@@ -79,6 +107,10 @@ impl AnnounceRequest {
         let mut event = AnnounceEvent::default();
         let mut opt_numwant = None;
         let mut opt_key = None;
+        let mut compact = true;
+        let mut no_peer_id = false;
+        let mut opt_ipv4 = None;
+        let mut opt_ipv6 = None;
 
         let query_string_bytes = query_string.as_bytes();
 
@@ -128,8 +160,31 @@ impl AnnounceRequest {
                         .map_err(|err| anyhow::anyhow!("invalid event: {}", err))?;
                 }
                 "compact" => {
-                    if value != "1" {
-                        return Err(anyhow::anyhow!("compact set, but not to 1"));
+                    compact = parse_bool_flag(value).with_context(|| "parse compact")?;
+                }
+                "no_peer_id" => {
+                    no_peer_id = parse_bool_flag(value).with_context(|| "parse no_peer_id")?;
+                }
+                "ipv4" => {
+                    let value = ::urlencoding::decode(value)?;
+
+                    if let Ok(addr) = value.parse::<SocketAddrV4>() {
+                        opt_ipv4 = Some((*addr.ip(), Some(addr.port())));
+                    } else if let Ok(ip) = value.parse::<Ipv4Addr>() {
+                        opt_ipv4 = Some((ip, None));
+                    } else {
+                        ::log::debug!("ignored invalid ipv4 value: {}", value);
+                    }
+                }
+                "ipv6" => {
+                    let value = ::urlencoding::decode(value)?;
+
+                    if let Ok(addr) = value.parse::<SocketAddrV6>() {
+                        opt_ipv6 = Some((*addr.ip(), Some(addr.port())));
+                    } else if let Ok(ip) = value.parse::<Ipv6Addr>() {
+                        opt_ipv6 = Some((ip, None));
+                    } else {
+                        ::log::debug!("ignored invalid ipv6 value: {}", value);
                     }
                 }
                 "numwant" => {
@@ -153,28 +208,67 @@ impl AnnounceRequest {
             }
         }
 
+        let port = opt_port.with_context(|| "no port")?;
+
         Ok(AnnounceRequest {
             info_hash: opt_info_hash.with_context(|| "no info_hash")?,
             peer_id: opt_peer_id.with_context(|| "no peer_id")?,
-            port: opt_port.with_context(|| "no port")?,
+            port,
             bytes_uploaded: opt_bytes_uploaded.with_context(|| "no uploaded")?,
             bytes_downloaded: opt_bytes_downloaded.with_context(|| "no downloaded")?,
             bytes_left: opt_bytes_left.with_context(|| "no left")?,
             event,
             numwant: opt_numwant,
             key: opt_key,
+            peer_list_format: if compact {
+                PeerListFormat::Compact
+            } else {
+                PeerListFormat::Dictionary {
+                    include_peer_id: !no_peer_id,
+                }
+            },
+            ipv4: opt_ipv4.map(|(ip, opt_port)| SocketAddrV4::new(ip, opt_port.unwrap_or(port))),
+            ipv6: opt_ipv6
+                .map(|(ip, opt_port)| SocketAddrV6::new(ip, opt_port.unwrap_or(port), 0, 0)),
+            passkey,
         })
     }
 }
 
+/// Write url-encoded address, leaving out port if it equals default port
+fn write_bep_seven_address<W: Write>(
+    output: &mut W,
+    addr: ::std::net::SocketAddr,
+    default_port: u16,
+) -> ::std::io::Result<()> {
+    let addr = if addr.port() == default_port {
+        addr.ip().to_string()
+    } else {
+        addr.to_string()
+    };
+
+    output.write_all(::urlencoding::encode(&addr).as_bytes())
+}
+
+fn parse_bool_flag(value: &str) -> anyhow::Result<bool> {
+    match value {
+        "0" => Ok(false),
+        "1" => Ok(true),
+        _ => Err(anyhow::anyhow!("expected 0 or 1, got {}", value)),
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ScrapeRequest {
     pub info_hashes: Vec<InfoHash>,
+    /// Passkey from request path (private tracker mode)
+    pub passkey: Option<Passkey>,
 }
 
 impl ScrapeRequest {
     fn write<W: Write>(&self, output: &mut W, url_suffix: &[u8]) -> ::std::io::Result<()> {
         output.write_all(b"GET /scrape")?;
+        write_passkey(output, self.passkey.as_ref())?;
         output.write_all(url_suffix)?;
         output.write_all(b"?")?;
 
@@ -196,7 +290,9 @@ impl ScrapeRequest {
         Ok(())
     }
 
-    pub fn from_query_string(query_string: &str) -> anyhow::Result<Self> {
+    /// Parse scrape request query string. If it contains no info hashes,
+    /// the request is a full scrape.
+    pub fn from_query_string(query_string: &str, passkey: Option<Passkey>) -> anyhow::Result<Self> {
         // -- Parse key-value pairs
 
         let mut info_hashes = Vec::new();
@@ -236,12 +332,62 @@ impl ScrapeRequest {
             }
         }
 
-        if info_hashes.is_empty() {
-            return Err(anyhow::anyhow!("No info hashes sent"));
+        Ok(ScrapeRequest {
+            info_hashes,
+            passkey,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RequestType {
+    Announce,
+    Scrape,
+}
+
+impl RequestType {
+    fn from_path_segment(segment: &str) -> Option<Self> {
+        match segment {
+            "announce" => Some(Self::Announce),
+            "scrape" => Some(Self::Scrape),
+            _ => None,
         }
+    }
+}
+
+/// Parse request type and optional passkey from path location
+///
+/// Accepted forms are `/announce`, `/announce/<passkey>` and
+/// `/<passkey>/announce` (and likewise for scrape), optionally with a
+/// trailing slash.
+fn parse_location(location: &str) -> anyhow::Result<(RequestType, Option<Passkey>)> {
+    let location = location
+        .strip_prefix('/')
+        .with_context(|| "location doesn't start with slash")?;
+    let location = location.strip_suffix('/').unwrap_or(location);
+
+    let (request_type, opt_passkey) = match location.split_once('/') {
+        None => (RequestType::from_path_segment(location), None),
+        Some((a, b)) => match RequestType::from_path_segment(a) {
+            Some(request_type) => (Some(request_type), Some(b)),
+            None => (RequestType::from_path_segment(b), Some(a)),
+        },
+    };
+
+    let request_type = request_type
+        .with_context(|| "Path must be /announce or /scrape, optionally with passkey")?;
+    let opt_passkey = opt_passkey.map(Passkey::from_path_segment).transpose()?;
+
+    Ok((request_type, opt_passkey))
+}
 
-        Ok(ScrapeRequest { info_hashes })
+fn write_passkey<W: Write>(output: &mut W, passkey: Option<&Passkey>) -> ::std::io::Result<()> {
+    if let Some(passkey) = passkey {
+        output.write_all(b"/")?;
+        output.write_all(passkey.as_str().as_bytes())?;
     }
+
+    Ok(())
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -271,6 +417,9 @@ impl Request {
 
     /// Parse Request from http GET path (`/announce?info_hash=...`)
     ///
+    /// A passkey may be included in the path as `/announce/<passkey>` or
+    /// `/<passkey>/announce` (and likewise for scrape).
+    ///
     /// Existing serde-url decode crates were insufficient, so the decision was
     /// made to create a custom parser. serde_urlencoded doesn't support multiple
     /// values with same key, and serde_qs pulls in lots of dependencies. Both
@@ -288,18 +437,39 @@ impl Request {
         let mut split_parts = path.splitn(2, '?');
 
         let location = split_parts.next().with_context(|| "no location")?;
-        let query_string = split_parts.next().with_context(|| "no query string")?;
-
-        if location == "/announce" {
-            Ok(Request::Announce(AnnounceRequest::from_query_string(
-                query_string,
-            )?))
-        } else if location == "/scrape" {
-            Ok(Request::Scrape(ScrapeRequest::from_query_string(
-                query_string,
-            )?))
-        } else {
-            Err(anyhow::anyhow!("Path must be /announce or /scrape"))
+        let opt_query_string = split_parts.next();
+
+        let (request_type, opt_passkey) = parse_location(location)?;
+
+        match request_type {
+            RequestType::Announce => {
+                let query_string = opt_query_string.with_context(|| "no query string")?;
+
+                Ok(Request::Announce(AnnounceRequest::from_query_string(
+                    query_string,
+                    opt_passkey,
+                )?))
+            }
+            RequestType::Scrape => {
+                // Scrape requests without query string are full scrapes
+                let request = if let Some(query_string) = opt_query_string {
+                    ScrapeRequest::from_query_string(query_string, opt_passkey)?
+                } else {
+                    ScrapeRequest {
+                        info_hashes: Vec::new(),
+                        passkey: opt_passkey,
+                    }
+                };
+
+                Ok(Request::Scrape(request))
+            }
+        }
+    }
+
+    pub fn passkey(&self) -> Option<&Passkey> {
+        match self {
+            Self::Announce(r) => r.passkey.as_ref(),
+            Self::Scrape(r) => r.passkey.as_ref(),
         }
     }
 
@@ -340,6 +510,10 @@ mod tests {
             event: AnnounceEvent::Started,
             numwant: Some(0),
             key: Some("4ab4b877".into()),
+            peer_list_format: PeerListFormat::Compact,
+            ipv4: None,
+            ipv6: None,
+            passkey: None,
         })
     }
 
@@ -368,11 +542,98 @@ mod tests {
         let parsed_request = Request::from_bytes(&bytes[..]).unwrap().unwrap();
         let reference_request = Request::Scrape(ScrapeRequest {
             info_hashes: vec![InfoHash(REFERENCE_INFO_HASH)],
+            passkey: None,
         });
 
         assert_eq!(parsed_request, reference_request);
     }
 
+    #[test]
+    fn test_full_scrape_request_from_bytes() {
+        let reference_request = Request::Scrape(ScrapeRequest {
+            info_hashes: Vec::new(),
+            passkey: None,
+        });
+
+        for path in ["/scrape", "/scrape?"] {
+            let bytes = format!("GET {} HTTP/1.1\r\n\r\n", path);
+
+            let parsed_request = Request::from_bytes(bytes.as_bytes()).unwrap().unwrap();
+
+            assert_eq!(parsed_request, reference_request);
+        }
+    }
+
+    #[test]
+    fn test_request_passkey_from_path() {
+        let query_string = ANNOUNCE_REQUEST_PATH.split_once('?').unwrap().1;
+        let passkey = Passkey("abcDEF0123456789".into());
+
+        for location in [
+            "/announce/abcDEF0123456789",
+            "/announce/abcDEF0123456789/",
+            "/abcDEF0123456789/announce",
+        ] {
+            let path = format!("{}?{}", location, query_string);
+
+            let request = Request::from_http_get_path(&path).unwrap();
+
+            assert!(matches!(request, Request::Announce(_)));
+            assert_eq!(request.passkey(), Some(&passkey));
+        }
+
+        for location in ["/scrape/abcDEF0123456789", "/abcDEF0123456789/scrape"] {
+            let request = Request::from_http_get_path(location).unwrap();
+
+            assert!(matches!(request, Request::Scrape(_)));
+            assert_eq!(request.passkey(), Some(&passkey));
+        }
+
+        for location in [
+            "/announce//",
+            "/announce/abc/def",
+            "/announce/abc.def",
+            "/abc/def",
+            "/announce/announce/announce",
+        ] {
+            let path = format!("{}?{}", location, query_string);
+
+            assert!(Request::from_http_get_path(&path).is_err());
+        }
+    }
+
+    #[test]
+    fn test_announce_request_bep_seven_addresses() {
+        let parse = |suffix: &str| {
+            let path = format!("{}{}", ANNOUNCE_REQUEST_PATH, suffix);
+
+            match Request::from_http_get_path(&path).unwrap() {
+                Request::Announce(request) => (request.ipv4, request.ipv6),
+                Request::Scrape(_) => panic!("not an announce request"),
+            }
+        };
+
+        assert_eq!(parse(""), (None, None));
+        assert_eq!(
+            parse("&ipv4=1.2.3.4&ipv6=2001%3Adb8%3A%3A1"),
+            (
+                Some("1.2.3.4:12345".parse().unwrap()),
+                Some("[2001:db8::1]:12345".parse().unwrap())
+            )
+        );
+        assert_eq!(
+            parse("&ipv4=1.2.3.4%3A1000&ipv6=%5B2001%3Adb8%3A%3A1%5D%3A2000"),
+            (
+                Some("1.2.3.4:1000".parse().unwrap()),
+                Some("[2001:db8::1]:2000".parse().unwrap())
+            )
+        );
+        assert_eq!(
+            parse("&ipv4=2001%3Adb8%3A%3A1&ipv6=example.com"),
+            (None, None)
+        );
+    }
+
     impl Arbitrary for AnnounceRequest {
         fn arbitrary(g: &mut Gen) -> Self {
             let key: Option<String> = Arbitrary::arbitrary(g);
@@ -387,6 +648,12 @@ mod tests {
                 event: Arbitrary::arbitrary(g),
                 numwant: Arbitrary::arbitrary(g),
                 key: key.map(|key| key.into()),
+                peer_list_format: Arbitrary::arbitrary(g),
+                ipv4: Arbitrary::arbitrary(g),
+                // Flow info and scope id are not sent
+                ipv6: Option::<(Ipv6Addr, u16)>::arbitrary(g)
+                    .map(|(ip, port)| SocketAddrV6::new(ip, port, 0, 0)),
+                passkey: Arbitrary::arbitrary(g),
             }
         }
     }
@@ -395,6 +662,7 @@ mod tests {
         fn arbitrary(g: &mut Gen) -> Self {
             ScrapeRequest {
                 info_hashes: Arbitrary::arbitrary(g),
+                passkey: Arbitrary::arbitrary(g),
             }
         }
     }
@@ -412,20 +680,13 @@ mod tests {
     #[test]
     fn quickcheck_serde_identity_request() {
         fn prop(request: Request) -> TestResult {
-            match request {
-                Request::Announce(AnnounceRequest {
-                    key: Some(ref key), ..
-                }) => {
-                    if key.len() > 30 {
-                        return TestResult::discard();
-                    }
-                }
-                Request::Scrape(ScrapeRequest { ref info_hashes }) => {
-                    if info_hashes.is_empty() {
-                        return TestResult::discard();
-                    }
+            if let Request::Announce(AnnounceRequest {
+                key: Some(ref key), ..
+            }) = request
+            {
+                if key.len() > 30 {
+                    return TestResult::discard();
                 }
-                _ => {}
             }
 
             let mut bytes = Vec::new();
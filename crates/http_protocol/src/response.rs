@@ -1,6 +1,6 @@
 use std::borrow::Cow;
 use std::io::Write;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
@@ -12,6 +12,10 @@ use super::utils::*;
 pub struct ResponsePeer<I: Eq> {
     pub ip_address: I,
     pub port: u16,
+    /// Only included in non-compact responses. Always None after
+    /// deserialization.
+    #[serde(skip)]
+    pub peer_id: Option<PeerId>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -58,6 +62,10 @@ pub struct AnnounceResponse {
         serialize_with = "serialize_optional_string"
     )]
     pub warning_message: Option<String>,
+    /// Representation of peer lists in output of `write`. Serde
+    /// (de)serialization always uses compact representation.
+    #[serde(skip)]
+    pub peer_list_format: PeerListFormat,
 }
 
 impl AnnounceResponse {
@@ -77,28 +85,53 @@ impl AnnounceResponse {
                 .as_bytes(),
         )?;
 
-        bytes_written += output.write(b"e5:peers")?;
-        bytes_written += output.write(
-            itoa::Buffer::new()
-                .format(self.peers.0.len() * 6)
-                .as_bytes(),
-        )?;
-        bytes_written += output.write(b":")?;
-        for peer in self.peers.0.iter() {
-            bytes_written += output.write(&u32::from(peer.ip_address).to_be_bytes())?;
-            bytes_written += output.write(&peer.port.to_be_bytes())?;
-        }
-
-        bytes_written += output.write(b"6:peers6")?;
-        bytes_written += output.write(
-            itoa::Buffer::new()
-                .format(self.peers6.0.len() * 18)
-                .as_bytes(),
-        )?;
-        bytes_written += output.write(b":")?;
-        for peer in self.peers6.0.iter() {
-            bytes_written += output.write(&u128::from(peer.ip_address).to_be_bytes())?;
-            bytes_written += output.write(&peer.port.to_be_bytes())?;
+        match self.peer_list_format {
+            PeerListFormat::Compact => {
+                bytes_written += output.write(b"e5:peers")?;
+                bytes_written += output.write(
+                    itoa::Buffer::new()
+                        .format(self.peers.0.len() * 6)
+                        .as_bytes(),
+                )?;
+                bytes_written += output.write(b":")?;
+                for peer in self.peers.0.iter() {
+                    bytes_written += output.write(&u32::from(peer.ip_address).to_be_bytes())?;
+                    bytes_written += output.write(&peer.port.to_be_bytes())?;
+                }
+
+                bytes_written += output.write(b"6:peers6")?;
+                bytes_written += output.write(
+                    itoa::Buffer::new()
+                        .format(self.peers6.0.len() * 18)
+                        .as_bytes(),
+                )?;
+                bytes_written += output.write(b":")?;
+                for peer in self.peers6.0.iter() {
+                    bytes_written += output.write(&u128::from(peer.ip_address).to_be_bytes())?;
+                    bytes_written += output.write(&peer.port.to_be_bytes())?;
+                }
+            }
+            PeerListFormat::Dictionary { include_peer_id } => {
+                // IPv4 and IPv6 peers share a single list
+                bytes_written += output.write(b"e5:peersl")?;
+                for peer in self.peers.0.iter() {
+                    bytes_written += write_dictionary_peer(
+                        output,
+                        peer.ip_address.into(),
+                        peer.port,
+                        peer.peer_id.filter(|_| include_peer_id),
+                    )?;
+                }
+                for peer in self.peers6.0.iter() {
+                    bytes_written += write_dictionary_peer(
+                        output,
+                        peer.ip_address.into(),
+                        peer.port,
+                        peer.peer_id.filter(|_| include_peer_id),
+                    )?;
+                }
+                bytes_written += output.write(b"e")?;
+            }
         }
 
         if let Some(ref warning_message) = self.warning_message {
@@ -117,6 +150,43 @@ impl AnnounceResponse {
     }
 }
 
+/// Write peer as dictionary with keys in sorted order
+fn write_dictionary_peer<W: Write>(
+    output: &mut W,
+    ip_address: IpAddr,
+    port: u16,
+    opt_peer_id: Option<PeerId>,
+) -> ::std::io::Result<usize> {
+    let mut bytes_written = 0usize;
+
+    // Large enough for the longest textual representation of an IPv6 address
+    let mut ip_buffer = [0u8; 45];
+
+    let ip_len = {
+        let mut cursor = ::std::io::Cursor::new(&mut ip_buffer[..]);
+
+        write!(cursor, "{}", ip_address)?;
+
+        cursor.position() as usize
+    };
+
+    bytes_written += output.write(b"d2:ip")?;
+    bytes_written += output.write(itoa::Buffer::new().format(ip_len).as_bytes())?;
+    bytes_written += output.write(b":")?;
+    bytes_written += output.write(&ip_buffer[..ip_len])?;
+
+    if let Some(peer_id) = opt_peer_id {
+        bytes_written += output.write(b"7:peer id20:")?;
+        bytes_written += output.write(&peer_id.0)?;
+    }
+
+    bytes_written += output.write(b"4:porti")?;
+    bytes_written += output.write(itoa::Buffer::new().format(port).as_bytes())?;
+    bytes_written += output.write(b"ee")?;
+
+    Ok(bytes_written)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ScrapeResponse {
     /// BTreeMap instead of HashMap since keys need to be serialized in order
@@ -135,7 +205,10 @@ impl ScrapeResponse {
             bytes_written += output.write(b"d8:completei")?;
             bytes_written +=
                 output.write(itoa::Buffer::new().format(statistics.complete).as_bytes())?;
-            bytes_written += output.write(b"e10:downloadedi0e10:incompletei")?;
+            bytes_written += output.write(b"e10:downloadedi")?;
+            bytes_written +=
+                output.write(itoa::Buffer::new().format(statistics.downloaded).as_bytes())?;
+            bytes_written += output.write(b"e10:incompletei")?;
             bytes_written +=
                 output.write(itoa::Buffer::new().format(statistics.incomplete).as_bytes())?;
             bytes_written += output.write(b"ee")?;
@@ -202,6 +275,8 @@ impl quickcheck::Arbitrary for ResponsePeer<Ipv4Addr> {
         Self {
             ip_address: Ipv4Addr::arbitrary(g),
             port: u16::arbitrary(g),
+            // Not part of serde representation
+            peer_id: None,
         }
     }
 }
@@ -212,6 +287,8 @@ impl quickcheck::Arbitrary for ResponsePeer<Ipv6Addr> {
         Self {
             ip_address: Ipv6Addr::arbitrary(g),
             port: u16::arbitrary(g),
+            // Not part of serde representation
+            peer_id: None,
         }
     }
 }
@@ -236,7 +313,7 @@ impl quickcheck::Arbitrary for ScrapeStatistics {
         Self {
             complete: usize::arbitrary(g),
             incomplete: usize::arbitrary(g),
-            downloaded: 0,
+            downloaded: usize::arbitrary(g),
         }
     }
 }
@@ -251,6 +328,8 @@ impl quickcheck::Arbitrary for AnnounceResponse {
             peers: ResponsePeerListV4::arbitrary(g),
             peers6: ResponsePeerListV6::arbitrary(g),
             warning_message: quickcheck::Arbitrary::arbitrary(g),
+            // Serde reference implementation only supports compact format
+            peer_list_format: PeerListFormat::Compact,
         }
     }
 }
@@ -297,6 +376,57 @@ mod tests {
         success
     }
 
+    #[test]
+    fn test_announce_response_dictionary_to_bytes() {
+        let peer_id = PeerId(*b"-ABC940-5ert69muw5t8");
+
+        let mut response = AnnounceResponse {
+            announce_interval: 120,
+            complete: 1,
+            incomplete: 2,
+            peers: ResponsePeerListV4(vec![ResponsePeer {
+                ip_address: Ipv4Addr::new(127, 0, 0, 1),
+                port: 6881,
+                peer_id: Some(peer_id),
+            }]),
+            peers6: ResponsePeerListV6(vec![ResponsePeer {
+                ip_address: Ipv6Addr::LOCALHOST,
+                port: 6882,
+                peer_id: Some(peer_id),
+            }]),
+            warning_message: None,
+            peer_list_format: PeerListFormat::Dictionary {
+                include_peer_id: true,
+            },
+        };
+
+        let mut bytes = Vec::new();
+
+        response.write(&mut bytes).unwrap();
+
+        assert_eq!(
+            String::from_utf8_lossy(&bytes),
+            "d8:completei1e10:incompletei2e8:intervali120e5:peersl\
+            d2:ip9:127.0.0.17:peer id20:-ABC940-5ert69muw5t84:porti6881ee\
+            d2:ip3:::17:peer id20:-ABC940-5ert69muw5t84:porti6882eeee"
+        );
+
+        response.peer_list_format = PeerListFormat::Dictionary {
+            include_peer_id: false,
+        };
+
+        let mut bytes = Vec::new();
+
+        response.write(&mut bytes).unwrap();
+
+        assert_eq!(
+            String::from_utf8_lossy(&bytes),
+            "d8:completei1e10:incompletei2e8:intervali120e5:peersl\
+            d2:ip9:127.0.0.14:porti6881ee\
+            d2:ip3:::14:porti6882eeee"
+        );
+    }
+
     #[quickcheck]
     fn test_scrape_response_to_bytes(response: ScrapeResponse) -> bool {
         let reference = bendy::serde::to_bytes(&Response::Scrape(response.clone())).unwrap();
@@ -176,6 +176,7 @@ impl<'de> Visitor<'de> for ResponsePeersIpv4Visitor {
                 ResponsePeer {
                     ip_address: Ipv4Addr::from(u32::from_be_bytes(ip_bytes)),
                     port: u16::from_be_bytes(port_bytes),
+                    peer_id: None,
                 }
             })
             .collect();
@@ -226,6 +227,7 @@ impl<'de> Visitor<'de> for ResponsePeersIpv6Visitor {
                 ResponsePeer {
                     ip_address: Ipv6Addr::from(u128::from_be_bytes(ip_bytes)),
                     port: u16::from_be_bytes(port_bytes),
+                    peer_id: None,
                 }
             })
             .collect();
@@ -20,7 +20,9 @@ macro_rules! gen_serialize_deserialize_test {
 /// Export structs to toml, converting Rust doc strings to comments.
 ///
 /// Supports one level of nesting. Fields containing structs must come
-/// after regular fields.
+/// after regular fields. Lists (including lists of structs implementing
+/// Serialize) are written on a single line without comments for their
+/// contents.
 ///
 /// Usage:
 /// ```
@@ -123,4 +125,44 @@ pub mod __private {
 
     impl_trait!(PathBuf);
     impl_trait!(SocketAddr);
+
+    impl<T: serde::Serialize> Private for Vec<T> {
+        fn __to_string(&self, comment: Option<String>, field_name: String) -> String {
+            let mut output = String::new();
+
+            if let Some(comment) = comment {
+                output.push_str(&comment);
+            }
+
+            let value = crate::toml::Value::try_from(self).unwrap();
+
+            output.push_str(&format!("{} = {}\n", field_name, to_inline_string(&value)));
+
+            output
+        }
+    }
+
+    /// Format value on a single line. Unlike the Display implementation of
+    /// toml::Value, this writes tables as inline tables, which allows lists
+    /// of structs.
+    fn to_inline_string(value: &crate::toml::Value) -> String {
+        use crate::toml::Value;
+
+        match value {
+            Value::Array(values) => {
+                let values = values.iter().map(to_inline_string).collect::<Vec<_>>();
+
+                format!("[{}]", values.join(", "))
+            }
+            Value::Table(table) => {
+                let entries = table
+                    .iter()
+                    .map(|(key, value)| format!("{} = {}", key, to_inline_string(value)))
+                    .collect::<Vec<_>>();
+
+                format!("{{ {} }}", entries.join(", "))
+            }
+            value => value.to_string(),
+        }
+    }
 }
@@ -1,8 +1,8 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use aquatic_toml_config::{gen_serialize_deserialize_test, TomlConfig};
 
-#[derive(Clone, Debug, PartialEq, Eq, TomlConfig, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, TomlConfig, Deserialize, Serialize)]
 struct TestConfigInnerA {
     /// Comment for a
     a: String,
@@ -28,6 +28,10 @@ struct TestConfig {
     /// Comment for b
     b: usize,
     c: bool,
+    /// Comment for d
+    d: Vec<String>,
+    /// Comment for e
+    e: Vec<TestConfigInnerA>,
     /// Comment for TestConfigInnerA
     inner_a: TestConfigInnerA,
 }
@@ -38,6 +42,8 @@ impl Default for TestConfig {
             a: "Hello, world!".into(),
             b: 100,
             c: true,
+            d: vec!["Hello".into(), "world".into()],
+            e: vec![Default::default(), Default::default()],
             inner_a: Default::default(),
         }
     }
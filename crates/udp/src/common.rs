@@ -7,9 +7,12 @@ use std::sync::Arc;
 use crossbeam_channel::{Sender, TrySendError};
 
 use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::full_scrape::FullScrapeCache;
+use aquatic_common::snapshot::ShutdownSnapshotSignal;
 use aquatic_common::CanonicalSocketAddr;
 use aquatic_udp_protocol::*;
 use hdrhistogram::Histogram;
+use serde::Serialize;
 
 use crate::config::Config;
 
@@ -40,9 +43,75 @@ pub enum ConnectedResponse {
     Scrape(PendingScrapeResponse),
 }
 
+/// Account that transfers are aggregated by
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize)]
+pub struct AccountId {
+    pub key: u32,
+}
+
+/// Summed scrape statistics of all torrents in a torrent map
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FullScrapeStatistics {
+    pub seeders: usize,
+    pub completed: usize,
+    pub leechers: usize,
+}
+
+impl FullScrapeStatistics {
+    pub fn add(&mut self, other: &Self) {
+        self.seeders += other.seeders;
+        self.completed += other.completed;
+        self.leechers += other.leechers;
+    }
+
+    pub fn to_torrent_scrape_statistics(self) -> TorrentScrapeStatistics {
+        let convert = |n: usize| n.try_into().unwrap_or(i32::MAX);
+
+        TorrentScrapeStatistics {
+            seeders: NumberOfPeers(convert(self.seeders)),
+            completed: NumberOfDownloads(convert(self.completed)),
+            leechers: NumberOfPeers(convert(self.leechers)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FullScrapeData {
+    pub ipv4: FullScrapeStatistics,
+    pub ipv6: FullScrapeStatistics,
+    /// Statistics of both IP versions with dual-stack peers only counted
+    /// once. Only set if `protocol.dual_stack_swarms` is enabled.
+    pub dual_stack: FullScrapeStatistics,
+}
+
+impl FullScrapeData {
+    pub fn merge(parts: &[Arc<Self>]) -> Self {
+        let mut merged = Self::default();
+
+        for part in parts {
+            merged.ipv4.add(&part.ipv4);
+            merged.ipv6.add(&part.ipv6);
+            merged.dual_stack.add(&part.dual_stack);
+        }
+
+        merged
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct SocketWorkerIndex(pub usize);
 
+/// Peer address and socket that request was received on
+///
+/// The response is sent from the same socket.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestSource {
+    pub addr: CanonicalSocketAddr,
+    /// Index of socket in socket worker, which is the same as the index of
+    /// its bind address in `config.network.addresses`
+    pub socket_index: usize,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct SwarmWorkerIndex(pub usize);
 
@@ -54,13 +123,13 @@ impl SwarmWorkerIndex {
 
 pub struct ConnectedRequestSender {
     index: SocketWorkerIndex,
-    senders: Vec<Sender<(SocketWorkerIndex, ConnectedRequest, CanonicalSocketAddr)>>,
+    senders: Vec<Sender<(SocketWorkerIndex, ConnectedRequest, RequestSource)>>,
 }
 
 impl ConnectedRequestSender {
     pub fn new(
         index: SocketWorkerIndex,
-        senders: Vec<Sender<(SocketWorkerIndex, ConnectedRequest, CanonicalSocketAddr)>>,
+        senders: Vec<Sender<(SocketWorkerIndex, ConnectedRequest, RequestSource)>>,
     ) -> Self {
         Self { index, senders }
     }
@@ -69,9 +138,9 @@ impl ConnectedRequestSender {
         &self,
         index: SwarmWorkerIndex,
         request: ConnectedRequest,
-        addr: CanonicalSocketAddr,
+        src: RequestSource,
     ) {
-        match self.senders[index.0].try_send((self.index, request, addr)) {
+        match self.senders[index.0].try_send((self.index, request, src)) {
             Ok(()) => {}
             Err(TrySendError::Full(_)) => {
                 ::log::error!("Request channel {} is full, dropping request. Try increasing number of swarm workers or raising config.worker_channel_size.", index.0)
@@ -84,11 +153,11 @@ impl ConnectedRequestSender {
 }
 
 pub struct ConnectedResponseSender {
-    senders: Vec<Sender<(ConnectedResponse, CanonicalSocketAddr)>>,
+    senders: Vec<Sender<(ConnectedResponse, RequestSource)>>,
 }
 
 impl ConnectedResponseSender {
-    pub fn new(senders: Vec<Sender<(ConnectedResponse, CanonicalSocketAddr)>>) -> Self {
+    pub fn new(senders: Vec<Sender<(ConnectedResponse, RequestSource)>>) -> Self {
         Self { senders }
     }
 
@@ -96,9 +165,9 @@ impl ConnectedResponseSender {
         &self,
         index: SocketWorkerIndex,
         response: ConnectedResponse,
-        addr: CanonicalSocketAddr,
+        src: RequestSource,
     ) {
-        match self.senders[index.0].try_send((response, addr)) {
+        match self.senders[index.0].try_send((response, src)) {
             Ok(()) => {}
             Err(TrySendError::Full(_)) => {
                 ::log::error!("Response channel {} is full, dropping response. Try increasing number of socket workers or raising config.worker_channel_size.", index.0)
@@ -148,6 +217,10 @@ pub struct Statistics {
     pub responses_sent_error: AtomicUsize,
     pub bytes_received: AtomicUsize,
     pub bytes_sent: AtomicUsize,
+    pub requests_rate_limited: AtomicUsize,
+    pub requests_banned: AtomicUsize,
+    pub bans_issued: AtomicUsize,
+    pub completed_downloads: AtomicUsize,
     pub torrents: Vec<AtomicUsize>,
     pub peers: Vec<AtomicUsize>,
 }
@@ -162,6 +235,10 @@ impl Statistics {
             responses_sent_error: Default::default(),
             bytes_received: Default::default(),
             bytes_sent: Default::default(),
+            requests_rate_limited: Default::default(),
+            requests_banned: Default::default(),
+            bans_issued: Default::default(),
+            completed_downloads: Default::default(),
             torrents: Self::create_atomic_usize_vec(num_swarm_workers),
             peers: Self::create_atomic_usize_vec(num_swarm_workers),
         }
@@ -179,14 +256,21 @@ pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
     pub statistics_ipv4: Arc<Statistics>,
     pub statistics_ipv6: Arc<Statistics>,
+    pub shutdown_snapshot_signal: ShutdownSnapshotSignal,
+    pub full_scrape: Arc<FullScrapeCache<FullScrapeData, FullScrapeData>>,
 }
 
 impl State {
-    pub fn new(num_swarm_workers: usize) -> Self {
+    pub fn new(config: &Config) -> Self {
         Self {
             access_list: Arc::new(AccessListArcSwap::default()),
-            statistics_ipv4: Arc::new(Statistics::new(num_swarm_workers)),
-            statistics_ipv6: Arc::new(Statistics::new(num_swarm_workers)),
+            statistics_ipv4: Arc::new(Statistics::new(config.swarm_workers)),
+            statistics_ipv6: Arc::new(Statistics::new(config.swarm_workers)),
+            shutdown_snapshot_signal: Default::default(),
+            full_scrape: Arc::new(FullScrapeCache::new(
+                &config.full_scrape,
+                config.swarm_workers,
+            )),
         }
     }
 }
@@ -1,8 +1,12 @@
 use std::{net::SocketAddr, path::PathBuf};
 
-use aquatic_common::{access_list::AccessListConfig, privileges::PrivilegeConfig};
+use aquatic_common::{
+    access_list::AccessListConfig, accounting::AccountingConfig, admin::AdminConfig,
+    file_watcher::FileWatcherConfig, full_scrape::FullScrapeConfig, privileges::PrivilegeConfig,
+    snapshot::SnapshotConfig,
+};
 use cfg_if::cfg_if;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use aquatic_common::cli::LogLevel;
 use aquatic_toml_config::TomlConfig;
@@ -40,6 +44,11 @@ pub struct Config {
     pub protocol: ProtocolConfig,
     pub statistics: StatisticsConfig,
     pub cleaning: CleaningConfig,
+    /// Per-source rate limiting configuration
+    ///
+    /// Each socket worker keeps its own state, so with several socket
+    /// workers, a source may be allowed more requests than configured.
+    pub rate_limit: RateLimitConfig,
     pub privileges: PrivilegeConfig,
 
     /// Access list configuration
@@ -49,6 +58,32 @@ pub struct Config {
     /// emitting of an error-level log message, while successful updates of the
     /// access list result in emitting of an info-level log message.
     pub access_list: AccessListConfig,
+    /// File watcher configuration
+    ///
+    /// When enabled, changes to the access list file (or directory) trigger
+    /// the same reload as `SIGUSR1`.
+    pub file_watcher: FileWatcherConfig,
+    /// Transfer accounting configuration
+    ///
+    /// Transfers are aggregated per announce request key. Lines look like
+    /// `{"time":1700000000,"key":12345,"uploaded":10,"downloaded":20}`.
+    /// Pending transfers are also flushed on `SIGTERM`.
+    pub accounting: AccountingConfig,
+    /// Swarm state snapshot configuration
+    ///
+    /// Snapshots are only loaded if they were saved with the same number of
+    /// swarm workers.
+    pub snapshot: SnapshotConfig,
+    /// Full scrape configuration
+    ///
+    /// Since UDP scrape responses don't include info hashes, the response
+    /// to a full scrape contains a single entry with the summed statistics
+    /// of all torrents for the IP version of the request.
+    pub full_scrape: FullScrapeConfig,
+    /// Admin socket configuration
+    ///
+    /// Use `aquatic ctl` to send commands.
+    pub admin: AdminConfig,
     #[cfg(feature = "cpu-pinning")]
     pub cpu_pinning: aquatic_common::cpu_pinning::asc::CpuPinningConfigAsc,
 }
@@ -65,8 +100,14 @@ impl Default for Config {
             protocol: ProtocolConfig::default(),
             statistics: StatisticsConfig::default(),
             cleaning: CleaningConfig::default(),
+            rate_limit: RateLimitConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            file_watcher: FileWatcherConfig::default(),
+            accounting: AccountingConfig::default(),
+            snapshot: SnapshotConfig::default(),
+            full_scrape: FullScrapeConfig::default(),
+            admin: AdminConfig::default(),
             #[cfg(feature = "cpu-pinning")]
             cpu_pinning: Default::default(),
         }
@@ -82,9 +123,21 @@ impl aquatic_common::cli::Config for Config {
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct NetworkConfig {
-    /// Bind to this address
-    pub address: SocketAddr,
-    /// Only allow access over IPv6
+    /// Bind to these addresses
+    ///
+    /// Every socket worker binds a socket to each address. IPv4 and IPv6
+    /// addresses as well as different ports can be mixed. Responses are
+    /// sent from the socket that the request was received on. A single
+    /// address (as with the old `address` key) is also accepted.
+    #[serde(
+        alias = "address",
+        deserialize_with = "aquatic_common::deserialize_one_or_many"
+    )]
+    pub addresses: Vec<SocketAddr>,
+    /// Only allow access over IPv6 on sockets bound to IPv6 addresses
+    ///
+    /// Set this if binding both IPv4 and IPv6 wildcard addresses on the same
+    /// port.
     pub only_ipv6: bool,
     /// Size of socket recv buffer. Use 0 for OS default.
     ///
@@ -120,17 +173,19 @@ pub struct NetworkConfig {
 
 impl NetworkConfig {
     pub fn ipv4_active(&self) -> bool {
-        self.address.is_ipv4() || !self.only_ipv6
+        self.addresses
+            .iter()
+            .any(|address| address.is_ipv4() || !self.only_ipv6)
     }
     pub fn ipv6_active(&self) -> bool {
-        self.address.is_ipv6()
+        self.addresses.iter().any(|address| address.is_ipv6())
     }
 }
 
 impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
-            address: SocketAddr::from(([0, 0, 0, 0], 3000)),
+            addresses: vec![SocketAddr::from(([0, 0, 0, 0], 3000))],
             only_ipv6: false,
             socket_recv_buffer_size: 4096 * 128,
             poll_event_capacity: 4096,
@@ -151,6 +206,17 @@ pub struct ProtocolConfig {
     pub max_response_peers: usize,
     /// Ask peers to announce this often (seconds)
     pub peer_announce_interval: i32,
+    /// Treat IPv4 and IPv6 peers of a torrent as a single swarm when
+    /// counting seeders, leechers and completed downloads
+    ///
+    /// Peers announcing with the same peer id over both IP versions are only
+    /// counted once.
+    ///
+    /// Affects scrape responses (including full scrapes) and seeder and
+    /// leecher counts in announce responses. Announce responses still only
+    /// contain peers of the IP version of the request, and statistics are
+    /// still collected separately for each IP version.
+    pub dual_stack_swarms: bool,
 }
 
 impl Default for ProtocolConfig {
@@ -159,6 +225,7 @@ impl Default for ProtocolConfig {
             max_scrape_torrents: 70,
             max_response_peers: 50,
             peer_announce_interval: 60 * 15,
+            dual_stack_swarms: false,
         }
     }
 }
@@ -249,6 +316,9 @@ pub struct CleaningConfig {
     pub max_connection_age: u32,
     /// Remove peers who have not announced for this long (seconds)
     pub max_peer_age: u32,
+    /// Keep torrents without peers for this long if they have a non-zero
+    /// completed count, so that it isn't lost (seconds)
+    pub max_torrent_idle_age: u32,
     /// Remove pending scrape responses that have not been returned from swarm
     /// workers for this long (seconds)
     pub max_pending_scrape_age: u32,
@@ -261,14 +331,114 @@ impl Default for CleaningConfig {
             pending_scrape_cleaning_interval: 60 * 10,
             max_connection_age: 60 * 2,
             max_peer_age: 60 * 20,
+            max_torrent_idle_age: 60 * 60 * 24,
             max_pending_scrape_age: 60,
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BanResponse {
+    /// Send an error response
+    Error,
+    /// Don't respond
+    Drop,
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Limit number of requests per source IPv4 address or IPv6 /64 network
+    ///
+    /// Requests exceeding limits are dropped without response. A rate of
+    /// zero disables limiting of that request type.
+    pub enabled: bool,
+    /// Sustained number of connect requests allowed per second
+    ///
+    /// Only applied to sources that have sent announce or scrape requests
+    /// with valid connection IDs, since connect request source addresses may
+    /// be spoofed.
+    pub connect_requests_per_second: f32,
+    /// Maximum number of connect requests allowed in a burst
+    pub connect_burst: f32,
+    /// Sustained number of announce requests allowed per second
+    pub announce_requests_per_second: f32,
+    /// Maximum number of announce requests allowed in a burst
+    pub announce_burst: f32,
+    /// Sustained number of scrape requests allowed per second
+    pub scrape_requests_per_second: f32,
+    /// Maximum number of scrape requests allowed in a burst
+    pub scrape_burst: f32,
+    /// Ban source after this many announce or scrape requests exceeding
+    /// limits within violation_window. Use 0 to never ban.
+    ///
+    /// Connect requests don't count towards bans, since their source
+    /// address may be spoofed.
+    pub violations_before_ban: u32,
+    /// Count requests exceeding limits within this period (seconds)
+    pub violation_window: u64,
+    /// Ban sources for this long (seconds)
+    pub ban_duration: u64,
+    /// How to handle requests from banned sources
+    ///
+    /// Options:
+    /// - error: send an error response
+    /// - drop: don't respond
+    pub ban_response: BanResponse,
+    /// Forget idle sources this often (seconds)
+    pub cleaning_interval: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            connect_requests_per_second: 5.0,
+            connect_burst: 50.0,
+            announce_requests_per_second: 5.0,
+            announce_burst: 50.0,
+            scrape_requests_per_second: 5.0,
+            scrape_burst: 50.0,
+            violations_before_ban: 100,
+            violation_window: 60,
+            ban_duration: 60 * 10,
+            ban_response: BanResponse::Error,
+            cleaning_interval: 60,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::net::SocketAddr;
+
     use super::Config;
 
     ::aquatic_toml_config::gen_serialize_deserialize_test!(Config);
+
+    #[test]
+    fn test_network_address_single_or_list() {
+        let config: Config =
+            ::aquatic_toml_config::toml::from_str("[network]\naddress = \"127.0.0.1:6969\"\n")
+                .unwrap();
+
+        assert_eq!(
+            config.network.addresses,
+            vec!["127.0.0.1:6969".parse::<SocketAddr>().unwrap()]
+        );
+
+        let config: Config = ::aquatic_toml_config::toml::from_str(
+            "[network]\naddresses = [\"0.0.0.0:6969\", \"[::]:1337\"]\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.network.addresses,
+            vec![
+                "0.0.0.0:6969".parse::<SocketAddr>().unwrap(),
+                "[::]:1337".parse::<SocketAddr>().unwrap()
+            ]
+        );
+    }
 }
@@ -12,30 +12,45 @@ use signal_hook::consts::{SIGTERM, SIGUSR1};
 use signal_hook::iterator::Signals;
 
 use aquatic_common::access_list::update_access_list;
+use aquatic_common::admin::{create_swarm_query_channels, spawn_admin_server};
 #[cfg(feature = "cpu-pinning")]
 use aquatic_common::cpu_pinning::{pin_current_if_configured_to, WorkerIndex};
+use aquatic_common::file_watcher::spawn_file_watcher;
 use aquatic_common::privileges::PrivilegeDropper;
+use aquatic_common::snapshot::SHUTDOWN_SNAPSHOT_TIMEOUT;
 use aquatic_common::{PanicSentinelWatcher, ServerStartInstant};
+use aquatic_udp_protocol::InfoHash;
 
 use common::{
     ConnectedRequestSender, ConnectedResponseSender, SocketWorkerIndex, State, SwarmWorkerIndex,
 };
 use config::Config;
 use workers::socket::ConnectionValidator;
+use workers::swarm::TorrentMap;
 
 pub const APP_NAME: &str = "aquatic_udp: UDP BitTorrent tracker";
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub fn run(config: Config) -> ::anyhow::Result<()> {
+    if config.network.addresses.is_empty() {
+        return Err(anyhow::anyhow!(
+            "configuration: network.addresses can't be empty"
+        ));
+    }
+
     let mut signals = Signals::new([SIGUSR1, SIGTERM])?;
 
-    let state = State::new(config.swarm_workers);
+    let state = State::new(&config);
     let connection_validator = ConnectionValidator::new(&config)?;
     let (sentinel_watcher, sentinel) = PanicSentinelWatcher::create_with_sentinel();
     let priv_dropper = PrivilegeDropper::new(config.privileges.clone(), config.socket_workers);
 
     update_access_list(&config.access_list, &state.access_list)?;
 
+    if config.access_list.mode.is_on() {
+        spawn_file_watcher(&config.file_watcher, vec![config.access_list.path.clone()])?;
+    }
+
     let mut request_senders = Vec::new();
     let mut request_receivers = BTreeMap::new();
 
@@ -43,6 +58,9 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
     let mut response_receivers = BTreeMap::new();
 
     let (statistics_sender, statistics_receiver) = unbounded();
+    let (swarm_query_senders, swarm_query_receivers) =
+        create_swarm_query_channels(config.swarm_workers);
+    let mut swarm_query_receivers = swarm_query_receivers.into_iter();
 
     let server_start_instant = ServerStartInstant::new();
 
@@ -75,6 +93,19 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         let request_receiver = request_receivers.remove(&i).unwrap().clone();
         let response_sender = ConnectedResponseSender::new(response_senders.clone());
         let statistics_sender = statistics_sender.clone();
+        let swarm_query_receiver = swarm_query_receivers.next().unwrap();
+
+        // Load snapshots before spawning socket workers, which might chroot
+        let torrents = if config.snapshot.enabled {
+            TorrentMap::load_snapshot(
+                &config,
+                &statistics_sender,
+                server_start_instant,
+                SwarmWorkerIndex(i),
+            )
+        } else {
+            TorrentMap::default()
+        };
 
         Builder::new()
             .name(format!("swarm-{:02}", i + 1))
@@ -92,15 +123,29 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                     config,
                     state,
                     server_start_instant,
+                    torrents,
                     request_receiver,
                     response_sender,
                     statistics_sender,
+                    swarm_query_receiver,
                     SwarmWorkerIndex(i),
                 )
             })
             .with_context(|| "spawn swarm worker")?;
     }
 
+    // Bind before socket workers spawn, since they might chroot
+    spawn_admin_server(
+        &config.admin,
+        state.access_list.clone(),
+        swarm_query_senders,
+        {
+            let config = config.clone();
+
+            move |info_hash| SwarmWorkerIndex::from_info_hash(&config, InfoHash(*info_hash)).0
+        },
+    )?;
+
     for i in 0..config.socket_workers {
         let sentinel = sentinel.clone();
         let state = state.clone();
@@ -200,6 +245,13 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                     return Err(anyhow::anyhow!("worker thread panicked"));
                 }
 
+                // Swarm workers also flush transfer accounting when signaled
+                if config.snapshot.enabled || config.accounting.enabled {
+                    state
+                        .shutdown_snapshot_signal
+                        .request_and_wait(config.swarm_workers, SHUTDOWN_SNAPSHOT_TIMEOUT);
+                }
+
                 break;
             }
             _ => unreachable!(),
@@ -17,20 +17,25 @@ use aquatic_udp_protocol::*;
 use crate::common::*;
 use crate::config::Config;
 
+use super::rate_limiter::{RateLimiter, RequestType};
 use super::storage::PendingScrapeResponseSlab;
 use super::validator::ConnectionValidator;
-use super::{create_socket, EXTRA_PACKET_SIZE_IPV4, EXTRA_PACKET_SIZE_IPV6};
+use super::{
+    check_rate_limits, create_full_scrape_response, create_sockets, EXTRA_PACKET_SIZE_IPV4,
+    EXTRA_PACKET_SIZE_IPV6,
+};
 
 pub struct SocketWorker {
     config: Config,
     shared_state: State,
     request_sender: ConnectedRequestSender,
-    response_receiver: Receiver<(ConnectedResponse, CanonicalSocketAddr)>,
+    response_receiver: Receiver<(ConnectedResponse, RequestSource)>,
     access_list_cache: AccessListCache,
     validator: ConnectionValidator,
+    opt_rate_limiter: Option<RateLimiter>,
     server_start_instant: ServerStartInstant,
     pending_scrape_responses: PendingScrapeResponseSlab,
-    socket: UdpSocket,
+    sockets: Vec<UdpSocket>,
     buffer: [u8; BUFFER_SIZE],
 }
 
@@ -42,23 +47,31 @@ impl SocketWorker {
         validator: ConnectionValidator,
         server_start_instant: ServerStartInstant,
         request_sender: ConnectedRequestSender,
-        response_receiver: Receiver<(ConnectedResponse, CanonicalSocketAddr)>,
+        response_receiver: Receiver<(ConnectedResponse, RequestSource)>,
         priv_dropper: PrivilegeDropper,
     ) {
-        let socket =
-            UdpSocket::from_std(create_socket(&config, priv_dropper).expect("create socket"));
+        let sockets = create_sockets(&config, priv_dropper)
+            .expect("create sockets")
+            .into_iter()
+            .map(UdpSocket::from_std)
+            .collect();
         let access_list_cache = create_access_list_cache(&shared_state.access_list);
+        let opt_rate_limiter = config
+            .rate_limit
+            .enabled
+            .then(|| RateLimiter::new(&config, Instant::now()));
 
         let mut worker = Self {
             config,
             shared_state,
             validator,
+            opt_rate_limiter,
             server_start_instant,
             request_sender,
             response_receiver,
             access_list_cache,
             pending_scrape_responses: Default::default(),
-            socket,
+            sockets,
             buffer: [0; BUFFER_SIZE],
         };
 
@@ -73,9 +86,12 @@ impl SocketWorker {
         let mut events = Events::with_capacity(self.config.network.poll_event_capacity);
         let mut poll = Poll::new().expect("create poll");
 
-        poll.registry()
-            .register(&mut self.socket, Token(0), Interest::READABLE)
-            .expect("register poll");
+        // Token value is index of socket
+        for (i, socket) in self.sockets.iter_mut().enumerate() {
+            poll.registry()
+                .register(socket, Token(i), Interest::READABLE)
+                .expect("register poll");
+        }
 
         let poll_timeout = Duration::from_millis(self.config.network.poll_timeout_ms);
 
@@ -96,40 +112,44 @@ impl SocketWorker {
 
             for event in events.iter() {
                 if event.is_readable() {
-                    self.read_and_handle_requests(&mut local_responses, pending_scrape_valid_until);
+                    self.read_and_handle_requests(
+                        &mut local_responses,
+                        pending_scrape_valid_until,
+                        event.token().0,
+                    );
                 }
             }
 
             // If resend buffer is enabled, send any responses in it
             if let Some(resend_buffer) = opt_resend_buffer.as_mut() {
-                for (response, addr) in resend_buffer.drain(..) {
+                for (response, src) in resend_buffer.drain(..) {
                     Self::send_response(
                         &self.config,
                         &self.shared_state,
-                        &mut self.socket,
+                        &mut self.sockets,
                         &mut self.buffer,
                         &mut None,
                         response,
-                        addr,
+                        src,
                     );
                 }
             }
 
             // Send any connect and error responses generated by this socket worker
-            for (response, addr) in local_responses.drain(..) {
+            for (response, src) in local_responses.drain(..) {
                 Self::send_response(
                     &self.config,
                     &self.shared_state,
-                    &mut self.socket,
+                    &mut self.sockets,
                     &mut self.buffer,
                     &mut opt_resend_buffer,
                     response,
-                    addr,
+                    src,
                 );
             }
 
             // Check channel for any responses generated by swarm workers
-            for (response, addr) in self.response_receiver.try_iter() {
+            for (response, src) in self.response_receiver.try_iter() {
                 let opt_response = match response {
                     ConnectedResponse::Scrape(r) => self
                         .pending_scrape_responses
@@ -143,11 +163,11 @@ impl SocketWorker {
                     Self::send_response(
                         &self.config,
                         &self.shared_state,
-                        &mut self.socket,
+                        &mut self.sockets,
                         &mut self.buffer,
                         &mut opt_resend_buffer,
                         response,
-                        addr,
+                        src,
                     );
                 }
             }
@@ -168,6 +188,10 @@ impl SocketWorker {
 
                     last_pending_scrape_cleaning = now;
                 }
+
+                if let Some(rate_limiter) = self.opt_rate_limiter.as_mut() {
+                    rate_limiter.clean_if_due(now);
+                }
             }
 
             iter_counter = iter_counter.wrapping_add(1);
@@ -176,8 +200,9 @@ impl SocketWorker {
 
     fn read_and_handle_requests(
         &mut self,
-        local_responses: &mut Vec<(Response, CanonicalSocketAddr)>,
+        local_responses: &mut Vec<(Response, RequestSource)>,
         pending_scrape_valid_until: ValidUntil,
+        socket_index: usize,
     ) {
         let mut requests_received_ipv4: usize = 0;
         let mut requests_received_ipv6: usize = 0;
@@ -185,7 +210,7 @@ impl SocketWorker {
         let mut bytes_received_ipv6 = 0;
 
         loop {
-            match self.socket.recv_from(&mut self.buffer[..]) {
+            match self.sockets[socket_index].recv_from(&mut self.buffer[..]) {
                 Ok((bytes_read, src)) => {
                     if src.port() == 0 {
                         ::log::info!("Ignored request from {} because source port is zero", src);
@@ -193,7 +218,10 @@ impl SocketWorker {
                         continue;
                     }
 
-                    let src = CanonicalSocketAddr::new(src);
+                    let src = RequestSource {
+                        addr: CanonicalSocketAddr::new(src),
+                        socket_index,
+                    };
 
                     let request_parsable = match Request::from_bytes(
                         &self.buffer[..bytes_read],
@@ -218,7 +246,7 @@ impl SocketWorker {
                                 err,
                             } = err
                             {
-                                if self.validator.connection_id_valid(src, connection_id) {
+                                if self.validator.connection_id_valid(src.addr, connection_id) {
                                     let response = ErrorResponse {
                                         transaction_id,
                                         message: err.right_or("Parse error").into(),
@@ -233,7 +261,7 @@ impl SocketWorker {
                     };
 
                     // Update statistics for converted address
-                    if src.is_ipv4() {
+                    if src.addr.is_ipv4() {
                         if request_parsable {
                             requests_received_ipv4 += 1;
                         }
@@ -276,16 +304,29 @@ impl SocketWorker {
 
     fn handle_request(
         &mut self,
-        local_responses: &mut Vec<(Response, CanonicalSocketAddr)>,
+        local_responses: &mut Vec<(Response, RequestSource)>,
         pending_scrape_valid_until: ValidUntil,
         request: Request,
-        src: CanonicalSocketAddr,
+        src: RequestSource,
     ) {
         let access_list_mode = self.config.access_list.mode;
 
         match request {
             Request::Connect(request) => {
-                let connection_id = self.validator.create_connection_id(src);
+                if let Err(opt_response) = check_rate_limits(
+                    &self.config,
+                    &self.shared_state,
+                    &mut self.opt_rate_limiter,
+                    RequestType::Connect,
+                    request.transaction_id,
+                    src.addr,
+                ) {
+                    local_responses.extend(opt_response.map(|response| (response, src)));
+
+                    return;
+                }
+
+                let connection_id = self.validator.create_connection_id(src.addr);
 
                 let response = Response::Connect(ConnectResponse {
                     connection_id,
@@ -297,8 +338,21 @@ impl SocketWorker {
             Request::Announce(request) => {
                 if self
                     .validator
-                    .connection_id_valid(src, request.connection_id)
+                    .connection_id_valid(src.addr, request.connection_id)
                 {
+                    if let Err(opt_response) = check_rate_limits(
+                        &self.config,
+                        &self.shared_state,
+                        &mut self.opt_rate_limiter,
+                        RequestType::Announce,
+                        request.transaction_id,
+                        src.addr,
+                    ) {
+                        local_responses.extend(opt_response.map(|response| (response, src)));
+
+                        return;
+                    }
+
                     if self
                         .access_list_cache
                         .load()
@@ -323,10 +377,36 @@ impl SocketWorker {
                 }
             }
             Request::Scrape(request) => {
-                if self
+                if !self
                     .validator
-                    .connection_id_valid(src, request.connection_id)
+                    .connection_id_valid(src.addr, request.connection_id)
                 {
+                    return;
+                }
+
+                if let Err(opt_response) = check_rate_limits(
+                    &self.config,
+                    &self.shared_state,
+                    &mut self.opt_rate_limiter,
+                    RequestType::Scrape,
+                    request.transaction_id,
+                    src.addr,
+                ) {
+                    local_responses.extend(opt_response.map(|response| (response, src)));
+
+                    return;
+                }
+
+                if request.info_hashes.is_empty() {
+                    let response = create_full_scrape_response(
+                        &self.config,
+                        &self.shared_state,
+                        request.transaction_id,
+                        src.addr,
+                    );
+
+                    local_responses.push((response, src));
+                } else {
                     let split_requests = self.pending_scrape_responses.prepare_split_requests(
                         &self.config,
                         request,
@@ -348,11 +428,11 @@ impl SocketWorker {
     fn send_response(
         config: &Config,
         shared_state: &State,
-        socket: &mut UdpSocket,
+        sockets: &mut [UdpSocket],
         buffer: &mut [u8],
-        opt_resend_buffer: &mut Option<Vec<(Response, CanonicalSocketAddr)>>,
+        opt_resend_buffer: &mut Option<Vec<(Response, RequestSource)>>,
         response: Response,
-        canonical_addr: CanonicalSocketAddr,
+        src: RequestSource,
     ) {
         let mut cursor = Cursor::new(buffer);
 
@@ -364,7 +444,9 @@ impl SocketWorker {
 
         let bytes_written = cursor.position() as usize;
 
-        let addr = if config.network.address.is_ipv4() {
+        let canonical_addr = src.addr;
+
+        let addr = if config.network.addresses[src.socket_index].is_ipv4() {
             canonical_addr
                 .get_ipv4()
                 .expect("found peer ipv6 address while running bound to ipv4 address")
@@ -372,7 +454,7 @@ impl SocketWorker {
             canonical_addr.get_ipv6_mapped()
         };
 
-        match socket.send_to(&cursor.get_ref()[..bytes_written], addr) {
+        match sockets[src.socket_index].send_to(&cursor.get_ref()[..bytes_written], addr) {
             Ok(amt) if config.statistics.active() => {
                 let stats = if canonical_addr.is_ipv4() {
                     let stats = &shared_state.statistics_ipv4;
@@ -418,7 +500,7 @@ impl SocketWorker {
                     if resend_buffer.len() < config.network.resend_buffer_max_len {
                         ::log::info!("Adding response to resend queue, since sending it to {} failed with: {:#}", addr, err);
 
-                        resend_buffer.push((response, canonical_addr));
+                        resend_buffer.push((response, src));
                     } else {
                         ::log::warn!("Response resend buffer full, dropping response");
                     }
@@ -1,21 +1,29 @@
 mod mio;
+mod rate_limiter;
 mod storage;
 #[cfg(feature = "io-uring")]
 mod uring;
 mod validator;
 
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
 use anyhow::Context;
 use aquatic_common::{
     privileges::PrivilegeDropper, CanonicalSocketAddr, PanicSentinel, ServerStartInstant,
 };
+use aquatic_udp_protocol::{ErrorResponse, Response, ScrapeResponse, TransactionId};
 use crossbeam_channel::Receiver;
 use socket2::{Domain, Protocol, Socket, Type};
 
 use crate::{
-    common::{ConnectedRequestSender, ConnectedResponse, State},
-    config::Config,
+    common::{ConnectedRequestSender, ConnectedResponse, RequestSource, State},
+    config::{BanResponse, Config},
 };
 
+use self::rate_limiter::{RateLimitOutcome, RateLimiter, RequestType};
+
 pub use self::validator::ConnectionValidator;
 
 /// Bytes of data transmitted when sending an IPv4 UDP packet, in addition to payload size
@@ -43,7 +51,7 @@ pub fn run_socket_worker(
     validator: ConnectionValidator,
     server_start_instant: ServerStartInstant,
     request_sender: ConnectedRequestSender,
-    response_receiver: Receiver<(ConnectedResponse, CanonicalSocketAddr)>,
+    response_receiver: Receiver<(ConnectedResponse, RequestSource)>,
     priv_dropper: PrivilegeDropper,
 ) {
     #[cfg(feature = "io-uring")]
@@ -82,17 +90,124 @@ pub fn run_socket_worker(
     );
 }
 
-fn create_socket(
+/// Create response to full scrape request (scrape request without info
+/// hashes) from data cached by swarm workers
+fn create_full_scrape_response(
+    config: &Config,
+    shared_state: &State,
+    transaction_id: TransactionId,
+    src: CanonicalSocketAddr,
+) -> Response {
+    if !config.full_scrape.enabled {
+        return Response::Error(ErrorResponse {
+            transaction_id,
+            message: "Full scrapes are not allowed".into(),
+        });
+    }
+
+    match shared_state.full_scrape.get() {
+        Ok(data) => {
+            let statistics = if config.protocol.dual_stack_swarms {
+                data.dual_stack
+            } else if src.is_ipv4() {
+                data.ipv4
+            } else {
+                data.ipv6
+            };
+
+            Response::Scrape(ScrapeResponse {
+                transaction_id,
+                torrent_stats: vec![statistics.to_torrent_scrape_statistics()],
+            })
+        }
+        Err(err) => Response::Error(ErrorResponse {
+            transaction_id,
+            message: err.failure_reason().into(),
+        }),
+    }
+}
+
+/// Check request against per-source rate limits
+///
+/// Announce and scrape requests must only be checked once their connection
+/// IDs have been validated. Returns Err if the request should not be
+/// handled, containing an error response to send if any.
+fn check_rate_limits(
+    config: &Config,
+    shared_state: &State,
+    opt_rate_limiter: &mut Option<RateLimiter>,
+    request_type: RequestType,
+    transaction_id: TransactionId,
+    src: CanonicalSocketAddr,
+) -> Result<(), Option<Response>> {
+    let rate_limiter = if let Some(rate_limiter) = opt_rate_limiter.as_mut() {
+        rate_limiter
+    } else {
+        return Ok(());
+    };
+
+    let outcome = rate_limiter.check(src.get().ip(), request_type, Instant::now());
+
+    let opt_error_response = match outcome {
+        RateLimitOutcome::Allowed => return Ok(()),
+        RateLimitOutcome::Banned if config.rate_limit.ban_response == BanResponse::Error => {
+            Some(Response::Error(ErrorResponse {
+                transaction_id,
+                message: "Too many requests, try again later".into(),
+            }))
+        }
+        RateLimitOutcome::BanIssued => {
+            ::log::info!("Banned {} for exceeding rate limits", src.get().ip());
+
+            None
+        }
+        _ => None,
+    };
+
+    if config.statistics.active() {
+        let statistics = if src.is_ipv4() {
+            &shared_state.statistics_ipv4
+        } else {
+            &shared_state.statistics_ipv6
+        };
+
+        let counter = match outcome {
+            RateLimitOutcome::BanIssued => &statistics.bans_issued,
+            RateLimitOutcome::Banned => &statistics.requests_banned,
+            _ => &statistics.requests_rate_limited,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    Err(opt_error_response)
+}
+
+/// Create one socket for each address in `config.network.addresses`
+fn create_sockets(
     config: &Config,
     priv_dropper: PrivilegeDropper,
-) -> anyhow::Result<::std::net::UdpSocket> {
-    let socket = if config.network.address.is_ipv4() {
+) -> anyhow::Result<Vec<::std::net::UdpSocket>> {
+    let sockets = config
+        .network
+        .addresses
+        .iter()
+        .map(|address| create_socket(config, *address))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    priv_dropper.after_socket_creation()?;
+
+    Ok(sockets)
+}
+
+fn create_socket(config: &Config, address: SocketAddr) -> anyhow::Result<::std::net::UdpSocket> {
+    let socket = if address.is_ipv4() {
         Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?
     } else {
         Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?
     };
 
-    if config.network.only_ipv6 {
+    if address.is_ipv6() && config.network.only_ipv6 {
         socket
             .set_only_v6(true)
             .with_context(|| "socket: set only ipv6")?;
@@ -119,10 +234,8 @@ fn create_socket(
     }
 
     socket
-        .bind(&config.network.address.into())
-        .with_context(|| format!("socket: bind to {}", config.network.address))?;
-
-    priv_dropper.after_socket_creation()?;
+        .bind(&address.into())
+        .with_context(|| format!("socket: bind to {}", address))?;
 
     Ok(socket.into())
 }
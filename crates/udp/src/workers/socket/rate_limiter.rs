@@ -0,0 +1,379 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use aquatic_common::token_bucket::{Limit, TokenBuckets};
+use aquatic_common::IndexMap;
+
+use crate::config::Config;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestType {
+    Connect = 0,
+    Announce = 1,
+    Scrape = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitOutcome {
+    Allowed,
+    /// Request exceeded rate limit
+    Limited,
+    /// Request exceeded rate limit and source was banned as a result
+    BanIssued,
+    /// Source is banned
+    Banned,
+}
+
+/// IPv4 address or IPv6 /64 network
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum SourceKey {
+    V4([u8; 4]),
+    V6([u8; 8]),
+}
+
+impl SourceKey {
+    fn new(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => Self::V4(ip.octets()),
+            IpAddr::V6(ip) => {
+                let mut network = [0; 8];
+
+                network.copy_from_slice(&ip.octets()[..8]);
+
+                Self::V6(network)
+            }
+        }
+    }
+}
+
+struct Source {
+    buckets: TokenBuckets<3>,
+    violations: u32,
+    first_violation: Instant,
+    opt_banned_until: Option<Instant>,
+}
+
+impl Source {
+    fn new(limits: &[Limit; 3], now: Instant) -> Self {
+        Self {
+            buckets: TokenBuckets::new(limits, now),
+            violations: 0,
+            first_violation: now,
+            opt_banned_until: None,
+        }
+    }
+}
+
+/// Per-source token bucket rate limiter with temporary bans
+///
+/// Sources are IPv4 addresses and IPv6 /64 networks. Only requests with valid
+/// connection IDs (announce and scrape requests) start tracking of sources
+/// and count towards bans, since the source address of connect requests may
+/// be spoofed.
+pub struct RateLimiter {
+    limits: [Limit; 3],
+    violations_before_ban: u32,
+    violation_window: Duration,
+    ban_duration: Duration,
+    /// Sources with full buckets and no violations or bans after this long
+    /// without requests are equivalent to new sources
+    max_idle: Duration,
+    cleaning_interval: Duration,
+    last_cleaning: Instant,
+    sources: IndexMap<SourceKey, Source>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config, now: Instant) -> Self {
+        let config = &config.rate_limit;
+
+        let limits = [
+            Limit {
+                per_second: config.connect_requests_per_second,
+                burst: config.connect_burst,
+            },
+            Limit {
+                per_second: config.announce_requests_per_second,
+                burst: config.announce_burst,
+            },
+            Limit {
+                per_second: config.scrape_requests_per_second,
+                burst: config.scrape_burst,
+            },
+        ];
+
+        let max_refill_secs = limits
+            .iter()
+            .filter(|limit| !limit.is_unlimited())
+            .map(|limit| limit.burst / limit.per_second)
+            .fold(0.0f32, f32::max);
+
+        let violation_window = Duration::from_secs(config.violation_window);
+
+        Self {
+            limits,
+            violations_before_ban: config.violations_before_ban,
+            violation_window,
+            ban_duration: Duration::from_secs(config.ban_duration),
+            max_idle: Duration::from_secs_f32(max_refill_secs).max(violation_window),
+            cleaning_interval: Duration::from_secs(config.cleaning_interval),
+            last_cleaning: now,
+            sources: Default::default(),
+        }
+    }
+
+    pub fn check(
+        &mut self,
+        ip: IpAddr,
+        request_type: RequestType,
+        now: Instant,
+    ) -> RateLimitOutcome {
+        let key = SourceKey::new(ip);
+
+        // Don't track sources for request types without limit, but do apply
+        // bans
+        if self.limits[request_type as usize].is_unlimited() {
+            let banned = self
+                .sources
+                .get(&key)
+                .and_then(|source| source.opt_banned_until)
+                .map(|banned_until| now < banned_until)
+                .unwrap_or(false);
+
+            return if banned {
+                RateLimitOutcome::Banned
+            } else {
+                RateLimitOutcome::Allowed
+            };
+        }
+
+        let limits = &self.limits;
+
+        let source = if request_type == RequestType::Connect {
+            match self.sources.get_mut(&key) {
+                Some(source) => source,
+                None => return RateLimitOutcome::Allowed,
+            }
+        } else {
+            self.sources
+                .entry(key)
+                .or_insert_with(|| Source::new(limits, now))
+        };
+
+        if let Some(banned_until) = source.opt_banned_until {
+            if now < banned_until {
+                return RateLimitOutcome::Banned;
+            }
+
+            source.opt_banned_until = None;
+            source.violations = 0;
+        }
+
+        if source.buckets.take(limits, request_type as usize, now) {
+            return RateLimitOutcome::Allowed;
+        }
+
+        if self.violations_before_ban == 0 || request_type == RequestType::Connect {
+            return RateLimitOutcome::Limited;
+        }
+
+        if now.saturating_duration_since(source.first_violation) > self.violation_window {
+            source.violations = 0;
+        }
+        if source.violations == 0 {
+            source.first_violation = now;
+        }
+
+        source.violations += 1;
+
+        if source.violations >= self.violations_before_ban {
+            source.opt_banned_until = Some(now + self.ban_duration);
+
+            RateLimitOutcome::BanIssued
+        } else {
+            RateLimitOutcome::Limited
+        }
+    }
+
+    /// Forget idle sources if cleaning interval has passed
+    pub fn clean_if_due(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.last_cleaning) < self.cleaning_interval {
+            return;
+        }
+
+        let max_idle = self.max_idle;
+
+        self.sources.retain(|_, source| {
+            let banned = source
+                .opt_banned_until
+                .map(|banned_until| now < banned_until)
+                .unwrap_or(false);
+
+            banned || now.saturating_duration_since(source.buckets.last_refill()) < max_idle
+        });
+
+        self.sources.shrink_to_fit();
+
+        self.last_cleaning = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    fn create_rate_limiter(now: Instant) -> RateLimiter {
+        let mut config = Config::default();
+
+        config.rate_limit.enabled = true;
+        config.rate_limit.announce_requests_per_second = 1.0;
+        config.rate_limit.announce_burst = 2.0;
+        config.rate_limit.connect_requests_per_second = 1.0;
+        config.rate_limit.connect_burst = 1.0;
+        config.rate_limit.scrape_requests_per_second = 0.0;
+        config.rate_limit.violations_before_ban = 2;
+        config.rate_limit.violation_window = 10;
+        config.rate_limit.ban_duration = 60;
+        config.rate_limit.cleaning_interval = 0;
+
+        RateLimiter::new(&config, now)
+    }
+
+    #[test]
+    fn test_rate_limiter_limit_and_ban() {
+        use RateLimitOutcome::*;
+
+        let now = Instant::now();
+        let ip = IpAddr::from([1, 2, 3, 4]);
+
+        let mut rate_limiter = create_rate_limiter(now);
+
+        assert_eq!(rate_limiter.check(ip, RequestType::Announce, now), Allowed);
+        assert_eq!(rate_limiter.check(ip, RequestType::Announce, now), Allowed);
+        assert_eq!(rate_limiter.check(ip, RequestType::Announce, now), Limited);
+
+        // Buckets are separate for each request type
+        assert_eq!(rate_limiter.check(ip, RequestType::Connect, now), Allowed);
+
+        // Tokens are refilled over time
+        let now = now + Duration::from_secs(1);
+
+        assert_eq!(rate_limiter.check(ip, RequestType::Announce, now), Allowed);
+        assert_eq!(
+            rate_limiter.check(ip, RequestType::Announce, now),
+            BanIssued
+        );
+
+        // Scrape requests are not limited, but bans apply to them
+        assert_eq!(rate_limiter.check(ip, RequestType::Scrape, now), Banned);
+        assert_eq!(rate_limiter.check(ip, RequestType::Connect, now), Banned);
+
+        // Other sources are not affected
+        assert_eq!(
+            rate_limiter.check(IpAddr::from([1, 2, 3, 5]), RequestType::Announce, now),
+            Allowed
+        );
+
+        let now = now + Duration::from_secs(60);
+
+        assert_eq!(rate_limiter.check(ip, RequestType::Announce, now), Allowed);
+    }
+
+    #[test]
+    fn test_rate_limiter_connect_untracked() {
+        let now = Instant::now();
+
+        let mut rate_limiter = create_rate_limiter(now);
+
+        for i in 0..10 {
+            assert_eq!(
+                rate_limiter.check(IpAddr::from([1, 2, 3, i]), RequestType::Connect, now),
+                RateLimitOutcome::Allowed
+            );
+            assert_eq!(
+                rate_limiter.check(IpAddr::from([1, 2, 3, i]), RequestType::Connect, now),
+                RateLimitOutcome::Allowed
+            );
+        }
+
+        assert!(rate_limiter.sources.is_empty());
+    }
+
+    #[test]
+    fn test_rate_limiter_connect_not_banned() {
+        let now = Instant::now();
+        let ip = IpAddr::from([1, 2, 3, 4]);
+
+        let mut rate_limiter = create_rate_limiter(now);
+
+        assert_eq!(
+            rate_limiter.check(ip, RequestType::Announce, now),
+            RateLimitOutcome::Allowed
+        );
+        assert_eq!(
+            rate_limiter.check(ip, RequestType::Connect, now),
+            RateLimitOutcome::Allowed
+        );
+
+        for _ in 0..10 {
+            assert_eq!(
+                rate_limiter.check(ip, RequestType::Connect, now),
+                RateLimitOutcome::Limited
+            );
+        }
+    }
+
+    #[test]
+    fn test_rate_limiter_ipv6_network() {
+        let now = Instant::now();
+
+        let mut rate_limiter = create_rate_limiter(now);
+
+        let a = IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 0, 0, 0, 1));
+        let b = IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 0, 0, 0, 2));
+        let c = IpAddr::from(Ipv6Addr::new(0x2001, 0xdb8, 1, 3, 0, 0, 0, 1));
+
+        assert_eq!(
+            rate_limiter.check(a, RequestType::Announce, now),
+            RateLimitOutcome::Allowed
+        );
+        assert_eq!(
+            rate_limiter.check(b, RequestType::Announce, now),
+            RateLimitOutcome::Allowed
+        );
+        assert_eq!(
+            rate_limiter.check(b, RequestType::Announce, now),
+            RateLimitOutcome::Limited
+        );
+        assert_eq!(
+            rate_limiter.check(c, RequestType::Announce, now),
+            RateLimitOutcome::Allowed
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter_clean() {
+        let now = Instant::now();
+        let ip = IpAddr::from([1, 2, 3, 4]);
+
+        let mut rate_limiter = create_rate_limiter(now);
+
+        rate_limiter.check(ip, RequestType::Announce, now);
+        rate_limiter.check(ip, RequestType::Announce, now);
+        rate_limiter.check(ip, RequestType::Announce, now);
+        rate_limiter.check(ip, RequestType::Announce, now);
+
+        rate_limiter.check(IpAddr::from([1, 2, 3, 5]), RequestType::Announce, now);
+
+        rate_limiter.clean_if_due(now + Duration::from_secs(30));
+
+        // Banned source is kept
+        assert_eq!(rate_limiter.sources.len(), 1);
+
+        rate_limiter.clean_if_due(now + Duration::from_secs(61));
+
+        assert!(rate_limiter.sources.is_empty());
+    }
+}
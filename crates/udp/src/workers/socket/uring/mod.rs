@@ -8,18 +8,18 @@ use std::net::UdpSocket;
 use std::ops::DerefMut;
 use std::os::fd::AsRawFd;
 use std::sync::atomic::Ordering;
+use std::time::Instant;
 
 use anyhow::Context;
 use aquatic_common::access_list::AccessListCache;
 use aquatic_common::ServerStartInstant;
 use crossbeam_channel::Receiver;
 use io_uring::opcode::Timeout;
-use io_uring::types::{Fixed, Timespec};
+use io_uring::types::Timespec;
 use io_uring::{IoUring, Probe};
 
 use aquatic_common::{
-    access_list::create_access_list_cache, privileges::PrivilegeDropper, CanonicalSocketAddr,
-    PanicSentinel, ValidUntil,
+    access_list::create_access_list_cache, privileges::PrivilegeDropper, PanicSentinel, ValidUntil,
 };
 use aquatic_udp_protocol::*;
 
@@ -30,9 +30,13 @@ use self::buf_ring::BufRing;
 use self::recv_helper::RecvHelper;
 use self::send_buffers::{ResponseType, SendBuffers};
 
+use super::rate_limiter::{RateLimiter, RequestType};
 use super::storage::PendingScrapeResponseSlab;
 use super::validator::ConnectionValidator;
-use super::{create_socket, EXTRA_PACKET_SIZE_IPV4, EXTRA_PACKET_SIZE_IPV6};
+use super::{
+    check_rate_limits, create_full_scrape_response, create_sockets, EXTRA_PACKET_SIZE_IPV4,
+    EXTRA_PACKET_SIZE_IPV6,
+};
 
 /// Size of each request buffer
 ///
@@ -46,11 +50,11 @@ const REQUEST_BUF_LEN: usize = 256;
 /// - scrape response for 170 info hashes
 const RESPONSE_BUF_LEN: usize = 2048;
 
-const USER_DATA_RECV: u64 = u64::MAX;
 const USER_DATA_PULSE_TIMEOUT: u64 = u64::MAX - 1;
 const USER_DATA_CLEANING_TIMEOUT: u64 = u64::MAX - 2;
-
-const SOCKET_IDENTIFIER: Fixed = Fixed(0);
+/// User data of recv entries is this value plus socket index. Lower values
+/// are send buffer indices.
+const USER_DATA_RECV_OFFSET: u64 = 1 << 32;
 
 thread_local! {
     /// Store IoUring instance here so that it can be accessed in BufRing::drop
@@ -76,19 +80,22 @@ pub struct SocketWorker {
     config: Config,
     shared_state: State,
     request_sender: ConnectedRequestSender,
-    response_receiver: Receiver<(ConnectedResponse, CanonicalSocketAddr)>,
+    response_receiver: Receiver<(ConnectedResponse, RequestSource)>,
     access_list_cache: AccessListCache,
     validator: ConnectionValidator,
+    opt_rate_limiter: Option<RateLimiter>,
     server_start_instant: ServerStartInstant,
     #[allow(dead_code)]
-    socket: UdpSocket,
+    sockets: Vec<UdpSocket>,
     pending_scrape_responses: PendingScrapeResponseSlab,
     buf_ring: BufRing,
     send_buffers: SendBuffers,
-    recv_helper: RecvHelper,
-    local_responses: VecDeque<(Response, CanonicalSocketAddr)>,
+    /// One recv helper per socket
+    recv_helpers: Vec<RecvHelper>,
+    local_responses: VecDeque<(Response, RequestSource)>,
     resubmittable_sqe_buf: Vec<io_uring::squeue::Entry>,
-    recv_sqe: io_uring::squeue::Entry,
+    /// One recv entry per socket
+    recv_sqes: Vec<io_uring::squeue::Entry>,
     pulse_timeout_sqe: io_uring::squeue::Entry,
     cleaning_timeout_sqe: io_uring::squeue::Entry,
     pending_scrape_valid_until: ValidUntil,
@@ -102,17 +109,19 @@ impl SocketWorker {
         validator: ConnectionValidator,
         server_start_instant: ServerStartInstant,
         request_sender: ConnectedRequestSender,
-        response_receiver: Receiver<(ConnectedResponse, CanonicalSocketAddr)>,
+        response_receiver: Receiver<(ConnectedResponse, RequestSource)>,
         priv_dropper: PrivilegeDropper,
     ) {
         let ring_entries = config.network.ring_size.next_power_of_two();
         // Try to fill up the ring with send requests
         let send_buffer_entries = ring_entries;
 
-        let socket = create_socket(&config, priv_dropper).expect("create socket");
+        let sockets = create_sockets(&config, priv_dropper).expect("create sockets");
         let access_list_cache = create_access_list_cache(&shared_state.access_list);
         let send_buffers = SendBuffers::new(&config, send_buffer_entries as usize);
-        let recv_helper = RecvHelper::new(&config);
+        let recv_helpers = (0..sockets.len())
+            .map(|socket_index| RecvHelper::new(&config, socket_index))
+            .collect::<Vec<_>>();
 
         let ring = IoUring::builder()
             .setup_coop_taskrun()
@@ -121,8 +130,14 @@ impl SocketWorker {
             .build(ring_entries.into())
             .unwrap();
 
+        // Fixed file index is socket index
         ring.submitter()
-            .register_files(&[socket.as_raw_fd()])
+            .register_files(
+                &sockets
+                    .iter()
+                    .map(|socket| socket.as_raw_fd())
+                    .collect::<Vec<_>>(),
+            )
             .unwrap();
 
         // Store ring in thread local storage before creating BufRing
@@ -134,7 +149,11 @@ impl SocketWorker {
             .build()
             .unwrap();
 
-        let recv_sqe = recv_helper.create_entry(buf_ring.bgid().try_into().unwrap());
+        // All sockets share the same buffer group
+        let recv_sqes = recv_helpers
+            .iter()
+            .map(|recv_helper| recv_helper.create_entry(buf_ring.bgid().try_into().unwrap()))
+            .collect::<Vec<_>>();
 
         // This timeout enables regular updates of pending_scrape_valid_until
         // and wakes the main loop to send any pending responses in the case
@@ -157,33 +176,38 @@ impl SocketWorker {
                 .user_data(USER_DATA_CLEANING_TIMEOUT)
         };
 
-        let resubmittable_sqe_buf = vec![
-            recv_sqe.clone(),
-            pulse_timeout_sqe.clone(),
-            cleaning_timeout_sqe.clone(),
-        ];
+        let mut resubmittable_sqe_buf = recv_sqes.clone();
+
+        resubmittable_sqe_buf.push(pulse_timeout_sqe.clone());
+        resubmittable_sqe_buf.push(cleaning_timeout_sqe.clone());
 
         let pending_scrape_valid_until =
             ValidUntil::new(server_start_instant, config.cleaning.max_pending_scrape_age);
 
+        let opt_rate_limiter = config
+            .rate_limit
+            .enabled
+            .then(|| RateLimiter::new(&config, Instant::now()));
+
         let mut worker = Self {
             config,
             shared_state,
             validator,
+            opt_rate_limiter,
             server_start_instant,
             request_sender,
             response_receiver,
             access_list_cache,
             pending_scrape_responses: Default::default(),
             send_buffers,
-            recv_helper,
+            recv_helpers,
             local_responses: Default::default(),
             buf_ring,
-            recv_sqe,
+            recv_sqes,
             pulse_timeout_sqe,
             cleaning_timeout_sqe,
             resubmittable_sqe_buf,
-            socket,
+            sockets,
             pending_scrape_valid_until,
         };
 
@@ -206,15 +230,15 @@ impl SocketWorker {
 
             // Enqueue local responses
             for _ in 0..sq_space {
-                if let Some((response, addr)) = self.local_responses.pop_front() {
-                    match self.send_buffers.prepare_entry(&response, addr) {
+                if let Some((response, src)) = self.local_responses.pop_front() {
+                    match self.send_buffers.prepare_entry(&response, src) {
                         Ok(entry) => {
                             unsafe { ring.submission().push(&entry).unwrap() };
 
                             num_send_added += 1;
                         }
                         Err(send_buffers::Error::NoBuffers) => {
-                            self.local_responses.push_front((response, addr));
+                            self.local_responses.push_front((response, src));
 
                             break;
                         }
@@ -229,15 +253,15 @@ impl SocketWorker {
 
             // Enqueue swarm worker responses
             for _ in 0..(sq_space - num_send_added) {
-                if let Some((response, addr)) = self.get_next_swarm_response() {
-                    match self.send_buffers.prepare_entry(&response, addr) {
+                if let Some((response, src)) = self.get_next_swarm_response() {
+                    match self.send_buffers.prepare_entry(&response, src) {
                         Ok(entry) => {
                             unsafe { ring.submission().push(&entry).unwrap() };
 
                             num_send_added += 1;
                         }
                         Err(send_buffers::Error::NoBuffers) => {
-                            self.local_responses.push_back((response, addr));
+                            self.local_responses.push_back((response, src));
 
                             break;
                         }
@@ -267,13 +291,6 @@ impl SocketWorker {
 
     fn handle_cqe(&mut self, cqe: io_uring::cqueue::Entry) {
         match cqe.user_data() {
-            USER_DATA_RECV => {
-                self.handle_recv_cqe(&cqe);
-
-                if !io_uring::cqueue::more(cqe.flags()) {
-                    self.resubmittable_sqe_buf.push(self.recv_sqe.clone());
-                }
-            }
             USER_DATA_PULSE_TIMEOUT => {
                 self.pending_scrape_valid_until = ValidUntil::new(
                     self.server_start_instant,
@@ -286,6 +303,10 @@ impl SocketWorker {
                     self.response_receiver.len()
                 );
 
+                if let Some(rate_limiter) = self.opt_rate_limiter.as_mut() {
+                    rate_limiter.clean_if_due(Instant::now());
+                }
+
                 self.resubmittable_sqe_buf
                     .push(self.pulse_timeout_sqe.clone());
             }
@@ -296,6 +317,16 @@ impl SocketWorker {
                 self.resubmittable_sqe_buf
                     .push(self.cleaning_timeout_sqe.clone());
             }
+            user_data if user_data >= USER_DATA_RECV_OFFSET => {
+                let socket_index = (user_data - USER_DATA_RECV_OFFSET) as usize;
+
+                self.handle_recv_cqe(&cqe, socket_index);
+
+                if !io_uring::cqueue::more(cqe.flags()) {
+                    self.resubmittable_sqe_buf
+                        .push(self.recv_sqes[socket_index].clone());
+                }
+            }
             send_buffer_index => {
                 let result = cqe.result();
 
@@ -340,7 +371,7 @@ impl SocketWorker {
         }
     }
 
-    fn handle_recv_cqe(&mut self, cqe: &io_uring::cqueue::Entry) {
+    fn handle_recv_cqe(&mut self, cqe: &io_uring::cqueue::Entry, socket_index: usize) {
         let result = cqe.result();
 
         if result < 0 {
@@ -374,36 +405,36 @@ impl SocketWorker {
 
         let buffer = buffer.as_slice();
 
-        let addr = match self.recv_helper.parse(buffer) {
-            Ok((request, addr)) => {
-                self.handle_request(request, addr);
+        let src = match self.recv_helpers[socket_index].parse(buffer) {
+            Ok((request, src)) => {
+                self.handle_request(request, src);
 
-                addr
+                src
             }
-            Err(self::recv_helper::Error::RequestParseError(err, addr)) => {
+            Err(self::recv_helper::Error::RequestParseError(err, src)) => {
                 match err {
                     RequestParseError::Sendable {
                         connection_id,
                         transaction_id,
                         err,
                     } => {
-                        ::log::debug!("Couldn't parse request from {:?}: {}", addr, err);
+                        ::log::debug!("Couldn't parse request from {:?}: {}", src.addr, err);
 
-                        if self.validator.connection_id_valid(addr, connection_id) {
+                        if self.validator.connection_id_valid(src.addr, connection_id) {
                             let response = ErrorResponse {
                                 transaction_id,
                                 message: err.right_or("Parse error").into(),
                             };
 
-                            self.local_responses.push_back((response.into(), addr));
+                            self.local_responses.push_back((response.into(), src));
                         }
                     }
                     RequestParseError::Unsendable { err } => {
-                        ::log::debug!("Couldn't parse request from {:?}: {}", addr, err);
+                        ::log::debug!("Couldn't parse request from {:?}: {}", src.addr, err);
                     }
                 }
 
-                addr
+                src
             }
             Err(self::recv_helper::Error::InvalidSocketAddress) => {
                 ::log::debug!("Ignored request claiming to be from port 0");
@@ -418,7 +449,7 @@ impl SocketWorker {
         };
 
         if self.config.statistics.active() {
-            let (statistics, extra_bytes) = if addr.is_ipv4() {
+            let (statistics, extra_bytes) = if src.addr.is_ipv4() {
                 (&self.shared_state.statistics_ipv4, EXTRA_PACKET_SIZE_IPV4)
             } else {
                 (&self.shared_state.statistics_ipv6, EXTRA_PACKET_SIZE_IPV6)
@@ -431,12 +462,26 @@ impl SocketWorker {
         }
     }
 
-    fn handle_request(&mut self, request: Request, src: CanonicalSocketAddr) {
+    fn handle_request(&mut self, request: Request, src: RequestSource) {
         let access_list_mode = self.config.access_list.mode;
 
         match request {
             Request::Connect(request) => {
-                let connection_id = self.validator.create_connection_id(src);
+                if let Err(opt_response) = check_rate_limits(
+                    &self.config,
+                    &self.shared_state,
+                    &mut self.opt_rate_limiter,
+                    RequestType::Connect,
+                    request.transaction_id,
+                    src.addr,
+                ) {
+                    self.local_responses
+                        .extend(opt_response.map(|response| (response, src)));
+
+                    return;
+                }
+
+                let connection_id = self.validator.create_connection_id(src.addr);
 
                 let response = Response::Connect(ConnectResponse {
                     connection_id,
@@ -448,8 +493,22 @@ impl SocketWorker {
             Request::Announce(request) => {
                 if self
                     .validator
-                    .connection_id_valid(src, request.connection_id)
+                    .connection_id_valid(src.addr, request.connection_id)
                 {
+                    if let Err(opt_response) = check_rate_limits(
+                        &self.config,
+                        &self.shared_state,
+                        &mut self.opt_rate_limiter,
+                        RequestType::Announce,
+                        request.transaction_id,
+                        src.addr,
+                    ) {
+                        self.local_responses
+                            .extend(opt_response.map(|response| (response, src)));
+
+                        return;
+                    }
+
                     if self
                         .access_list_cache
                         .load()
@@ -474,10 +533,37 @@ impl SocketWorker {
                 }
             }
             Request::Scrape(request) => {
-                if self
+                if !self
                     .validator
-                    .connection_id_valid(src, request.connection_id)
+                    .connection_id_valid(src.addr, request.connection_id)
                 {
+                    return;
+                }
+
+                if let Err(opt_response) = check_rate_limits(
+                    &self.config,
+                    &self.shared_state,
+                    &mut self.opt_rate_limiter,
+                    RequestType::Scrape,
+                    request.transaction_id,
+                    src.addr,
+                ) {
+                    self.local_responses
+                        .extend(opt_response.map(|response| (response, src)));
+
+                    return;
+                }
+
+                if request.info_hashes.is_empty() {
+                    let response = create_full_scrape_response(
+                        &self.config,
+                        &self.shared_state,
+                        request.transaction_id,
+                        src.addr,
+                    );
+
+                    self.local_responses.push_back((response, src));
+                } else {
                     let split_requests = self.pending_scrape_responses.prepare_split_requests(
                         &self.config,
                         request,
@@ -496,20 +582,20 @@ impl SocketWorker {
         }
     }
 
-    fn get_next_swarm_response(&mut self) -> Option<(Response, CanonicalSocketAddr)> {
+    fn get_next_swarm_response(&mut self) -> Option<(Response, RequestSource)> {
         loop {
             match self.response_receiver.try_recv() {
-                Ok((ConnectedResponse::AnnounceIpv4(response), addr)) => {
-                    return Some((Response::AnnounceIpv4(response), addr));
+                Ok((ConnectedResponse::AnnounceIpv4(response), src)) => {
+                    return Some((Response::AnnounceIpv4(response), src));
                 }
-                Ok((ConnectedResponse::AnnounceIpv6(response), addr)) => {
-                    return Some((Response::AnnounceIpv6(response), addr));
+                Ok((ConnectedResponse::AnnounceIpv6(response), src)) => {
+                    return Some((Response::AnnounceIpv6(response), src));
                 }
-                Ok((ConnectedResponse::Scrape(response), addr)) => {
+                Ok((ConnectedResponse::Scrape(response), src)) => {
                     if let Some(response) =
                         self.pending_scrape_responses.add_and_get_finished(response)
                     {
-                        return Some((Response::Scrape(response), addr));
+                        return Some((Response::Scrape(response), src));
                     }
                 }
                 Err(_) => {
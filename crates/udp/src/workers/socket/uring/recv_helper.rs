@@ -6,19 +6,24 @@ use std::{
 
 use aquatic_common::CanonicalSocketAddr;
 use aquatic_udp_protocol::{Request, RequestParseError};
-use io_uring::{opcode::RecvMsgMulti, types::RecvMsgOut};
+use io_uring::{
+    opcode::RecvMsgMulti,
+    types::{Fixed, RecvMsgOut},
+};
 
+use crate::common::RequestSource;
 use crate::config::Config;
 
-use super::{SOCKET_IDENTIFIER, USER_DATA_RECV};
+use super::USER_DATA_RECV_OFFSET;
 
 pub enum Error {
     RecvMsgParseError,
-    RequestParseError(RequestParseError, CanonicalSocketAddr),
+    RequestParseError(RequestParseError, RequestSource),
     InvalidSocketAddress,
 }
 
 pub struct RecvHelper {
+    socket_index: usize,
     socket_is_ipv4: bool,
     max_scrape_torrents: u8,
     #[allow(dead_code)]
@@ -30,7 +35,7 @@ pub struct RecvHelper {
 }
 
 impl RecvHelper {
-    pub fn new(config: &Config) -> Self {
+    pub fn new(config: &Config, socket_index: usize) -> Self {
         let name_v4 = Box::new(UnsafeCell::new(libc::sockaddr_in {
             sin_family: 0,
             sin_port: 0,
@@ -67,7 +72,8 @@ impl RecvHelper {
         }));
 
         Self {
-            socket_is_ipv4: config.network.address.is_ipv4(),
+            socket_index,
+            socket_is_ipv4: config.network.addresses[socket_index].is_ipv4(),
             max_scrape_torrents: config.protocol.max_scrape_torrents,
             name_v4,
             msghdr_v4,
@@ -83,12 +89,12 @@ impl RecvHelper {
             self.msghdr_v6.get()
         };
 
-        RecvMsgMulti::new(SOCKET_IDENTIFIER, msghdr, buf_group)
+        RecvMsgMulti::new(Fixed(self.socket_index as u32), msghdr, buf_group)
             .build()
-            .user_data(USER_DATA_RECV)
+            .user_data(USER_DATA_RECV_OFFSET + self.socket_index as u64)
     }
 
-    pub fn parse(&self, buffer: &[u8]) -> Result<(Request, CanonicalSocketAddr), Error> {
+    pub fn parse(&self, buffer: &[u8]) -> Result<(Request, RequestSource), Error> {
         let (msg, addr) = if self.socket_is_ipv4 {
             let msg = unsafe {
                 let msghdr = &*(self.msghdr_v4.get() as *const _);
@@ -135,11 +141,14 @@ impl RecvHelper {
             (msg, addr)
         };
 
-        let addr = CanonicalSocketAddr::new(addr);
+        let src = RequestSource {
+            addr: CanonicalSocketAddr::new(addr),
+            socket_index: self.socket_index,
+        };
 
         let request = Request::from_bytes(msg.payload_data(), self.max_scrape_torrents)
-            .map_err(|err| Error::RequestParseError(err, addr))?;
+            .map_err(|err| Error::RequestParseError(err, src))?;
 
-        Ok((request, addr))
+        Ok((request, src))
     }
 }
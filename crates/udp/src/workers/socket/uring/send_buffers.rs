@@ -1,12 +1,12 @@
 use std::{cell::UnsafeCell, io::Cursor, net::SocketAddr, ops::IndexMut, ptr::null_mut};
 
-use aquatic_common::CanonicalSocketAddr;
 use aquatic_udp_protocol::Response;
-use io_uring::opcode::SendMsg;
+use io_uring::{opcode::SendMsg, types::Fixed};
 
+use crate::common::RequestSource;
 use crate::config::Config;
 
-use super::{RESPONSE_BUF_LEN, SOCKET_IDENTIFIER};
+use super::RESPONSE_BUF_LEN;
 
 pub enum Error {
     NoBuffers,
@@ -81,7 +81,7 @@ impl SendBuffer {
         }
     }
 
-    fn setup_pointers(&mut self, socket_is_ipv4: bool) {
+    fn setup_pointers(&mut self) {
         unsafe {
             let iovec = &mut *self.iovec.get();
 
@@ -91,14 +91,6 @@ impl SendBuffer {
             let msghdr = &mut *self.msghdr.get();
 
             msghdr.msg_iov = self.iovec.get();
-
-            if socket_is_ipv4 {
-                msghdr.msg_name = self.name_v4.get() as *mut libc::c_void;
-                msghdr.msg_namelen = core::mem::size_of::<libc::sockaddr_in>() as u32;
-            } else {
-                msghdr.msg_name = self.name_v6.get() as *mut libc::c_void;
-                msghdr.msg_namelen = core::mem::size_of::<libc::sockaddr_in6>() as u32;
-            }
         }
     }
 
@@ -112,11 +104,17 @@ impl SendBuffer {
     unsafe fn prepare_entry(
         &mut self,
         response: &Response,
-        addr: CanonicalSocketAddr,
+        src: RequestSource,
         socket_is_ipv4: bool,
     ) -> Result<io_uring::squeue::Entry, Error> {
+        let addr = src.addr;
+        let msghdr = &mut *self.msghdr.get();
+
         // Set receiver socket addr
         if socket_is_ipv4 {
+            msghdr.msg_name = self.name_v4.get() as *mut libc::c_void;
+            msghdr.msg_namelen = core::mem::size_of::<libc::sockaddr_in>() as u32;
+
             self.receiver_is_ipv4 = true;
 
             let addr = if let Some(SocketAddr::V4(addr)) = addr.get_ipv4() {
@@ -130,6 +128,9 @@ impl SendBuffer {
             name.sin_port = addr.port().to_be();
             name.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
         } else {
+            msghdr.msg_name = self.name_v6.get() as *mut libc::c_void;
+            msghdr.msg_namelen = core::mem::size_of::<libc::sockaddr_in6>() as u32;
+
             // Set receiver protocol type before calling addr.get_ipv6_mapped()
             self.receiver_is_ipv4 = addr.is_ipv4();
 
@@ -156,7 +157,7 @@ impl SendBuffer {
                 self.response_type = ResponseType::from_response(response);
                 self.free = false;
 
-                Ok(SendMsg::new(SOCKET_IDENTIFIER, self.msghdr.get()).build())
+                Ok(SendMsg::new(Fixed(src.socket_index as u32), self.msghdr.get()).build())
             }
             Err(err) => Err(Error::SerializationFailed(err)),
         }
@@ -165,13 +166,19 @@ impl SendBuffer {
 
 pub struct SendBuffers {
     likely_next_free_index: usize,
-    socket_is_ipv4: bool,
+    /// IP version of each socket
+    sockets_are_ipv4: Vec<bool>,
     buffers: Box<[SendBuffer]>,
 }
 
 impl SendBuffers {
     pub fn new(config: &Config, capacity: usize) -> Self {
-        let socket_is_ipv4 = config.network.address.is_ipv4();
+        let sockets_are_ipv4 = config
+            .network
+            .addresses
+            .iter()
+            .map(|address| address.is_ipv4())
+            .collect();
 
         let mut buffers = ::std::iter::repeat_with(|| SendBuffer::new_with_null_pointers())
             .take(capacity)
@@ -179,12 +186,12 @@ impl SendBuffers {
             .into_boxed_slice();
 
         for buffer in buffers.iter_mut() {
-            buffer.setup_pointers(socket_is_ipv4);
+            buffer.setup_pointers();
         }
 
         Self {
             likely_next_free_index: 0,
-            socket_is_ipv4,
+            sockets_are_ipv4,
             buffers,
         }
     }
@@ -211,9 +218,10 @@ impl SendBuffers {
     pub fn prepare_entry(
         &mut self,
         response: &Response,
-        addr: CanonicalSocketAddr,
+        src: RequestSource,
     ) -> Result<io_uring::squeue::Entry, Error> {
         let index = self.next_free_index()?;
+        let socket_is_ipv4 = self.sockets_are_ipv4[src.socket_index];
 
         let buffer = self.buffers.index_mut(index);
 
@@ -221,7 +229,7 @@ impl SendBuffers {
         // buffer pointers were set up in SendBuffers::new() and pointers to
         // SendBuffer UnsafeCell contents are not accessed elsewhere
         unsafe {
-            match buffer.prepare_entry(response, addr, self.socket_is_ipv4) {
+            match buffer.prepare_entry(response, src, socket_is_ipv4) {
                 Ok(entry) => {
                     self.likely_next_free_index = index + 1;
 
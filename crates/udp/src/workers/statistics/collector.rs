@@ -14,6 +14,8 @@ pub struct StatisticsCollector {
     last_update: Instant,
     pending_histograms: Vec<Histogram<u64>>,
     last_complete_histogram: PeerHistogramStatistics,
+    total_completed_downloads: usize,
+    total_bans_issued: usize,
     #[cfg(feature = "prometheus")]
     ip_version: String,
 }
@@ -25,6 +27,8 @@ impl StatisticsCollector {
             last_update: Instant::now(),
             pending_histograms: Vec::new(),
             last_complete_histogram: Default::default(),
+            total_completed_downloads: 0,
+            total_bans_issued: 0,
             #[cfg(feature = "prometheus")]
             ip_version,
         }
@@ -52,6 +56,15 @@ impl StatisticsCollector {
         let bytes_received = Self::fetch_and_reset(&self.shared.bytes_received);
         let bytes_sent = Self::fetch_and_reset(&self.shared.bytes_sent);
 
+        let requests_rate_limited = Self::fetch_and_reset(&self.shared.requests_rate_limited);
+        let requests_banned = Self::fetch_and_reset(&self.shared.requests_banned);
+        let bans_issued = Self::fetch_and_reset(&self.shared.bans_issued);
+
+        let completed_downloads = Self::fetch_and_reset(&self.shared.completed_downloads);
+
+        self.total_completed_downloads += completed_downloads;
+        self.total_bans_issued += bans_issued;
+
         let num_torrents_by_worker: Vec<usize> = self
             .shared
             .torrents
@@ -116,6 +129,26 @@ impl StatisticsCollector {
                 bytes_sent.try_into().unwrap(),
                 "ip_version" => self.ip_version.clone(),
             );
+            ::metrics::counter!(
+                "aquatic_requests_rate_limited_total",
+                requests_rate_limited.try_into().unwrap(),
+                "ip_version" => self.ip_version.clone(),
+            );
+            ::metrics::counter!(
+                "aquatic_requests_banned_total",
+                requests_banned.try_into().unwrap(),
+                "ip_version" => self.ip_version.clone(),
+            );
+            ::metrics::counter!(
+                "aquatic_bans_issued_total",
+                bans_issued.try_into().unwrap(),
+                "ip_version" => self.ip_version.clone(),
+            );
+            ::metrics::counter!(
+                "aquatic_completed_downloads_total",
+                completed_downloads.try_into().unwrap(),
+                "ip_version" => self.ip_version.clone(),
+            );
 
             for (worker_index, n) in num_torrents_by_worker.iter().copied().enumerate() {
                 ::metrics::gauge!(
@@ -148,6 +181,8 @@ impl StatisticsCollector {
         let responses_per_second_announce = responses_sent_announce as f64 / elapsed;
         let responses_per_second_scrape = responses_sent_scrape as f64 / elapsed;
         let responses_per_second_error = responses_sent_error as f64 / elapsed;
+        let requests_rate_limited_per_second = requests_rate_limited as f64 / elapsed;
+        let requests_banned_per_second = requests_banned as f64 / elapsed;
         let bytes_received_per_second = bytes_received as f64 / elapsed;
         let bytes_sent_per_second = bytes_sent as f64 / elapsed;
 
@@ -168,10 +203,18 @@ impl StatisticsCollector {
                 .to_formatted_string(&Locale::en),
             responses_per_second_error: (responses_per_second_error as usize)
                 .to_formatted_string(&Locale::en),
+            requests_per_second_rate_limited: (requests_rate_limited_per_second as usize)
+                .to_formatted_string(&Locale::en),
+            requests_per_second_banned: (requests_banned_per_second as usize)
+                .to_formatted_string(&Locale::en),
+            total_bans_issued: self.total_bans_issued.to_formatted_string(&Locale::en),
             rx_mbits: format!("{:.2}", bytes_received_per_second * 8.0 / 1_000_000.0),
             tx_mbits: format!("{:.2}", bytes_sent_per_second * 8.0 / 1_000_000.0),
             num_torrents: num_torrents.to_formatted_string(&Locale::en),
             num_peers: num_peers.to_formatted_string(&Locale::en),
+            total_completed_downloads: self
+                .total_completed_downloads
+                .to_formatted_string(&Locale::en),
             peer_histogram: self.last_complete_histogram.clone(),
         }
     }
@@ -189,10 +232,14 @@ pub struct CollectedStatistics {
     pub responses_per_second_announce: String,
     pub responses_per_second_scrape: String,
     pub responses_per_second_error: String,
+    pub requests_per_second_rate_limited: String,
+    pub requests_per_second_banned: String,
+    pub total_bans_issued: String,
     pub rx_mbits: String,
     pub tx_mbits: String,
     pub num_torrents: String,
     pub num_peers: String,
+    pub total_completed_downloads: String,
     pub peer_histogram: PeerHistogramStatistics,
 }
 
@@ -34,6 +34,7 @@ struct TemplateData {
     ipv4_active: bool,
     ipv6_active: bool,
     extended_active: bool,
+    rate_limit_active: bool,
     ipv4: CollectedStatistics,
     ipv6: CollectedStatistics,
     last_updated: String,
@@ -205,6 +206,7 @@ pub fn run_statistics_worker(
                 ipv4_active: config.network.ipv4_active(),
                 ipv6_active: config.network.ipv6_active(),
                 extended_active: config.statistics.torrent_peer_histograms,
+                rate_limit_active: config.rate_limit.enabled,
                 ipv4: statistics_ipv4,
                 ipv6: statistics_ipv6,
                 last_updated: OffsetDateTime::now_utc()
@@ -260,11 +262,30 @@ fn print_to_stdout(config: &Config, statistics: &CollectedStatistics) {
         "    error:         {:>10}",
         statistics.responses_per_second_error
     );
+    if config.rate_limit.enabled {
+        println!("  rate limiting");
+        println!(
+            "    limited requests/second: {:>10}",
+            statistics.requests_per_second_rate_limited
+        );
+        println!(
+            "    banned requests/second:  {:>10}",
+            statistics.requests_per_second_banned
+        );
+        println!(
+            "    bans (since start):      {:>10}",
+            statistics.total_bans_issued
+        );
+    }
     println!("  torrents:        {:>10}", statistics.num_torrents);
     println!(
         "  peers:           {:>10} (updated every {}s)",
         statistics.num_peers, config.cleaning.torrent_cleaning_interval
     );
+    println!(
+        "  completed downloads (since start): {:>10}",
+        statistics.total_completed_downloads
+    );
 
     if config.statistics.torrent_peer_histograms {
         println!(
@@ -1,88 +1,133 @@
 mod storage;
 
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::atomic::Ordering;
 use std::time::Duration;
 use std::time::Instant;
 
+use aquatic_common::accounting::TransferAccounting;
+use aquatic_common::admin::SwarmQueryReceiver;
 use aquatic_common::ServerStartInstant;
 use crossbeam_channel::Receiver;
 use crossbeam_channel::Sender;
 use rand::{rngs::SmallRng, SeedableRng};
 
-use aquatic_common::{CanonicalSocketAddr, PanicSentinel, ValidUntil};
+use aquatic_common::{PanicSentinel, ValidUntil};
 
 use aquatic_udp_protocol::*;
 
 use crate::common::*;
 use crate::config::Config;
 
-use storage::{TorrentMap, TorrentMaps};
+use storage::TorrentIp;
+
+pub use storage::TorrentMap;
 
 pub fn run_swarm_worker(
     _sentinel: PanicSentinel,
     config: Config,
     state: State,
     server_start_instant: ServerStartInstant,
-    request_receiver: Receiver<(SocketWorkerIndex, ConnectedRequest, CanonicalSocketAddr)>,
+    mut torrents: TorrentMap,
+    request_receiver: Receiver<(SocketWorkerIndex, ConnectedRequest, RequestSource)>,
     response_sender: ConnectedResponseSender,
     statistics_sender: Sender<StatisticsMessage>,
+    swarm_query_receiver: SwarmQueryReceiver,
     worker_index: SwarmWorkerIndex,
 ) {
-    let mut torrents = TorrentMaps::default();
     let mut rng = SmallRng::from_entropy();
+    let mut accounting = TransferAccounting::new(&config.accounting);
 
     let timeout = Duration::from_millis(config.request_channel_recv_timeout_ms);
     let mut peer_valid_until = ValidUntil::new(server_start_instant, config.cleaning.max_peer_age);
 
     let cleaning_interval = Duration::from_secs(config.cleaning.torrent_cleaning_interval);
     let statistics_update_interval = Duration::from_secs(config.statistics.interval);
+    let snapshot_interval = Duration::from_secs(config.snapshot.interval);
+    let full_scrape_refresh_interval = Duration::from_secs(config.full_scrape.refresh_interval);
+    let accounting_flush_interval = Duration::from_secs(config.accounting.flush_interval);
 
     let mut last_cleaning = Instant::now();
     let mut last_statistics_update = Instant::now();
+    let mut last_snapshot = Instant::now();
+    let mut last_full_scrape_refresh: Option<Instant> = None;
+    let mut last_accounting_flush = Instant::now();
+    let mut shutdown_snapshot_saved = false;
 
     let mut iter_counter = 0usize;
 
     loop {
         if let Ok((sender_index, request, src)) = request_receiver.recv_timeout(timeout) {
-            let response = match (request, src.get().ip()) {
+            let response = match (request, src.addr.get().ip()) {
                 (ConnectedRequest::Announce(request), IpAddr::V4(ip)) => {
+                    let mut ctx = AnnounceContext {
+                        config: &config,
+                        rng: &mut rng,
+                        statistics_sender: &statistics_sender,
+                        accounting: &mut accounting,
+                        peer_valid_until,
+                    };
+
                     let response = handle_announce_request(
-                        &config,
-                        &mut rng,
-                        &statistics_sender,
-                        &mut torrents.ipv4,
+                        &mut ctx,
+                        &state.statistics_ipv4,
+                        &mut torrents,
                         request,
                         ip,
-                        peer_valid_until,
                     );
 
                     ConnectedResponse::AnnounceIpv4(response)
                 }
                 (ConnectedRequest::Announce(request), IpAddr::V6(ip)) => {
+                    let mut ctx = AnnounceContext {
+                        config: &config,
+                        rng: &mut rng,
+                        statistics_sender: &statistics_sender,
+                        accounting: &mut accounting,
+                        peer_valid_until,
+                    };
+
                     let response = handle_announce_request(
-                        &config,
-                        &mut rng,
-                        &statistics_sender,
-                        &mut torrents.ipv6,
+                        &mut ctx,
+                        &state.statistics_ipv6,
+                        &mut torrents,
                         request,
                         ip,
-                        peer_valid_until,
                     );
 
                     ConnectedResponse::AnnounceIpv6(response)
                 }
-                (ConnectedRequest::Scrape(request), IpAddr::V4(_)) => {
-                    ConnectedResponse::Scrape(handle_scrape_request(&mut torrents.ipv4, request))
-                }
-                (ConnectedRequest::Scrape(request), IpAddr::V6(_)) => {
-                    ConnectedResponse::Scrape(handle_scrape_request(&mut torrents.ipv6, request))
-                }
+                (ConnectedRequest::Scrape(request), IpAddr::V4(_)) => ConnectedResponse::Scrape(
+                    handle_scrape_request::<Ipv4Addr>(&config, &torrents, request),
+                ),
+                (ConnectedRequest::Scrape(request), IpAddr::V6(_)) => ConnectedResponse::Scrape(
+                    handle_scrape_request::<Ipv6Addr>(&config, &torrents, request),
+                ),
             };
 
             response_sender.try_send_to(sender_index, response, src);
         }
 
+        if let Ok(query_request) = swarm_query_receiver.try_recv() {
+            let summaries = torrents.handle_swarm_query(query_request.query);
+
+            query_request.respond(summaries);
+        }
+
+        if (config.snapshot.enabled || config.accounting.enabled)
+            && !shutdown_snapshot_saved
+            && state.shutdown_snapshot_signal.requested()
+        {
+            if config.snapshot.enabled {
+                torrents.save_snapshot(&config, server_start_instant, worker_index);
+            }
+
+            accounting.flush(&config.accounting);
+            state.shutdown_snapshot_signal.mark_done();
+
+            shutdown_snapshot_saved = true;
+        }
+
         // Run periodic tasks
         if iter_counter % 128 == 0 {
             let now = Instant::now();
@@ -94,7 +139,7 @@ pub fn run_swarm_worker(
                     &config,
                     &state,
                     &statistics_sender,
-                    &state.access_list,
+                    &mut accounting,
                     server_start_instant,
                     worker_index,
                 );
@@ -104,28 +149,70 @@ pub fn run_swarm_worker(
             if config.statistics.active()
                 && now > last_statistics_update + statistics_update_interval
             {
+                let (num_torrents_ipv4, num_torrents_ipv6) = torrents.num_torrents();
+
                 state.statistics_ipv4.torrents[worker_index.0]
-                    .store(torrents.ipv4.num_torrents(), Ordering::Release);
+                    .store(num_torrents_ipv4, Ordering::Release);
                 state.statistics_ipv6.torrents[worker_index.0]
-                    .store(torrents.ipv6.num_torrents(), Ordering::Release);
+                    .store(num_torrents_ipv6, Ordering::Release);
 
                 last_statistics_update = now;
             }
+            if config.snapshot.enabled
+                && config.snapshot.interval != 0
+                && now > last_snapshot + snapshot_interval
+            {
+                torrents.save_snapshot(&config, server_start_instant, worker_index);
+
+                last_snapshot = now;
+            }
+            if config.full_scrape.enabled
+                && last_full_scrape_refresh
+                    .map(|last| now > last + full_scrape_refresh_interval)
+                    .unwrap_or(true)
+            {
+                state.full_scrape.update(
+                    worker_index.0,
+                    torrents.full_scrape_data(&config),
+                    FullScrapeData::merge,
+                );
+
+                last_full_scrape_refresh = Some(now);
+            }
+            if config.accounting.enabled && now > last_accounting_flush + accounting_flush_interval
+            {
+                accounting.flush(&config.accounting);
+
+                last_accounting_flush = now;
+            }
         }
 
         iter_counter = iter_counter.wrapping_add(1);
     }
 }
 
-fn handle_announce_request<I: Ip>(
-    config: &Config,
-    rng: &mut SmallRng,
-    statistics_sender: &Sender<StatisticsMessage>,
-    torrents: &mut TorrentMap<I>,
+/// Swarm worker state used when handling announce requests
+struct AnnounceContext<'a> {
+    config: &'a Config,
+    rng: &'a mut SmallRng,
+    statistics_sender: &'a Sender<StatisticsMessage>,
+    accounting: &'a mut TransferAccounting<AccountId, (InfoHash, PeerId)>,
+    peer_valid_until: ValidUntil,
+}
+
+/// Handle announce request
+///
+/// If `protocol.dual_stack_swarms` is set, peers of the other IP version are
+/// included in seeder and leecher counts, but not in response peers.
+fn handle_announce_request<I: TorrentIp>(
+    ctx: &mut AnnounceContext,
+    statistics: &Statistics,
+    torrents: &mut TorrentMap,
     request: AnnounceRequest,
     peer_ip: I,
-    peer_valid_until: ValidUntil,
 ) -> AnnounceResponse<I> {
+    let config = ctx.config;
+
     let max_num_peers_to_take: usize = if request.peers_wanted.0 <= 0 {
         config.protocol.max_response_peers
     } else {
@@ -139,48 +226,48 @@ fn handle_announce_request<I: Ip>(
 
     let peer_status = PeerStatus::from_event_and_bytes_left(request.event, request.bytes_left);
 
-    torrent_data.update_peer(
-        config,
-        statistics_sender,
-        request.peer_id,
-        peer_ip,
-        request.port,
-        peer_status,
-        peer_valid_until,
-    );
+    let completed = torrent_data.update_peer(ctx, &request, peer_ip, peer_status);
+
+    if completed && config.statistics.active() {
+        statistics
+            .completed_downloads
+            .fetch_add(1, Ordering::Relaxed);
+    }
 
     let response_peers = if let PeerStatus::Stopped = peer_status {
         Vec::new()
     } else {
-        torrent_data.extract_response_peers(rng, request.peer_id, max_num_peers_to_take)
+        torrent_data.extract_response_peers(ctx.rng, request.peer_id, max_num_peers_to_take)
     };
 
+    let counts = torrent_data.counts::<I>(config.protocol.dual_stack_swarms);
+
     AnnounceResponse {
         transaction_id: request.transaction_id,
         announce_interval: AnnounceInterval(config.protocol.peer_announce_interval),
-        leechers: NumberOfPeers(torrent_data.num_leechers().try_into().unwrap_or(i32::MAX)),
-        seeders: NumberOfPeers(torrent_data.num_seeders().try_into().unwrap_or(i32::MAX)),
+        leechers: NumberOfPeers(counts.num_leechers.try_into().unwrap_or(i32::MAX)),
+        seeders: NumberOfPeers(counts.num_seeders.try_into().unwrap_or(i32::MAX)),
         peers: response_peers,
     }
 }
 
-fn handle_scrape_request<I: Ip>(
-    torrents: &mut TorrentMap<I>,
+/// Handle scrape request from peer of IP version `I`
+///
+/// If `protocol.dual_stack_swarms` is set, statistics of both IP versions
+/// are combined.
+fn handle_scrape_request<I: TorrentIp>(
+    config: &Config,
+    torrents: &TorrentMap,
     request: PendingScrapeRequest,
 ) -> PendingScrapeResponse {
-    const EMPTY_STATS: TorrentScrapeStatistics = create_torrent_scrape_statistics(0, 0);
-
     let torrent_stats = request
         .info_hashes
         .into_iter()
         .map(|(i, info_hash)| {
-            let stats = torrents
-                .0
-                .get(&info_hash)
-                .map(|torrent_data| torrent_data.scrape_statistics())
-                .unwrap_or(EMPTY_STATS);
-
-            (i, stats)
+            (
+                i,
+                torrents.scrape_statistics::<I>(config.protocol.dual_stack_swarms, info_hash),
+            )
         })
         .collect();
 
@@ -191,10 +278,14 @@ fn handle_scrape_request<I: Ip>(
 }
 
 #[inline(always)]
-const fn create_torrent_scrape_statistics(seeders: i32, leechers: i32) -> TorrentScrapeStatistics {
+const fn create_torrent_scrape_statistics(
+    seeders: i32,
+    completed: i32,
+    leechers: i32,
+) -> TorrentScrapeStatistics {
     TorrentScrapeStatistics {
         seeders: NumberOfPeers(seeders),
-        completed: NumberOfDownloads(0), // No implementation planned
+        completed: NumberOfDownloads(completed),
         leechers: NumberOfPeers(leechers),
     }
 }
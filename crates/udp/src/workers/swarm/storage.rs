@@ -1,13 +1,18 @@
 use std::net::Ipv4Addr;
 use std::net::Ipv6Addr;
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
 
+use aquatic_common::accounting::{Transfer, TransferAccounting};
+use aquatic_common::admin::{top_torrents, SwarmQuery, TorrentSummary};
+use aquatic_common::snapshot::{
+    load_snapshot, save_snapshot, SnapshotDecoder, SnapshotEncoder, SnapshotHeader, SnapshotIp,
+    SnapshotProtocol,
+};
 use aquatic_common::IndexMap;
 use aquatic_common::SecondsSinceServerStart;
 use aquatic_common::ServerStartInstant;
 use aquatic_common::{
-    access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache, AccessListMode},
+    access_list::{create_access_list_cache, AccessListCache, AccessListMode},
     extract_response_peers, ValidUntil,
 };
 
@@ -19,7 +24,51 @@ use rand::prelude::SmallRng;
 use crate::common::*;
 use crate::config::Config;
 
-use super::create_torrent_scrape_statistics;
+use super::{create_torrent_scrape_statistics, AnnounceContext};
+
+/// Bump when changing snapshot payload encoding
+const SNAPSHOT_FORMAT_VERSION: u16 = 5;
+
+/// Access to the peers of one IP version in torrent data
+pub trait TorrentIp: Ip + SnapshotIp {
+    type Other: Ip + SnapshotIp;
+
+    fn swarm(torrent: &TorrentData) -> &IpSwarm<Self>;
+
+    /// Return swarm of this IP version, swarm of the other IP version and
+    /// dual-stack counts
+    fn split_mut(
+        torrent: &mut TorrentData,
+    ) -> (&mut IpSwarm<Self>, &IpSwarm<Self::Other>, &mut SwarmCounts);
+}
+
+impl TorrentIp for Ipv4Addr {
+    type Other = Ipv6Addr;
+
+    fn swarm(torrent: &TorrentData) -> &IpSwarm<Self> {
+        &torrent.ipv4
+    }
+
+    fn split_mut(
+        torrent: &mut TorrentData,
+    ) -> (&mut IpSwarm<Self>, &IpSwarm<Self::Other>, &mut SwarmCounts) {
+        (&mut torrent.ipv4, &torrent.ipv6, &mut torrent.dual_stack)
+    }
+}
+
+impl TorrentIp for Ipv6Addr {
+    type Other = Ipv4Addr;
+
+    fn swarm(torrent: &TorrentData) -> &IpSwarm<Self> {
+        &torrent.ipv6
+    }
+
+    fn split_mut(
+        torrent: &mut TorrentData,
+    ) -> (&mut IpSwarm<Self>, &IpSwarm<Self::Other>, &mut SwarmCounts) {
+        (&mut torrent.ipv6, &torrent.ipv4, &mut torrent.dual_stack)
+    }
+}
 
 #[derive(Clone, Debug)]
 struct Peer<I: Ip> {
@@ -27,6 +76,10 @@ struct Peer<I: Ip> {
     port: Port,
     is_seeder: bool,
     valid_until: ValidUntil,
+    /// Transfer counts from latest announce request
+    transfer: Transfer,
+    /// Account from latest announce request
+    account: AccountId,
 }
 
 impl<I: Ip> Peer<I> {
@@ -36,50 +89,302 @@ impl<I: Ip> Peer<I> {
             port: peer.port,
         }
     }
+
+    fn status(&self) -> PeerStatus {
+        if self.is_seeder {
+            PeerStatus::Seeding
+        } else {
+            PeerStatus::Leeching
+        }
+    }
 }
 
 type PeerMap<I> = IndexMap<PeerId, Peer<I>>;
 
-pub struct TorrentData<I: Ip> {
+/// Status of peer in swarms of both IP versions combined, where peers
+/// announcing with the same peer id over both are counted once, as seeders
+/// if they are seeding over either
+fn dual_stack_peer_status(status: PeerStatus, other_status: PeerStatus) -> PeerStatus {
+    match (status, other_status) {
+        (PeerStatus::Seeding, _) | (_, PeerStatus::Seeding) => PeerStatus::Seeding,
+        (PeerStatus::Leeching, _) | (_, PeerStatus::Leeching) => PeerStatus::Leeching,
+        (PeerStatus::Stopped, PeerStatus::Stopped) => PeerStatus::Stopped,
+    }
+}
+
+/// Seeder, leecher and completed download counts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SwarmCounts {
+    pub num_seeders: usize,
+    pub num_leechers: usize,
+    pub num_completed: usize,
+}
+
+impl SwarmCounts {
+    fn add_peer(&mut self, status: PeerStatus) {
+        match status {
+            PeerStatus::Seeding => self.num_seeders += 1,
+            PeerStatus::Leeching => self.num_leechers += 1,
+            PeerStatus::Stopped => (),
+        }
+    }
+
+    fn remove_peer(&mut self, status: PeerStatus) {
+        match status {
+            PeerStatus::Seeding => self.num_seeders -= 1,
+            PeerStatus::Leeching => self.num_leechers -= 1,
+            PeerStatus::Stopped => (),
+        }
+    }
+
+    /// Update counts after status of peer changed in swarm of one IP
+    /// version, given its status in swarm of the other one
+    fn update_dual_stack_peer(
+        &mut self,
+        previous_status: PeerStatus,
+        status: PeerStatus,
+        other_status: PeerStatus,
+    ) {
+        self.remove_peer(dual_stack_peer_status(previous_status, other_status));
+        self.add_peer(dual_stack_peer_status(status, other_status));
+    }
+
+    fn summary(self, info_hash: InfoHash) -> TorrentSummary {
+        TorrentSummary {
+            info_hash: info_hash.0,
+            seeders: self.num_seeders,
+            leechers: self.num_leechers,
+            completed: self.num_completed,
+        }
+    }
+
+    fn add_to_full_scrape_statistics(self, statistics: &mut FullScrapeStatistics) {
+        statistics.seeders += self.num_seeders;
+        statistics.completed += self.num_completed;
+        statistics.leechers += self.num_leechers;
+    }
+}
+
+/// Peers of one IP version in a torrent
+pub struct IpSwarm<I: Ip> {
     peers: PeerMap<I>,
     num_seeders: usize,
+    num_completed: usize,
 }
 
-impl<I: Ip> TorrentData<I> {
-    pub fn update_peer(
+impl<I: Ip> Default for IpSwarm<I> {
+    fn default() -> Self {
+        Self {
+            peers: Default::default(),
+            num_seeders: 0,
+            num_completed: 0,
+        }
+    }
+}
+
+impl<I: Ip> IpSwarm<I> {
+    fn num_leechers(&self) -> usize {
+        self.peers.len() - self.num_seeders
+    }
+
+    fn counts(&self) -> SwarmCounts {
+        SwarmCounts {
+            num_seeders: self.num_seeders,
+            num_leechers: self.num_leechers(),
+            num_completed: self.num_completed,
+        }
+    }
+
+    /// Returns true if swarm has neither peers nor completed downloads
+    fn is_empty(&self) -> bool {
+        self.peers.is_empty() && self.num_completed == 0
+    }
+
+    fn peer_status(&self, peer_id: &PeerId) -> PeerStatus {
+        self.peers
+            .get(peer_id)
+            .map(Peer::status)
+            .unwrap_or(PeerStatus::Stopped)
+    }
+
+    /// Remove inactive peers, update dual-stack counts and reclaim space
+    ///
+    /// Removed peers are reported to `opt_peer_clients_sender` if given.
+    fn clean<J: Ip>(
         &mut self,
-        config: &Config,
-        statistics_sender: &Sender<StatisticsMessage>,
-        peer_id: PeerId,
+        other: &IpSwarm<J>,
+        dual_stack: &mut SwarmCounts,
+        opt_peer_clients_sender: Option<&Sender<StatisticsMessage>>,
+        accounting: &mut TransferAccounting<AccountId, (InfoHash, PeerId)>,
+        info_hash: InfoHash,
+        now: SecondsSinceServerStart,
+    ) {
+        self.peers.retain(|peer_id, peer| {
+            let keep = peer.valid_until.valid(now);
+
+            if !keep {
+                if peer.is_seeder {
+                    self.num_seeders -= 1;
+                }
+
+                dual_stack.update_dual_stack_peer(
+                    peer.status(),
+                    PeerStatus::Stopped,
+                    other.peer_status(peer_id),
+                );
+
+                accounting.record_expired_peer(
+                    peer.account,
+                    (info_hash, *peer_id),
+                    peer.transfer,
+                    now,
+                );
+
+                if let Some(statistics_sender) = opt_peer_clients_sender {
+                    if let Err(_) =
+                        statistics_sender.try_send(StatisticsMessage::PeerRemoved(*peer_id))
+                    {
+                        // Should never happen in practice
+                        ::log::error!("Couldn't send StatisticsMessage::PeerRemoved");
+                    }
+                }
+            }
+
+            keep
+        });
+
+        // Also shrink if empty, since torrent might be kept for its completed count
+        self.peers.shrink_to_fit();
+    }
+}
+
+impl<I: Ip + SnapshotIp> IpSwarm<I> {
+    fn write_peers_snapshot(
+        &self,
+        encoder: &mut SnapshotEncoder,
+        server_start_instant: ServerStartInstant,
+    ) {
+        encoder.write_len(self.peers.len());
+
+        for (peer_id, peer) in self.peers.iter() {
+            encoder.write_bytes(&peer_id.0);
+            encoder.write_ip(peer.ip_address);
+            encoder.write_u16(peer.port.0);
+            encoder.write_bool(peer.is_seeder);
+            encoder.write_valid_until(peer.valid_until, server_start_instant);
+            encoder.write_u64(peer.transfer.uploaded);
+            encoder.write_u64(peer.transfer.downloaded);
+            encoder.write_u32(peer.account.key);
+        }
+    }
+
+    fn read_peers_snapshot(
+        &mut self,
+        decoder: &mut SnapshotDecoder,
+        server_start_instant: ServerStartInstant,
+    ) -> anyhow::Result<()> {
+        let num_peers = decoder.read_len(20)?;
+
+        self.peers.reserve(num_peers);
+
+        for _ in 0..num_peers {
+            let peer_id = PeerId(decoder.read_array()?);
+            let peer = Peer {
+                ip_address: decoder.read_ip()?,
+                port: Port(decoder.read_u16()?),
+                is_seeder: decoder.read_bool()?,
+                valid_until: decoder.read_valid_until(server_start_instant)?,
+                transfer: Transfer::new(decoder.read_u64()?, decoder.read_u64()?),
+                account: AccountId {
+                    key: decoder.read_u32()?,
+                },
+            };
+
+            if peer.is_seeder {
+                self.num_seeders += 1;
+            }
+
+            if self.peers.insert(peer_id, peer).is_some() {
+                return Err(anyhow::anyhow!("duplicate peer id in snapshot"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct TorrentData {
+    ipv4: IpSwarm<Ipv4Addr>,
+    ipv6: IpSwarm<Ipv6Addr>,
+    /// Counts of both IP versions combined (see [`dual_stack_peer_status`])
+    dual_stack: SwarmCounts,
+    /// Set when torrent has no peers left but is kept to preserve its
+    /// completed count
+    idle_valid_until: Option<ValidUntil>,
+}
+
+impl TorrentData {
+    /// Insert, update or remove peer. Returns true if announce was counted
+    /// as a completed download.
+    ///
+    /// Completed downloads are counted both for the IP version of the peer
+    /// and for both IP versions combined. In the latter case, they are not
+    /// counted if the peer is already seeding over the other IP version. The
+    /// return value is based on the combined count if
+    /// `protocol.dual_stack_swarms` is set.
+    pub(super) fn update_peer<I: TorrentIp>(
+        &mut self,
+        ctx: &mut AnnounceContext,
+        request: &AnnounceRequest,
         ip_address: I,
-        port: Port,
         status: PeerStatus,
-        valid_until: ValidUntil,
-    ) {
+    ) -> bool {
+        let AnnounceContext {
+            config,
+            statistics_sender,
+            accounting,
+            peer_valid_until: valid_until,
+            ..
+        } = ctx;
+        let valid_until = *valid_until;
+        let peer_id = request.peer_id;
+        let transfer = Transfer::new(
+            request.bytes_uploaded.0.max(0) as u64,
+            request.bytes_downloaded.0.max(0) as u64,
+        );
+        let account = AccountId { key: request.key.0 };
+
+        let (swarm, other_swarm, dual_stack) = I::split_mut(self);
+
         let opt_removed_peer = match status {
             PeerStatus::Leeching => {
                 let peer = Peer {
                     ip_address,
-                    port,
+                    port: request.port,
                     is_seeder: false,
                     valid_until,
+                    transfer,
+                    account,
                 };
 
-                self.peers.insert(peer_id, peer)
+                swarm.peers.insert(peer_id, peer)
             }
             PeerStatus::Seeding => {
                 let peer = Peer {
                     ip_address,
-                    port,
+                    port: request.port,
                     is_seeder: true,
                     valid_until,
+                    transfer,
+                    account,
                 };
 
-                self.num_seeders += 1;
+                swarm.num_seeders += 1;
 
-                self.peers.insert(peer_id, peer)
+                swarm.peers.insert(peer_id, peer)
             }
-            PeerStatus::Stopped => self.peers.remove(&peer_id),
+            PeerStatus::Stopped => swarm.peers.remove(&peer_id),
         };
 
         if config.statistics.peer_clients {
@@ -106,15 +411,51 @@ impl<I: Ip> TorrentData<I> {
             }
         }
 
-        if let Some(Peer {
-            is_seeder: true, ..
-        }) = opt_removed_peer
-        {
-            self.num_seeders -= 1;
+        let previous_status = opt_removed_peer
+            .as_ref()
+            .map(Peer::status)
+            .unwrap_or(PeerStatus::Stopped);
+        let previously_seeding = previous_status == PeerStatus::Seeding;
+        let other_status = other_swarm.peer_status(&peer_id);
+
+        if previously_seeding {
+            swarm.num_seeders -= 1;
+        }
+
+        dual_stack.update_dual_stack_peer(previous_status, status, other_status);
+
+        // Stopped peers are accounted for here, since they were just removed
+        if accounting.is_enabled() {
+            let started = request.event == AnnounceEvent::Started;
+            let previous_transfer = match opt_removed_peer.as_ref() {
+                Some(peer) => Some(peer.transfer),
+                None => accounting
+                    .take_expired_peer_transfer(account, (request.info_hash, peer_id))
+                    .filter(|_| !started),
+            };
+
+            accounting.record(account, transfer.delta(previous_transfer, started));
+        }
+
+        // Ignore repeated completed events from seeders
+        let completed = request.event == AnnounceEvent::Completed && !previously_seeding;
+        let dual_stack_completed = completed && other_status != PeerStatus::Seeding;
+
+        if completed {
+            swarm.num_completed += 1;
+        }
+        if dual_stack_completed {
+            dual_stack.num_completed += 1;
+        }
+
+        if config.protocol.dual_stack_swarms {
+            dual_stack_completed
+        } else {
+            completed
         }
     }
 
-    pub fn extract_response_peers(
+    pub fn extract_response_peers<I: TorrentIp>(
         &self,
         rng: &mut SmallRng,
         peer_id: PeerId,
@@ -122,26 +463,45 @@ impl<I: Ip> TorrentData<I> {
     ) -> Vec<ResponsePeer<I>> {
         extract_response_peers(
             rng,
-            &self.peers,
+            &I::swarm(self).peers,
             max_num_peers_to_take,
             peer_id,
             Peer::to_response_peer,
         )
     }
 
-    pub fn num_leechers(&self) -> usize {
-        self.peers.len() - self.num_seeders
+    /// Counts of IP version `I`, or of both IP versions combined if
+    /// `dual_stack` is true
+    pub fn counts<I: TorrentIp>(&self, dual_stack: bool) -> SwarmCounts {
+        if dual_stack {
+            self.dual_stack
+        } else {
+            I::swarm(self).counts()
+        }
     }
 
-    pub fn num_seeders(&self) -> usize {
-        self.num_seeders
-    }
+    /// Returns true if torrent should be kept after its peers were cleaned
+    /// (see `cleaning.max_torrent_idle_age`)
+    fn keep_after_cleaning(&mut self, config: &Config, now: SecondsSinceServerStart) -> bool {
+        if !(self.ipv4.peers.is_empty() && self.ipv6.peers.is_empty()) {
+            self.idle_valid_until = None;
 
-    pub fn scrape_statistics(&self) -> TorrentScrapeStatistics {
-        create_torrent_scrape_statistics(
-            self.num_seeders.try_into().unwrap_or(i32::MAX),
-            self.num_leechers().try_into().unwrap_or(i32::MAX),
-        )
+            true
+        } else if self.ipv4.num_completed == 0
+            && self.ipv6.num_completed == 0
+            && self.dual_stack.num_completed == 0
+        {
+            false
+        } else if let Some(valid_until) = self.idle_valid_until {
+            valid_until.valid(now)
+        } else {
+            self.idle_valid_until = Some(ValidUntil::new_with_now(
+                now,
+                config.cleaning.max_torrent_idle_age,
+            ));
+
+            true
+        }
     }
 
     /// Remove inactive peers and reclaim space
@@ -149,147 +509,284 @@ impl<I: Ip> TorrentData<I> {
         &mut self,
         config: &Config,
         statistics_sender: &Sender<StatisticsMessage>,
+        accounting: &mut TransferAccounting<AccountId, (InfoHash, PeerId)>,
+        info_hash: InfoHash,
         now: SecondsSinceServerStart,
     ) {
-        self.peers.retain(|peer_id, peer| {
-            let keep = peer.valid_until.valid(now);
-
-            if !keep {
-                if peer.is_seeder {
-                    self.num_seeders -= 1;
-                }
-                if config.statistics.peer_clients {
-                    if let Err(_) =
-                        statistics_sender.try_send(StatisticsMessage::PeerRemoved(*peer_id))
-                    {
-                        // Should never happen in practice
-                        ::log::error!("Couldn't send StatisticsMessage::PeerRemoved");
-                    }
-                }
-            }
+        let Self {
+            ipv4,
+            ipv6,
+            dual_stack,
+            ..
+        } = self;
+
+        let opt_peer_clients_sender = config.statistics.peer_clients.then_some(statistics_sender);
+
+        ipv4.clean(
+            ipv6,
+            dual_stack,
+            opt_peer_clients_sender,
+            accounting,
+            info_hash,
+            now,
+        );
+        ipv6.clean(
+            ipv4,
+            dual_stack,
+            opt_peer_clients_sender,
+            accounting,
+            info_hash,
+            now,
+        );
+    }
 
-            keep
-        });
+    fn write_snapshot(
+        &self,
+        encoder: &mut SnapshotEncoder,
+        server_start_instant: ServerStartInstant,
+    ) {
+        encoder.write_u64(self.ipv4.num_completed as u64);
+        encoder.write_u64(self.ipv6.num_completed as u64);
+        encoder.write_u64(self.dual_stack.num_completed as u64);
+        encoder.write_bool(self.idle_valid_until.is_some());
 
-        if !self.peers.is_empty() {
-            self.peers.shrink_to_fit();
+        if let Some(valid_until) = self.idle_valid_until {
+            encoder.write_valid_until(valid_until, server_start_instant);
         }
+
+        self.ipv4
+            .write_peers_snapshot(encoder, server_start_instant);
+        self.ipv6
+            .write_peers_snapshot(encoder, server_start_instant);
     }
-}
 
-impl<I: Ip> Default for TorrentData<I> {
-    fn default() -> Self {
-        Self {
-            peers: Default::default(),
-            num_seeders: 0,
+    fn read_snapshot(
+        decoder: &mut SnapshotDecoder,
+        server_start_instant: ServerStartInstant,
+    ) -> anyhow::Result<Self> {
+        let mut torrent_data = Self::default();
+
+        torrent_data.ipv4.num_completed = decoder.read_u64()? as usize;
+        torrent_data.ipv6.num_completed = decoder.read_u64()? as usize;
+        torrent_data.dual_stack.num_completed = decoder.read_u64()? as usize;
+        torrent_data.idle_valid_until = if decoder.read_bool()? {
+            Some(decoder.read_valid_until(server_start_instant)?)
+        } else {
+            None
+        };
+
+        torrent_data
+            .ipv4
+            .read_peers_snapshot(decoder, server_start_instant)?;
+        torrent_data
+            .ipv6
+            .read_peers_snapshot(decoder, server_start_instant)?;
+
+        for (peer_id, peer) in torrent_data.ipv4.peers.iter() {
+            let status =
+                dual_stack_peer_status(peer.status(), torrent_data.ipv6.peer_status(peer_id));
+
+            torrent_data.dual_stack.add_peer(status);
+        }
+        for (peer_id, peer) in torrent_data.ipv6.peers.iter() {
+            if !torrent_data.ipv4.peers.contains_key(peer_id) {
+                torrent_data.dual_stack.add_peer(peer.status());
+            }
         }
+
+        Ok(torrent_data)
     }
 }
 
 #[derive(Default)]
-pub struct TorrentMap<I: Ip>(pub IndexMap<InfoHash, TorrentData<I>>);
+pub struct TorrentMap(pub IndexMap<InfoHash, TorrentData>);
 
-impl<I: Ip> TorrentMap<I> {
-    /// Remove forbidden or inactive torrents, reclaim space and return number of remaining peers
-    fn clean_and_get_statistics(
-        &mut self,
+impl TorrentMap {
+    /// Load torrents from snapshot file, falling back to empty map
+    pub fn load_snapshot(
         config: &Config,
         statistics_sender: &Sender<StatisticsMessage>,
-        access_list_cache: &mut AccessListCache,
-        access_list_mode: AccessListMode,
-        now: SecondsSinceServerStart,
-    ) -> (usize, Option<Histogram<u64>>) {
-        let mut num_peers = 0;
+        server_start_instant: ServerStartInstant,
+        worker_index: SwarmWorkerIndex,
+    ) -> Self {
+        let header = Self::snapshot_header(config, worker_index);
 
-        let mut opt_histogram: Option<Histogram<u64>> = if config.statistics.torrent_peer_histograms
-        {
-            match Histogram::new(3) {
-                Ok(histogram) => Some(histogram),
-                Err(err) => {
-                    ::log::error!("Couldn't create peer histogram: {:#}", err);
+        let opt_torrents = load_snapshot(&config.snapshot, header, |decoder| {
+            let num_torrents = decoder.read_len(20)?;
 
-                    None
-                }
-            }
-        } else {
-            None
-        };
+            let mut torrent_map =
+                IndexMap::with_capacity_and_hasher(num_torrents, Default::default());
 
-        self.0.retain(|info_hash, torrent| {
-            if !access_list_cache
-                .load()
-                .allows(access_list_mode, &info_hash.0)
-            {
-                return false;
-            }
+            for _ in 0..num_torrents {
+                let info_hash = InfoHash(decoder.read_array()?);
+                let torrent_data = TorrentData::read_snapshot(decoder, server_start_instant)?;
 
-            torrent.clean(config, statistics_sender, now);
+                torrent_map.insert(info_hash, torrent_data);
+            }
 
-            num_peers += torrent.peers.len();
+            Ok(Self(torrent_map))
+        });
 
-            match opt_histogram {
-                Some(ref mut histogram) if torrent.peers.len() != 0 => {
-                    let n = torrent
-                        .peers
-                        .len()
-                        .try_into()
-                        .expect("Couldn't fit usize into u64");
+        let torrents = opt_torrents.unwrap_or_default();
 
-                    if let Err(err) = histogram.record(n) {
-                        ::log::error!("Couldn't record {} to histogram: {:#}", n, err);
-                    }
+        if config.statistics.peer_clients {
+            let peer_ids = torrents.0.values().flat_map(|torrent_data| {
+                torrent_data
+                    .ipv4
+                    .peers
+                    .keys()
+                    .chain(torrent_data.ipv6.peers.keys())
+            });
+
+            for peer_id in peer_ids {
+                if statistics_sender
+                    .try_send(StatisticsMessage::PeerAdded(*peer_id))
+                    .is_err()
+                {
+                    // Should never happen in practice
+                    ::log::error!("Couldn't send StatisticsMessage::PeerAdded");
                 }
-                _ => (),
             }
+        }
 
-            !torrent.peers.is_empty()
+        torrents
+    }
+
+    pub fn save_snapshot(
+        &self,
+        config: &Config,
+        server_start_instant: ServerStartInstant,
+        worker_index: SwarmWorkerIndex,
+    ) {
+        let header = Self::snapshot_header(config, worker_index);
+
+        save_snapshot(&config.snapshot, header, |encoder| {
+            encoder.write_len(self.0.len());
+
+            for (info_hash, torrent_data) in self.0.iter() {
+                encoder.write_bytes(&info_hash.0);
+
+                torrent_data.write_snapshot(encoder, server_start_instant);
+            }
         });
+    }
 
-        self.0.shrink_to_fit();
+    fn snapshot_header(config: &Config, worker_index: SwarmWorkerIndex) -> SnapshotHeader {
+        SnapshotHeader::new(
+            SnapshotProtocol::Udp,
+            SNAPSHOT_FORMAT_VERSION,
+            worker_index.0,
+            config.swarm_workers,
+        )
+    }
+
+    /// Number of torrents with peers or completed downloads of each IP
+    /// version
+    pub fn num_torrents(&self) -> (usize, usize) {
+        let mut num_ipv4 = 0;
+        let mut num_ipv6 = 0;
 
-        (num_peers, opt_histogram)
+        for torrent_data in self.0.values() {
+            if !torrent_data.ipv4.is_empty() {
+                num_ipv4 += 1;
+            }
+            if !torrent_data.ipv6.is_empty() {
+                num_ipv6 += 1;
+            }
+        }
+
+        (num_ipv4, num_ipv6)
     }
 
-    pub fn num_torrents(&self) -> usize {
-        self.0.len()
+    /// Scrape statistics of torrent for IP version `I`, or for both IP
+    /// versions combined if `dual_stack` is true
+    pub fn scrape_statistics<I: TorrentIp>(
+        &self,
+        dual_stack: bool,
+        info_hash: InfoHash,
+    ) -> TorrentScrapeStatistics {
+        match self.0.get(&info_hash) {
+            Some(torrent_data) => {
+                let counts = torrent_data.counts::<I>(dual_stack);
+
+                create_torrent_scrape_statistics(
+                    counts.num_seeders.try_into().unwrap_or(i32::MAX),
+                    counts.num_completed.try_into().unwrap_or(i32::MAX),
+                    counts.num_leechers.try_into().unwrap_or(i32::MAX),
+                )
+            }
+            None => create_torrent_scrape_statistics(0, 0, 0),
+        }
     }
-}
 
-pub struct TorrentMaps {
-    pub ipv4: TorrentMap<Ipv4Addr>,
-    pub ipv6: TorrentMap<Ipv6Addr>,
-}
+    pub fn full_scrape_data(&self, config: &Config) -> FullScrapeData {
+        let mut data = FullScrapeData::default();
+
+        for torrent_data in self.0.values() {
+            torrent_data
+                .ipv4
+                .counts()
+                .add_to_full_scrape_statistics(&mut data.ipv4);
+            torrent_data
+                .ipv6
+                .counts()
+                .add_to_full_scrape_statistics(&mut data.ipv6);
+
+            if config.protocol.dual_stack_swarms {
+                torrent_data
+                    .dual_stack
+                    .add_to_full_scrape_statistics(&mut data.dual_stack);
+            }
+        }
 
-impl Default for TorrentMaps {
-    fn default() -> Self {
-        Self {
-            ipv4: TorrentMap(Default::default()),
-            ipv6: TorrentMap(Default::default()),
+        data
+    }
+
+    /// Summaries have dual-stack peers counted once
+    pub fn handle_swarm_query(&self, query: SwarmQuery) -> Vec<TorrentSummary> {
+        match query {
+            SwarmQuery::Torrent(info_hash) => {
+                let info_hash = InfoHash(info_hash);
+
+                self.0
+                    .get(&info_hash)
+                    .map(|torrent_data| torrent_data.dual_stack.summary(info_hash))
+                    .into_iter()
+                    .collect()
+            }
+            SwarmQuery::TopTorrents(limit) => top_torrents(
+                self.0
+                    .iter()
+                    .map(|(info_hash, torrent_data)| torrent_data.dual_stack.summary(*info_hash)),
+                limit,
+            ),
         }
     }
-}
 
-impl TorrentMaps {
     /// Remove forbidden or inactive torrents, reclaim space and update statistics
     pub fn clean_and_update_statistics(
         &mut self,
         config: &Config,
         state: &State,
         statistics_sender: &Sender<StatisticsMessage>,
-        access_list: &Arc<AccessListArcSwap>,
+        accounting: &mut TransferAccounting<AccountId, (InfoHash, PeerId)>,
         server_start_instant: ServerStartInstant,
         worker_index: SwarmWorkerIndex,
     ) {
-        let mut cache = create_access_list_cache(access_list);
+        let mut cache = create_access_list_cache(&state.access_list);
         let mode = config.access_list.mode;
         let now = server_start_instant.seconds_elapsed();
 
-        let ipv4 =
-            self.ipv4
-                .clean_and_get_statistics(config, statistics_sender, &mut cache, mode, now);
-        let ipv6 =
-            self.ipv6
-                .clean_and_get_statistics(config, statistics_sender, &mut cache, mode, now);
+        let (ipv4, ipv6) = self.clean_and_get_statistics(
+            config,
+            statistics_sender,
+            accounting,
+            &mut cache,
+            mode,
+            now,
+        );
+
+        accounting.clean(now);
 
         if config.statistics.active() {
             state.statistics_ipv4.peers[worker_index.0].store(ipv4.0, Ordering::Release);
@@ -307,6 +804,76 @@ impl TorrentMaps {
             }
         }
     }
+
+    /// Remove forbidden or inactive torrents, reclaim space and return peer
+    /// statistics of each IP version
+    fn clean_and_get_statistics(
+        &mut self,
+        config: &Config,
+        statistics_sender: &Sender<StatisticsMessage>,
+        accounting: &mut TransferAccounting<AccountId, (InfoHash, PeerId)>,
+        access_list_cache: &mut AccessListCache,
+        access_list_mode: AccessListMode,
+        now: SecondsSinceServerStart,
+    ) -> (PeerStatistics, PeerStatistics) {
+        let mut ipv4 = (0, create_peer_histogram(config));
+        let mut ipv6 = (0, create_peer_histogram(config));
+
+        self.0.retain(|info_hash, torrent| {
+            if !access_list_cache
+                .load()
+                .allows(access_list_mode, &info_hash.0)
+            {
+                return false;
+            }
+
+            torrent.clean(config, statistics_sender, accounting, *info_hash, now);
+
+            ipv4.0 += torrent.ipv4.peers.len();
+            ipv6.0 += torrent.ipv6.peers.len();
+
+            record_peer_histogram(&mut ipv4.1, torrent.ipv4.peers.len());
+            record_peer_histogram(&mut ipv6.1, torrent.ipv6.peers.len());
+
+            torrent.keep_after_cleaning(config, now)
+        });
+
+        self.0.shrink_to_fit();
+
+        (ipv4, ipv6)
+    }
+}
+
+/// Number of peers and optional histogram of peers per torrent
+type PeerStatistics = (usize, Option<Histogram<u64>>);
+
+fn create_peer_histogram(config: &Config) -> Option<Histogram<u64>> {
+    if config.statistics.torrent_peer_histograms {
+        match Histogram::new(3) {
+            Ok(histogram) => Some(histogram),
+            Err(err) => {
+                ::log::error!("Couldn't create peer histogram: {:#}", err);
+
+                None
+            }
+        }
+    } else {
+        None
+    }
+}
+
+/// Record number of peers of torrent, unless it is zero
+fn record_peer_histogram(opt_histogram: &mut Option<Histogram<u64>>, num_peers: usize) {
+    match opt_histogram {
+        Some(ref mut histogram) if num_peers != 0 => {
+            let n = num_peers.try_into().expect("Couldn't fit usize into u64");
+
+            if let Err(err) = histogram.record(n) {
+                ::log::error!("Couldn't record {} to histogram: {:#}", n, err);
+            }
+        }
+        _ => (),
+    }
 }
 
 #[cfg(test)]
@@ -315,7 +882,7 @@ mod tests {
     use std::net::Ipv4Addr;
 
     use quickcheck::{quickcheck, TestResult};
-    use rand::thread_rng;
+    use rand::{thread_rng, SeedableRng};
 
     use super::*;
 
@@ -332,9 +899,117 @@ mod tests {
             port: Port(1),
             is_seeder: false,
             valid_until: ValidUntil::new(ServerStartInstant::new(), 0),
+            transfer: Default::default(),
+            account: AccountId { key: 0 },
         }
     }
 
+    #[test]
+    fn test_dual_stack_counts() {
+        let mut config = Config::default();
+
+        config.protocol.dual_stack_swarms = true;
+
+        let (statistics_sender, _statistics_receiver) = crossbeam_channel::unbounded();
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut accounting = TransferAccounting::new(&config.accounting);
+
+        let server_start_instant = ServerStartInstant::new();
+
+        let mut ctx = AnnounceContext {
+            config: &config,
+            rng: &mut rng,
+            statistics_sender: &statistics_sender,
+            accounting: &mut accounting,
+            peer_valid_until: ValidUntil::new(server_start_instant, 60),
+        };
+
+        let info_hash = InfoHash([0; 20]);
+
+        let mut torrent_data = TorrentData::default();
+
+        let mut announce = |peer_id: u32, bytes_left: i64, event: AnnounceEvent, ipv4: bool| {
+            let request = AnnounceRequest {
+                connection_id: ConnectionId(0),
+                transaction_id: TransactionId(0),
+                info_hash,
+                peer_id: gen_peer_id(peer_id),
+                bytes_downloaded: NumberOfBytes(0),
+                bytes_uploaded: NumberOfBytes(0),
+                bytes_left: NumberOfBytes(bytes_left),
+                event,
+                ip_address: None,
+                key: PeerKey(0),
+                peers_wanted: NumberOfPeers(0),
+                port: Port(1),
+                url_data: Vec::new(),
+            };
+            let status = PeerStatus::from_event_and_bytes_left(event, request.bytes_left);
+
+            let completed = if ipv4 {
+                torrent_data.update_peer(&mut ctx, &request, Ipv4Addr::LOCALHOST, status)
+            } else {
+                torrent_data.update_peer(&mut ctx, &request, Ipv6Addr::LOCALHOST, status)
+            };
+
+            (
+                completed,
+                torrent_data.counts::<Ipv4Addr>(false),
+                torrent_data.counts::<Ipv6Addr>(false),
+                torrent_data.counts::<Ipv4Addr>(true),
+            )
+        };
+
+        let counts = |num_seeders, num_leechers, num_completed| SwarmCounts {
+            num_seeders,
+            num_leechers,
+            num_completed,
+        };
+
+        // Dual-stack peer completes over both IP versions
+        assert!(announce(0, 0, AnnounceEvent::Completed, true).0);
+        assert!(!announce(0, 0, AnnounceEvent::Completed, false).0);
+
+        // Dual-stack leecher
+        announce(1, 1, AnnounceEvent::Started, true);
+        announce(1, 1, AnnounceEvent::Started, false);
+
+        // Dual-stack peer that has only announced completion over IPv6
+        announce(2, 1, AnnounceEvent::Started, true);
+        assert!(announce(2, 0, AnnounceEvent::Completed, false).0);
+
+        // Single-stack leecher
+        let (_, ipv4, ipv6, dual_stack) = announce(3, 1, AnnounceEvent::Started, false);
+
+        assert_eq!(ipv4, counts(1, 2, 1));
+        assert_eq!(ipv6, counts(2, 2, 2));
+        assert_eq!(dual_stack, counts(2, 2, 2));
+
+        // Dual-stack leecher stops over one IP version only
+        let (_, _, _, dual_stack) = announce(1, 1, AnnounceEvent::Stopped, true);
+
+        assert_eq!(dual_stack, counts(2, 2, 2));
+
+        let (_, _, _, dual_stack) = announce(1, 1, AnnounceEvent::Stopped, false);
+
+        assert_eq!(dual_stack, counts(2, 1, 2));
+
+        // Peers expiring over one IP version are still counted over the other
+        torrent_data.ipv4.peers[&gen_peer_id(0)].valid_until =
+            ValidUntil::new(server_start_instant, 0);
+
+        torrent_data.clean(
+            &config,
+            &statistics_sender,
+            &mut accounting,
+            info_hash,
+            server_start_instant.seconds_elapsed(),
+        );
+
+        assert_eq!(torrent_data.counts::<Ipv4Addr>(false), counts(0, 1, 1));
+        assert_eq!(torrent_data.counts::<Ipv4Addr>(true), counts(2, 1, 2));
+    }
+
     #[test]
     fn test_extract_response_peers() {
         fn prop(data: (u16, u16)) -> TestResult {
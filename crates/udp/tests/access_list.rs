@@ -59,7 +59,7 @@ fn test_access_list(
 
     let mut config = Config::default();
 
-    config.network.address.set_port(tracker_port);
+    config.network.addresses[0].set_port(tracker_port);
 
     config.access_list.mode = mode;
     config.access_list.path = access_list_path;
@@ -2,7 +2,7 @@
 
 use std::{
     io::Cursor,
-    net::{SocketAddr, UdpSocket},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
     time::Duration,
 };
 
@@ -24,6 +24,24 @@ pub fn run_tracker(config: Config) {
     ::std::thread::sleep(Duration::from_secs(1));
 }
 
+/// Bind socket to localhost address of same IP version as tracker address
+/// and connect to tracker
+pub fn bind_and_connect(tracker_addr: SocketAddr) -> anyhow::Result<(UdpSocket, ConnectionId)> {
+    let ip = if tracker_addr.is_ipv4() {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    } else {
+        IpAddr::V6(Ipv6Addr::LOCALHOST)
+    };
+
+    let socket = UdpSocket::bind(SocketAddr::new(ip, 0))?;
+
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    let connection_id = connect(&socket, tracker_addr).with_context(|| "connect")?;
+
+    Ok((socket, connection_id))
+}
+
 pub fn connect(socket: &UdpSocket, tracker_addr: SocketAddr) -> anyhow::Result<ConnectionId> {
     let request = Request::Connect(ConnectRequest {
         transaction_id: TransactionId(0),
@@ -66,6 +84,7 @@ pub fn announce(
         key: PeerKey(0),
         peers_wanted: NumberOfPeers(peers_wanted as i32),
         port: Port(peer_port),
+        url_data: Vec::new(),
     });
 
     Ok(request_and_response(&socket, tracker_addr, request)?)
@@ -118,6 +137,9 @@ pub fn request_and_response(
             .recv_from(&mut buffer)
             .with_context(|| "recv response")?;
 
-        Ok(Response::from_bytes(&buffer[..bytes_read], true).with_context(|| "parse response")?)
+        Ok(
+            Response::from_bytes(&buffer[..bytes_read], tracker_addr.is_ipv4())
+                .with_context(|| "parse response")?,
+        )
     }
 }
@@ -0,0 +1,98 @@
+mod common;
+
+use common::*;
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::Context;
+use aquatic_udp::config::Config;
+use aquatic_udp_protocol::{InfoHash, Response};
+
+#[test]
+fn test_dual_stack_swarms() -> anyhow::Result<()> {
+    const TRACKER_PORT: u16 = 40_115;
+
+    let tracker_addr_ipv4 = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), TRACKER_PORT);
+    let tracker_addr_ipv6 = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), TRACKER_PORT);
+
+    let mut config = Config::default();
+
+    config.network.addresses = vec![tracker_addr_ipv4, tracker_addr_ipv6];
+    config.protocol.dual_stack_swarms = true;
+
+    run_tracker(config);
+
+    let info_hash = InfoHash([0; 20]);
+
+    let (socket_ipv4, connection_id_ipv4) = bind_and_connect(tracker_addr_ipv4)?;
+    let (socket_ipv6, connection_id_ipv6) = bind_and_connect(tracker_addr_ipv6)?;
+
+    // Seeder announcing over IPv6
+
+    match announce(
+        &socket_ipv6,
+        tracker_addr_ipv6,
+        connection_id_ipv6,
+        1,
+        info_hash,
+        10,
+        true,
+    )? {
+        Response::AnnounceIpv6(response) => {
+            assert_eq!(response.seeders.0, 1);
+            assert_eq!(response.leechers.0, 0);
+        }
+        response => return Err(anyhow::anyhow!("not announce response: {:?}", response)),
+    }
+
+    // Same seeder announcing over IPv4 is only counted once
+
+    match announce(
+        &socket_ipv4,
+        tracker_addr_ipv4,
+        connection_id_ipv4,
+        1,
+        info_hash,
+        10,
+        true,
+    )? {
+        Response::AnnounceIpv4(response) => {
+            assert_eq!(response.seeders.0, 1);
+            assert_eq!(response.leechers.0, 0);
+        }
+        response => return Err(anyhow::anyhow!("not announce response: {:?}", response)),
+    }
+
+    // Leecher announcing over IPv4
+
+    match announce(
+        &socket_ipv4,
+        tracker_addr_ipv4,
+        connection_id_ipv4,
+        2,
+        info_hash,
+        10,
+        false,
+    )? {
+        Response::AnnounceIpv4(response) => {
+            assert_eq!(response.seeders.0, 1);
+            assert_eq!(response.leechers.0, 1);
+            // Only the seeder's IPv4 address is returned
+            assert_eq!(response.peers.len(), 1);
+        }
+        response => return Err(anyhow::anyhow!("not announce response: {:?}", response)),
+    }
+
+    let scrape_response = scrape(
+        &socket_ipv6,
+        tracker_addr_ipv6,
+        connection_id_ipv6,
+        vec![info_hash],
+    )
+    .with_context(|| "scrape")?;
+
+    assert_eq!(scrape_response.torrent_stats[0].seeders.0, 1);
+    assert_eq!(scrape_response.torrent_stats[0].leechers.0, 1);
+
+    Ok(())
+}
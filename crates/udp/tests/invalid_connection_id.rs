@@ -21,7 +21,7 @@ fn test_invalid_connection_id() -> anyhow::Result<()> {
 
     let mut config = Config::default();
 
-    config.network.address.set_port(TRACKER_PORT);
+    config.network.addresses[0].set_port(TRACKER_PORT);
 
     run_tracker(config);
 
@@ -51,6 +51,7 @@ fn test_invalid_connection_id() -> anyhow::Result<()> {
         key: PeerKey(0),
         peers_wanted: NumberOfPeers(10),
         port: Port(1),
+        url_data: Vec::new(),
     });
 
     let scrape_request = Request::Scrape(ScrapeRequest {
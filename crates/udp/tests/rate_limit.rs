@@ -0,0 +1,82 @@
+mod common;
+
+use common::*;
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+
+use anyhow::Context;
+use aquatic_udp::config::{BanResponse, Config};
+use aquatic_udp_protocol::{InfoHash, Response};
+
+#[test]
+fn test_rate_limit_ban() -> anyhow::Result<()> {
+    const TRACKER_PORT: u16 = 40_116;
+
+    let mut config = Config::default();
+
+    config.network.addresses[0].set_port(TRACKER_PORT);
+
+    config.rate_limit.enabled = true;
+    config.rate_limit.connect_requests_per_second = 0.0;
+    config.rate_limit.announce_requests_per_second = 0.01;
+    config.rate_limit.announce_burst = 2.0;
+    config.rate_limit.violations_before_ban = 2;
+    config.rate_limit.ban_response = BanResponse::Error;
+
+    run_tracker(config);
+
+    let tracker_addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, TRACKER_PORT));
+
+    let (socket, connection_id) = bind_and_connect(tracker_addr)?;
+
+    let info_hash = InfoHash([0; 20]);
+
+    for i in 0..2 {
+        let response = announce(
+            &socket,
+            tracker_addr,
+            connection_id,
+            1,
+            info_hash,
+            10,
+            false,
+        )
+        .with_context(|| format!("announce {}", i))?;
+
+        assert!(matches!(response, Response::AnnounceIpv4(_)));
+    }
+
+    // Rate limited, then banned
+    for _ in 0..2 {
+        assert!(announce(
+            &socket,
+            tracker_addr,
+            connection_id,
+            1,
+            info_hash,
+            10,
+            false
+        )
+        .is_err());
+    }
+
+    let response = announce(
+        &socket,
+        tracker_addr,
+        connection_id,
+        1,
+        info_hash,
+        10,
+        false,
+    )
+    .with_context(|| "announce while banned")?;
+
+    assert!(matches!(response, Response::Error(_)));
+
+    // Bans apply to all request types
+    let response = scrape(&socket, tracker_addr, connection_id, vec![info_hash]);
+
+    assert!(response.is_err());
+
+    Ok(())
+}
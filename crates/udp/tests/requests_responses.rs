@@ -20,7 +20,7 @@ fn test_multiple_connect_announce_scrape() -> anyhow::Result<()> {
 
     let mut config = Config::default();
 
-    config.network.address.set_port(TRACKER_PORT);
+    config.network.addresses[0].set_port(TRACKER_PORT);
 
     run_tracker(config);
 
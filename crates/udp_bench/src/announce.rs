@@ -15,8 +15,8 @@ use crate::config::BenchConfig;
 
 pub fn bench_announce_handler(
     bench_config: &BenchConfig,
-    request_sender: &Sender<(SocketWorkerIndex, ConnectedRequest, CanonicalSocketAddr)>,
-    response_receiver: &Receiver<(ConnectedResponse, CanonicalSocketAddr)>,
+    request_sender: &Sender<(SocketWorkerIndex, ConnectedRequest, RequestSource)>,
+    response_receiver: &Receiver<(ConnectedResponse, RequestSource)>,
     rng: &mut impl Rng,
     info_hashes: &[InfoHash],
 ) -> (usize, Duration) {
@@ -80,7 +80,7 @@ pub fn create_requests(
     rng: &mut impl Rng,
     info_hashes: &[InfoHash],
     number: usize,
-) -> Vec<(AnnounceRequest, CanonicalSocketAddr)> {
+) -> Vec<(AnnounceRequest, RequestSource)> {
     let gamma = Gamma::new(GAMMA_SHAPE, GAMMA_SCALE).unwrap();
 
     let max_index = info_hashes.len() - 1;
@@ -103,11 +103,18 @@ pub fn create_requests(
             key: PeerKey(rng.gen()),
             peers_wanted: NumberOfPeers(rng.gen()),
             port: Port(rng.gen()),
+            url_data: Vec::new(),
         };
 
         requests.push((
             request,
-            CanonicalSocketAddr::new(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1))),
+            RequestSource {
+                addr: CanonicalSocketAddr::new(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::LOCALHOST,
+                    1,
+                ))),
+                socket_index: 0,
+            },
         ));
     }
 
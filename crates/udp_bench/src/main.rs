@@ -7,8 +7,9 @@
 //! Scrape:    1 873 545 requests/second,   533.75 ns/request
 //! ```
 
+use aquatic_common::admin::create_swarm_query_channels;
 use aquatic_common::{PanicSentinelWatcher, ServerStartInstant};
-use aquatic_udp::workers::swarm::run_swarm_worker;
+use aquatic_udp::workers::swarm::{run_swarm_worker, TorrentMap};
 use crossbeam_channel::unbounded;
 use num_format::{Locale, ToFormattedString};
 use rand::{rngs::SmallRng, thread_rng, Rng, SeedableRng};
@@ -56,7 +57,8 @@ pub fn run(bench_config: BenchConfig) -> ::anyhow::Result<()> {
 
     {
         let config = aquatic_config.clone();
-        let state = State::new(config.swarm_workers);
+        let state = State::new(&config);
+        let (_swarm_query_senders, mut swarm_query_receivers) = create_swarm_query_channels(1);
 
         ::std::thread::spawn(move || {
             run_swarm_worker(
@@ -64,9 +66,11 @@ pub fn run(bench_config: BenchConfig) -> ::anyhow::Result<()> {
                 config,
                 state,
                 server_start_instant,
+                TorrentMap::default(),
                 request_receiver,
                 response_sender,
                 statistics_sender,
+                swarm_query_receivers.pop().unwrap(),
                 SwarmWorkerIndex(0),
             )
         });
@@ -15,8 +15,8 @@ use crate::config::BenchConfig;
 
 pub fn bench_scrape_handler(
     bench_config: &BenchConfig,
-    request_sender: &Sender<(SocketWorkerIndex, ConnectedRequest, CanonicalSocketAddr)>,
-    response_receiver: &Receiver<(ConnectedResponse, CanonicalSocketAddr)>,
+    request_sender: &Sender<(SocketWorkerIndex, ConnectedRequest, RequestSource)>,
+    response_receiver: &Receiver<(ConnectedResponse, RequestSource)>,
     rng: &mut impl Rng,
     info_hashes: &[InfoHash],
 ) -> (usize, Duration) {
@@ -92,7 +92,7 @@ pub fn create_requests(
     info_hashes: &[InfoHash],
     number: usize,
     hashes_per_request: usize,
-) -> Vec<(ScrapeRequest, CanonicalSocketAddr)> {
+) -> Vec<(ScrapeRequest, RequestSource)> {
     let gamma = Gamma::new(GAMMA_SHAPE, GAMMA_SCALE).unwrap();
 
     let max_index = info_hashes.len() - 1;
@@ -115,7 +115,13 @@ pub fn create_requests(
 
         requests.push((
             request,
-            CanonicalSocketAddr::new(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1))),
+            RequestSource {
+                addr: CanonicalSocketAddr::new(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::LOCALHOST,
+                    1,
+                ))),
+                socket_index: 0,
+            },
         ));
     }
 
@@ -162,6 +162,7 @@ fn create_announce_request(
         key: PeerKey(12345),
         peers_wanted: NumberOfPeers(100),
         port: torrent_peer.port,
+        url_data: Vec::new(),
     })
     .into()
 }
@@ -11,6 +11,11 @@ use super::common::*;
 
 const PROTOCOL_IDENTIFIER: i64 = 4_497_486_125_440;
 
+/// BEP 41 announce request option types
+const OPTION_END_OF_OPTIONS: u8 = 0x0;
+const OPTION_NOP: u8 = 0x1;
+const OPTION_URL_DATA: u8 = 0x2;
+
 #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub enum AnnounceEvent {
     Started,
@@ -60,6 +65,9 @@ pub struct AnnounceRequest {
     pub key: PeerKey,
     pub peers_wanted: NumberOfPeers,
     pub port: Port,
+    /// Concatenated contents of BEP 41 URLData options, i.e., the path and
+    /// query string of the announce URL. Empty if none were sent.
+    pub url_data: Vec<u8>,
 }
 
 #[derive(PartialEq, Eq, Clone, Debug)]
@@ -161,6 +169,13 @@ impl Request {
                 bytes.write_u32::<NetworkEndian>(r.key.0)?;
                 bytes.write_i32::<NetworkEndian>(r.peers_wanted.0)?;
                 bytes.write_u16::<NetworkEndian>(r.port.0)?;
+
+                // Options can carry at most 255 bytes each, so split URL data
+                for chunk in r.url_data.chunks(u8::MAX as usize) {
+                    bytes.write_u8(OPTION_URL_DATA)?;
+                    bytes.write_u8(chunk.len() as u8)?;
+                    bytes.write_all(chunk)?;
+                }
             }
 
             Request::Scrape(r) => {
@@ -251,6 +266,9 @@ impl Request {
                     Some(Ipv4Addr::from(ip))
                 };
 
+                let position = cursor.position() as usize;
+                let url_data = parse_announce_options(&cursor.into_inner()[position..]);
+
                 Ok((AnnounceRequest {
                     connection_id: ConnectionId(connection_id),
                     transaction_id: TransactionId(transaction_id),
@@ -264,6 +282,7 @@ impl Request {
                     key: PeerKey(key),
                     peers_wanted: NumberOfPeers(peers_wanted),
                     port: Port(port),
+                    url_data,
                 })
                 .into())
             }
@@ -279,20 +298,15 @@ impl Request {
                     .map(|chunk| InfoHash(chunk.try_into().unwrap()))
                     .collect();
 
-                if info_hashes.is_empty() {
-                    Err(RequestParseError::sendable_text(
-                        "Full scrapes are not allowed",
-                        connection_id,
-                        transaction_id,
-                    ))
-                } else {
-                    Ok((ScrapeRequest {
-                        connection_id: ConnectionId(connection_id),
-                        transaction_id: TransactionId(transaction_id),
-                        info_hashes,
-                    })
-                    .into())
-                }
+                // An empty list of info hashes means that this is a full
+                // scrape. It is up to the tracker to decide whether to
+                // support it.
+                Ok((ScrapeRequest {
+                    connection_id: ConnectionId(connection_id),
+                    transaction_id: TransactionId(transaction_id),
+                    info_hashes,
+                })
+                .into())
             }
 
             _ => Err(RequestParseError::sendable_text(
@@ -304,9 +318,48 @@ impl Request {
     }
 }
 
+/// Parse BEP 41 options following announce request and return concatenated
+/// URLData contents. Unknown options are skipped.
+///
+/// Truncated trailing options are ignored, since receive buffers might be
+/// shorter than the datagram (e.g., 256 bytes with io_uring in aquatic_udp).
+fn parse_announce_options(mut bytes: &[u8]) -> Vec<u8> {
+    let mut url_data = Vec::new();
+
+    while let Some((&option_type, rest)) = bytes.split_first() {
+        bytes = rest;
+
+        match option_type {
+            OPTION_END_OF_OPTIONS => break,
+            OPTION_NOP => continue,
+            option_type => {
+                let (len, rest) = match bytes.split_first() {
+                    Some((&len, rest)) => (len, rest),
+                    None => break,
+                };
+
+                let len = len as usize;
+
+                if rest.len() < len {
+                    break;
+                }
+
+                let (data, rest) = rest.split_at(len);
+
+                if option_type == OPTION_URL_DATA {
+                    url_data.extend_from_slice(data);
+                }
+
+                bytes = rest;
+            }
+        }
+    }
+
+    url_data
+}
+
 #[cfg(test)]
 mod tests {
-    use quickcheck::TestResult;
     use quickcheck_macros::quickcheck;
 
     use super::*;
@@ -345,6 +398,7 @@ mod tests {
                 key: PeerKey(u32::arbitrary(g)),
                 peers_wanted: NumberOfPeers(i32::arbitrary(g)),
                 port: Port(u16::arbitrary(g)),
+                url_data: Vec::arbitrary(g),
             }
         }
     }
@@ -388,12 +442,88 @@ mod tests {
         same_after_conversion(request.into())
     }
 
-    #[quickcheck]
-    fn test_scrape_request_convert_identity(request: ScrapeRequest) -> TestResult {
-        if request.info_hashes.is_empty() {
-            return TestResult::discard();
+    #[test]
+    fn test_parse_announce_options() {
+        assert_eq!(parse_announce_options(&[]), vec![]);
+
+        // URLData concatenation with NOP in between and trailing bytes
+        // after EndOfOptions
+        let bytes = [
+            OPTION_URL_DATA,
+            3,
+            b'/',
+            b'a',
+            b'b',
+            OPTION_NOP,
+            OPTION_URL_DATA,
+            2,
+            b'?',
+            b'c',
+            OPTION_END_OF_OPTIONS,
+            OPTION_URL_DATA,
+            1,
+            b'd',
+        ];
+
+        assert_eq!(parse_announce_options(&bytes), b"/ab?c".to_vec());
+
+        // Unknown option types are skipped
+        let bytes = [0x7f, 2, 1, 2, OPTION_URL_DATA, 1, b'/'];
+
+        assert_eq!(parse_announce_options(&bytes), b"/".to_vec());
+
+        // Truncated trailing options are ignored
+        assert_eq!(parse_announce_options(&[OPTION_URL_DATA]), vec![]);
+        assert_eq!(
+            parse_announce_options(&[OPTION_URL_DATA, 1, b'/', OPTION_URL_DATA, 2, b'a']),
+            b"/".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_parse_announce_request_truncated_options() {
+        // Receive buffer length used with io_uring in aquatic_udp
+        const REQUEST_BUF_LEN: usize = 256;
+
+        let request = AnnounceRequest {
+            connection_id: ConnectionId(1),
+            transaction_id: TransactionId(2),
+            info_hash: InfoHash([3; 20]),
+            peer_id: PeerId([4; 20]),
+            bytes_downloaded: NumberOfBytes(5),
+            bytes_uploaded: NumberOfBytes(6),
+            bytes_left: NumberOfBytes(7),
+            event: AnnounceEvent::Started,
+            ip_address: None,
+            key: PeerKey(8),
+            peers_wanted: NumberOfPeers(9),
+            port: Port(10),
+            url_data: vec![b'a'; 200],
+        };
+
+        let mut buf = Vec::new();
+
+        Request::from(request.clone()).write(&mut buf).unwrap();
+
+        assert!(buf.len() > REQUEST_BUF_LEN);
+
+        match Request::from_bytes(&buf[..REQUEST_BUF_LEN], u8::MAX).unwrap() {
+            Request::Announce(parsed) => {
+                // Truncated URLData option is ignored
+                assert_eq!(
+                    parsed,
+                    AnnounceRequest {
+                        url_data: vec![],
+                        ..request
+                    }
+                );
+            }
+            _ => panic!("not an announce request"),
         }
+    }
 
-        TestResult::from_bool(same_after_conversion(request.into()))
+    #[quickcheck]
+    fn test_scrape_request_convert_identity(request: ScrapeRequest) -> bool {
+        same_after_conversion(request.into())
     }
 }
@@ -1,9 +1,15 @@
 use std::{net::IpAddr, sync::Arc};
 
 use aquatic_common::access_list::AccessListArcSwap;
+use aquatic_common::connection_limits::SourceConnectionCounts;
+use aquatic_common::snapshot::ShutdownSnapshotSignal;
 
 pub use aquatic_common::ValidUntil;
-use aquatic_ws_protocol::{InfoHash, PeerId};
+use aquatic_ws_protocol::{
+    EncodedScrapeResponse, InfoHash, PeerId, ScrapeAction, ScrapeResponse, ScrapeStatistics,
+};
+
+use crate::config::Config;
 
 #[derive(Copy, Clone, Debug)]
 pub enum IpVersion {
@@ -23,9 +29,61 @@ impl IpVersion {
     }
 }
 
-#[derive(Default, Clone)]
+pub type FullScrapeCache =
+    aquatic_common::full_scrape::FullScrapeCache<FullScrapePart, FullScrapeResponse>;
+
+/// Scrape statistics of all torrents of a swarm worker
+///
+/// When IP versions are mixed, both fields point to the same statistics.
+pub struct FullScrapePart {
+    pub ipv4: Arc<Vec<(InfoHash, ScrapeStatistics)>>,
+    pub ipv6: Arc<Vec<(InfoHash, ScrapeStatistics)>>,
+}
+
+/// Pre-encoded full scrape responses
+pub struct FullScrapeResponse {
+    pub ipv4: EncodedScrapeResponse,
+    pub ipv6: EncodedScrapeResponse,
+}
+
+impl FullScrapeResponse {
+    pub fn merge(parts: &[Arc<FullScrapePart>]) -> Self {
+        Self {
+            ipv4: Self::encode(parts.iter().flat_map(|part| part.ipv4.iter())),
+            ipv6: Self::encode(parts.iter().flat_map(|part| part.ipv6.iter())),
+        }
+    }
+
+    fn encode<'a>(
+        files: impl Iterator<Item = &'a (InfoHash, ScrapeStatistics)>,
+    ) -> EncodedScrapeResponse {
+        EncodedScrapeResponse::new(&ScrapeResponse {
+            action: ScrapeAction::Scrape,
+            files: files.cloned().collect(),
+        })
+    }
+}
+
+#[derive(Clone)]
 pub struct State {
     pub access_list: Arc<AccessListArcSwap>,
+    pub shutdown_snapshot_signal: ShutdownSnapshotSignal,
+    pub full_scrape: Arc<FullScrapeCache>,
+    pub source_connection_counts: Arc<SourceConnectionCounts>,
+}
+
+impl State {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            access_list: Default::default(),
+            shutdown_snapshot_signal: Default::default(),
+            full_scrape: Arc::new(FullScrapeCache::new(
+                &config.full_scrape,
+                config.swarm_workers,
+            )),
+            source_connection_counts: Default::default(),
+        }
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -34,9 +92,10 @@ pub struct PendingScrapeId(pub u8);
 #[derive(Copy, Clone, Debug)]
 pub struct ConsumerId(pub u8);
 
-slotmap::new_key_type! {
-    pub struct ConnectionId;
-}
+/// Identifies a connection within a socket worker. Never reused, so that
+/// swarm entries of closed connections can't reach new ones.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ConnectionId(pub u64);
 
 #[derive(Clone, Copy, Debug)]
 pub struct InMessageMeta {
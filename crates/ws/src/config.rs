@@ -2,16 +2,25 @@ use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use aquatic_common::cpu_pinning::asc::CpuPinningConfigAsc;
-use aquatic_common::{access_list::AccessListConfig, privileges::PrivilegeConfig};
-use serde::Deserialize;
+use aquatic_common::{
+    access_list::AccessListConfig, admin::AdminConfig, connection_limits::ConnectionLimitConfig,
+    file_watcher::FileWatcherConfig, full_scrape::FullScrapeConfig, privileges::PrivilegeConfig,
+    reverse_proxy::ReverseProxyPeerIpHeaderFormat, rustls_config::SniCertificateConfig,
+    snapshot::SnapshotConfig,
+};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
 
 use aquatic_common::cli::LogLevel;
 use aquatic_toml_config::TomlConfig;
+use aquatic_ws_protocol::sdp::SdpValidationRules;
 
 /// aquatic_ws configuration
 ///
-/// Running behind a reverse proxy is supported, but IPv4 peer requests have
-/// to be proxied to IPv4 requests, and IPv6 requests to IPv6 requests.
+/// Running behind a reverse proxy is supported. Unless the PROXY protocol or
+/// network.runs_behind_reverse_proxy is enabled, IPv4 peer requests have to
+/// be proxied to IPv4 requests, and IPv6 requests to IPv6 requests, at least
+/// when protocol.mixed_ip_version_swarms is disabled.
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct Config {
@@ -36,7 +45,24 @@ pub struct Config {
     pub log_level: LogLevel,
     pub network: NetworkConfig,
     pub protocol: ProtocolConfig,
+    /// Validation of session descriptions (SDP) in offers and answers
+    ///
+    /// Announce requests with offers or answers failing validation are
+    /// rejected with an error response.
+    pub sdp_validation: SdpValidationConfig,
     pub cleaning: CleaningConfig,
+    /// Periodic return of unused memory to the operating system
+    ///
+    /// The allocator (mimalloc) keeps freed memory around for reuse, so
+    /// memory usage might otherwise stay high after traffic drops.
+    pub memory_reclamation: MemoryReclamationConfig,
+    /// Concurrent connection limits
+    ///
+    /// Connections over a limit are closed right after being accepted. When
+    /// running behind a reverse proxy, per-IP limits use the address from the
+    /// peer IP header and are applied after the WebSocket handshake.
+    pub connection_limits: ConnectionLimitConfig,
+    pub rate_limit: RateLimitConfig,
     pub privileges: PrivilegeConfig,
     /// Access list configuration
     ///
@@ -45,6 +71,27 @@ pub struct Config {
     /// emitting of an error-level log message, while successful updates of the
     /// access list result in emitting of an info-level log message.
     pub access_list: AccessListConfig,
+    /// File watcher configuration
+    ///
+    /// When enabled, changes to the access list and the TLS certificate and
+    /// private key trigger the same reload as `SIGUSR1`.
+    pub file_watcher: FileWatcherConfig,
+    /// Swarm state snapshot configuration
+    ///
+    /// Snapshots are only loaded if they were saved with the same number of
+    /// swarm workers. Since peers are tied to WebSocket connections, which
+    /// don't survive restarts, only completed counts are saved.
+    pub snapshot: SnapshotConfig,
+    /// Full scrape configuration
+    ///
+    /// A scrape request without `info_hash` is a full scrape. The response
+    /// contains all torrents for the IP version of the connection, or torrents
+    /// of both IP versions when protocol.mixed_ip_version_swarms is enabled.
+    pub full_scrape: FullScrapeConfig,
+    /// Admin socket configuration
+    ///
+    /// Use `aquatic ctl` to send commands.
+    pub admin: AdminConfig,
     #[cfg(feature = "metrics")]
     pub metrics: MetricsConfig,
     pub cpu_pinning: CpuPinningConfigAsc,
@@ -58,9 +105,17 @@ impl Default for Config {
             log_level: LogLevel::default(),
             network: NetworkConfig::default(),
             protocol: ProtocolConfig::default(),
+            sdp_validation: SdpValidationConfig::default(),
             cleaning: CleaningConfig::default(),
+            memory_reclamation: MemoryReclamationConfig::default(),
+            connection_limits: ConnectionLimitConfig::default(),
+            rate_limit: RateLimitConfig::default(),
             privileges: PrivilegeConfig::default(),
             access_list: AccessListConfig::default(),
+            file_watcher: FileWatcherConfig::default(),
+            snapshot: SnapshotConfig::default(),
+            full_scrape: FullScrapeConfig::default(),
+            admin: AdminConfig::default(),
             #[cfg(feature = "metrics")]
             metrics: Default::default(),
             cpu_pinning: Default::default(),
@@ -92,10 +147,63 @@ pub struct NetworkConfig {
     /// result in emitting of an info-level log message. Updates only affect
     /// new connections.
     pub enable_tls: bool,
-    /// Path to TLS certificate (DER-encoded X.509)
+    /// Path to default TLS certificate chain (PEM)
     pub tls_certificate_path: PathBuf,
-    /// Path to TLS private key (DER-encoded ASN.1 in PKCS#8 or PKCS#1 format)
+    /// Path to default TLS private key (PEM, in PKCS#8, PKCS#1 or SEC1 format)
     pub tls_private_key_path: PathBuf,
+    /// Additional certificates served to clients requesting one of their
+    /// server names through SNI. Other clients get the default certificate.
+    ///
+    /// Each entry is a table with server_names (list of names, which may
+    /// start with `*.`), certificate_path and private_key_path.
+    pub tls_sni_certificates: Vec<SniCertificateConfig>,
+
+    /// Require a PROXY protocol (v1 or v2) header at the start of each
+    /// connection, before TLS, and use the client address in it to determine
+    /// the IP version of peers
+    pub proxy_protocol: bool,
+    /// Networks, e.g., `10.0.0.0/8`, allowed to connect when proxy_protocol
+    /// is enabled. Connections from other addresses are closed. Can't be
+    /// empty when proxy_protocol is enabled.
+    pub proxy_protocol_trusted_sources: Vec<IpNet>,
+
+    /// Does tracker run behind reverse proxy?
+    ///
+    /// MUST be set to false if not running behind reverse proxy.
+    ///
+    /// If set to true, the peer IP is read from a header in the WebSocket
+    /// upgrade request and used to determine the IP version of peers. Make
+    /// sure that reverse_proxy_ip_header_name and
+    /// reverse_proxy_ip_header_format are set to match your reverse proxy
+    /// setup. Upgrade requests without a valid header are rejected.
+    ///
+    /// More info on what can go wrong when running behind reverse proxies:
+    /// https://adam-p.ca/blog/2022/03/x-forwarded-for/
+    pub runs_behind_reverse_proxy: bool,
+    /// Name of header set by reverse proxy to indicate peer ip
+    pub reverse_proxy_ip_header_name: String,
+    /// How to extract peer IP from header field
+    ///
+    /// Multiple instances of the header are treated as a single
+    /// comma-separated list of addresses.
+    ///
+    /// Options:
+    /// - last_address: use the last address in the header
+    /// - nth_last_address: use the address at position
+    ///   reverse_proxy_ip_header_position counted from the right
+    /// - rightmost_untrusted_address: use the rightmost address not in
+    ///   reverse_proxy_trusted_networks, or the leftmost one if all are
+    ///   trusted
+    /// - forwarded: parse the for parameter of RFC 7239 elements (set header
+    ///   name to Forwarded) and select address like
+    ///   rightmost_untrusted_address
+    pub reverse_proxy_ip_header_format: ReverseProxyPeerIpHeaderFormat,
+    /// Position of peer address counted from the right, starting at 1 (the
+    /// last address). Only used with nth_last_address format.
+    pub reverse_proxy_ip_header_position: usize,
+    /// Addresses of proxies, e.g., `10.0.0.0/8`. Only used with
+    /// rightmost_untrusted_address and forwarded formats.
+    pub reverse_proxy_trusted_networks: Vec<IpNet>,
 
     pub websocket_max_message_size: usize,
     pub websocket_max_frame_size: usize,
@@ -116,6 +224,16 @@ impl Default for NetworkConfig {
             enable_tls: false,
             tls_certificate_path: "".into(),
             tls_private_key_path: "".into(),
+            tls_sni_certificates: Vec::new(),
+
+            proxy_protocol: false,
+            proxy_protocol_trusted_sources: Vec::new(),
+
+            runs_behind_reverse_proxy: false,
+            reverse_proxy_ip_header_name: "X-Forwarded-For".into(),
+            reverse_proxy_ip_header_format: Default::default(),
+            reverse_proxy_ip_header_position: 1,
+            reverse_proxy_trusted_networks: Vec::new(),
 
             websocket_max_message_size: 64 * 1024,
             websocket_max_frame_size: 16 * 1024,
@@ -133,8 +251,21 @@ pub struct ProtocolConfig {
     pub max_scrape_torrents: usize,
     /// Maximum number of offers to accept in announce request
     pub max_offers: usize,
+    /// Maximum number of forwarded offers per peer awaiting answers
+    ///
+    /// Further offers from the peer are not forwarded until answers arrive
+    /// or the offers expire (see cleaning.max_offer_age).
+    pub max_expecting_answers: usize,
     /// Ask peers to announce this often (seconds)
     pub peer_announce_interval: usize,
+    /// Forward offers and answers between IPv4 and IPv6 peers
+    ///
+    /// WebRTC peers negotiate connectivity themselves, so peers connected to
+    /// the tracker over different IP versions can often reach each other.
+    /// When enabled, IPv4 and IPv6 peers of a torrent form a single swarm and
+    /// scrape and announce response counts cover both. Peers are still
+    /// tracked (and counted in metrics) per IP version.
+    pub mixed_ip_version_swarms: bool,
 }
 
 impl Default for ProtocolConfig {
@@ -142,7 +273,116 @@ impl Default for ProtocolConfig {
         Self {
             max_scrape_torrents: 255,
             max_offers: 10,
+            max_expecting_answers: 50,
             peer_announce_interval: 120,
+            mixed_ip_version_swarms: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SdpValidationMode {
+    Disabled,
+    #[default]
+    Lenient,
+    Strict,
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SdpValidationConfig {
+    /// Validation mode
+    ///
+    /// - disabled: forward SDP verbatim
+    /// - lenient: reject SDP with invalid structure or non-data channel
+    ///   media, remove unknown lines and invalid ICE candidates
+    /// - strict: also reject SDP with unknown lines, invalid ICE candidates,
+    ///   no session name or more than one media description
+    pub mode: SdpValidationMode,
+    /// Maximum SDP size in bytes
+    pub max_size: usize,
+    /// Maximum number of ICE candidates per SDP
+    pub max_candidates: usize,
+}
+
+impl SdpValidationConfig {
+    pub fn rules(&self) -> Option<SdpValidationRules> {
+        let strict = match self.mode {
+            SdpValidationMode::Disabled => return None,
+            SdpValidationMode::Lenient => false,
+            SdpValidationMode::Strict => true,
+        };
+
+        Some(SdpValidationRules {
+            strict,
+            max_size: self.max_size,
+            max_candidates: self.max_candidates,
+        })
+    }
+}
+
+impl Default for SdpValidationConfig {
+    fn default() -> Self {
+        Self {
+            mode: SdpValidationMode::default(),
+            max_size: 16 * 1024,
+            max_candidates: 32,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, TomlConfig, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThrottleResponse {
+    /// Ignore message
+    Drop,
+    /// Send an error response
+    Error,
+    /// Close connection
+    Close,
+}
+
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Limit number of messages per connection
+    ///
+    /// Announce messages containing an answer count as answer messages. A
+    /// rate of zero disables limiting of that message type.
+    pub enabled: bool,
+    /// Sustained number of announce messages allowed per second
+    pub announce_messages_per_second: f32,
+    /// Maximum number of announce messages allowed in a burst
+    pub announce_burst: f32,
+    /// Sustained number of scrape messages allowed per second
+    pub scrape_messages_per_second: f32,
+    /// Maximum number of scrape messages allowed in a burst
+    pub scrape_burst: f32,
+    /// Sustained number of answer messages allowed per second
+    pub answer_messages_per_second: f32,
+    /// Maximum number of answer messages allowed in a burst
+    pub answer_burst: f32,
+    /// How to handle messages exceeding limits
+    ///
+    /// Options:
+    /// - drop: ignore message
+    /// - error: send an error response
+    /// - close: close connection
+    pub throttle_response: ThrottleResponse,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            announce_messages_per_second: 2.0,
+            announce_burst: 20.0,
+            scrape_messages_per_second: 1.0,
+            scrape_burst: 10.0,
+            answer_messages_per_second: 10.0,
+            answer_burst: 50.0,
+            throttle_response: ThrottleResponse::Error,
         }
     }
 }
@@ -154,6 +394,9 @@ pub struct CleaningConfig {
     pub torrent_cleaning_interval: u64,
     /// Remove peers that have not announced for this long (seconds)
     pub max_peer_age: u32,
+    /// Keep torrents without peers for this long if they have a non-zero
+    /// completed count, so that it isn't lost (seconds)
+    pub max_torrent_idle_age: u32,
     /// Require that offers are answered to withing this period (seconds)
     pub max_offer_age: u32,
     // Clean connections this often (seconds)
@@ -172,6 +415,7 @@ impl Default for CleaningConfig {
         Self {
             torrent_cleaning_interval: 30,
             max_peer_age: 180,
+            max_torrent_idle_age: 60 * 60 * 24,
             max_offer_age: 120,
             max_connection_idle: 180,
             connection_cleaning_interval: 30,
@@ -180,6 +424,34 @@ impl Default for CleaningConfig {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryReclamationConfig {
+    /// Reclaim memory in socket and swarm workers
+    ///
+    /// Collecting and inspecting allocator heaps takes time proportional to
+    /// heap size, during which workers don't handle requests.
+    pub enabled: bool,
+    /// Reclaim memory this often (seconds)
+    pub interval: u64,
+    /// Return all unused memory to the operating system at once, not just
+    /// memory that has been unused for a while
+    ///
+    /// More effective, but more expensive, since memory might need to be
+    /// requested again soon.
+    pub force: bool,
+}
+
+impl Default for MemoryReclamationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: 60,
+            force: false,
+        }
+    }
+}
+
 #[cfg(feature = "metrics")]
 #[derive(Clone, Debug, PartialEq, TomlConfig, Deserialize)]
 #[serde(default, deny_unknown_fields)]
@@ -8,8 +8,11 @@ use std::time::Duration;
 use anyhow::Context;
 use aquatic_common::cpu_pinning::glommio::{get_worker_placement, set_affinity_for_util_worker};
 use aquatic_common::cpu_pinning::WorkerIndex;
+use aquatic_common::file_watcher::spawn_file_watcher;
 use aquatic_common::rustls_config::create_rustls_config;
+use aquatic_common::snapshot::SHUTDOWN_SNAPSHOT_TIMEOUT;
 use aquatic_common::{PanicSentinelWatcher, ServerStartInstant};
+use aquatic_ws_protocol::InfoHash;
 use arc_swap::ArcSwap;
 use glommio::{channels::channel_mesh::MeshBuilder, prelude::*};
 use signal_hook::{
@@ -18,10 +21,13 @@ use signal_hook::{
 };
 
 use aquatic_common::access_list::update_access_list;
+use aquatic_common::admin::{create_swarm_query_channels, spawn_admin_server};
 use aquatic_common::privileges::PrivilegeDropper;
 
 use common::*;
 use config::Config;
+use workers::socket::calculate_in_message_consumer_index;
+use workers::swarm::TorrentMaps;
 
 pub const APP_NAME: &str = "aquatic_ws: WebTorrent tracker";
 pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -34,6 +40,16 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
             "configuration: network.enable_tls and network.enable_http_health_check can't both be set to true"
         ));
     }
+    if config.network.proxy_protocol && config.network.proxy_protocol_trusted_sources.is_empty() {
+        return Err(anyhow::anyhow!(
+            "configuration: network.proxy_protocol_trusted_sources can't be empty when network.proxy_protocol is enabled"
+        ));
+    }
+    if config.network.reverse_proxy_ip_header_position == 0 {
+        return Err(anyhow::anyhow!(
+            "configuration: network.reverse_proxy_ip_header_position must be at least 1"
+        ));
+    }
 
     let mut signals = Signals::new([SIGUSR1, SIGTERM])?;
 
@@ -46,6 +62,7 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
             .connection_cleaning_interval
             .max(config.cleaning.torrent_cleaning_interval)
             .max(config.metrics.torrent_count_update_interval)
+            .max(config.memory_reclamation.interval)
             * 2;
 
         PrometheusBuilder::new()
@@ -63,7 +80,7 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
             })?;
     }
 
-    let state = State::default();
+    let state = State::new(&config);
 
     update_access_list(&config.access_list, &state.access_list)?;
 
@@ -81,6 +98,7 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
             create_rustls_config(
                 &config.network.tls_certificate_path,
                 &config.network.tls_private_key_path,
+                &config.network.tls_sni_certificates,
             )
             .with_context(|| "create rustls config")?,
         )))
@@ -88,16 +106,58 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         None
     };
     let mut opt_tls_cert_data = if config.network.enable_tls {
-        Some(
-            ::std::fs::read(&config.network.tls_certificate_path)
-                .with_context(|| "open tls certificate file")?,
-        )
+        Some(read_tls_certificates(&config).with_context(|| "open tls certificate file")?)
     } else {
         None
     };
 
+    let mut watched_paths = Vec::new();
+
+    if config.access_list.mode.is_on() {
+        watched_paths.push(config.access_list.path.clone());
+    }
+    if config.network.enable_tls {
+        watched_paths.push(config.network.tls_certificate_path.clone());
+        watched_paths.push(config.network.tls_private_key_path.clone());
+
+        for sni_certificate in config.network.tls_sni_certificates.iter() {
+            watched_paths.push(sni_certificate.certificate_path.clone());
+            watched_paths.push(sni_certificate.private_key_path.clone());
+        }
+    }
+
+    spawn_file_watcher(&config.file_watcher, watched_paths)?;
+
+    let (swarm_query_senders, swarm_query_receivers) =
+        create_swarm_query_channels(config.swarm_workers);
+    let mut swarm_query_receivers = swarm_query_receivers.into_iter();
+
+    // Bind before socket workers spawn, since they might chroot
+    spawn_admin_server(
+        &config.admin,
+        state.access_list.clone(),
+        swarm_query_senders,
+        {
+            let config = config.clone();
+
+            move |info_hash| calculate_in_message_consumer_index(&config, InfoHash(*info_hash))
+        },
+    )?;
+
     let server_start_instant = ServerStartInstant::new();
 
+    // Load snapshots before spawning socket workers, which might chroot
+    let mut loaded_torrents = (0..config.swarm_workers)
+        .map(|i| {
+            if config.snapshot.enabled {
+                TorrentMaps::load_snapshot(&config, server_start_instant, i)
+            } else {
+                TorrentMaps::default()
+            }
+        })
+        .collect::<Vec<_>>()
+        .into_iter();
+
     let mut executors = Vec::new();
 
     for i in 0..(config.socket_workers) {
@@ -148,6 +208,8 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
         let control_mesh_builder = control_mesh_builder.clone();
         let request_mesh_builder = request_mesh_builder.clone();
         let response_mesh_builder = response_mesh_builder.clone();
+        let torrents = loaded_torrents.next().unwrap();
+        let swarm_query_receiver = swarm_query_receivers.next().unwrap();
 
         let placement = get_worker_placement(
             &config.cpu_pinning,
@@ -167,6 +229,8 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                     request_mesh_builder,
                     response_mesh_builder,
                     server_start_instant,
+                    torrents,
+                    swarm_query_receiver,
                     i,
                 )
                 .await
@@ -192,14 +256,15 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
                 let _ = update_access_list(&config.access_list, &state.access_list);
 
                 if let Some(tls_config) = opt_tls_config.as_ref() {
-                    match ::std::fs::read(&config.network.tls_certificate_path) {
+                    match read_tls_certificates(&config) {
                         Ok(data) if &data == opt_tls_cert_data.as_ref().unwrap() => {
-                            ::log::info!("skipping tls config update: certificate identical to currently loaded");
+                            ::log::info!("skipping tls config update: certificates identical to currently loaded");
                         }
                         Ok(data) => {
                             match create_rustls_config(
                                 &config.network.tls_certificate_path,
                                 &config.network.tls_private_key_path,
+                                &config.network.tls_sni_certificates,
                             ) {
                                 Ok(config) => {
                                     tls_config.store(Arc::new(config));
@@ -217,9 +282,15 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
             SIGTERM => {
                 if sentinel_watcher.panic_was_triggered() {
                     return Err(anyhow::anyhow!("worker thread panicked"));
-                } else {
-                    return Ok(());
                 }
+
+                if config.snapshot.enabled {
+                    state
+                        .shutdown_snapshot_signal
+                        .request_and_wait(config.swarm_workers, SHUTDOWN_SNAPSHOT_TIMEOUT);
+                }
+
+                return Ok(());
             }
             _ => unreachable!(),
         }
@@ -227,3 +298,17 @@ pub fn run(config: Config) -> ::anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Read default and SNI certificate files, in order to detect changes
+fn read_tls_certificates(config: &Config) -> ::std::io::Result<Vec<Vec<u8>>> {
+    ::std::iter::once(&config.network.tls_certificate_path)
+        .chain(
+            config
+                .network
+                .tls_sni_certificates
+                .iter()
+                .map(|sni_certificate| &sni_certificate.certificate_path),
+        )
+        .map(::std::fs::read)
+        .collect()
+}
@@ -0,0 +1,105 @@
+//! Return of memory cached by the allocator to the operating system
+//!
+//! mimalloc heaps are thread-local, so this needs to be done on each glommio
+//! executor.
+
+use std::ffi::c_void;
+
+use libmimalloc_sys::{
+    mi_collect, mi_heap_area_t, mi_heap_get_default, mi_heap_t, mi_heap_visit_blocks,
+};
+
+use crate::config::Config;
+
+/// Allocator heap statistics for the current thread
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapStatistics {
+    /// Bytes committed for heap pages
+    pub committed: usize,
+    /// Bytes in use by allocated blocks
+    pub used: usize,
+}
+
+impl HeapStatistics {
+    pub fn current() -> Self {
+        let mut statistics = Self::default();
+
+        // Only visit areas (pages), not individual blocks
+        unsafe {
+            mi_heap_visit_blocks(
+                mi_heap_get_default(),
+                false,
+                Some(visit_heap_area),
+                &mut statistics as *mut Self as *mut c_void,
+            );
+        }
+
+        statistics
+    }
+}
+
+unsafe extern "C" fn visit_heap_area(
+    _heap: *const mi_heap_t,
+    area: *const mi_heap_area_t,
+    block: *mut c_void,
+    _block_size: usize,
+    arg: *mut c_void,
+) -> bool {
+    // Areas are visited with a null block pointer
+    if block.is_null() {
+        if let (Some(area), Some(statistics)) =
+            (area.as_ref(), (arg as *mut HeapStatistics).as_mut())
+        {
+            statistics.committed += area.committed;
+            // Number of blocks in use, not bytes
+            statistics.used += area.used * area.full_block_size;
+        }
+    }
+
+    true
+}
+
+/// Free memory cached by the allocator for the current thread and report heap
+/// statistics
+pub fn reclaim_allocator_memory(config: &Config, worker_type: &'static str, worker_index: usize) {
+    let before = HeapStatistics::current();
+
+    unsafe {
+        mi_collect(config.memory_reclamation.force);
+    }
+
+    let after = HeapStatistics::current();
+    let reclaimed = before.committed.saturating_sub(after.committed);
+
+    ::log::debug!(
+        "reclaimed {} bytes of memory in {} worker {}: {} bytes committed to heap ({} before), {} bytes used",
+        reclaimed,
+        worker_type,
+        worker_index,
+        after.committed,
+        before.committed,
+        after.used,
+    );
+
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::counter!(
+            "aquatic_heap_reclaimed_bytes_total",
+            reclaimed as u64,
+            "worker_type" => worker_type,
+            "worker_index" => worker_index.to_string(),
+        );
+        ::metrics::gauge!(
+            "aquatic_heap_committed_bytes",
+            after.committed as f64,
+            "worker_type" => worker_type,
+            "worker_index" => worker_index.to_string(),
+        );
+        ::metrics::gauge!(
+            "aquatic_heap_used_bytes",
+            after.used as f64,
+            "worker_type" => worker_type,
+            "worker_index" => worker_index.to_string(),
+        );
+    }
+}
@@ -1,20 +1,24 @@
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::BTreeMap;
+use std::net::IpAddr;
 use std::rc::Rc;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::connection_limits::{ConnectionLimiter, SourceSlot};
+use aquatic_common::proxy_protocol::read_source_addr;
+use aquatic_common::reverse_proxy::extract_peer_ip;
 use aquatic_common::rustls_config::RustlsConfig;
 use aquatic_common::ServerStartInstant;
 use aquatic_peer_id::PeerClient;
+use aquatic_ws_protocol::sdp::{sanitize_sdp, SdpError, SdpValidationRules};
 use aquatic_ws_protocol::*;
 use arc_swap::ArcSwap;
 use async_tungstenite::WebSocketStream;
-use futures::stream::{SplitSink, SplitStream};
-use futures::{AsyncWriteExt, StreamExt};
+use futures::AsyncWriteExt;
 use futures_lite::future::race;
 use futures_rustls::TlsAcceptor;
 use glommio::channels::channel_mesh::Senders;
@@ -25,10 +29,14 @@ use glommio::{enclose, prelude::*};
 use hashbrown::hash_map::Entry;
 use hashbrown::HashMap;
 use slab::Slab;
+use tungstenite::handshake::server as handshake;
+use tungstenite::http::StatusCode;
 
 use crate::common::*;
-use crate::config::Config;
+use crate::config::{Config, NetworkConfig, ThrottleResponse};
 use crate::workers::socket::calculate_in_message_consumer_index;
+use crate::workers::socket::rate_limiter::{MessageRateLimiter, MessageType};
+use crate::workers::socket::websocket::{SharedIo, SharedWebSocket};
 
 #[cfg(feature = "metrics")]
 use crate::workers::socket::{ip_version_to_metrics_str, WORKER_INDEX};
@@ -36,48 +44,71 @@ use crate::workers::socket::{ip_version_to_metrics_str, WORKER_INDEX};
 pub struct ConnectionRunner {
     pub config: Rc<Config>,
     pub access_list: Arc<AccessListArcSwap>,
+    pub full_scrape: Arc<FullScrapeCache>,
     pub in_message_senders: Rc<Senders<(InMessageMeta, InMessage)>>,
     pub tq_prioritized: TaskQueueHandle,
     pub tq_regular: TaskQueueHandle,
     pub connection_valid_until: Rc<RefCell<ValidUntil>>,
+    pub announced_info_hashes: Rc<RefCell<AnnouncedInfoHashes>>,
     pub out_message_sender: Rc<LocalSender<(OutMessageMeta, OutMessage)>>,
     pub out_message_receiver: LocalReceiver<(OutMessageMeta, OutMessage)>,
     pub close_conn_receiver: LocalReceiver<()>,
+    /// Receives requests to shrink buffers if connection is idle
+    pub shrink_buffers_receiver: LocalReceiver<()>,
     pub server_start_instant: ServerStartInstant,
     pub out_message_consumer_id: ConsumerId,
     pub connection_id: ConnectionId,
     pub opt_tls_config: Option<Arc<ArcSwap<RustlsConfig>>>,
+    pub connection_limiter: Rc<ConnectionLimiter>,
+    /// Held for the lifetime of the connection
+    pub opt_source_slot: Option<SourceSlot>,
+    pub peer_ip: IpAddr,
     pub ip_version: IpVersion,
 }
 
 impl ConnectionRunner {
     pub async fn run(
-        self,
+        mut self,
         control_message_senders: Rc<Senders<SwarmControlMessage>>,
-        stream: TcpStream,
+        mut stream: TcpStream,
     ) {
-        let clean_up_data = ConnectionCleanupData {
-            announced_info_hashes: Default::default(),
-            ip_version: self.ip_version,
-            opt_peer_client: Default::default(),
-        };
+        if self.config.network.proxy_protocol {
+            match read_source_addr(
+                &mut stream,
+                &self.config.network.proxy_protocol_trusted_sources,
+            )
+            .await
+            {
+                Ok(addr) => {
+                    self.peer_ip = addr.ip();
+                    self.ip_version = IpVersion::canonical_from_ip(addr.ip());
+                }
+                Err(err) => {
+                    ::log::debug!("connection error: {:#}", err);
 
-        clean_up_data.before_open();
+                    return;
+                }
+            }
+        }
 
-        let config = self.config.clone();
+        // When running behind a reverse proxy, the peer IP is only known
+        // after the handshake
+        if !self.config.network.runs_behind_reverse_proxy {
+            if let Err(err) = self.acquire_source_slot(self.peer_ip) {
+                ::log::debug!("connection error: {:#}", err);
 
-        if let Err(err) = self.run_inner(clean_up_data.clone(), stream).await {
-            ::log::debug!("connection error: {:#}", err);
+                return;
+            }
         }
 
-        clean_up_data
-            .after_close(&config, control_message_senders)
-            .await;
+        if let Err(err) = self.run_inner(control_message_senders, stream).await {
+            ::log::debug!("connection error: {:#}", err);
+        }
     }
 
     async fn run_inner(
         self,
-        clean_up_data: ConnectionCleanupData,
+        control_message_senders: Rc<Senders<SwarmControlMessage>>,
         mut stream: TcpStream,
     ) -> anyhow::Result<()> {
         if let Some(tls_config) = self.opt_tls_config.as_ref() {
@@ -85,7 +116,8 @@ impl ConnectionRunner {
 
             let stream = tls_acceptor.accept(stream).await?;
 
-            self.run_inner_stream_agnostic(clean_up_data, stream).await
+            self.run_inner_stream_agnostic(control_message_senders, stream)
+                .await
         } else {
             // Implementing this over TLS is too cumbersome, since the crate used
             // for TLS streams doesn't support peek and tungstenite doesn't
@@ -116,18 +148,21 @@ impl ConnectionRunner {
                 }
             }
 
-            self.run_inner_stream_agnostic(clean_up_data, stream).await
+            self.run_inner_stream_agnostic(control_message_senders, stream)
+                .await
         }
     }
 
     async fn run_inner_stream_agnostic<S>(
-        self,
-        clean_up_data: ConnectionCleanupData,
+        mut self,
+        control_message_senders: Rc<Senders<SwarmControlMessage>>,
         stream: S,
     ) -> anyhow::Result<()>
     where
         S: futures::AsyncRead + futures::AsyncWrite + Unpin + 'static,
     {
+        let stream = SharedIo::new(stream);
+
         let ws_config = tungstenite::protocol::WebSocketConfig {
             max_frame_size: Some(self.config.network.websocket_max_frame_size),
             max_message_size: Some(self.config.network.websocket_max_message_size),
@@ -135,9 +170,82 @@ impl ConnectionRunner {
             max_write_buffer_size: self.config.network.websocket_write_buffer_size * 3,
             ..Default::default()
         };
-        let stream = async_tungstenite::accept_async_with_config(stream, Some(ws_config)).await?;
+        let stream = if self.config.network.runs_behind_reverse_proxy {
+            let mut opt_peer_ip = None;
+
+            let stream = async_tungstenite::accept_hdr_async_with_config(
+                stream,
+                ReverseProxyHeaderCallback {
+                    network_config: &self.config.network,
+                    opt_peer_ip: &mut opt_peer_ip,
+                },
+                Some(ws_config),
+            )
+            .await?;
+
+            if let Some(peer_ip) = opt_peer_ip {
+                self.peer_ip = peer_ip;
+                self.ip_version = IpVersion::canonical_from_ip(peer_ip);
+
+                self.acquire_source_slot(peer_ip)?;
+            }
+
+            stream
+        } else {
+            async_tungstenite::accept_async_with_config(stream, Some(ws_config)).await?
+        };
+
+        let _opt_source_slot = self.opt_source_slot.take();
+
+        // IP version is final once the handshake is done
+        let clean_up_data = ConnectionCleanupData {
+            announced_info_hashes: self.announced_info_hashes.clone(),
+            ip_version: self.ip_version,
+            opt_peer_client: Default::default(),
+        };
+
+        clean_up_data.before_open();
+
+        let config = self.config.clone();
+
+        let result = self.run_stream(clean_up_data.clone(), stream).await;
 
-        let (ws_out, ws_in) = futures::StreamExt::split(stream);
+        clean_up_data
+            .after_close(&config, control_message_senders)
+            .await;
+
+        result
+    }
+
+    fn acquire_source_slot(&mut self, ip: IpAddr) -> anyhow::Result<()> {
+        match self.connection_limiter.try_acquire_source_slot(ip) {
+            Some(slot) => {
+                self.opt_source_slot = Some(slot);
+
+                Ok(())
+            }
+            None => {
+                #[cfg(feature = "metrics")]
+                ::metrics::increment_counter!(
+                    "aquatic_connections_rejected_total",
+                    "reason" => "source_limit",
+                    "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+                );
+
+                Err(anyhow::anyhow!("source connection limit reached"))
+            }
+        }
+    }
+
+    async fn run_stream<S>(
+        self,
+        clean_up_data: ConnectionCleanupData,
+        stream: WebSocketStream<SharedIo<S>>,
+    ) -> anyhow::Result<()>
+    where
+        S: futures::AsyncRead + futures::AsyncWrite + Unpin + 'static,
+    {
+        let ws = Rc::new(SharedWebSocket::new(stream));
 
         let pending_scrape_slab = Rc::new(RefCell::new(Slab::new()));
         let access_list_cache = create_access_list_cache(&self.access_list);
@@ -145,18 +253,25 @@ impl ConnectionRunner {
         let config = self.config.clone();
 
         let reader_handle = spawn_local_into(
-            enclose!((pending_scrape_slab, clean_up_data) async move {
+            enclose!((ws, pending_scrape_slab, clean_up_data) async move {
                 let mut reader = ConnectionReader {
                     config: self.config.clone(),
                     access_list_cache,
+                    full_scrape: self.full_scrape,
                     in_message_senders: self.in_message_senders,
                     out_message_sender: self.out_message_sender,
                     pending_scrape_slab,
                     out_message_consumer_id: self.out_message_consumer_id,
-                    ws_in,
+                    ws,
                     ip_version: self.ip_version,
                     connection_id: self.connection_id,
+                    server_start_instant: self.server_start_instant,
                     clean_up_data: clean_up_data.clone(),
+                    opt_rate_limiter: self
+                        .config
+                        .rate_limit
+                        .enabled
+                        .then(|| MessageRateLimiter::new(&self.config, Instant::now())),
                 };
 
                 reader.run_in_message_loop().await
@@ -166,12 +281,12 @@ impl ConnectionRunner {
         .unwrap();
 
         let writer_handle = spawn_local_into(
-            async move {
+            enclose!((ws, pending_scrape_slab) async move {
                 let mut writer = ConnectionWriter {
                     config,
                     out_message_receiver: self.out_message_receiver,
                     connection_valid_until: self.connection_valid_until,
-                    ws_out,
+                    ws,
                     pending_scrape_slab,
                     server_start_instant: self.server_start_instant,
                     ip_version: self.ip_version,
@@ -179,11 +294,25 @@ impl ConnectionRunner {
                 };
 
                 writer.run_out_message_loop().await
-            },
+            }),
             self.tq_prioritized,
         )
         .unwrap();
 
+        let shrink_buffers_handle = spawn_local_into(
+            async move {
+                while let Some(()) = self.shrink_buffers_receiver.recv().await {
+                    if ws.rebuild_if_idle().await {
+                        pending_scrape_slab.borrow_mut().shrink_to_fit();
+                    }
+                }
+
+                Ok(())
+            },
+            self.tq_regular,
+        )
+        .unwrap();
+
         let close_conn_future = spawn_local_into(
             async move {
                 self.close_conn_receiver.recv().await;
@@ -194,21 +323,71 @@ impl ConnectionRunner {
         )
         .unwrap();
 
-        race(close_conn_future, race(reader_handle, writer_handle)).await
+        race(
+            close_conn_future,
+            race(reader_handle, race(writer_handle, shrink_buffers_handle)),
+        )
+        .await
+    }
+}
+
+/// WebSocket handshake callback that extracts peer IP from reverse proxy
+/// header and rejects requests where it is missing or invalid
+struct ReverseProxyHeaderCallback<'a> {
+    network_config: &'a NetworkConfig,
+    opt_peer_ip: &'a mut Option<IpAddr>,
+}
+
+impl handshake::Callback for ReverseProxyHeaderCallback<'_> {
+    fn on_request(
+        self,
+        request: &handshake::Request,
+        response: handshake::Response,
+    ) -> Result<handshake::Response, handshake::ErrorResponse> {
+        let header_values = request
+            .headers()
+            .get_all(self.network_config.reverse_proxy_ip_header_name.as_str())
+            .into_iter()
+            .map(|value| value.as_bytes());
+
+        match extract_peer_ip(
+            self.network_config.reverse_proxy_ip_header_format,
+            self.network_config.reverse_proxy_ip_header_position,
+            &self.network_config.reverse_proxy_trusted_networks,
+            header_values,
+        ) {
+            Ok(peer_ip) => {
+                *self.opt_peer_ip = Some(peer_ip);
+
+                Ok(response)
+            }
+            Err(err) => {
+                ::log::debug!("required peer ip header missing or invalid: {:#}", err);
+
+                let mut response = handshake::ErrorResponse::new(None);
+
+                *response.status_mut() = StatusCode::BAD_REQUEST;
+
+                Err(response)
+            }
+        }
     }
 }
 
 struct ConnectionReader<S> {
     config: Rc<Config>,
     access_list_cache: AccessListCache,
+    full_scrape: Arc<FullScrapeCache>,
     in_message_senders: Rc<Senders<(InMessageMeta, InMessage)>>,
     out_message_sender: Rc<LocalSender<(OutMessageMeta, OutMessage)>>,
     pending_scrape_slab: Rc<RefCell<Slab<PendingScrapeResponse>>>,
     out_message_consumer_id: ConsumerId,
-    ws_in: SplitStream<WebSocketStream<S>>,
+    ws: Rc<SharedWebSocket<S>>,
     ip_version: IpVersion,
     connection_id: ConnectionId,
+    server_start_instant: ServerStartInstant,
     clean_up_data: ConnectionCleanupData,
+    opt_rate_limiter: Option<MessageRateLimiter>,
 }
 
 impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
@@ -221,7 +400,7 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
             }
 
             let message = self
-                .ws_in
+                .ws
                 .next()
                 .await
                 .ok_or_else(|| anyhow::anyhow!("Stream ended"))??;
@@ -230,10 +409,23 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
                 tungstenite::Message::Text(_) | tungstenite::Message::Binary(_) => {
                     match InMessage::from_ws_message(message) {
                         Ok(InMessage::AnnounceRequest(request)) => {
-                            self.handle_announce_request(request).await?;
+                            let message_type = if request.answer.is_some() {
+                                MessageType::Answer
+                            } else {
+                                MessageType::Announce
+                            };
+
+                            if self
+                                .check_rate_limit(message_type, Some(request.info_hash))
+                                .await?
+                            {
+                                self.handle_announce_request(request).await?;
+                            }
                         }
                         Ok(InMessage::ScrapeRequest(request)) => {
-                            self.handle_scrape_request(request).await?;
+                            if self.check_rate_limit(MessageType::Scrape, None).await? {
+                                self.handle_scrape_request(request).await?;
+                            }
                         }
                         Err(err) => {
                             ::log::debug!("Couldn't parse in_message: {:#}", err);
@@ -264,7 +456,55 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
         }
     }
 
-    async fn handle_announce_request(&mut self, request: AnnounceRequest) -> anyhow::Result<()> {
+    /// Returns Ok(false) if message should be ignored and an error if
+    /// connection should be closed because of rate limit
+    async fn check_rate_limit(
+        &mut self,
+        message_type: MessageType,
+        opt_info_hash: Option<InfoHash>,
+    ) -> anyhow::Result<bool> {
+        let rate_limiter = if let Some(rate_limiter) = self.opt_rate_limiter.as_mut() {
+            rate_limiter
+        } else {
+            return Ok(true);
+        };
+
+        if rate_limiter.check(message_type, Instant::now()) {
+            return Ok(true);
+        }
+
+        #[cfg(feature = "metrics")]
+        ::metrics::increment_counter!(
+            "aquatic_messages_throttled_total",
+            "type" => message_type.as_str(),
+            "ip_version" => ip_version_to_metrics_str(self.ip_version),
+            "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+        );
+
+        match self.config.rate_limit.throttle_response {
+            ThrottleResponse::Drop => Ok(false),
+            ThrottleResponse::Error => {
+                let action = match message_type {
+                    MessageType::Scrape => ErrorResponseAction::Scrape,
+                    MessageType::Announce | MessageType::Answer => ErrorResponseAction::Announce,
+                };
+
+                self.send_error_response("Rate limit exceeded".into(), Some(action), opt_info_hash)
+                    .await?;
+
+                Ok(false)
+            }
+            ThrottleResponse::Close => Err(anyhow::anyhow!(
+                "{} message rate limit exceeded",
+                message_type.as_str()
+            )),
+        }
+    }
+
+    async fn handle_announce_request(
+        &mut self,
+        mut request: AnnounceRequest,
+    ) -> anyhow::Result<()> {
         #[cfg(feature = "metrics")]
         ::metrics::increment_counter!(
             "aquatic_requests_total",
@@ -275,6 +515,30 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
 
         let info_hash = request.info_hash;
 
+        if let Some(rules) = self.config.sdp_validation.rules() {
+            if let Err((sdp_type, err)) = sanitize_announce_request_sdp(&mut request, &rules) {
+                ::log::debug!("invalid sdp in {}: {}", sdp_type, err);
+
+                #[cfg(feature = "metrics")]
+                ::metrics::increment_counter!(
+                    "aquatic_invalid_sdp_total",
+                    "type" => sdp_type,
+                    "reason" => err.as_str(),
+                    "ip_version" => ip_version_to_metrics_str(self.ip_version),
+                    "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+                );
+
+                self.send_error_response(
+                    format!("Invalid {}: {}", sdp_type, err).into(),
+                    Some(ErrorResponseAction::Announce),
+                    Some(info_hash),
+                )
+                .await?;
+
+                return Ok(());
+            }
+        }
+
         if self
             .access_list_cache
             .load()
@@ -282,10 +546,16 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
         {
             let mut announced_info_hashes = self.clean_up_data.announced_info_hashes.borrow_mut();
 
+            // Peer is valid in swarm worker for this long after announcing
+            let peer_valid_until =
+                ValidUntil::new(self.server_start_instant, self.config.cleaning.max_peer_age);
+
             // Store peer id / check if stored peer id matches
             match announced_info_hashes.entry(request.info_hash) {
-                Entry::Occupied(entry) => {
-                    if *entry.get() != request.peer_id {
+                Entry::Occupied(mut entry) => {
+                    let (peer_id, valid_until) = entry.get_mut();
+
+                    if *peer_id != request.peer_id {
                         // Drop Rc borrow before awaiting
                         drop(announced_info_hashes);
 
@@ -300,9 +570,11 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
                             "Peer used more than one PeerId for a single torrent"
                         ));
                     }
+
+                    *valid_until = peer_valid_until;
                 }
                 Entry::Vacant(entry) => {
-                    entry.insert(request.peer_id);
+                    entry.insert((request.peer_id, peer_valid_until));
 
                     // Set peer client info if not set
                     #[cfg(feature = "metrics")]
@@ -376,14 +648,7 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
         let info_hashes = if let Some(info_hashes) = request.info_hashes {
             info_hashes
         } else {
-            // If request.info_hashes is empty, don't return scrape for all
-            // torrents, even though reference server does it. It is too expensive.
-            self.send_error_response(
-                "Full scrapes are not allowed".into(),
-                Some(ErrorResponseAction::Scrape),
-                None,
-            )
-            .await?;
+            self.handle_full_scrape_request().await?;
 
             return Ok(());
         };
@@ -430,6 +695,53 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
         Ok(())
     }
 
+    /// Send cached full scrape response, or error response if full scrapes
+    /// are disabled, not yet available or rate limited
+    async fn handle_full_scrape_request(&mut self) -> anyhow::Result<()> {
+        if !self.config.full_scrape.enabled {
+            return self
+                .send_error_response(
+                    "Full scrapes are not allowed".into(),
+                    Some(ErrorResponseAction::Scrape),
+                    None,
+                )
+                .await;
+        }
+
+        match self.full_scrape.get() {
+            Ok(response) => {
+                let response = if let IpVersion::V4 = self.ip_version {
+                    response.ipv4.clone()
+                } else {
+                    response.ipv6.clone()
+                };
+
+                // Encoded once per refresh, so only a copy of the bytes is
+                // made for each request
+                self.out_message_sender
+                    .send((
+                        self.make_connection_meta(None).into(),
+                        OutMessage::EncodedScrapeResponse(response),
+                    ))
+                    .await
+                    .map_err(|err| {
+                        anyhow::anyhow!(
+                            "ConnectionReader::handle_full_scrape_request failed: {:#}",
+                            err
+                        )
+                    })
+            }
+            Err(err) => {
+                self.send_error_response(
+                    err.failure_reason().into(),
+                    Some(ErrorResponseAction::Scrape),
+                    None,
+                )
+                .await
+            }
+        }
+    }
+
     async fn send_error_response(
         &self,
         failure_reason: Cow<'static, str>,
@@ -460,11 +772,43 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionReader<S> {
     }
 }
 
+/// Validate and sanitize SDP of offers and answer in place
+///
+/// On failure, returns "offer" or "answer" along with the error
+fn sanitize_announce_request_sdp(
+    request: &mut AnnounceRequest,
+    rules: &SdpValidationRules,
+) -> Result<(), (&'static str, SdpError)> {
+    let offer_sdps = request
+        .offers
+        .iter_mut()
+        .flatten()
+        .map(|offer| ("offer", &mut offer.offer.sdp));
+    let answer_sdp = request
+        .answer
+        .as_mut()
+        .map(|answer| ("answer", &mut answer.sdp));
+
+    for (sdp_type, sdp) in offer_sdps.chain(answer_sdp) {
+        let opt_sanitized = match sanitize_sdp(sdp, rules) {
+            Ok(Cow::Owned(sanitized)) => Some(sanitized),
+            Ok(Cow::Borrowed(_)) => None,
+            Err(err) => return Err((sdp_type, err)),
+        };
+
+        if let Some(sanitized) = opt_sanitized {
+            *sdp = sanitized.into();
+        }
+    }
+
+    Ok(())
+}
+
 struct ConnectionWriter<S> {
     config: Rc<Config>,
     out_message_receiver: LocalReceiver<(OutMessageMeta, OutMessage)>,
     connection_valid_until: Rc<RefCell<ValidUntil>>,
-    ws_out: SplitSink<WebSocketStream<S>, tungstenite::Message>,
+    ws: Rc<SharedWebSocket<S>>,
     pending_scrape_slab: Rc<RefCell<Slab<PendingScrapeResponse>>>,
     server_start_instant: ServerStartInstant,
     ip_version: IpVersion,
@@ -480,9 +824,15 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionWriter<S> {
 
             match out_message {
                 OutMessage::ScrapeResponse(out_message) => {
-                    let pending_scrape_id = meta
-                        .pending_scrape_id
-                        .expect("meta.pending_scrape_id not set");
+                    // Full scrape responses are complete already
+                    let pending_scrape_id = if let Some(id) = meta.pending_scrape_id {
+                        id
+                    } else {
+                        self.send_out_message(&OutMessage::ScrapeResponse(out_message))
+                            .await?;
+
+                        continue;
+                    };
 
                     let mut pending_responses = self.pending_scrape_slab.borrow_mut();
 
@@ -519,7 +869,7 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionWriter<S> {
 
     async fn send_out_message(&mut self, out_message: &OutMessage) -> anyhow::Result<()> {
         timeout(Duration::from_secs(10), async {
-            Ok(futures::SinkExt::send(&mut self.ws_out, out_message.to_ws_message()).await)
+            Ok(self.ws.send(out_message.to_ws_message()).await)
         })
         .await
         .map_err(|err| {
@@ -527,7 +877,10 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionWriter<S> {
         })?
         .with_context(|| "send_out_message")?;
 
-        if let OutMessage::AnnounceResponse(_) | OutMessage::ScrapeResponse(_) = out_message {
+        if let OutMessage::AnnounceResponse(_)
+        | OutMessage::ScrapeResponse(_)
+        | OutMessage::EncodedScrapeResponse(_) = out_message
+        {
             *self.connection_valid_until.borrow_mut() = ValidUntil::new(
                 self.server_start_instant,
                 self.config.cleaning.max_connection_idle,
@@ -540,7 +893,7 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionWriter<S> {
                 OutMessage::OfferOutMessage(_) => "offer",
                 OutMessage::AnswerOutMessage(_) => "offer_answer",
                 OutMessage::AnnounceResponse(_) => "announce",
-                OutMessage::ScrapeResponse(_) => "scrape",
+                OutMessage::ScrapeResponse(_) | OutMessage::EncodedScrapeResponse(_) => "scrape",
                 OutMessage::ErrorResponse(_) => "error",
             };
 
@@ -578,10 +931,14 @@ impl<S: futures::AsyncRead + futures::AsyncWrite + Unpin> ConnectionWriter<S> {
     }
 }
 
+/// Peer ids used for announced info hashes, along with when peers expire in
+/// swarm workers if they don't announce again
+pub type AnnouncedInfoHashes = HashMap<InfoHash, (PeerId, ValidUntil)>;
+
 /// Data stored with connection needed for cleanup after it closes
 #[derive(Clone)]
 struct ConnectionCleanupData {
-    announced_info_hashes: Rc<RefCell<HashMap<InfoHash, PeerId>>>,
+    announced_info_hashes: Rc<RefCell<AnnouncedInfoHashes>>,
     ip_version: IpVersion,
     opt_peer_client: Rc<RefCell<Option<(PeerClient, String)>>>,
 }
@@ -605,7 +962,7 @@ impl ConnectionCleanupData {
         let announced_info_hashes = self.announced_info_hashes.take();
 
         // Tell swarm workers to remove peer
-        for (info_hash, peer_id) in announced_info_hashes.into_iter() {
+        for (info_hash, (peer_id, _)) in announced_info_hashes.into_iter() {
             let message = SwarmControlMessage::ConnectionClosed {
                 info_hash,
                 peer_id,
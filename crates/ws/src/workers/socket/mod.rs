@@ -5,6 +5,7 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Context;
+use aquatic_common::connection_limits::ConnectionLimiter;
 use aquatic_common::privileges::PrivilegeDropper;
 use aquatic_common::rustls_config::RustlsConfig;
 use aquatic_common::{PanicSentinel, ServerStartInstant};
@@ -17,16 +18,19 @@ use glommio::channels::shared_channel::ConnectedReceiver;
 use glommio::net::TcpListener;
 use glommio::timer::TimerActionRepeat;
 use glommio::{enclose, prelude::*};
-use slotmap::HopSlotMap;
+use hashbrown::HashMap;
 
 use crate::config::Config;
 
 use crate::common::*;
-use crate::workers::socket::connection::ConnectionRunner;
+use crate::workers::memory::reclaim_allocator_memory;
+use crate::workers::socket::connection::{AnnouncedInfoHashes, ConnectionRunner};
 
 mod connection;
+mod rate_limiter;
+mod websocket;
 
-type ConnectionHandles = HopSlotMap<ConnectionId, ConnectionHandle>;
+type ConnectionHandles = HashMap<ConnectionId, ConnectionHandle>;
 
 const LOCAL_CHANNEL_SIZE: usize = 16;
 
@@ -36,11 +40,15 @@ thread_local! { static WORKER_INDEX: ::std::cell::Cell<usize> = Default::default
 /// Used to interact with the connection tasks
 struct ConnectionHandle {
     close_conn_sender: LocalSender<()>,
+    /// Used to tell connection task to shrink its buffers if it is idle
+    shrink_buffers_sender: LocalSender<()>,
     /// Sender part of channel used to pass on outgoing messages from request
     /// worker
     out_message_sender: Rc<LocalSender<(OutMessageMeta, OutMessage)>>,
     /// Updated after sending message to peer
     valid_until: Rc<RefCell<ValidUntil>>,
+    /// Shared with connection task. Cleaned and shrunk periodically.
+    announced_info_hashes: Rc<RefCell<AnnouncedInfoHashes>>,
     /// The TLS config used for this connection
     opt_tls_config: Option<Arc<RustlsConfig>>,
     valid_until_after_tls_update: Option<ValidUntil>,
@@ -63,6 +71,11 @@ pub async fn run_socket_worker(
 
     let config = Rc::new(config);
     let access_list = state.access_list;
+    let full_scrape = state.full_scrape;
+    let connection_limiter = Rc::new(ConnectionLimiter::new(
+        &config.connection_limits,
+        state.source_connection_counts,
+    ));
 
     let listener = create_tcp_listener(&config, priv_dropper).expect("create tcp listener");
 
@@ -98,6 +111,7 @@ pub async fn run_socket_worker(
     ::log::info!("joined channels");
 
     let connection_handles = Rc::new(RefCell::new(ConnectionHandles::default()));
+    let mut next_connection_id = 0u64;
 
     // Periodically clean connections
     TimerActionRepeat::repeat_into(
@@ -113,6 +127,17 @@ pub async fn run_socket_worker(
     )
     .unwrap();
 
+    // Periodically reclaim memory
+    if config.memory_reclamation.enabled {
+        TimerActionRepeat::repeat_into(
+            enclose!((config, connection_handles) move || {
+                reclaim_memory(config.clone(), connection_handles.clone(), worker_index)
+            }),
+            tq_regular,
+        )
+        .unwrap();
+    }
+
     for (_, out_message_receiver) in out_message_receivers.streams() {
         spawn_local_into(
             receive_out_messages(out_message_receiver, connection_handles.clone()),
@@ -130,8 +155,25 @@ pub async fn run_socket_worker(
                 ::log::error!("accept connection: {:#}", err);
             }
             Ok(stream) => {
-                let ip_version = match stream.peer_addr() {
-                    Ok(addr) => IpVersion::canonical_from_ip(addr.ip()),
+                // Stream is closed when dropped
+                let worker_slot = match connection_limiter.try_acquire_worker_slot() {
+                    Some(slot) => slot,
+                    None => {
+                        ::log::debug!("connection closed: socket worker connection limit reached");
+
+                        #[cfg(feature = "metrics")]
+                        ::metrics::increment_counter!(
+                            "aquatic_connections_rejected_total",
+                            "reason" => "worker_limit",
+                            "worker_index" => worker_index.to_string(),
+                        );
+
+                        continue;
+                    }
+                };
+
+                let peer_ip = match stream.peer_addr() {
+                    Ok(addr) => addr.ip(),
                     Err(err) => {
                         ::log::info!("could not extract ip version (v4 or v6): {:#}", err);
 
@@ -143,52 +185,72 @@ pub async fn run_socket_worker(
                 let out_message_sender = Rc::new(out_message_sender);
 
                 let (close_conn_sender, close_conn_receiver) = new_bounded(1);
+                let (shrink_buffers_sender, shrink_buffers_receiver) = new_bounded(1);
 
                 let connection_valid_until = Rc::new(RefCell::new(ValidUntil::new(
                     server_start_instant,
                     config.cleaning.max_connection_idle,
                 )));
+                let announced_info_hashes = Rc::new(RefCell::new(Default::default()));
 
                 let connection_handle = ConnectionHandle {
                     close_conn_sender,
+                    shrink_buffers_sender,
                     out_message_sender: out_message_sender.clone(),
                     valid_until: connection_valid_until.clone(),
+                    announced_info_hashes: announced_info_hashes.clone(),
                     opt_tls_config: opt_tls_config.as_ref().map(|c| c.load_full()),
                     valid_until_after_tls_update: None,
                 };
 
-                let connection_id = connection_handles.borrow_mut().insert(connection_handle);
+                let connection_id = ConnectionId(next_connection_id);
+
+                next_connection_id += 1;
+
+                connection_handles
+                    .borrow_mut()
+                    .insert(connection_id, connection_handle);
 
                 spawn_local_into(
                     enclose!((
                         config,
                         access_list,
+                        full_scrape,
                         in_message_senders,
                         connection_valid_until,
                         opt_tls_config,
                         control_message_senders,
-                        connection_handles
+                        connection_handles,
+                        connection_limiter
                     ) async move {
                         let runner = ConnectionRunner {
                             config,
                             access_list,
+                            full_scrape,
                             in_message_senders,
                             tq_prioritized,
                             tq_regular,
                             connection_valid_until,
+                            announced_info_hashes,
                             out_message_sender,
                             out_message_receiver,
                             close_conn_receiver,
+                            shrink_buffers_receiver,
                             server_start_instant,
                             out_message_consumer_id,
                             connection_id,
                             opt_tls_config,
-                            ip_version
+                            connection_limiter,
+                            opt_source_slot: None,
+                            peer_ip,
+                            ip_version: IpVersion::canonical_from_ip(peer_ip),
                         };
 
                         runner.run(control_message_senders, stream).await;
 
-                        connection_handles.borrow_mut().remove(connection_id);
+                        connection_handles.borrow_mut().remove(&connection_id);
+
+                        drop(worker_slot);
                     }),
                     tq_regular,
                 )
@@ -230,6 +292,13 @@ async fn clean_connections(
         keep &= reference.valid_until.borrow().valid(now);
 
         if keep {
+            // Swarm workers remove peers that haven't announced for a while,
+            // so there is no need to tell them to do so when connection closes
+            reference
+                .announced_info_hashes
+                .borrow_mut()
+                .retain(|_, (_, valid_until)| valid_until.valid(now));
+
             true
         } else {
             if let Err(err) = reference.close_conn_sender.try_send(()) {
@@ -276,6 +345,31 @@ async fn clean_connections(
     ))
 }
 
+async fn reclaim_memory(
+    config: Rc<Config>,
+    connection_slab: Rc<RefCell<ConnectionHandles>>,
+    worker_index: usize,
+) -> Option<Duration> {
+    let mut connection_slab = connection_slab.borrow_mut();
+
+    for reference in connection_slab.values() {
+        reference.announced_info_hashes.borrow_mut().shrink_to_fit();
+
+        // Fails if previous request hasn't been handled yet, which is fine
+        let _ = reference.shrink_buffers_sender.try_send(());
+    }
+
+    // Connection ids are never reused, so they stay valid when the map
+    // is shrunk
+    connection_slab.shrink_to_fit();
+
+    drop(connection_slab);
+
+    reclaim_allocator_memory(&config, "socket", worker_index);
+
+    Some(Duration::from_secs(config.memory_reclamation.interval))
+}
+
 async fn receive_out_messages(
     mut out_message_receiver: ConnectedReceiver<(OutMessageMeta, OutMessage)>,
     connection_references: Rc<RefCell<ConnectionHandles>>,
@@ -283,7 +377,7 @@ async fn receive_out_messages(
     let connection_references = &connection_references;
 
     while let Some((meta, out_message)) = out_message_receiver.next().await {
-        if let Some(reference) = connection_references.borrow().get(meta.connection_id) {
+        if let Some(reference) = connection_references.borrow().get(&meta.connection_id) {
             match reference.out_message_sender.try_send((meta, out_message)) {
                 Ok(()) => {}
                 Err(GlommioError::Closed(_)) => {}
@@ -357,6 +451,6 @@ fn ip_version_to_metrics_str(ip_version: IpVersion) -> &'static str {
     }
 }
 
-fn calculate_in_message_consumer_index(config: &Config, info_hash: InfoHash) -> usize {
+pub(crate) fn calculate_in_message_consumer_index(config: &Config, info_hash: InfoHash) -> usize {
     (info_hash.0[0] as usize) % config.swarm_workers
 }
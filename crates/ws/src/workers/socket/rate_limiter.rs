@@ -0,0 +1,60 @@
+use std::time::Instant;
+
+use aquatic_common::token_bucket::{Limit, TokenBuckets};
+
+use crate::config::Config;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    Announce = 0,
+    Scrape = 1,
+    /// Announce message containing an answer
+    Answer = 2,
+}
+
+impl MessageType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Announce => "announce",
+            Self::Scrape => "scrape",
+            Self::Answer => "answer",
+        }
+    }
+}
+
+/// Per-connection token bucket rate limiter
+pub struct MessageRateLimiter {
+    limits: [Limit; 3],
+    buckets: TokenBuckets<3>,
+}
+
+impl MessageRateLimiter {
+    pub fn new(config: &Config, now: Instant) -> Self {
+        let config = &config.rate_limit;
+
+        let limits = [
+            Limit {
+                per_second: config.announce_messages_per_second,
+                burst: config.announce_burst,
+            },
+            Limit {
+                per_second: config.scrape_messages_per_second,
+                burst: config.scrape_burst,
+            },
+            Limit {
+                per_second: config.answer_messages_per_second,
+                burst: config.answer_burst,
+            },
+        ];
+
+        Self {
+            limits,
+            buckets: TokenBuckets::new(&limits, now),
+        }
+    }
+
+    /// Returns false if message exceeds limit
+    pub fn check(&mut self, message_type: MessageType, now: Instant) -> bool {
+        self.buckets.take(&self.limits, message_type as usize, now)
+    }
+}
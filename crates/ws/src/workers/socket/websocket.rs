@@ -0,0 +1,151 @@
+use std::cell::{Cell, RefCell};
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use async_tungstenite::WebSocketStream;
+use futures::future::poll_fn;
+use futures::{AsyncRead, AsyncWrite, SinkExt, StreamExt};
+use tungstenite::protocol::Role;
+use tungstenite::Message;
+
+/// Stream shared between a WebSocket and its replacement
+///
+/// Keeps track of whether any bytes were read or written since the activity
+/// flag was last cleared.
+pub struct SharedIo<S> {
+    stream: Rc<RefCell<S>>,
+    active: Rc<Cell<bool>>,
+}
+
+impl<S> SharedIo<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream: Rc::new(RefCell::new(stream)),
+            active: Default::default(),
+        }
+    }
+
+    fn record_activity(&self, poll: Poll<io::Result<usize>>) -> Poll<io::Result<usize>> {
+        if let Poll::Ready(Ok(1..)) = poll {
+            self.active.set(true);
+        }
+
+        poll
+    }
+}
+
+impl<S> Clone for SharedIo<S> {
+    fn clone(&self) -> Self {
+        Self {
+            stream: self.stream.clone(),
+            active: self.active.clone(),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SharedIo<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut *self.stream.borrow_mut()).poll_read(cx, buf);
+
+        self.record_activity(poll)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SharedIo<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut *self.stream.borrow_mut()).poll_write(cx, buf);
+
+        self.record_activity(poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.stream.borrow_mut()).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.stream.borrow_mut()).poll_close(cx)
+    }
+}
+
+/// WebSocket stream shared by connection reader and writer tasks
+///
+/// Unlike the halves returned by `StreamExt::split`, the stream can be
+/// rebuilt in place. This drops the tungstenite read and write buffers,
+/// which otherwise keep the capacity needed for the largest message of the
+/// connection for as long as it stays open.
+pub struct SharedWebSocket<S> {
+    ws: RefCell<WebSocketStream<SharedIo<S>>>,
+    sending: Cell<bool>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> SharedWebSocket<S> {
+    pub fn new(ws: WebSocketStream<SharedIo<S>>) -> Self {
+        Self {
+            ws: RefCell::new(ws),
+            sending: Cell::new(false),
+        }
+    }
+
+    pub async fn next(&self) -> Option<Result<Message, tungstenite::Error>> {
+        poll_fn(|cx| self.ws.borrow_mut().poll_next_unpin(cx)).await
+    }
+
+    pub async fn send(&self, message: Message) -> Result<(), tungstenite::Error> {
+        self.sending.set(true);
+
+        let result = async {
+            poll_fn(|cx| self.ws.borrow_mut().poll_ready_unpin(cx)).await?;
+
+            self.ws.borrow_mut().start_send_unpin(message)?;
+
+            poll_fn(|cx| self.ws.borrow_mut().poll_flush_unpin(cx)).await
+        }
+        .await;
+
+        self.sending.set(false);
+
+        result
+    }
+
+    /// Replace WebSocket with a fresh one if no bytes were read or written
+    /// since the previous call and no message is being sent
+    ///
+    /// Bytes of a partially received frame are discarded, but a peer would
+    /// have to stall in the middle of a frame for a whole reclamation
+    /// interval for that to happen. The connection then fails with a
+    /// protocol error.
+    ///
+    /// Returns true if the WebSocket was replaced.
+    pub async fn rebuild_if_idle(&self) -> bool {
+        let (io, config) = {
+            let ws = self.ws.borrow();
+
+            (ws.get_ref().clone(), *ws.get_config())
+        };
+
+        if io.active.replace(false) || self.sending.get() {
+            return false;
+        }
+
+        let ws = WebSocketStream::from_raw_socket(io.clone(), Role::Server, Some(config)).await;
+
+        // Check again in case other tasks ran while awaiting
+        if io.active.get() || self.sending.get() {
+            return false;
+        }
+
+        *self.ws.borrow_mut() = ws;
+
+        true
+    }
+}
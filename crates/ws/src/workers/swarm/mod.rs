@@ -1,8 +1,8 @@
 mod storage;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use futures::StreamExt;
 use glommio::channels::channel_mesh::{MeshBuilder, Partial, Role, Senders};
@@ -11,14 +11,16 @@ use glommio::prelude::*;
 use glommio::timer::TimerActionRepeat;
 use rand::{rngs::SmallRng, SeedableRng};
 
+use aquatic_common::admin::SwarmQueryReceiver;
 use aquatic_common::{PanicSentinel, ServerStartInstant};
 use aquatic_ws_protocol::*;
 
 use crate::common::*;
 use crate::config::Config;
+use crate::workers::memory::reclaim_allocator_memory;
 use crate::SHARED_IN_CHANNEL_SIZE;
 
-use self::storage::TorrentMaps;
+pub use self::storage::TorrentMaps;
 
 #[cfg(feature = "metrics")]
 thread_local! { static WORKER_INDEX: ::std::cell::Cell<usize> = Default::default() }
@@ -31,6 +33,8 @@ pub async fn run_swarm_worker(
     in_message_mesh_builder: MeshBuilder<(InMessageMeta, InMessage), Partial>,
     out_message_mesh_builder: MeshBuilder<(OutMessageMeta, OutMessage), Partial>,
     server_start_instant: ServerStartInstant,
+    torrents: TorrentMaps,
+    swarm_query_receiver: SwarmQueryReceiver,
     worker_index: usize,
 ) {
     #[cfg(feature = "metrics")]
@@ -46,8 +50,10 @@ pub async fn run_swarm_worker(
 
     let out_message_senders = Rc::new(out_message_senders);
 
-    let torrents = Rc::new(RefCell::new(TorrentMaps::default()));
+    let torrents = Rc::new(RefCell::new(torrents));
     let access_list = state.access_list;
+    let shutdown_snapshot_signal = state.shutdown_snapshot_signal;
+    let full_scrape = state.full_scrape;
 
     // Periodically clean torrents
     TimerActionRepeat::repeat(enclose!((config, torrents, access_list) move || {
@@ -58,6 +64,17 @@ pub async fn run_swarm_worker(
         })()
     }));
 
+    // Periodically reclaim memory. Torrent maps are shrunk when cleaning.
+    if config.memory_reclamation.enabled {
+        TimerActionRepeat::repeat(enclose!((config) move || {
+            enclose!((config) move || async move {
+                reclaim_allocator_memory(&config, "swarm", worker_index);
+
+                Some(Duration::from_secs(config.memory_reclamation.interval))
+            })()
+        }));
+    }
+
     // Periodically update torrent count metrics
     #[cfg(feature = "metrics")]
     TimerActionRepeat::repeat(enclose!((config, torrents) move || {
@@ -68,6 +85,66 @@ pub async fn run_swarm_worker(
         })()
     }));
 
+    // Periodically save snapshots. Also save one when requested on shutdown.
+    if config.snapshot.enabled {
+        let last_snapshot = Rc::new(Cell::new(Instant::now()));
+        let snapshot_interval = Duration::from_secs(config.snapshot.interval);
+
+        TimerActionRepeat::repeat(
+            enclose!((config, torrents, shutdown_snapshot_signal) move || {
+                enclose!((config, torrents, shutdown_snapshot_signal, last_snapshot) move || async move {
+                    if shutdown_snapshot_signal.requested() {
+                        torrents.borrow().save_snapshot(&config, server_start_instant, worker_index);
+                        shutdown_snapshot_signal.mark_done();
+
+                        return None;
+                    }
+
+                    if config.snapshot.interval != 0
+                        && last_snapshot.get().elapsed() >= snapshot_interval
+                    {
+                        torrents.borrow().save_snapshot(&config, server_start_instant, worker_index);
+                        last_snapshot.set(Instant::now());
+                    }
+
+                    Some(Duration::from_secs(1))
+                })()
+            }),
+        );
+    }
+
+    // Periodically publish data for full scrape responses
+    if config.full_scrape.enabled {
+        TimerActionRepeat::repeat(enclose!((config, torrents, full_scrape) move || {
+            enclose!((config, torrents, full_scrape) move || async move {
+                full_scrape.update(
+                    worker_index,
+                    torrents.borrow().full_scrape_part(&config),
+                    FullScrapeResponse::merge,
+                );
+
+                Some(Duration::from_secs(config.full_scrape.refresh_interval))
+            })()
+        }));
+    }
+
+    // Answer admin socket queries
+    if config.admin.enabled {
+        let swarm_query_receiver = Rc::new(swarm_query_receiver);
+
+        TimerActionRepeat::repeat(enclose!((torrents, swarm_query_receiver) move || {
+            enclose!((torrents, swarm_query_receiver) move || async move {
+                while let Ok(query_request) = swarm_query_receiver.try_recv() {
+                    let summaries = torrents.borrow().handle_swarm_query(query_request.query);
+
+                    query_request.respond(summaries);
+                }
+
+                Some(Duration::from_millis(100))
+            })()
+        }));
+    }
+
     let mut handles = Vec::new();
 
     for (_, receiver) in control_message_receivers.streams() {
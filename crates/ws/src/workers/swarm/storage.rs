@@ -1,8 +1,14 @@
 use std::sync::Arc;
 
 use aquatic_common::access_list::{create_access_list_cache, AccessListArcSwap, AccessListCache};
+use aquatic_common::admin::{top_torrents, SwarmQuery, TorrentSummary};
+use aquatic_common::snapshot::{
+    load_snapshot, save_snapshot, SnapshotDecoder, SnapshotEncoder, SnapshotHeader,
+    SnapshotProtocol,
+};
 use hashbrown::HashMap;
 use rand::rngs::SmallRng;
+use rand::Rng;
 
 use aquatic_common::{
     extract_response_peers, IndexMap, SecondsSinceServerStart, ServerStartInstant,
@@ -13,6 +19,9 @@ use crate::common::*;
 use crate::config::Config;
 use crate::workers::swarm::WORKER_INDEX;
 
+/// Bump when changing snapshot payload encoding
+const SNAPSHOT_FORMAT_VERSION: u16 = 3;
+
 type TorrentMap = IndexMap<InfoHash, TorrentData>;
 type PeerMap = IndexMap<PeerId, Peer>;
 
@@ -23,6 +32,46 @@ pub struct TorrentMaps {
 }
 
 impl TorrentMaps {
+    /// Load torrents from snapshot file, falling back to empty maps
+    pub fn load_snapshot(
+        config: &Config,
+        server_start_instant: ServerStartInstant,
+        worker_index: usize,
+    ) -> Self {
+        let header = Self::snapshot_header(config, worker_index);
+
+        load_snapshot(&config.snapshot, header, |decoder| {
+            Ok(Self {
+                ipv4: read_torrent_map_snapshot(decoder, server_start_instant)?,
+                ipv6: read_torrent_map_snapshot(decoder, server_start_instant)?,
+            })
+        })
+        .unwrap_or_default()
+    }
+
+    pub fn save_snapshot(
+        &self,
+        config: &Config,
+        server_start_instant: ServerStartInstant,
+        worker_index: usize,
+    ) {
+        let header = Self::snapshot_header(config, worker_index);
+
+        save_snapshot(&config.snapshot, header, |encoder| {
+            write_torrent_map_snapshot(encoder, &self.ipv4, server_start_instant);
+            write_torrent_map_snapshot(encoder, &self.ipv6, server_start_instant);
+        });
+    }
+
+    fn snapshot_header(config: &Config, worker_index: usize) -> SnapshotHeader {
+        SnapshotHeader::new(
+            SnapshotProtocol::Ws,
+            SNAPSHOT_FORMAT_VERSION,
+            worker_index,
+            config.swarm_workers,
+        )
+    }
+
     pub fn handle_announce_request(
         &mut self,
         config: &Config,
@@ -33,13 +82,22 @@ impl TorrentMaps {
         request_sender_meta: InMessageMeta,
         request: AnnounceRequest,
     ) {
-        let (torrent_data, ip_version): (&mut TorrentData, &'static str) =
+        let (torrent_map, other_torrent_map, ip_version): (_, _, &'static str) =
             if let IpVersion::V4 = request_sender_meta.ip_version {
-                (self.ipv4.entry(request.info_hash).or_default(), "4")
+                (&mut self.ipv4, &mut self.ipv6, "4")
             } else {
-                (self.ipv6.entry(request.info_hash).or_default(), "6")
+                (&mut self.ipv6, &mut self.ipv4, "6")
             };
 
+        let torrent_data = torrent_map.entry(request.info_hash).or_default();
+
+        // Swarm of other IP version, if it is joined with this one
+        let mut opt_other_torrent_data = if config.protocol.mixed_ip_version_swarms {
+            other_torrent_map.get_mut(&request.info_hash)
+        } else {
+            None
+        };
+
         // If there is already a peer with this peer_id, check that connection id
         // is same as that of request sender. Otherwise, ignore request. Since
         // peers have access to each others peer_id's, they could send requests
@@ -49,16 +107,33 @@ impl TorrentMaps {
                 return;
             }
         }
+        // In mixed swarms, the peer_id may also be in use in the swarm of the
+        // other IP version
+        if let Some(previous_peer) = opt_other_torrent_data
+            .as_ref()
+            .and_then(|other_torrent_data| other_torrent_data.peers.get(&request.peer_id))
+        {
+            if request_sender_meta.connection_id != previous_peer.connection_id {
+                return;
+            }
+        }
 
         ::log::trace!("received request from {:?}", request_sender_meta);
 
         // Insert/update/remove peer who sent this request
         {
+            let completed_event = request.event == Some(AnnounceEvent::Completed);
             let peer_status = PeerStatus::from_event_and_bytes_left(
                 request.event.unwrap_or_default(),
                 request.bytes_left,
             );
 
+            let previously_seeding = torrent_data
+                .peers
+                .get(&request.peer_id)
+                .map(|peer| peer.seeder)
+                .unwrap_or(false);
+
             match torrent_data.peers.entry(request.peer_id) {
                 ::indexmap::map::Entry::Occupied(mut entry) => match peer_status {
                     PeerStatus::Leeching => {
@@ -143,12 +218,50 @@ impl TorrentMaps {
                     PeerStatus::Stopped => return,
                 },
             }
+
+            // Ignore repeated completed events from seeders
+            if completed_event && !previously_seeding {
+                torrent_data.num_completed += 1;
+
+                #[cfg(feature = "metrics")]
+                ::metrics::increment_counter!(
+                    "aquatic_completed_downloads_total",
+                    "ip_version" => ip_version,
+                    "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+                );
+            }
         };
 
         // If peer sent offers, send them on to random peers
         if let Some(offers) = request.offers {
+            let max_expecting_answers = config.protocol.max_expecting_answers;
+
+            let offer_quota = if let Some(peer) = torrent_data.peers.get_mut(&request.peer_id) {
+                if peer.expecting_answers.len() >= max_expecting_answers {
+                    let now = server_start_instant.seconds_elapsed();
+
+                    peer.expecting_answers
+                        .retain(|_, valid_until| valid_until.valid(now));
+                }
+
+                max_expecting_answers.saturating_sub(peer.expecting_answers.len())
+            } else {
+                0
+            };
+
             // FIXME: config: also maybe check this when parsing request
-            let max_num_peers_to_take = offers.len().min(config.protocol.max_offers);
+            let num_offers = offers.len().min(config.protocol.max_offers);
+            let max_num_peers_to_take = num_offers.min(offer_quota);
+
+            #[cfg(feature = "metrics")]
+            if max_num_peers_to_take < num_offers {
+                ::metrics::counter!(
+                    "aquatic_offers_over_quota_total",
+                    (num_offers - max_num_peers_to_take) as u64,
+                    "ip_version" => ip_version,
+                    "worker_index" => WORKER_INDEX.with(|index| index.get()).to_string(),
+                );
+            }
 
             #[inline]
             fn convert_offer_receiver_peer(
@@ -158,13 +271,42 @@ impl TorrentMaps {
                 (*peer_id, peer.connection_id, peer.consumer_id)
             }
 
-            let offer_receivers: Vec<(PeerId, ConnectionId, ConsumerId)> = extract_response_peers(
-                rng,
-                &torrent_data.peers,
-                max_num_peers_to_take,
-                request.peer_id,
-                convert_offer_receiver_peer,
-            );
+            let offer_receivers: Vec<(PeerId, ConnectionId, ConsumerId)> =
+                if let Some(other_torrent_data) = opt_other_torrent_data.as_deref() {
+                    // Sender is in own swarm
+                    let (num_to_take, num_other_to_take) = split_num_peers_to_take(
+                        rng,
+                        torrent_data.peers.len().saturating_sub(1),
+                        other_torrent_data.peers.len(),
+                        max_num_peers_to_take,
+                    );
+
+                    let mut offer_receivers = extract_response_peers(
+                        rng,
+                        &torrent_data.peers,
+                        num_to_take,
+                        request.peer_id,
+                        convert_offer_receiver_peer,
+                    );
+
+                    offer_receivers.extend(extract_response_peers(
+                        rng,
+                        &other_torrent_data.peers,
+                        num_other_to_take,
+                        request.peer_id,
+                        convert_offer_receiver_peer,
+                    ));
+
+                    offer_receivers
+                } else {
+                    extract_response_peers(
+                        rng,
+                        &torrent_data.peers,
+                        max_num_peers_to_take,
+                        request.peer_id,
+                        convert_offer_receiver_peer,
+                    )
+                };
 
             if let Some(peer) = torrent_data.peers.get_mut(&request.peer_id) {
                 for (
@@ -210,7 +352,14 @@ impl TorrentMaps {
             request.answer_to_peer_id,
             request.answer_offer_id,
         ) {
-            if let Some(answer_receiver) = torrent_data.peers.get_mut(&answer_receiver_id) {
+            let opt_answer_receiver = match torrent_data.peers.get_mut(&answer_receiver_id) {
+                Some(answer_receiver) => Some(answer_receiver),
+                None => opt_other_torrent_data
+                    .as_mut()
+                    .and_then(|torrent_data| torrent_data.peers.get_mut(&answer_receiver_id)),
+            };
+
+            if let Some(answer_receiver) = opt_answer_receiver {
                 let expecting_answer = ExpectingAnswer {
                     from_peer_id: request.peer_id,
                     regarding_offer_id: offer_id,
@@ -250,17 +399,92 @@ impl TorrentMaps {
             }
         }
 
+        let mut stats = torrent_data.scrape_statistics();
+
+        if let Some(other_torrent_data) = opt_other_torrent_data {
+            stats = combine_scrape_statistics(stats, other_torrent_data.scrape_statistics());
+        }
+
         let out_message = OutMessage::AnnounceResponse(AnnounceResponse {
             action: AnnounceAction::Announce,
             info_hash: request.info_hash,
-            complete: torrent_data.num_seeders,
-            incomplete: torrent_data.num_leechers(),
+            complete: stats.complete,
+            incomplete: stats.incomplete,
             announce_interval: config.protocol.peer_announce_interval,
         });
 
         out_messages.push((request_sender_meta.into(), out_message));
     }
 
+    pub fn full_scrape_part(&self, config: &Config) -> FullScrapePart {
+        if config.protocol.mixed_ip_version_swarms {
+            let stats = Arc::new(Self::mixed_full_scrape_statistics(&self.ipv4, &self.ipv6));
+
+            FullScrapePart {
+                ipv4: stats.clone(),
+                ipv6: stats,
+            }
+        } else {
+            FullScrapePart {
+                ipv4: Arc::new(Self::full_scrape_statistics(&self.ipv4)),
+                ipv6: Arc::new(Self::full_scrape_statistics(&self.ipv6)),
+            }
+        }
+    }
+
+    fn full_scrape_statistics(torrent_map: &TorrentMap) -> Vec<(InfoHash, ScrapeStatistics)> {
+        torrent_map
+            .iter()
+            .map(|(info_hash, torrent_data)| (*info_hash, torrent_data.scrape_statistics()))
+            .collect()
+    }
+
+    fn mixed_full_scrape_statistics(
+        ipv4: &TorrentMap,
+        ipv6: &TorrentMap,
+    ) -> Vec<(InfoHash, ScrapeStatistics)> {
+        let mut stats = Self::full_scrape_statistics(ipv4);
+
+        for (info_hash, stats) in stats.iter_mut() {
+            if let Some(torrent_data) = ipv6.get(info_hash) {
+                *stats = combine_scrape_statistics(stats.clone(), torrent_data.scrape_statistics());
+            }
+        }
+
+        stats.extend(
+            ipv6.iter()
+                .filter(|(info_hash, _)| !ipv4.contains_key(*info_hash))
+                .map(|(info_hash, torrent_data)| (*info_hash, torrent_data.scrape_statistics())),
+        );
+
+        stats
+    }
+
+    pub fn handle_swarm_query(&self, query: SwarmQuery) -> Vec<TorrentSummary> {
+        match query {
+            SwarmQuery::Torrent(info_hash) => {
+                let info_hash = InfoHash(info_hash);
+
+                let summaries = [
+                    self.ipv4.get(&info_hash).map(|t| t.summary(info_hash)),
+                    self.ipv6.get(&info_hash).map(|t| t.summary(info_hash)),
+                ];
+
+                top_torrents(summaries.into_iter().flatten(), 1)
+            }
+            SwarmQuery::TopTorrents(limit) => top_torrents(
+                Self::torrent_summaries(&self.ipv4).chain(Self::torrent_summaries(&self.ipv6)),
+                limit,
+            ),
+        }
+    }
+
+    fn torrent_summaries(torrent_map: &TorrentMap) -> impl Iterator<Item = TorrentSummary> + '_ {
+        torrent_map
+            .iter()
+            .map(|(info_hash, torrent_data)| torrent_data.summary(*info_hash))
+    }
+
     pub fn handle_scrape_request(
         &mut self,
         config: &Config,
@@ -281,20 +505,33 @@ impl TorrentMaps {
             files: HashMap::with_capacity(num_to_take),
         };
 
-        let torrent_map: &mut TorrentMap = if let IpVersion::V4 = meta.ip_version {
-            &mut self.ipv4
+        let (torrent_map, other_torrent_map) = if let IpVersion::V4 = meta.ip_version {
+            (&self.ipv4, &self.ipv6)
         } else {
-            &mut self.ipv6
+            (&self.ipv6, &self.ipv4)
         };
 
+        let opt_other_torrent_map = config
+            .protocol
+            .mixed_ip_version_swarms
+            .then_some(other_torrent_map);
+
         for info_hash in info_hashes.into_iter().take(num_to_take) {
-            if let Some(torrent_data) = torrent_map.get(&info_hash) {
-                let stats = ScrapeStatistics {
-                    complete: torrent_data.num_seeders,
-                    downloaded: 0, // No implementation planned
-                    incomplete: torrent_data.num_leechers(),
-                };
+            let opt_stats = torrent_map
+                .get(&info_hash)
+                .map(TorrentData::scrape_statistics);
+            let opt_other_stats = opt_other_torrent_map
+                .and_then(|torrent_map| torrent_map.get(&info_hash))
+                .map(TorrentData::scrape_statistics);
+
+            let opt_stats = match (opt_stats, opt_other_stats) {
+                (Some(stats), Some(other_stats)) => {
+                    Some(combine_scrape_statistics(stats, other_stats))
+                }
+                (opt_stats, opt_other_stats) => opt_stats.or(opt_other_stats),
+            };
 
+            if let Some(stats) = opt_stats {
                 out_message.files.insert(info_hash, stats);
             }
         }
@@ -352,7 +589,7 @@ impl TorrentMaps {
 
             torrent_data.peers.shrink_to_fit();
 
-            !torrent_data.peers.is_empty()
+            torrent_data.keep_after_cleaning(config, now)
         });
 
         torrent_map.shrink_to_fit();
@@ -420,9 +657,69 @@ impl TorrentMaps {
     }
 }
 
+fn write_torrent_map_snapshot(
+    encoder: &mut SnapshotEncoder,
+    torrent_map: &TorrentMap,
+    server_start_instant: ServerStartInstant,
+) {
+    // Peers are tied to connections, which don't survive restarts, so only
+    // torrents with completed counts are saved
+    let torrents = || {
+        torrent_map
+            .iter()
+            .filter(|(_, torrent_data)| torrent_data.num_completed != 0)
+    };
+
+    encoder.write_len(torrents().count());
+
+    for (info_hash, torrent_data) in torrents() {
+        encoder.write_bytes(&info_hash.0);
+        encoder.write_u64(torrent_data.num_completed as u64);
+
+        if let Some(idle_valid_until) = torrent_data.idle_valid_until {
+            encoder.write_bool(true);
+            encoder.write_valid_until(idle_valid_until, server_start_instant);
+        } else {
+            encoder.write_bool(false);
+        }
+    }
+}
+
+fn read_torrent_map_snapshot(
+    decoder: &mut SnapshotDecoder,
+    server_start_instant: ServerStartInstant,
+) -> anyhow::Result<TorrentMap> {
+    let num_torrents = decoder.read_len(20)?;
+
+    let mut torrent_map = TorrentMap::with_capacity_and_hasher(num_torrents, Default::default());
+
+    for _ in 0..num_torrents {
+        let info_hash = InfoHash(decoder.read_array()?);
+        let num_completed = decoder.read_u64()? as usize;
+        let idle_valid_until = if decoder.read_bool()? {
+            Some(decoder.read_valid_until(server_start_instant)?)
+        } else {
+            None
+        };
+
+        let torrent_data = TorrentData {
+            num_completed,
+            idle_valid_until,
+            ..Default::default()
+        };
+
+        torrent_map.insert(info_hash, torrent_data);
+    }
+
+    Ok(torrent_map)
+}
+
 struct TorrentData {
     peers: PeerMap,
     num_seeders: usize,
+    num_completed: usize,
+    /// Set when torrent without peers is kept because of its completed count
+    idle_valid_until: Option<ValidUntil>,
 }
 
 impl Default for TorrentData {
@@ -431,6 +728,8 @@ impl Default for TorrentData {
         Self {
             peers: Default::default(),
             num_seeders: 0,
+            num_completed: 0,
+            idle_valid_until: None,
         }
     }
 }
@@ -447,6 +746,80 @@ impl TorrentData {
     fn num_leechers(&self) -> usize {
         self.peers.len() - self.num_seeders
     }
+
+    fn scrape_statistics(&self) -> ScrapeStatistics {
+        ScrapeStatistics {
+            complete: self.num_seeders,
+            downloaded: self.num_completed,
+            incomplete: self.num_leechers(),
+        }
+    }
+
+    fn summary(&self, info_hash: InfoHash) -> TorrentSummary {
+        TorrentSummary {
+            info_hash: info_hash.0,
+            seeders: self.num_seeders,
+            leechers: self.num_leechers(),
+            completed: self.num_completed,
+        }
+    }
+
+    /// Returns true if torrent should be kept after its peers were cleaned
+    /// (see `cleaning.max_torrent_idle_age`)
+    fn keep_after_cleaning(&mut self, config: &Config, now: SecondsSinceServerStart) -> bool {
+        if !self.peers.is_empty() {
+            self.idle_valid_until = None;
+
+            true
+        } else if self.num_completed == 0 {
+            false
+        } else if let Some(valid_until) = self.idle_valid_until {
+            valid_until.valid(now)
+        } else {
+            self.idle_valid_until = Some(ValidUntil::new_with_now(
+                now,
+                config.cleaning.max_torrent_idle_age,
+            ));
+
+            true
+        }
+    }
+}
+
+fn combine_scrape_statistics(a: ScrapeStatistics, b: ScrapeStatistics) -> ScrapeStatistics {
+    ScrapeStatistics {
+        complete: a.complete + b.complete,
+        downloaded: a.downloaded + b.downloaded,
+        incomplete: a.incomplete + b.incomplete,
+    }
+}
+
+/// Randomly split number of peers to take between two swarms, in proportion
+/// to their sizes
+fn split_num_peers_to_take(
+    rng: &mut impl Rng,
+    num_peers_a: usize,
+    num_peers_b: usize,
+    num_to_take: usize,
+) -> (usize, usize) {
+    let mut remaining_a = num_peers_a;
+    let mut remaining_b = num_peers_b;
+
+    for _ in 0..num_to_take {
+        let remaining = remaining_a + remaining_b;
+
+        if remaining == 0 {
+            break;
+        }
+
+        if rng.gen_range(0..remaining) < remaining_a {
+            remaining_a -= 1;
+        } else {
+            remaining_b -= 1;
+        }
+    }
+
+    (num_peers_a - remaining_a, num_peers_b - remaining_b)
 }
 
 #[derive(Clone, Debug)]
@@ -486,3 +859,388 @@ impl PeerStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn peer_id(i: u8) -> PeerId {
+        PeerId([i; 20])
+    }
+
+    fn offer_id(i: u8) -> OfferId {
+        OfferId([i; 20])
+    }
+
+    fn announce_request(peer_id: PeerId, offer_ids: &[OfferId]) -> AnnounceRequest {
+        let offers = offer_ids
+            .iter()
+            .map(|offer_id| AnnounceRequestOffer {
+                offer_id: *offer_id,
+                offer: RtcOffer {
+                    t: RtcOfferType::Offer,
+                    sdp: "offer".into(),
+                },
+            })
+            .collect::<Vec<_>>();
+
+        AnnounceRequest {
+            action: AnnounceAction::Announce,
+            info_hash: InfoHash([0; 20]),
+            peer_id,
+            bytes_left: Some(1),
+            event: None,
+            numwant: Some(offers.len()),
+            offers: Some(offers),
+            answer: None,
+            answer_to_peer_id: None,
+            answer_offer_id: None,
+        }
+    }
+
+    struct Swarm {
+        config: Config,
+        rng: SmallRng,
+        server_start_instant: ServerStartInstant,
+        next_connection_id: u64,
+        torrent_maps: TorrentMaps,
+    }
+
+    impl Swarm {
+        fn new(config: Config) -> Self {
+            Self {
+                config,
+                rng: SmallRng::seed_from_u64(0),
+                server_start_instant: ServerStartInstant::new(),
+                next_connection_id: 0,
+                torrent_maps: Default::default(),
+            }
+        }
+
+        fn meta(&mut self) -> InMessageMeta {
+            self.next_connection_id += 1;
+
+            InMessageMeta {
+                out_message_consumer_id: ConsumerId(0),
+                connection_id: ConnectionId(self.next_connection_id),
+                ip_version: IpVersion::V4,
+                pending_scrape_id: None,
+            }
+        }
+
+        fn announce(
+            &mut self,
+            meta: InMessageMeta,
+            request: AnnounceRequest,
+        ) -> Vec<(OutMessageMeta, OutMessage)> {
+            let mut out_messages = Vec::new();
+
+            self.torrent_maps.handle_announce_request(
+                &self.config,
+                &mut self.rng,
+                &mut out_messages,
+                self.server_start_instant,
+                ValidUntil::new(self.server_start_instant, 120),
+                meta,
+                request,
+            );
+
+            out_messages
+        }
+
+        /// Announce with offers and return forwarded offers as (receiver peer
+        /// id, offer id)
+        fn forwarded_offers(
+            &mut self,
+            meta: InMessageMeta,
+            request: AnnounceRequest,
+        ) -> Vec<(PeerId, OfferId)> {
+            let offer_receivers: Vec<(ConnectionId, PeerId)> = self.torrent_maps.ipv4
+                [&request.info_hash]
+                .peers
+                .iter()
+                .map(|(peer_id, peer)| (peer.connection_id, *peer_id))
+                .collect();
+
+            self.announce(meta, request)
+                .into_iter()
+                .filter_map(|(meta, message)| match message {
+                    OutMessage::OfferOutMessage(message) => {
+                        let (_, receiver) = offer_receivers
+                            .iter()
+                            .find(|(connection_id, _)| *connection_id == meta.connection_id)
+                            .unwrap();
+
+                        Some((*receiver, message.offer_id))
+                    }
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_offer_quota() {
+        let mut config = Config::default();
+
+        config.protocol.max_expecting_answers = 2;
+
+        let mut swarm = Swarm::new(config);
+
+        for i in 1..=5 {
+            let meta = swarm.meta();
+
+            swarm.announce(meta, announce_request(peer_id(i), &[]));
+        }
+
+        let meta = swarm.meta();
+        let offer_ids = [offer_id(1), offer_id(2), offer_id(3)];
+
+        let forwarded = swarm.forwarded_offers(meta, announce_request(peer_id(0), &offer_ids));
+
+        assert_eq!(forwarded.len(), 2);
+
+        // Quota is used up until answers arrive
+        assert!(swarm
+            .forwarded_offers(meta, announce_request(peer_id(0), &offer_ids))
+            .is_empty());
+
+        // Answering frees up quota
+        let (answering_peer_id, answered_offer_id) = forwarded[0];
+
+        let mut answer_request = announce_request(answering_peer_id, &[]);
+
+        answer_request.offers = None;
+        answer_request.answer = Some(RtcAnswer {
+            t: RtcAnswerType::Answer,
+            sdp: "answer".into(),
+        });
+        answer_request.answer_to_peer_id = Some(peer_id(0));
+        answer_request.answer_offer_id = Some(answered_offer_id);
+
+        let answering_meta = InMessageMeta {
+            connection_id: swarm.torrent_maps.ipv4[&InfoHash([0; 20])].peers[&answering_peer_id]
+                .connection_id,
+            ..swarm.meta()
+        };
+
+        let out_messages = swarm.announce(answering_meta, answer_request);
+
+        assert!(out_messages
+            .iter()
+            .any(|(_, message)| matches!(message, OutMessage::AnswerOutMessage(_))));
+
+        assert_eq!(
+            swarm
+                .forwarded_offers(meta, announce_request(peer_id(0), &offer_ids))
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_offer_quota_expired_offers() {
+        let mut config = Config::default();
+
+        config.protocol.max_expecting_answers = 2;
+        config.cleaning.max_offer_age = 0;
+
+        let mut swarm = Swarm::new(config);
+
+        for i in 1..=5 {
+            let meta = swarm.meta();
+
+            swarm.announce(meta, announce_request(peer_id(i), &[]));
+        }
+
+        let meta = swarm.meta();
+        let offer_ids = [offer_id(1), offer_id(2), offer_id(3)];
+
+        // Expired offers don't count towards quota
+        for _ in 0..3 {
+            assert_eq!(
+                swarm
+                    .forwarded_offers(meta, announce_request(peer_id(0), &offer_ids))
+                    .len(),
+                2
+            );
+        }
+    }
+
+    fn torrent_data(num_seeders: u8, num_leechers: u8, num_completed: usize) -> TorrentData {
+        let valid_until = ValidUntil::new(ServerStartInstant::new(), 120);
+
+        let peers = (0..num_seeders + num_leechers)
+            .map(|i| {
+                let peer = Peer {
+                    consumer_id: ConsumerId(0),
+                    connection_id: ConnectionId(i.into()),
+                    seeder: i < num_seeders,
+                    valid_until,
+                    expecting_answers: Default::default(),
+                };
+
+                (peer_id(i), peer)
+            })
+            .collect();
+
+        TorrentData {
+            peers,
+            num_seeders: num_seeders.into(),
+            num_completed,
+            idle_valid_until: None,
+        }
+    }
+
+    fn scrape_statistics(
+        complete: usize,
+        incomplete: usize,
+        downloaded: usize,
+    ) -> ScrapeStatistics {
+        ScrapeStatistics {
+            complete,
+            incomplete,
+            downloaded,
+        }
+    }
+
+    #[test]
+    fn test_combine_scrape_statistics() {
+        assert_eq!(
+            combine_scrape_statistics(scrape_statistics(1, 2, 3), scrape_statistics(4, 5, 6)),
+            scrape_statistics(5, 7, 9)
+        );
+    }
+
+    #[test]
+    fn test_full_scrape_part() {
+        let mut torrent_maps = TorrentMaps::default();
+
+        torrent_maps
+            .ipv4
+            .insert(InfoHash([1; 20]), torrent_data(1, 2, 3));
+        torrent_maps
+            .ipv4
+            .insert(InfoHash([2; 20]), torrent_data(0, 1, 0));
+        torrent_maps
+            .ipv6
+            .insert(InfoHash([1; 20]), torrent_data(2, 0, 1));
+        torrent_maps
+            .ipv6
+            .insert(InfoHash([3; 20]), torrent_data(1, 1, 5));
+
+        let mut config = Config::default();
+
+        let part = torrent_maps.full_scrape_part(&config);
+
+        assert_eq!(
+            *part.ipv4,
+            vec![
+                (InfoHash([1; 20]), scrape_statistics(1, 2, 3)),
+                (InfoHash([2; 20]), scrape_statistics(0, 1, 0)),
+            ]
+        );
+        assert_eq!(
+            *part.ipv6,
+            vec![
+                (InfoHash([1; 20]), scrape_statistics(2, 0, 1)),
+                (InfoHash([3; 20]), scrape_statistics(1, 1, 5)),
+            ]
+        );
+
+        config.protocol.mixed_ip_version_swarms = true;
+
+        let part = torrent_maps.full_scrape_part(&config);
+
+        assert_eq!(
+            *part.ipv4,
+            vec![
+                (InfoHash([1; 20]), scrape_statistics(3, 2, 4)),
+                (InfoHash([2; 20]), scrape_statistics(0, 1, 0)),
+                (InfoHash([3; 20]), scrape_statistics(1, 1, 5)),
+            ]
+        );
+        assert!(Arc::ptr_eq(&part.ipv4, &part.ipv6));
+    }
+
+    #[test]
+    fn test_mixed_swarm_peer_id_from_other_connection() {
+        let mut config = Config::default();
+
+        config.protocol.mixed_ip_version_swarms = true;
+
+        let mut swarm = Swarm::new(config);
+
+        let meta = swarm.meta();
+
+        swarm.announce(meta, announce_request(peer_id(1), &[]));
+
+        // Request using same peer_id from connection of other IP version is
+        // ignored
+        let other_meta = InMessageMeta {
+            ip_version: IpVersion::V6,
+            ..swarm.meta()
+        };
+
+        let mut request = announce_request(peer_id(1), &[]);
+
+        request.bytes_left = Some(0);
+
+        assert!(swarm.announce(other_meta, request).is_empty());
+
+        let info_hash = InfoHash([0; 20]);
+
+        assert!(!swarm.torrent_maps.ipv4[&info_hash].peers[&peer_id(1)].seeder);
+        assert!(swarm
+            .torrent_maps
+            .ipv6
+            .get(&info_hash)
+            .map_or(true, |torrent_data| torrent_data.peers.is_empty()));
+    }
+
+    #[test]
+    fn test_split_num_peers_to_take() {
+        let mut rng = SmallRng::seed_from_u64(0);
+
+        // Takes all available peers when there are not enough
+        assert_eq!(split_num_peers_to_take(&mut rng, 3, 4, 10), (3, 4));
+        assert_eq!(split_num_peers_to_take(&mut rng, 0, 4, 10), (0, 4));
+        assert_eq!(split_num_peers_to_take(&mut rng, 3, 0, 10), (3, 0));
+        assert_eq!(split_num_peers_to_take(&mut rng, 0, 0, 10), (0, 0));
+
+        // Takes only from non-empty swarm
+        assert_eq!(split_num_peers_to_take(&mut rng, 0, 100, 10), (0, 10));
+        assert_eq!(split_num_peers_to_take(&mut rng, 100, 0, 10), (10, 0));
+
+        for (num_peers_a, num_peers_b, num_to_take) in
+            [(1, 1, 1), (5, 3, 4), (100, 1, 50), (10, 10, 19)]
+        {
+            for _ in 0..100 {
+                let (a, b) =
+                    split_num_peers_to_take(&mut rng, num_peers_a, num_peers_b, num_to_take);
+
+                assert_eq!(a + b, num_to_take);
+                assert!(a <= num_peers_a);
+                assert!(b <= num_peers_b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_num_peers_to_take_proportional() {
+        let mut rng = SmallRng::seed_from_u64(0);
+
+        let iterations = 1000;
+
+        let total_a: usize = (0..iterations)
+            .map(|_| split_num_peers_to_take(&mut rng, 300, 100, 40).0)
+            .sum();
+
+        // Expected average is 30 peers from a
+        let average_a = total_a as f64 / iterations as f64;
+
+        assert!((29.0..31.0).contains(&average_a), "{}", average_a);
+    }
+}
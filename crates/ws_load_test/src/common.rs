@@ -4,6 +4,38 @@ use rand_distr::Gamma;
 
 pub use aquatic_ws_protocol::*;
 
+/// Data channel offer SDP of typical size, passing tracker validation
+pub const OFFER_SDP: &str = "v=0\r\n\
+    o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+    s=-\r\n\
+    t=0 0\r\n\
+    a=group:BUNDLE 0\r\n\
+    m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+    c=IN IP4 0.0.0.0\r\n\
+    a=candidate:842163049 1 udp 1677729535 192.0.2.1 54400 typ srflx raddr 0.0.0.0 rport 0\r\n\
+    a=ice-ufrag:Wf3C\r\n\
+    a=ice-pwd:9+nD1o5VW2bqXjFlPmXbMZ3W\r\n\
+    a=fingerprint:sha-256 3C:4A:2B:1D:0E:9F:8A:7B:6C:5D:4E:3F:2A:1B:0C:9D:8E:7F:6A:5B:4C:3D:2E:1F:0A:9B:8C:7D:6E:5F:4A:3B\r\n\
+    a=setup:actpass\r\n\
+    a=mid:0\r\n\
+    a=sctp-port:5000\r\n";
+
+/// Data channel answer SDP of typical size, passing tracker validation
+pub const ANSWER_SDP: &str = "v=0\r\n\
+    o=- 2894723912884569270 2 IN IP4 127.0.0.1\r\n\
+    s=-\r\n\
+    t=0 0\r\n\
+    a=group:BUNDLE 0\r\n\
+    m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+    c=IN IP4 0.0.0.0\r\n\
+    a=candidate:1467250027 1 udp 1677729535 192.0.2.2 61000 typ srflx raddr 0.0.0.0 rport 0\r\n\
+    a=ice-ufrag:Xk9p\r\n\
+    a=ice-pwd:Q2y7tH4mZ1wVb8nLc3rD6sFe\r\n\
+    a=fingerprint:sha-256 7B:6C:5D:4E:3F:2A:1B:0C:9D:8E:7F:6A:5B:4C:3D:2E:1F:0A:9B:8C:7D:6E:5F:4A:3B:3C:4A:2B:1D:0E:9F:8A\r\n\
+    a=setup:active\r\n\
+    a=mid:0\r\n\
+    a=sctp-port:5000\r\n";
+
 #[derive(Default)]
 pub struct Statistics {
     pub requests: AtomicUsize,
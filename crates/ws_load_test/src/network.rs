@@ -14,7 +14,11 @@ use glommio::net::TcpStream;
 use glommio::{prelude::*, timer::TimerActionRepeat};
 use rand::{prelude::SmallRng, Rng, SeedableRng};
 
-use crate::{common::LoadTestState, config::Config, utils::create_random_request};
+use crate::{
+    common::{LoadTestState, ANSWER_SDP},
+    config::Config,
+    utils::create_random_request,
+};
 
 pub async fn run_socket_thread(
     config: Config,
@@ -136,7 +140,7 @@ impl Connection {
                         r.answer_offer_id = Some(offer_id);
                         r.answer = Some(RtcAnswer {
                             t: RtcAnswerType::Answer,
-                            sdp: "abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-".into()
+                            sdp: ANSWER_SDP.into(),
                         });
                         r.event = None;
                         r.offers = None;
@@ -210,7 +214,7 @@ impl Connection {
 
                 self.can_send = true;
             }
-            Ok(OutMessage::ScrapeResponse(_)) => {
+            Ok(OutMessage::ScrapeResponse(_) | OutMessage::EncodedScrapeResponse(_)) => {
                 self.load_test_state
                     .statistics
                     .responses_scrape
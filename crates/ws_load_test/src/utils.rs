@@ -57,7 +57,7 @@ fn create_announce_request(
                 offer_id: OfferId(rng.gen()),
                 offer: RtcOffer {
                     t: RtcOfferType::Offer,
-                    sdp: "abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-abcdefg-".into()
+                    sdp: OFFER_SDP.into(),
                 },
             })
         }
@@ -0,0 +1,115 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use serde::Serialize;
+use std::time::Duration;
+
+use aquatic_ws_protocol::*;
+
+/// Offer message with SDP stored as String, as before SDPs were shared and
+/// pre-encoded. The SDP is copied and escaped for each recipient.
+#[derive(Clone, Serialize)]
+struct StringSdpOfferOutMessage {
+    action: AnnounceAction,
+    peer_id: PeerId,
+    info_hash: InfoHash,
+    offer: StringSdpRtcOffer,
+    offer_id: OfferId,
+}
+
+#[derive(Clone, Serialize)]
+struct StringSdpRtcOffer {
+    #[serde(rename = "type")]
+    t: RtcOfferType,
+    sdp: String,
+}
+
+pub fn bench(c: &mut Criterion) {
+    let info_hash = InfoHash([
+        b'a', b'b', b'c', b'd', b'e', b'?', b'\n', b'1', b'2', b'3', 0, 1, 2, 3, 4, 0, 1, 2, 3, 4,
+    ]);
+
+    // Data channel offer with a typical number of candidates
+    let mut sdp = String::from(
+        "v=0\r\n\
+        o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+        s=-\r\n\
+        t=0 0\r\n\
+        a=group:BUNDLE 0\r\n\
+        a=extmap-allow-mixed\r\n\
+        a=msid-semantic: WMS\r\n\
+        m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+        c=IN IP4 0.0.0.0\r\n",
+    );
+
+    for i in 0..16 {
+        sdp.push_str(&format!(
+            "a=candidate:{} 1 udp 1677729535 192.0.2.{} {} typ srflx raddr 0.0.0.0 rport 0 generation 0 network-cost 999\r\n",
+            842163049 + i,
+            i + 1,
+            50000 + i
+        ));
+    }
+
+    sdp.push_str(
+        "a=ice-ufrag:Wf3C\r\n\
+        a=ice-pwd:9+nD1o5VW2bqXjFlPmXbMZ3W\r\n\
+        a=ice-options:trickle\r\n\
+        a=fingerprint:sha-256 3C:4A:2B:1D:0E:9F:8A:7B:6C:5D:4E:3F:2A:1B:0C:9D:8E:7F:6A:5B:4C:3D:2E:1F:0A:9B:8C:7D:6E:5F:4A:3B\r\n\
+        a=setup:actpass\r\n\
+        a=mid:0\r\n\
+        a=sctp-port:5000\r\n\
+        a=max-message-size:262144\r\n",
+    );
+
+    let string_sdp_message = StringSdpOfferOutMessage {
+        action: AnnounceAction::Announce,
+        peer_id: PeerId(info_hash.0),
+        info_hash,
+        offer: StringSdpRtcOffer {
+            t: RtcOfferType::Offer,
+            sdp: sdp.clone(),
+        },
+        offer_id: OfferId(info_hash.0),
+    };
+
+    let out_message = OutMessage::OfferOutMessage(OfferOutMessage {
+        action: AnnounceAction::Announce,
+        peer_id: PeerId(info_hash.0),
+        info_hash,
+        offer: RtcOffer {
+            t: RtcOfferType::Offer,
+            sdp: sdp.into(),
+        },
+        offer_id: OfferId(info_hash.0),
+    });
+
+    assert_eq!(
+        ::serde_json::to_string(&string_sdp_message).unwrap(),
+        ::serde_json::to_string(&out_message).unwrap()
+    );
+
+    let mut group = c.benchmark_group("serialize-offer-out-message");
+
+    // Message is cloned as when sending it to a recipient
+    group.bench_function("string-sdp", |b| {
+        b.iter(|| {
+            let message = black_box(&string_sdp_message).clone();
+
+            tungstenite::Message::from(::serde_json::to_string(&message).unwrap())
+        })
+    });
+    group.bench_function("pre-encoded-sdp", |b| {
+        b.iter(|| black_box(&out_message).clone().to_ws_message())
+    });
+
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default()
+        .sample_size(1000)
+        .measurement_time(Duration::from_secs(180))
+        .significance_level(0.01);
+    targets = bench
+}
+criterion_main!(benches);
@@ -1,4 +1,9 @@
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+
 use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::value::RawValue;
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(transparent)]
@@ -66,7 +71,7 @@ pub struct RtcOffer {
     /// Always "offer"
     #[serde(rename = "type")]
     pub t: RtcOfferType,
-    pub sdp: String,
+    pub sdp: Sdp,
 }
 
 /// Nested structure with SDP answer from https://www.npmjs.com/package/simple-peer
@@ -77,7 +82,98 @@ pub struct RtcAnswer {
     /// Always "answer"
     #[serde(rename = "type")]
     pub t: RtcAnswerType,
-    pub sdp: String,
+    pub sdp: Sdp,
+}
+
+/// Session description of WebRTC offer or answer
+///
+/// Reference counted, so that it can be passed on from request to outgoing
+/// message without being copied. The JSON-escaped form is created once and
+/// spliced into serialized messages as-is.
+#[derive(Debug, Clone)]
+pub struct Sdp {
+    text: Arc<str>,
+    encoded: Arc<RawValue>,
+}
+
+impl Sdp {
+    pub fn new(text: Arc<str>) -> Self {
+        let encoded = ::serde_json::value::to_raw_value(&*text).unwrap().into();
+
+        Self { text, encoded }
+    }
+}
+
+impl Deref for Sdp {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.text
+    }
+}
+
+impl PartialEq for Sdp {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+    }
+}
+
+impl Eq for Sdp {}
+
+impl Hash for Sdp {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.text.hash(state)
+    }
+}
+
+impl From<&str> for Sdp {
+    fn from(sdp: &str) -> Self {
+        Self::new(sdp.into())
+    }
+}
+
+impl From<String> for Sdp {
+    fn from(sdp: String) -> Self {
+        Self::new(sdp.into())
+    }
+}
+
+impl Serialize for Sdp {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.encoded.serialize(serializer)
+    }
+}
+
+struct SdpVisitor;
+
+impl<'de> Visitor<'de> for SdpVisitor {
+    type Value = Sdp;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("string")
+    }
+
+    /// Copy directly from (unescaped) input without intermediate String
+    #[inline]
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: ::serde::de::Error,
+    {
+        Ok(value.into())
+    }
+}
+
+impl<'de> Deserialize<'de> for Sdp {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(SdpVisitor)
+    }
 }
 
 fn serialize_20_bytes<S>(data: &[u8; 20], serializer: S) -> Result<S::Ok, S::Error>
@@ -149,7 +245,7 @@ where
 mod tests {
     use quickcheck_macros::quickcheck;
 
-    use crate::common::InfoHash;
+    use crate::common::{InfoHash, Sdp};
 
     fn info_hash_from_bytes(bytes: &[u8]) -> InfoHash {
         let mut arr = [0u8; 20];
@@ -200,6 +296,19 @@ mod tests {
         assert_eq!(info_hash, info_hash_2);
     }
 
+    #[test]
+    fn test_serde_sdp() {
+        let sdp = Sdp::from("v=0\r\na=\"\\\u{0}\r\n");
+
+        let json = ::serde_json::to_string(&sdp).unwrap();
+
+        assert_eq!(json, r#""v=0\r\na=\"\\\u0000\r\n""#);
+
+        let sdp_2: Sdp = unsafe { ::simd_json::serde::from_str(&mut json.clone()).unwrap() };
+
+        assert_eq!(sdp, sdp_2);
+    }
+
     #[quickcheck]
     fn quickcheck_serde_20_bytes(info_hash: InfoHash) -> bool {
         unsafe {
@@ -13,6 +13,7 @@
 pub mod common;
 pub mod incoming;
 pub mod outgoing;
+pub mod sdp;
 
 pub use common::*;
 pub use incoming::*;
@@ -259,6 +260,15 @@ mod tests {
         success
     }
 
+    #[quickcheck]
+    fn quickcheck_encoded_scrape_response(response: ScrapeResponse) -> bool {
+        let encoded = OutMessage::EncodedScrapeResponse(EncodedScrapeResponse::new(&response));
+
+        let parsed = OutMessage::from_ws_message(encoded.to_ws_message()).unwrap();
+
+        parsed == OutMessage::ScrapeResponse(response)
+    }
+
     fn info_hash_from_bytes(bytes: &[u8]) -> InfoHash {
         let mut arr = [0u8; 20];
 
@@ -21,6 +21,9 @@ pub enum OutMessage {
     AnnounceResponse(AnnounceResponse),
     ScrapeResponse(ScrapeResponse),
     ErrorResponse(ErrorResponse),
+    /// Only sent by tracker, never parsed
+    #[serde(skip_deserializing)]
+    EncodedScrapeResponse(EncodedScrapeResponse),
 }
 
 impl OutMessage {
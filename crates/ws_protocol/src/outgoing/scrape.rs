@@ -1,5 +1,8 @@
+use std::sync::Arc;
+
 use hashbrown::HashMap;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::value::RawValue;
 
 use crate::common::*;
 
@@ -11,6 +14,40 @@ pub struct ScrapeResponse {
     // pub flags: HashMap<String, usize>,
 }
 
+/// Scrape response serialized in advance, e.g., for serving the same full
+/// scrape response many times. Cloning is cheap.
+#[derive(Debug, Clone)]
+pub struct EncodedScrapeResponse(Arc<RawValue>);
+
+impl EncodedScrapeResponse {
+    pub fn new(response: &ScrapeResponse) -> Self {
+        let json = ::serde_json::to_string(response).unwrap();
+
+        Self(RawValue::from_string(json).unwrap().into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.get()
+    }
+}
+
+impl PartialEq for EncodedScrapeResponse {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for EncodedScrapeResponse {}
+
+impl Serialize for EncodedScrapeResponse {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.0.serialize(serializer)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ScrapeStatistics {
     pub complete: usize,
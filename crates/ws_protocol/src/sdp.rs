@@ -0,0 +1,471 @@
+//! Validation and sanitization of session descriptions (SDP, RFC 8866) in
+//! WebRTC offers and answers
+//!
+//! WebTorrent peers only use data channels, so media descriptions other than
+//! `m=application` with an SCTP based transport are not allowed.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::net::IpAddr;
+
+/// Line types defined in RFC 8866
+const KNOWN_LINE_TYPES: &[u8] = b"vosiuepcbtrzkam";
+const DATA_CHANNEL_PROTOCOLS: &[&str] = &["UDP/DTLS/SCTP", "TCP/DTLS/SCTP", "DTLS/SCTP"];
+const CANDIDATE_TYPES: &[&str] = &["host", "srflx", "prflx", "relay"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SdpValidationRules {
+    /// Reject SDP with unknown line types, empty lines or invalid candidates
+    /// instead of removing those lines, and require a session name and
+    /// exactly one media description
+    pub strict: bool,
+    /// Maximum SDP size in bytes
+    pub max_size: usize,
+    /// Maximum number of ICE candidates
+    pub max_candidates: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SdpError {
+    TooLarge,
+    MalformedLine,
+    InvalidVersion,
+    InvalidOrigin,
+    MissingSessionName,
+    NoMediaDescription,
+    TooManyMediaDescriptions,
+    UnsupportedMedia,
+    UnknownLineType,
+    InvalidCandidate,
+    TooManyCandidates,
+}
+
+impl SdpError {
+    /// Short identifier, e.g., for use as metrics label
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::TooLarge => "too_large",
+            Self::MalformedLine => "malformed_line",
+            Self::InvalidVersion => "invalid_version",
+            Self::InvalidOrigin => "invalid_origin",
+            Self::MissingSessionName => "missing_session_name",
+            Self::NoMediaDescription => "no_media_description",
+            Self::TooManyMediaDescriptions => "too_many_media_descriptions",
+            Self::UnsupportedMedia => "unsupported_media",
+            Self::UnknownLineType => "unknown_line_type",
+            Self::InvalidCandidate => "invalid_candidate",
+            Self::TooManyCandidates => "too_many_candidates",
+        }
+    }
+}
+
+impl fmt::Display for SdpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Self::TooLarge => "SDP too large",
+            Self::MalformedLine => "malformed SDP line",
+            Self::InvalidVersion => "SDP doesn't start with v=0",
+            Self::InvalidOrigin => "missing, duplicate or invalid SDP origin",
+            Self::MissingSessionName => "missing SDP session name",
+            Self::NoMediaDescription => "no SDP media description",
+            Self::TooManyMediaDescriptions => "too many SDP media descriptions",
+            Self::UnsupportedMedia => "SDP media is not a data channel",
+            Self::UnknownLineType => "unknown SDP line type",
+            Self::InvalidCandidate => "invalid ICE candidate in SDP",
+            Self::TooManyCandidates => "too many ICE candidates in SDP",
+        };
+
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for SdpError {}
+
+/// Validate SDP, removing lines that are not allowed unless rules are strict
+///
+/// Returns borrowed input if no lines were removed. Otherwise, returns the
+/// remaining lines joined with CRLF line endings.
+pub fn sanitize_sdp<'a>(
+    sdp: &'a str,
+    rules: &SdpValidationRules,
+) -> Result<Cow<'a, str>, SdpError> {
+    if sdp.len() > rules.max_size {
+        return Err(SdpError::TooLarge);
+    }
+
+    let mut lines = Vec::new();
+    let mut lines_removed = false;
+    let mut has_origin = false;
+    let mut has_session_name = false;
+    let mut num_media_descriptions = 0usize;
+    let mut num_candidates = 0usize;
+
+    // Final line ending is optional. Bare LF line endings are accepted.
+    let sdp_lines = sdp
+        .strip_suffix('\n')
+        .unwrap_or(sdp)
+        .split('\n')
+        .map(|line| line.strip_suffix('\r').unwrap_or(line));
+
+    for (line_index, line) in sdp_lines.enumerate() {
+        if line_index == 0 {
+            if line != "v=0" {
+                return Err(SdpError::InvalidVersion);
+            }
+
+            lines.push(line);
+
+            continue;
+        }
+
+        if line.is_empty() && !rules.strict {
+            lines_removed = true;
+
+            continue;
+        }
+
+        let (line_type, value) = match line.as_bytes() {
+            [line_type, b'=', ..] => (*line_type, &line[2..]),
+            _ => return Err(SdpError::MalformedLine),
+        };
+
+        if value.chars().any(|c| c.is_control() && c != '\t') {
+            return Err(SdpError::MalformedLine);
+        }
+
+        let in_media_description = num_media_descriptions > 0;
+
+        match line_type {
+            b'v' => return Err(SdpError::InvalidVersion),
+            b'o' => {
+                if has_origin || in_media_description || value.split(' ').count() != 6 {
+                    return Err(SdpError::InvalidOrigin);
+                }
+
+                has_origin = true;
+            }
+            b's' => {
+                has_session_name = true;
+            }
+            b'm' => {
+                if !media_description_is_data_channel(value) {
+                    return Err(SdpError::UnsupportedMedia);
+                }
+
+                num_media_descriptions += 1;
+
+                if rules.strict && num_media_descriptions > 1 {
+                    return Err(SdpError::TooManyMediaDescriptions);
+                }
+            }
+            b'a' if value.starts_with("candidate:") => {
+                // Candidates are media-level attributes
+                if !(in_media_description && candidate_is_valid(&value["candidate:".len()..])) {
+                    if rules.strict {
+                        return Err(SdpError::InvalidCandidate);
+                    }
+
+                    lines_removed = true;
+
+                    continue;
+                }
+
+                num_candidates += 1;
+
+                if num_candidates > rules.max_candidates {
+                    return Err(SdpError::TooManyCandidates);
+                }
+            }
+            line_type if !KNOWN_LINE_TYPES.contains(&line_type) => {
+                if rules.strict {
+                    return Err(SdpError::UnknownLineType);
+                }
+
+                lines_removed = true;
+
+                continue;
+            }
+            _ => (),
+        }
+
+        lines.push(line);
+    }
+
+    if !has_origin {
+        return Err(SdpError::InvalidOrigin);
+    }
+    if rules.strict && !has_session_name {
+        return Err(SdpError::MissingSessionName);
+    }
+    if num_media_descriptions == 0 {
+        return Err(SdpError::NoMediaDescription);
+    }
+
+    if lines_removed {
+        let mut sanitized = lines.join("\r\n");
+
+        sanitized.push_str("\r\n");
+
+        Ok(Cow::Owned(sanitized))
+    } else {
+        Ok(Cow::Borrowed(sdp))
+    }
+}
+
+/// Check media description value, e.g.,
+/// `application 9 UDP/DTLS/SCTP webrtc-datachannel`
+fn media_description_is_data_channel(value: &str) -> bool {
+    let mut fields = value.split(' ');
+
+    let media = fields.next();
+    let port = fields.next();
+    let protocol = fields.next();
+    let format = fields.next();
+
+    media == Some("application")
+        && port.and_then(|port| port.parse::<u16>().ok()).is_some()
+        && protocol
+            .map(|protocol| DATA_CHANNEL_PROTOCOLS.contains(&protocol))
+            .unwrap_or(false)
+        && format.map(|format| !format.is_empty()).unwrap_or(false)
+}
+
+/// Check candidate attribute value (RFC 8839) following `candidate:`, e.g.,
+/// `842163049 1 udp 1677729535 192.0.2.1 46154 typ srflx raddr 0.0.0.0 rport 0`
+fn candidate_is_valid(value: &str) -> bool {
+    let fields = value.split(' ').collect::<Vec<_>>();
+
+    let (foundation, component, transport, priority, address, port, typ, candidate_type) =
+        match fields[..] {
+            [foundation, component, transport, priority, address, port, typ, candidate_type, ..] => {
+                (
+                    foundation,
+                    component,
+                    transport,
+                    priority,
+                    address,
+                    port,
+                    typ,
+                    candidate_type,
+                )
+            }
+            _ => return false,
+        };
+
+    let foundation_valid = (1..=32).contains(&foundation.len())
+        && foundation
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/');
+    let component_valid = component
+        .parse::<u16>()
+        .map(|component| (1..=256).contains(&component))
+        .unwrap_or(false);
+    let transport_valid =
+        transport.eq_ignore_ascii_case("udp") || transport.eq_ignore_ascii_case("tcp");
+
+    // Extension attributes are name-value pairs
+    let extensions_valid = fields.len() % 2 == 0;
+
+    foundation_valid
+        && component_valid
+        && transport_valid
+        && priority.parse::<u32>().is_ok()
+        && candidate_address_is_valid(address)
+        && port.parse::<u16>().is_ok()
+        && typ == "typ"
+        && CANDIDATE_TYPES.contains(&candidate_type)
+        && extensions_valid
+}
+
+/// Address must be a routable IP address or an mDNS hostname
+/// (`<uuid>.local`) used by browsers to hide local addresses
+fn candidate_address_is_valid(address: &str) -> bool {
+    if let Ok(ip) = address.parse::<IpAddr>() {
+        let broadcast = match ip {
+            IpAddr::V4(ip) => ip.is_broadcast(),
+            IpAddr::V6(_) => false,
+        };
+
+        !(ip.is_unspecified() || ip.is_loopback() || ip.is_multicast() || broadcast)
+    } else if let Some(name) = address.strip_suffix(".local") {
+        !name.is_empty()
+            && name.len() <= 63
+            && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHROME_OFFER: &str = "v=0\r\n\
+        o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n\
+        s=-\r\n\
+        t=0 0\r\n\
+        a=group:BUNDLE 0\r\n\
+        a=extmap-allow-mixed\r\n\
+        a=msid-semantic: WMS\r\n\
+        m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+        c=IN IP4 0.0.0.0\r\n\
+        a=candidate:3115260372 1 udp 2113937151 4c3a0b1e-3f4d-4b8e-9f2a-1d2c3b4a5f6e.local 54400 typ host generation 0 network-cost 999\r\n\
+        a=candidate:842163049 1 udp 1677729535 192.0.2.1 54400 typ srflx raddr 0.0.0.0 rport 0 generation 0 network-cost 999\r\n\
+        a=ice-ufrag:Wf3C\r\n\
+        a=ice-pwd:9+nD1o5VW2bqXjFlPmXbMZ3W\r\n\
+        a=ice-options:trickle\r\n\
+        a=fingerprint:sha-256 3C:4A:2B:1D:0E:9F:8A:7B:6C:5D:4E:3F:2A:1B:0C:9D:8E:7F:6A:5B:4C:3D:2E:1F:0A:9B:8C:7D:6E:5F:4A:3B\r\n\
+        a=setup:actpass\r\n\
+        a=mid:0\r\n\
+        a=sctp-port:5000\r\n\
+        a=max-message-size:262144\r\n";
+
+    fn rules(strict: bool) -> SdpValidationRules {
+        SdpValidationRules {
+            strict,
+            max_size: 8192,
+            max_candidates: 4,
+        }
+    }
+
+    #[test]
+    fn test_valid_sdp() {
+        for strict in [false, true] {
+            assert_eq!(
+                sanitize_sdp(CHROME_OFFER, &rules(strict)),
+                Ok(Cow::Borrowed(CHROME_OFFER))
+            );
+        }
+
+        let firefox_answer = "v=0\n\
+            o=mozilla...THIS_IS_SDPARTA-99.0 5917418204146893232 0 IN IP4 0.0.0.0\n\
+            s=-\n\
+            t=0 0\n\
+            a=fingerprint:sha-256 3C:4A:2B:1D\n\
+            a=group:BUNDLE 0\n\
+            m=application 9 UDP/DTLS/SCTP webrtc-datachannel\n\
+            c=IN IP4 0.0.0.0\n\
+            a=candidate:0 1 UDP 2122252543 2001:db8::1 50735 typ host\n\
+            a=candidate:1 1 TCP 2105524479 2001:db8::1 9 typ host tcptype active\n\
+            a=sendrecv\n\
+            a=setup:active\n";
+
+        assert!(sanitize_sdp(firefox_answer, &rules(true)).is_ok());
+    }
+
+    #[test]
+    fn test_sanitize_sdp() {
+        let sdp = CHROME_OFFER.replace(
+            "a=ice-ufrag",
+            "a=candidate:1 1 udp 1 127.0.0.1 1000 typ host\r\n\
+            x=unknown\r\n\
+            \r\n\
+            a=ice-ufrag",
+        );
+
+        assert_eq!(
+            sanitize_sdp(&sdp, &rules(false)),
+            Ok(Cow::Owned(CHROME_OFFER.to_string()))
+        );
+
+        let sdp = CHROME_OFFER.replace("\r\n", "\n");
+
+        assert_eq!(
+            sanitize_sdp(&sdp, &rules(false)),
+            Ok(Cow::Borrowed(&sdp[..]))
+        );
+    }
+
+    #[test]
+    fn test_strict_sdp() {
+        let with_invalid_candidate = CHROME_OFFER.replace(
+            "a=ice-ufrag",
+            "a=candidate:1 1 udp 1 0.0.0.0 1000 typ host\r\na=ice-ufrag",
+        );
+        let with_unknown_line = CHROME_OFFER.replace("a=ice-ufrag", "x=unknown\r\na=ice-ufrag");
+        let with_two_media_descriptions = format!(
+            "{}m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n",
+            CHROME_OFFER
+        );
+
+        assert_eq!(
+            sanitize_sdp(&with_invalid_candidate, &rules(true)),
+            Err(SdpError::InvalidCandidate)
+        );
+        assert_eq!(
+            sanitize_sdp(&with_unknown_line, &rules(true)),
+            Err(SdpError::UnknownLineType)
+        );
+        assert_eq!(
+            sanitize_sdp(&with_two_media_descriptions, &rules(true)),
+            Err(SdpError::TooManyMediaDescriptions)
+        );
+        assert!(sanitize_sdp(&with_two_media_descriptions, &rules(false)).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_sdp() {
+        let cases = [
+            ("test".to_string(), SdpError::InvalidVersion),
+            (
+                CHROME_OFFER.replace(
+                    "m=application 9 UDP/DTLS/SCTP",
+                    "m=video 9 UDP/TLS/RTP/SAVPF",
+                ),
+                SdpError::UnsupportedMedia,
+            ),
+            (
+                CHROME_OFFER.replace("o=- 4611731400430051336 2 IN IP4 127.0.0.1\r\n", ""),
+                SdpError::InvalidOrigin,
+            ),
+            (
+                CHROME_OFFER.replace("s=-", "s=\u{7}"),
+                SdpError::MalformedLine,
+            ),
+            (CHROME_OFFER.replace("t=0 0", "t"), SdpError::MalformedLine),
+            (
+                "v=0\r\no=- 1 2 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\n".to_string(),
+                SdpError::NoMediaDescription,
+            ),
+            (
+                CHROME_OFFER.replace(
+                    "a=ice-ufrag",
+                    "a=candidate:1 1 udp 1 192.0.2.2 1 typ host\r\n\
+                    a=candidate:2 1 udp 1 192.0.2.3 1 typ host\r\n\
+                    a=candidate:3 1 udp 1 192.0.2.4 1 typ host\r\n\
+                    a=ice-ufrag",
+                ),
+                SdpError::TooManyCandidates,
+            ),
+            ("a".repeat(8193), SdpError::TooLarge),
+        ];
+
+        for (sdp, expected_error) in cases {
+            for strict in [false, true] {
+                assert_eq!(sanitize_sdp(&sdp, &rules(strict)), Err(expected_error));
+            }
+        }
+    }
+
+    #[test]
+    fn test_candidate_is_valid() {
+        assert!(candidate_is_valid(
+            "842163049 1 udp 1677729535 192.0.2.1 46154 typ srflx raddr 0.0.0.0 rport 0"
+        ));
+        assert!(candidate_is_valid(
+            "1 1 tcp 1 abc-123.local 9 typ host tcptype active"
+        ));
+
+        assert!(!candidate_is_valid("1 1 udp 1 192.0.2.1 46154 typ"));
+        assert!(!candidate_is_valid("1 0 udp 1 192.0.2.1 46154 typ host"));
+        assert!(!candidate_is_valid("1 1 sctp 1 192.0.2.1 46154 typ host"));
+        assert!(!candidate_is_valid("1 1 udp 1 224.0.0.1 46154 typ host"));
+        assert!(!candidate_is_valid("1 1 udp 1 ::1 46154 typ host"));
+        assert!(!candidate_is_valid("1 1 udp 1 example.com 46154 typ host"));
+        assert!(!candidate_is_valid("1 1 udp 1 192.0.2.1 65536 typ host"));
+        assert!(!candidate_is_valid("1 1 udp 1 192.0.2.1 1 typ other"));
+        assert!(!candidate_is_valid(
+            "1 1 udp 1 192.0.2.1 1 typ host generation"
+        ));
+    }
+}